@@ -27,8 +27,8 @@ pub async fn run(services: Vec<String>, detach: bool) -> Result<()> {
     } else {
         services
             .iter()
-            .filter(|s| all_services.contains(&s.as_str()))
-            .map(|s| s.to_string())
+            .map(|s| s.as_str())
+            .filter(|s| all_services.contains(s))
             .collect()
     };
 
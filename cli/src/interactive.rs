@@ -159,6 +159,7 @@ pub async fn mark_tma_interactive(client: &ApiClient) -> Result<()> {
         },
         file_path: file_path.clone(),
         rubric_path,
+        ..Default::default()
     };
 
     let upload_result = client.upload_tma(&submission).await?;
@@ -243,7 +244,7 @@ pub async fn mark_tma_interactive(client: &ApiClient) -> Result<()> {
         }
         2 => {
             // Mark another TMA
-            return mark_tma_interactive(client).await;
+            return Box::pin(mark_tma_interactive(client)).await;
         }
         3 => {
             // Upload to Moodle
@@ -40,10 +40,11 @@ fi
 
 # Build container image
 echo -e "${GREEN}Building container image...${NC}"
+# The context is components/ so the shared ipc-protocol crate is included
 "${CONTAINER_RUNTIME}" build \
     -t "${IMAGE_NAME}:${IMAGE_TAG}" \
     -f Containerfile \
-    .
+    ..
 
 if [ $? -eq 0 ]; then
     echo ""
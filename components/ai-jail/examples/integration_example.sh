@@ -8,24 +8,29 @@ CONTAINER_RUNTIME="${CONTAINER_RUNTIME:-podman}"
 IMAGE_NAME="ai-jail:latest"
 MODELS_DIR="${MODELS_DIR:-/models}"
 
-# Request template
+# Request template (aws-ipc-protocol FeedbackRequest, one JSON object per line)
 create_request() {
-    local tma_content="$1"
-    local rubric="$2"
-    local question_number="$3"
-    local student_answer="${4:-}"
-
-    cat <<EOF
-{
-  "tma_content": "${tma_content}",
-  "rubric": "${rubric}",
-  "question_number": ${question_number},
-  "student_answer": ${student_answer:+\"$student_answer\"},
-  "max_tokens": 512,
-  "temperature": 0.7,
-  "top_p": 0.9
-}
-EOF
+    local request_id="$1"
+    local content="$2"
+    local rubric="$3"
+    local question_number="$4"
+
+    jq -cn \
+        --arg request_id "${request_id}" \
+        --arg content "${content}" \
+        --arg rubric "${rubric}" \
+        --argjson question_number "${question_number}" \
+        '{
+            type: "FeedbackRequest",
+            payload: {
+                request_id: $request_id,
+                content: $content,
+                rubric: $rubric,
+                criteria: [],
+                question_number: $question_number,
+                params: {max_tokens: 512, temperature: 0.7, top_p: 0.9}
+            }
+        }'
 }
 
 # Start AI Jail container
@@ -61,8 +66,8 @@ send_request() {
     echo ""
 
     # Extract feedback
-    feedback=$(echo "${response}" | jq -r '.feedback // .message')
-    confidence=$(echo "${response}" | jq -r '.confidence // 0')
+    feedback=$(echo "${response}" | jq -r '.payload.feedback // .payload.message')
+    confidence=$(echo "${response}" | jq -r '.payload.metadata.confidence // 0')
 
     echo "Feedback: ${feedback}"
     echo "Confidence: ${confidence}"
@@ -81,10 +86,10 @@ main() {
     echo "-----------------------------"
 
     request=$(create_request \
-        "Explain the water cycle." \
+        "example-1" \
+        "Water evaporates from oceans, forms clouds, and falls as rain." \
         "Award 5 marks for covering evaporation, condensation, and precipitation." \
-        1 \
-        "Water evaporates from oceans, forms clouds, and falls as rain.")
+        1)
 
     send_request "${request}"
 
@@ -14,6 +14,7 @@ IMAGE_NAME="${IMAGE_NAME:-ai-jail}"
 IMAGE_TAG="${IMAGE_TAG:-latest}"
 CONTAINER_RUNTIME="${CONTAINER_RUNTIME:-podman}"
 MODELS_DIR="${MODELS_DIR:-/models}"
+QUANTIZATION="${QUANTIZATION:-q4}"
 
 # Security options
 NETWORK_MODE="none"
@@ -46,7 +47,12 @@ if [ ! -d "${MODELS_DIR}/mistral-7b" ]; then
 fi
 
 # Check if model files exist
-MODEL_FILE="${MODELS_DIR}/mistral-7b/model.safetensors"
+case "${QUANTIZATION}" in
+    none|fp16) MODEL_NAME="model.safetensors" ;;
+    q8) MODEL_NAME="model-q8_0.gguf" ;;
+    *) MODEL_NAME="model-q4_k.gguf" ;;
+esac
+MODEL_FILE="${MODELS_DIR}/mistral-7b/${MODEL_NAME}"
 TOKENIZER_FILE="${MODELS_DIR}/mistral-7b/tokenizer.json"
 
 if [ ! -f "${MODEL_FILE}" ]; then
@@ -80,6 +86,11 @@ echo ""
     --cpu-shares="${CPU_SHARES}" \
     -v "${MODELS_DIR}:/models:ro" \
     -e RUST_LOG="${RUST_LOG:-info}" \
+    -e QUANTIZATION="${QUANTIZATION}" \
+    -e MODEL_PATH="/models/mistral-7b/${MODEL_NAME}" \
+    -e MODEL_MANIFEST \
+    -e MODEL_MANIFEST_KEY_FILE \
+    -e ALLOW_UNVERIFIED_MODELS \
     "${IMAGE_NAME}:${IMAGE_TAG}"
 
 echo ""
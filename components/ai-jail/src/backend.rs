@@ -0,0 +1,637 @@
+//! Inference backends
+//!
+//! The inference engine drives generation through the [`InferenceBackend`]
+//! trait. The Candle model in `model.rs` is the production backend; the
+//! byte-level mock backends in this module let the jail run end to end
+//! without model weights or a GPU.
+
+use anyhow::{Context, Result};
+use candle_core::{Device, Tensor};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::prompt::ChatFormat;
+
+/// Token-level model interface used by the inference engine
+pub trait InferenceBackend {
+    /// Short name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Device that input tensors must be created on
+    fn device(&self) -> &Device;
+
+    /// Encode text to token IDs
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>>;
+
+    /// Decode token IDs to text
+    fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String>;
+
+    /// Get the end-of-sequence token ID
+    fn eos_token_id(&self) -> Option<u32>;
+
+    /// Number of token IDs the tokenizer knows
+    fn vocab_size(&self) -> usize;
+
+    /// Bytes a token adds to the output when it follows other text
+    ///
+    /// Used to build grammar masks. Special tokens map to no bytes. The
+    /// default decodes the token on its own, which loses the leading space
+    /// some tokenizers attach to word-initial tokens; backends with such a
+    /// tokenizer should override it.
+    fn token_bytes(&self, token: u32) -> Result<Vec<u8>> {
+        Ok(self.decode(&[token], true)?.into_bytes())
+    }
+
+    /// Layout the model expects its prompt in
+    ///
+    /// The mock backends' byte tokenizer knows the ChatML markers, so
+    /// that is the default.
+    fn chat_format(&self) -> ChatFormat {
+        ChatFormat::ChatMl
+    }
+
+    /// Most tokens the model attends to at once: the prompt plus
+    /// everything generated after it
+    fn context_length(&self) -> usize {
+        DEFAULT_CONTEXT_LENGTH
+    }
+
+    /// Run the model and return logits for the last input position
+    ///
+    /// `input_ids` has shape `(batch, seq_len)`; the result has shape
+    /// `(batch, 1, vocab_size)`. `seqlen_offset` is the number of tokens
+    /// already in the KV cache: the engine feeds the prompt at offset 0 and
+    /// then one new token per step.
+    fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor>;
+
+    /// Most sequences [`forward_padded`](Self::forward_padded) can decode
+    /// together; 1 means the engine decodes sequences one at a time
+    fn max_batch_size(&self) -> usize {
+        1
+    }
+
+    /// Run the model over a batch of left-padded rows
+    ///
+    /// Row `i` of `input_ids` started with `padding[i]` pad tokens in the
+    /// first pass, which must not be attended to in that pass or any later
+    /// one. Otherwise this behaves like [`forward`](Self::forward). The
+    /// default only handles batches without padding; backends that report
+    /// a `max_batch_size` above 1 must override it.
+    fn forward_padded(
+        &mut self,
+        input_ids: &Tensor,
+        padding: &[usize],
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        if padding.iter().any(|&p| p > 0) {
+            anyhow::bail!("The {} backend cannot mask padding", self.name());
+        }
+        self.forward(input_ids, seqlen_offset)
+    }
+
+    /// Memory held by the model's weights, in bytes; 0 if negligible
+    fn memory_usage(&self) -> usize {
+        0
+    }
+
+    /// Clear per-sequence state (e.g. the KV cache) before a new generation
+    fn reset(&mut self);
+}
+
+/// Backend selected at startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Mistral model loaded with Candle
+    Candle,
+    /// Mock that repeats the prompt back
+    Echo,
+    /// Mock that replays canned responses
+    Scripted,
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "candle" => Ok(BackendKind::Candle),
+            "echo" => Ok(BackendKind::Echo),
+            "scripted" => Ok(BackendKind::Scripted),
+            other => anyhow::bail!(
+                "Unknown backend '{}' (expected candle, echo or scripted)",
+                other
+            ),
+        }
+    }
+}
+
+/// Context window assumed when the model does not say otherwise: the
+/// sequence length Mistral 7B was trained on
+pub const DEFAULT_CONTEXT_LENGTH: usize = 8192;
+
+/// Context window from `CONTEXT_LENGTH`, or the default if it is unset
+pub fn context_length_from_env() -> Result<usize> {
+    match std::env::var("CONTEXT_LENGTH") {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("Invalid CONTEXT_LENGTH '{}'", value)),
+        Err(_) => Ok(DEFAULT_CONTEXT_LENGTH),
+    }
+}
+
+/// Logit given to the forced token so it wins under any sampling settings
+const FORCED_LOGIT: f32 = 1.0e4;
+
+/// Rows the mock backends decode together
+const MOCK_MAX_BATCH_SIZE: usize = 8;
+
+const BOS_TOKEN: u32 = 1;
+const EOS_TOKEN: u32 = 2;
+
+/// Special tokens recognised by the byte tokenizer, indexed by token ID
+const SPECIAL_TOKENS: [&str; 5] = ["<unk>", "<s>", "</s>", "<|im_start|>", "<|im_end|>"];
+
+/// Tokenizer for the mock backends: one token per byte plus a few
+/// special tokens, so no tokenizer file is needed
+#[derive(Debug, Clone, Copy, Default)]
+struct ByteTokenizer;
+
+impl ByteTokenizer {
+    const BYTE_OFFSET: u32 = SPECIAL_TOKENS.len() as u32;
+    const VOCAB_SIZE: usize = SPECIAL_TOKENS.len() + 256;
+
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Vec<u32> {
+        let mut tokens = Vec::with_capacity(text.len() + 1);
+        if add_special_tokens {
+            tokens.push(BOS_TOKEN);
+        }
+
+        let mut rest = text;
+        'outer: while !rest.is_empty() {
+            for (id, special) in SPECIAL_TOKENS.iter().enumerate().skip(1) {
+                if let Some(tail) = rest.strip_prefix(special) {
+                    tokens.push(id as u32);
+                    rest = tail;
+                    continue 'outer;
+                }
+            }
+
+            let len = rest.chars().next().map_or(1, char::len_utf8);
+            tokens.extend(
+                rest.as_bytes()[..len]
+                    .iter()
+                    .map(|&b| Self::BYTE_OFFSET + b as u32),
+            );
+            rest = &rest[len..];
+        }
+
+        tokens
+    }
+
+    fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> String {
+        let mut bytes = Vec::with_capacity(tokens.len());
+        for &token in tokens {
+            if Self::is_special(token) {
+                if !skip_special_tokens {
+                    bytes.extend_from_slice(SPECIAL_TOKENS[token as usize].as_bytes());
+                }
+            } else if let Ok(byte) = u8::try_from(token - Self::BYTE_OFFSET) {
+                bytes.push(byte);
+            }
+        }
+
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    fn is_special(token: u32) -> bool {
+        token < Self::BYTE_OFFSET
+    }
+
+    fn token_bytes(&self, token: u32) -> Vec<u8> {
+        match token.checked_sub(Self::BYTE_OFFSET).map(u8::try_from) {
+            Some(Ok(byte)) => vec![byte],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Logits of shape `(batch, 1, vocab)` that force row `i` to sample
+    /// `tokens[i]`
+    fn forced_logits(tokens: &[u32], device: &Device) -> Result<Tensor> {
+        let mut logits = vec![0.0f32; tokens.len() * Self::VOCAB_SIZE];
+        for (row, &token) in tokens.iter().enumerate() {
+            logits[row * Self::VOCAB_SIZE + token as usize] = FORCED_LOGIT;
+        }
+        let shape = (tokens.len(), 1, Self::VOCAB_SIZE);
+        Ok(Tensor::from_vec(logits, shape, device)?)
+    }
+}
+
+/// Mock backend that repeats the prompt text back, then ends the sequence
+///
+/// Special tokens in the prompt are skipped, so the output is the plain
+/// prompt text truncated to the token budget. Each row of a batch echoes
+/// its own prompt; padding is made of special tokens and so is skipped too.
+pub struct EchoBackend {
+    tokenizer: ByteTokenizer,
+    device: Device,
+    /// Prompt of each row, captured from the first pass after a reset
+    prompts: Option<Vec<Vec<u32>>>,
+    cursor: usize,
+    context_length: usize,
+}
+
+impl EchoBackend {
+    pub fn new() -> Self {
+        Self {
+            tokenizer: ByteTokenizer,
+            device: Device::Cpu,
+            prompts: None,
+            cursor: 0,
+            context_length: DEFAULT_CONTEXT_LENGTH,
+        }
+    }
+
+    /// Report a different context window, e.g. to exercise long answers
+    pub fn with_context_length(mut self, tokens: usize) -> Self {
+        self.context_length = tokens;
+        self
+    }
+}
+
+impl Default for EchoBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InferenceBackend for EchoBackend {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        Ok(self.tokenizer.encode(text, add_special_tokens))
+    }
+
+    fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+        Ok(self.tokenizer.decode(tokens, skip_special_tokens))
+    }
+
+    fn eos_token_id(&self) -> Option<u32> {
+        Some(EOS_TOKEN)
+    }
+
+    fn vocab_size(&self) -> usize {
+        ByteTokenizer::VOCAB_SIZE
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    fn token_bytes(&self, token: u32) -> Result<Vec<u8>> {
+        Ok(self.tokenizer.token_bytes(token))
+    }
+
+    fn forward(&mut self, input_ids: &Tensor, _seqlen_offset: usize) -> Result<Tensor> {
+        if self.prompts.is_none() {
+            let prompts = input_ids
+                .to_vec2::<u32>()?
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .filter(|&t| !ByteTokenizer::is_special(t))
+                        .collect()
+                })
+                .collect();
+            self.prompts = Some(prompts);
+        }
+
+        let tokens: Vec<u32> = self
+            .prompts
+            .iter()
+            .flatten()
+            .map(|prompt| prompt.get(self.cursor).copied().unwrap_or(EOS_TOKEN))
+            .collect();
+        self.cursor += 1;
+
+        ByteTokenizer::forced_logits(&tokens, &self.device)
+    }
+
+    fn max_batch_size(&self) -> usize {
+        MOCK_MAX_BATCH_SIZE
+    }
+
+    fn forward_padded(
+        &mut self,
+        input_ids: &Tensor,
+        _padding: &[usize],
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        self.forward(input_ids, seqlen_offset)
+    }
+
+    fn reset(&mut self) {
+        self.prompts = None;
+        self.cursor = 0;
+    }
+}
+
+/// Mock backend that replays canned responses in order
+///
+/// Each generation emits the next response followed by end-of-sequence,
+/// wrapping around after the last one. The rows of a batch take
+/// consecutive responses.
+pub struct ScriptedBackend {
+    tokenizer: ByteTokenizer,
+    device: Device,
+    responses: Vec<Vec<u32>>,
+    /// Sequences served before the current generation
+    served: usize,
+    /// Rows in the current generation, once its first pass has run
+    rows: usize,
+    cursor: usize,
+    context_length: usize,
+    /// Time each forward pass takes
+    token_delay: Duration,
+}
+
+impl ScriptedBackend {
+    /// Response served when no script file is configured
+    pub const DEFAULT_RESPONSE: &'static str = "Good use of examples to support the main argument.\n\
+        Consider linking each point back to the question.\n\
+        Overall the answer meets most of the rubric criteria.";
+
+    /// Separator between responses in a script file
+    pub const SEPARATOR: &'static str = "---";
+
+    pub fn new(responses: Vec<String>) -> Self {
+        let tokenizer = ByteTokenizer;
+        let mut responses: Vec<Vec<u32>> = responses
+            .iter()
+            .map(|r| tokenizer.encode(r, false))
+            .collect();
+
+        if responses.is_empty() {
+            responses.push(tokenizer.encode(Self::DEFAULT_RESPONSE, false));
+        }
+
+        Self {
+            tokenizer,
+            device: Device::Cpu,
+            responses,
+            served: 0,
+            rows: 0,
+            cursor: 0,
+            context_length: DEFAULT_CONTEXT_LENGTH,
+            token_delay: Duration::ZERO,
+        }
+    }
+
+    /// Report a different context window, e.g. to exercise long answers
+    pub fn with_context_length(mut self, tokens: usize) -> Self {
+        self.context_length = tokens;
+        self
+    }
+
+    /// Sleep for `delay` on every forward pass, standing in for a slow
+    /// model, e.g. to exercise timeouts and cancellation
+    pub fn with_token_delay(mut self, delay: Duration) -> Self {
+        self.token_delay = delay;
+        self
+    }
+
+    /// Load responses from a file, separated by lines containing only `---`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let script = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read mock script {}", path.display()))?;
+
+        let mut responses = Vec::new();
+        let mut current = Vec::new();
+        for line in script.lines() {
+            if line.trim() == Self::SEPARATOR {
+                responses.push(current.join("\n"));
+                current.clear();
+            } else {
+                current.push(line);
+            }
+        }
+        responses.push(current.join("\n"));
+
+        let responses = responses
+            .into_iter()
+            .map(|r| r.trim().to_string())
+            .filter(|r| !r.is_empty())
+            .collect();
+
+        Ok(Self::new(responses))
+    }
+}
+
+impl Default for ScriptedBackend {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl InferenceBackend for ScriptedBackend {
+    fn name(&self) -> &'static str {
+        "scripted"
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        Ok(self.tokenizer.encode(text, add_special_tokens))
+    }
+
+    fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+        Ok(self.tokenizer.decode(tokens, skip_special_tokens))
+    }
+
+    fn eos_token_id(&self) -> Option<u32> {
+        Some(EOS_TOKEN)
+    }
+
+    fn vocab_size(&self) -> usize {
+        ByteTokenizer::VOCAB_SIZE
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
+
+    fn token_bytes(&self, token: u32) -> Result<Vec<u8>> {
+        Ok(self.tokenizer.token_bytes(token))
+    }
+
+    fn forward(&mut self, input_ids: &Tensor, _seqlen_offset: usize) -> Result<Tensor> {
+        self.rows = input_ids.dim(0)?;
+        if !self.token_delay.is_zero() {
+            std::thread::sleep(self.token_delay);
+        }
+
+        let tokens: Vec<u32> = (self.served..self.served + self.rows)
+            .map(|n| &self.responses[n % self.responses.len()])
+            .map(|response| response.get(self.cursor).copied().unwrap_or(EOS_TOKEN))
+            .collect();
+        self.cursor += 1;
+
+        ByteTokenizer::forced_logits(&tokens, &self.device)
+    }
+
+    fn max_batch_size(&self) -> usize {
+        MOCK_MAX_BATCH_SIZE
+    }
+
+    fn forward_padded(
+        &mut self,
+        input_ids: &Tensor,
+        _padding: &[usize],
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        self.forward(input_ids, seqlen_offset)
+    }
+
+    fn reset(&mut self) {
+        self.served += self.rows;
+        self.rows = 0;
+        self.cursor = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(backend: &dyn InferenceBackend, text: &str) -> Tensor {
+        let tokens = backend.encode(text, true).unwrap();
+        Tensor::new(&tokens[..], backend.device())
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap()
+    }
+
+    fn forced_token(logits: &Tensor) -> u32 {
+        logits
+            .flatten_all()
+            .unwrap()
+            .argmax(0)
+            .unwrap()
+            .to_scalar::<u32>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_backend_kind_from_str() {
+        assert_eq!("candle".parse::<BackendKind>().unwrap(), BackendKind::Candle);
+        assert_eq!("echo".parse::<BackendKind>().unwrap(), BackendKind::Echo);
+        assert_eq!("scripted".parse::<BackendKind>().unwrap(), BackendKind::Scripted);
+        assert!("gpt".parse::<BackendKind>().is_err());
+    }
+
+    #[test]
+    fn test_byte_tokenizer_roundtrip() {
+        let tokenizer = ByteTokenizer;
+        let text = "<|im_start|>user\nCafé ☕<|im_end|>";
+        let tokens = tokenizer.encode(text, true);
+
+        assert_eq!(tokens[0], BOS_TOKEN);
+        assert_eq!(tokens[1], 3);
+        assert_eq!(tokenizer.decode(&tokens, false), format!("<s>{}", text));
+        assert_eq!(tokenizer.decode(&tokens, true), "user\nCafé ☕");
+    }
+
+    #[test]
+    fn test_byte_tokenizer_token_bytes() {
+        let backend = EchoBackend::new();
+
+        assert_eq!(backend.token_bytes(EOS_TOKEN).unwrap(), Vec::<u8>::new());
+        assert_eq!(backend.token_bytes(ByteTokenizer::BYTE_OFFSET + 0xe9).unwrap(), vec![0xe9]);
+        assert_eq!(backend.vocab_size(), 261);
+    }
+
+    #[test]
+    fn test_echo_backend_repeats_prompt() {
+        let mut backend = EchoBackend::new();
+        backend.reset();
+        let prompt = input(&backend, "<|im_start|>hi");
+
+        let mut generated = Vec::new();
+        loop {
+            let token = forced_token(&backend.forward(&prompt, 0).unwrap());
+            if token == EOS_TOKEN {
+                break;
+            }
+            generated.push(token);
+        }
+
+        assert_eq!(backend.decode(&generated, true).unwrap(), "hi");
+    }
+
+    #[test]
+    fn test_scripted_backend_cycles_responses() {
+        let mut backend = ScriptedBackend::new(vec!["A".to_string(), "B".to_string()]);
+        let prompt = input(&backend, "prompt");
+
+        let mut first_tokens = Vec::new();
+        for _ in 0..3 {
+            backend.reset();
+            first_tokens.push(forced_token(&backend.forward(&prompt, 0).unwrap()));
+        }
+
+        let decoded = backend.decode(&first_tokens, true).unwrap();
+        assert_eq!(decoded, "ABA");
+    }
+
+    #[test]
+    fn test_mock_backends_decode_rows_independently() {
+        let rows = |logits: Tensor| {
+            logits.squeeze(1).unwrap().argmax(1).unwrap().to_vec1::<u32>().unwrap()
+        };
+        let mut backend = EchoBackend::new();
+        let a = backend.encode("a", false).unwrap()[0];
+        let b = backend.encode("b", false).unwrap()[0];
+        // The second row is left-padded to the first row's length
+        let batch = Tensor::new(&[[a, a], [EOS_TOKEN, b]], &Device::Cpu).unwrap();
+
+        let tokens = rows(backend.forward_padded(&batch, &[0, 1], 0).unwrap());
+        assert_eq!(tokens, vec![a, b]);
+
+        // Rows take consecutive responses, and the next batch carries on
+        let mut backend = ScriptedBackend::new(["A", "B", "C"].map(String::from).to_vec());
+        for expected in ["AB", "CA"] {
+            backend.reset();
+            let tokens = rows(backend.forward_padded(&batch, &[0, 1], 0).unwrap());
+            assert_eq!(backend.decode(&tokens, true).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_scripted_backend_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script.txt");
+        std::fs::write(&path, "First reply\nline two\n---\nSecond reply\n").unwrap();
+
+        let backend = ScriptedBackend::from_file(&path).unwrap();
+
+        assert_eq!(backend.responses.len(), 2);
+        assert_eq!(
+            backend.decode(&backend.responses[0], true).unwrap(),
+            "First reply\nline two"
+        );
+    }
+
+    #[test]
+    fn test_scripted_backend_missing_file() {
+        assert!(ScriptedBackend::from_file("/nonexistent/script.txt").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! Confidence scoring from token probabilities
+//!
+//! The logits processor records, for every sampled token, its log
+//! probability and the entropy of the model's raw next-token distribution,
+//! before penalties or a grammar reshape it. The overall confidence is the
+//! geometric mean token probability (1 / perplexity); the same measure per
+//! sentence picks out the parts of the feedback a tutor should
+//! double-check.
+
+use aws_ipc_protocol::ConfidenceSpan;
+
+/// Sentences below this confidence are reported as low-confidence spans
+pub const LOW_CONFIDENCE_THRESHOLD: f32 = 0.5;
+
+/// Probability facts about one sampled token
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenStat {
+    /// Natural log of the token's probability under the model
+    pub logprob: f32,
+    /// Entropy of the model's next-token distribution, in nats
+    pub entropy: f32,
+    /// Whether a grammar allowed no other token, so the model made no
+    /// choice
+    pub forced: bool,
+}
+
+/// Geometric mean probability of the tokens, i.e. 1 / perplexity
+///
+/// Forced tokens are left out. A generation with no other tokens has
+/// nothing to doubt and scores 1.0.
+pub fn confidence(stats: &[TokenStat]) -> f32 {
+    let chosen: Vec<f32> = stats
+        .iter()
+        .filter(|s| !s.forced)
+        .map(|s| s.logprob)
+        .collect();
+    if chosen.is_empty() {
+        return 1.0;
+    }
+
+    let mean_logprob = chosen.iter().sum::<f32>() / chosen.len() as f32;
+    mean_logprob.exp().clamp(0.0, 1.0)
+}
+
+/// Sentences of `text` whose tokens score below `threshold`
+///
+/// `fragments` maps decoded text back to tokens: each entry is the byte
+/// length of the text and the number of tokens generated at the moment a
+/// fragment was emitted, in order. `stats` holds one entry per token.
+pub fn low_confidence_spans(
+    text: &str,
+    fragments: &[(usize, usize)],
+    stats: &[TokenStat],
+    threshold: f32,
+) -> Vec<ConfidenceSpan> {
+    sentences(text)
+        .into_iter()
+        .filter_map(|(start, end)| {
+            let tokens = tokens_in(fragments, start, end);
+            let sentence_stats = stats.get(tokens)?;
+            let chosen: Vec<&TokenStat> = sentence_stats.iter().filter(|s| !s.forced).collect();
+            if chosen.is_empty() {
+                return None;
+            }
+
+            let confidence = confidence(sentence_stats);
+            let entropy = chosen.iter().map(|s| s.entropy).sum::<f32>() / chosen.len() as f32;
+
+            (confidence < threshold).then(|| ConfidenceSpan {
+                start,
+                end,
+                text: text[start..end].to_string(),
+                confidence,
+                entropy,
+            })
+        })
+        .collect()
+}
+
+/// Range of tokens whose text overlaps bytes `start..end`
+fn tokens_in(fragments: &[(usize, usize)], start: usize, end: usize) -> std::ops::Range<usize> {
+    let mut first = None;
+    let mut last = 0;
+    let mut prev = (0, 0);
+
+    for &(text_end, token_end) in fragments {
+        let (text_start, token_start) = prev;
+        if text_start < end && text_end > start {
+            first.get_or_insert(token_start);
+            last = token_end;
+        }
+        prev = (text_end, token_end);
+    }
+
+    first.unwrap_or(last)..last
+}
+
+/// Byte ranges of the sentences in `text`, without surrounding whitespace
+///
+/// A sentence ends at `.`, `!` or `?` followed by whitespace, or at a
+/// line break, so each feedback paragraph and bullet is split apart.
+fn sentences(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        let at_end = match c {
+            '\n' => Some(i),
+            '.' | '!' | '?' => match chars.peek() {
+                Some((_, next)) if !next.is_whitespace() => None,
+                _ => Some(i + c.len_utf8()),
+            },
+            _ => None,
+        };
+
+        if let Some(end) = at_end {
+            push_trimmed(&mut spans, text, start, end);
+            start = end;
+        }
+    }
+    push_trimmed(&mut spans, text, start, text.len());
+
+    spans
+}
+
+fn push_trimmed(spans: &mut Vec<(usize, usize)>, text: &str, start: usize, end: usize) {
+    let slice = &text[start..end];
+    let trimmed = slice.trim();
+    if !trimmed.is_empty() {
+        let offset = start + (slice.len() - slice.trim_start().len());
+        spans.push((offset, offset + trimmed.len()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(probability: f32) -> TokenStat {
+        TokenStat {
+            logprob: probability.ln(),
+            entropy: 1.0,
+            forced: false,
+        }
+    }
+
+    #[test]
+    fn test_confidence_is_inverse_perplexity() {
+        let stats = [stat(0.5), stat(0.5)];
+        assert!((confidence(&stats) - 0.5).abs() < 1e-6);
+
+        // Geometric, not arithmetic, mean
+        let stats = [stat(1.0), stat(0.25)];
+        assert!((confidence(&stats) - 0.5).abs() < 1e-6);
+
+        assert_eq!(confidence(&[]), 1.0);
+    }
+
+    #[test]
+    fn test_forced_tokens_left_out_of_confidence() {
+        let forced = TokenStat {
+            forced: true,
+            ..stat(0.01)
+        };
+
+        assert!((confidence(&[stat(0.5), forced, stat(0.5)]) - 0.5).abs() < 1e-6);
+        assert_eq!(confidence(&[forced, forced]), 1.0);
+    }
+
+    #[test]
+    fn test_sentences() {
+        let text = "Good start. The base case is wrong!\n\n- Check 3.5 again";
+
+        let sentences: Vec<&str> = sentences(text).iter().map(|&(s, e)| &text[s..e]).collect();
+
+        assert_eq!(
+            sentences,
+            vec![
+                "Good start.",
+                "The base case is wrong!",
+                "- Check 3.5 again"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_low_confidence_sentence_flagged() {
+        // One token per fragment: "Sure. " then "Maybe" then "."
+        let text = "Sure. Maybe.";
+        let fragments = [(6, 1), (11, 2), (12, 3)];
+        let stats = [stat(0.9), stat(0.1), stat(0.2)];
+
+        let spans = low_confidence_spans(text, &fragments, &stats, LOW_CONFIDENCE_THRESHOLD);
+
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].text, "Maybe.");
+        assert_eq!((spans[0].start, spans[0].end), (6, 12));
+        assert!(spans[0].confidence < 0.2);
+    }
+
+    #[test]
+    fn test_tokens_in_fragment_spanning_sentences() {
+        // A single fragment holding two tokens covers both sentences
+        let fragments = [(4, 2), (9, 3)];
+
+        assert_eq!(tokens_in(&fragments, 0, 2), 0..2);
+        assert_eq!(tokens_in(&fragments, 3, 9), 0..3);
+        assert_eq!(tokens_in(&fragments, 5, 9), 2..3);
+    }
+}
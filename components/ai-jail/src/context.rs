@@ -0,0 +1,452 @@
+//! Fitting long answers into the model's context window
+//!
+//! A prompt must leave room for `max_tokens` of output within the model's
+//! context window. Answers too long for that are split into sections at
+//! paragraph, line, sentence and finally word boundaries. Each section is
+//! marked on its own against the criteria it mentions, and the results are
+//! merged back into one response.
+
+use anyhow::Result;
+use aws_ipc_protocol::{ContextUsage, CriterionScore, RubricCriterion};
+use std::collections::BTreeMap;
+
+use crate::protocol::{InferenceResponse, StructuredFeedback};
+
+/// Most sections an answer is split into; anything beyond is left out and
+/// reported as truncated
+pub const MAX_SECTIONS: usize = 8;
+
+/// Boundaries to split at, coarsest first
+const SEPARATORS: [&str; 4] = ["\n\n", "\n", ". ", " "];
+
+/// Label put in front of a section so the model knows it is seeing part of
+/// the answer
+pub fn section_header(part: usize, sections: usize) -> String {
+    format!("[Part {} of {} of the answer]\n\n", part, sections)
+}
+
+/// Split `text` into sections of at most `budget` tokens
+///
+/// `count` measures text in tokens. Pieces are counted separately and the
+/// counts added up, which slightly overestimates most tokenizers, so a
+/// section never ends up over budget. Sections keep their separators, so
+/// they concatenate back to `text`.
+pub fn split_sections(
+    text: &str,
+    budget: usize,
+    count: &dyn Fn(&str) -> Result<usize>,
+) -> Result<Vec<String>> {
+    let mut sections = Vec::new();
+    let mut current = (String::new(), 0);
+    pack(text, 0, budget.max(1), count, &mut current, &mut sections)?;
+    if !current.0.is_empty() {
+        sections.push(current.0);
+    }
+
+    Ok(sections)
+}
+
+/// Add the pieces of `text` to `current`, starting a new section whenever
+/// the next piece would not fit
+fn pack(
+    text: &str,
+    level: usize,
+    budget: usize,
+    count: &dyn Fn(&str) -> Result<usize>,
+    current: &mut (String, usize),
+    sections: &mut Vec<String>,
+) -> Result<()> {
+    let Some(separator) = SEPARATORS.get(level) else {
+        return pack_chars(text, budget, count, current, sections);
+    };
+
+    for piece in text.split_inclusive(separator) {
+        let tokens = count(piece)?;
+        if tokens > budget {
+            pack(piece, level + 1, budget, count, current, sections)?;
+            continue;
+        }
+
+        if current.1 + tokens > budget {
+            sections.push(std::mem::take(&mut current.0));
+            current.1 = 0;
+        }
+        current.0.push_str(piece);
+        current.1 += tokens;
+    }
+
+    Ok(())
+}
+
+/// Last resort for a word longer than the budget: cut it between
+/// characters
+fn pack_chars(
+    text: &str,
+    budget: usize,
+    count: &dyn Fn(&str) -> Result<usize>,
+    current: &mut (String, usize),
+    sections: &mut Vec<String>,
+) -> Result<()> {
+    if !current.0.is_empty() {
+        sections.push(std::mem::take(&mut current.0));
+        current.1 = 0;
+    }
+
+    let mut rest = text;
+    while !rest.is_empty() {
+        // Start from one character per token and halve until it fits
+        let mut len = rest
+            .char_indices()
+            .nth(budget)
+            .map_or(rest.len(), |(i, _)| i);
+        while len > 0 && count(&rest[..len])? > budget {
+            len /= 2;
+            while !rest.is_char_boundary(len) {
+                len -= 1;
+            }
+        }
+        // A single character over budget still has to go somewhere
+        if len == 0 {
+            len = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+
+        sections.push(rest[..len].to_string());
+        rest = &rest[len..];
+    }
+
+    Ok(())
+}
+
+/// Criteria to mark each section against
+///
+/// A criterion goes to every section that shares one of its key terms
+/// with it, or to all sections if none do. A section that mentions no
+/// criterion is marked against all of them.
+pub fn assign_criteria(
+    sections: &[String],
+    criteria: &[RubricCriterion],
+) -> Vec<Vec<RubricCriterion>> {
+    let section_words: Vec<String> = sections.iter().map(|s| s.to_lowercase()).collect();
+
+    let mut assigned: Vec<Vec<RubricCriterion>> = vec![Vec::new(); sections.len()];
+    for criterion in criteria {
+        let terms = key_terms(&criterion.description);
+        let mentioned: Vec<usize> = section_words
+            .iter()
+            .enumerate()
+            .filter(|(_, text)| terms.iter().any(|term| text.contains(term.as_str())))
+            .map(|(i, _)| i)
+            .collect();
+
+        let targets = if mentioned.is_empty() {
+            (0..sections.len()).collect()
+        } else {
+            mentioned
+        };
+        for i in targets {
+            assigned[i].push(criterion.clone());
+        }
+    }
+
+    for section in &mut assigned {
+        if section.is_empty() {
+            *section = criteria.to_vec();
+        }
+    }
+
+    assigned
+}
+
+/// Lowercase words of a criterion long enough to be worth matching
+fn key_terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.len() > 4)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Combine the responses for each section of one answer
+///
+/// Free-text feedback is joined under a heading per part. Structured
+/// feedback keeps each criterion's best score, since a criterion is
+/// usually addressed in one part of the answer and absent from the rest,
+/// and the overall grade is recomputed from the merged scores. Confidence
+/// is the token-weighted geometric mean of the parts'.
+pub fn merge_sections(parts: Vec<InferenceResponse>, context: ContextUsage) -> InferenceResponse {
+    let sections = parts.len();
+    let tokens_generated: usize = parts.iter().map(|p| p.tokens_generated).sum();
+    let inference_time_ms = parts.iter().map(|p| p.inference_time_ms).sum();
+    let rubric_alignment =
+        parts.iter().map(|p| p.rubric_alignment).sum::<f32>() / sections.max(1) as f32;
+
+    let confidence = if tokens_generated == 0 {
+        1.0
+    } else {
+        let log_sum: f32 = parts
+            .iter()
+            .map(|p| p.confidence.max(f32::MIN_POSITIVE).ln() * p.tokens_generated as f32)
+            .sum();
+        (log_sum / tokens_generated as f32).exp().clamp(0.0, 1.0)
+    };
+
+    let params = parts.first().map(|p| p.params.clone()).unwrap_or_default();
+    let batched = parts.iter().any(|p| p.batched);
+    let model = parts.first().map(|p| p.model.clone()).unwrap_or_default();
+
+    let structured: Option<Vec<StructuredFeedback>> =
+        parts.iter().map(|p| p.structured.clone()).collect();
+    let (feedback, low_confidence_spans, structured) = match structured {
+        Some(structured) if !structured.is_empty() => {
+            let merged = merge_structured(structured);
+            (merged.to_text(), Vec::new(), Some(merged))
+        }
+        _ => {
+            let mut feedback = String::new();
+            let mut spans = Vec::new();
+            for (i, part) in parts.into_iter().enumerate() {
+                if i > 0 {
+                    feedback.push_str("\n\n");
+                }
+                feedback.push_str(&format!("Part {} of {}:\n", i + 1, sections));
+
+                // Span offsets refer to the merged feedback
+                let offset = feedback.len();
+                feedback.push_str(&part.feedback);
+                spans.extend(part.low_confidence_spans.into_iter().map(|mut span| {
+                    span.start += offset;
+                    span.end += offset;
+                    span
+                }));
+            }
+            (feedback, spans, None)
+        }
+    };
+
+    InferenceResponse {
+        feedback,
+        confidence,
+        low_confidence_spans,
+        rubric_alignment,
+        tokens_generated,
+        inference_time_ms,
+        structured,
+        params,
+        batched,
+        context,
+        model,
+    }
+}
+
+/// Merge the structured feedback of several sections
+fn merge_structured(parts: Vec<StructuredFeedback>) -> StructuredFeedback {
+    let sections = parts.len();
+    let mut scores: BTreeMap<u32, (CriterionScore, Vec<String>)> = BTreeMap::new();
+    let mut strengths: Vec<String> = Vec::new();
+    let mut suggestions: Vec<String> = Vec::new();
+
+    for (i, part) in parts.iter().enumerate() {
+        for score in &part.criterion_scores {
+            let note = format!("Part {}: {}", i + 1, score.feedback);
+            let entry = scores
+                .entry(score.criterion_number)
+                .or_insert_with(|| (score.clone(), Vec::new()));
+            if score.score > entry.0.score {
+                entry.0.score = score.score;
+            }
+            entry.1.push(note);
+        }
+
+        for item in &part.strengths {
+            if !strengths.contains(item) {
+                strengths.push(item.clone());
+            }
+        }
+        for item in &part.suggestions {
+            if !suggestions.contains(item) {
+                suggestions.push(item.clone());
+            }
+        }
+    }
+
+    let criterion_scores: Vec<CriterionScore> = scores
+        .into_values()
+        .map(|(mut score, notes)| {
+            score.feedback = notes.join(" ");
+            score
+        })
+        .collect();
+
+    let total_max: f32 = criterion_scores.iter().map(|s| s.max_score).sum();
+    let overall_grade = if total_max > 0.0 {
+        let total: f32 = criterion_scores.iter().map(|s| s.score).sum();
+        (total / total_max * 100.0).clamp(0.0, 100.0)
+    } else {
+        parts.iter().map(|p| p.overall_grade).sum::<f32>() / sections as f32
+    };
+
+    StructuredFeedback {
+        criterion_scores,
+        strengths,
+        suggestions,
+        overall_grade,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_ipc_protocol::GenerationParams;
+
+    /// One token per byte, like the mock backends
+    fn bytes(text: &str) -> Result<usize> {
+        Ok(text.len())
+    }
+
+    fn criterion(number: u32, description: &str) -> RubricCriterion {
+        RubricCriterion {
+            number,
+            description: description.to_string(),
+            max_marks: Some(10.0),
+        }
+    }
+
+    fn part(feedback: &str, tokens: usize, confidence: f32) -> InferenceResponse {
+        InferenceResponse {
+            feedback: feedback.to_string(),
+            confidence,
+            low_confidence_spans: Vec::new(),
+            rubric_alignment: 0.5,
+            tokens_generated: tokens,
+            inference_time_ms: 10,
+            structured: None,
+            params: GenerationParams::default(),
+            batched: false,
+            context: ContextUsage::default(),
+            model: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_short_text_is_one_section() {
+        let sections = split_sections("A short answer.", 100, &bytes).unwrap();
+        assert_eq!(sections, vec!["A short answer."]);
+    }
+
+    #[test]
+    fn test_split_prefers_paragraphs() {
+        let text = "First paragraph here.\n\nSecond paragraph here.\n\nThird.";
+        let sections = split_sections(text, 30, &bytes).unwrap();
+
+        assert_eq!(
+            sections,
+            vec!["First paragraph here.\n\n", "Second paragraph here.\n\nThird."]
+        );
+        assert_eq!(sections.concat(), text);
+    }
+
+    #[test]
+    fn test_split_falls_back_to_sentences_and_words() {
+        let text = "One sentence here. Another sentence follows it. Supercalifragilistic";
+        let sections = split_sections(text, 12, &bytes).unwrap();
+
+        assert!(sections.iter().all(|s| s.len() <= 12), "{:?}", sections);
+        assert_eq!(sections.concat(), text);
+    }
+
+    #[test]
+    fn test_split_respects_char_boundaries() {
+        let text = "ééééééééééé";
+        let sections = split_sections(text, 4, &bytes).unwrap();
+
+        assert!(sections.iter().all(|s| !s.is_empty() && s.len() <= 4));
+        assert_eq!(sections.concat(), text);
+    }
+
+    #[test]
+    fn test_assign_criteria() {
+        let sections = vec![
+            "The algorithm sorts the list.".to_string(),
+            "Testing covers edge cases.".to_string(),
+            "Unrelated closing remarks.".to_string(),
+        ];
+        let criteria = vec![
+            criterion(1, "Explains the algorithm"),
+            criterion(2, "Describes testing"),
+            criterion(3, "Cites references"),
+        ];
+
+        let assigned = assign_criteria(&sections, &criteria);
+        let numbers: Vec<Vec<u32>> = assigned
+            .iter()
+            .map(|c| c.iter().map(|c| c.number).collect())
+            .collect();
+
+        // Criterion 3 is mentioned nowhere, so every section gets it
+        assert_eq!(numbers, vec![vec![1, 3], vec![2, 3], vec![3]]);
+    }
+
+    #[test]
+    fn test_merge_text_sections() {
+        let mut first = part("Good start.", 10, 0.9);
+        first.low_confidence_spans = vec![aws_ipc_protocol::ConfidenceSpan {
+            start: 0,
+            end: 11,
+            text: "Good start.".to_string(),
+            confidence: 0.2,
+            entropy: 1.0,
+        }];
+        let context = ContextUsage {
+            sections: 2,
+            ..Default::default()
+        };
+
+        let merged = merge_sections(vec![first, part("Weak ending.", 30, 0.5)], context);
+
+        assert_eq!(
+            merged.feedback,
+            "Part 1 of 2:\nGood start.\n\nPart 2 of 2:\nWeak ending."
+        );
+        let span = &merged.low_confidence_spans[0];
+        assert_eq!(&merged.feedback[span.start..span.end], "Good start.");
+        assert_eq!(merged.tokens_generated, 40);
+        assert_eq!(merged.context.sections, 2);
+
+        let expected = ((0.9f32.ln() * 10.0 + 0.5f32.ln() * 30.0) / 40.0).exp();
+        assert!((merged.confidence - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_merge_structured_keeps_best_scores() {
+        let score = |number: u32, score: f32| CriterionScore {
+            criterion_number: number,
+            criterion_text: format!("Criterion {}", number),
+            score,
+            max_score: 10.0,
+            feedback: format!("Scored {}", score),
+        };
+        let structured = |scores, strength: &str| StructuredFeedback {
+            criterion_scores: scores,
+            strengths: vec![strength.to_string()],
+            suggestions: Vec::new(),
+            overall_grade: 0.0,
+        };
+
+        let mut first = part("", 5, 1.0);
+        first.structured = Some(structured(vec![score(1, 8.0), score(2, 2.0)], "Clear"));
+        let mut second = part("", 5, 1.0);
+        second.structured = Some(structured(vec![score(2, 6.0)], "Clear"));
+
+        let merged = merge_sections(vec![first, second], ContextUsage::default());
+        let structured = merged.structured.unwrap();
+
+        assert_eq!(structured.criterion_scores[0].score, 8.0);
+        assert_eq!(structured.criterion_scores[1].score, 6.0);
+        assert_eq!(
+            structured.criterion_scores[1].feedback,
+            "Part 1: Scored 2 Part 2: Scored 6"
+        );
+        assert_eq!(structured.strengths, vec!["Clear".to_string()]);
+        assert_eq!(structured.overall_grade, 70.0);
+        assert_eq!(merged.feedback, structured.to_text());
+    }
+}
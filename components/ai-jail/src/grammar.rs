@@ -0,0 +1,463 @@
+//! Grammar constraints for structured output
+//!
+//! [`JsonGrammar`] recognises JSON text matching a fixed [`Schema`] one byte
+//! at a time. The logits processor asks it which tokens could extend the
+//! output and masks out the rest, so a constrained generation can only
+//! ever produce (a prefix of) a document that parses into the schema.
+//!
+//! Objects must contain every field of their schema in schema order, which
+//! keeps the recogniser a simple pushdown automaton. Numbers are
+//! non-negative and have no exponent; rubric scores never need either.
+
+/// Shape of a JSON value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Schema {
+    /// Object with exactly these fields, in this order
+    Object(&'static [(&'static str, Schema)]),
+    /// Array whose items all match the inner schema
+    Array(&'static Schema),
+    String,
+    /// Non-negative integer
+    Integer,
+    /// Non-negative decimal number
+    Number,
+}
+
+/// Schema of one `CriterionScore`
+const CRITERION_SCORE: Schema = Schema::Object(&[
+    ("criterion_number", Schema::Integer),
+    ("criterion_text", Schema::String),
+    ("score", Schema::Number),
+    ("max_score", Schema::Number),
+    ("feedback", Schema::String),
+]);
+
+/// Schema of the structured feedback object produced for
+/// `ResponseFormat::RubricJson`
+pub const RUBRIC_FEEDBACK: Schema = Schema::Object(&[
+    ("criterion_scores", Schema::Array(&CRITERION_SCORE)),
+    ("strengths", Schema::Array(&Schema::String)),
+    ("suggestions", Schema::Array(&Schema::String)),
+    ("overall_grade", Schema::Number),
+]);
+
+/// Longest run of insignificant whitespace allowed between tokens
+///
+/// Enough for pretty-printed output, but stops a model from padding the
+/// document with whitespace until it runs out of tokens.
+const MAX_WHITESPACE_RUN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ObjectState {
+    /// Expecting the opening quote of the current field's key
+    Key,
+    /// Matched this many bytes of the current field's key
+    KeyByte(usize),
+    /// Expecting the colon after the key
+    Colon,
+    /// The field's value is complete; expecting `,` or `}`
+    AfterValue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ArrayState {
+    /// Just after `[`: an item or `]`
+    First,
+    /// After an item: `,` or `]`
+    AfterItem,
+    /// After `,`: an item
+    Item,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum StringState {
+    Normal,
+    Escape,
+    /// Hex digits still expected in a `\u` escape
+    Unicode(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum NumberState {
+    /// Leading zero; no more integer digits may follow
+    Zero,
+    Integer,
+    /// Just after the decimal point
+    Point,
+    Fraction,
+}
+
+impl NumberState {
+    fn next(self, byte: u8, integer_only: bool) -> Option<Self> {
+        match (self, byte) {
+            (NumberState::Integer, b'0'..=b'9') => Some(NumberState::Integer),
+            (NumberState::Zero | NumberState::Integer, b'.') if !integer_only => {
+                Some(NumberState::Point)
+            }
+            (NumberState::Point | NumberState::Fraction, b'0'..=b'9') => {
+                Some(NumberState::Fraction)
+            }
+            _ => None,
+        }
+    }
+
+    fn is_complete(self) -> bool {
+        self != NumberState::Point
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Frame {
+    /// Expecting the start of a value
+    Value(Schema),
+    Object {
+        fields: &'static [(&'static str, Schema)],
+        index: usize,
+        state: ObjectState,
+    },
+    Array {
+        item: Schema,
+        state: ArrayState,
+    },
+    String(StringState),
+    Number {
+        state: NumberState,
+        integer_only: bool,
+    },
+}
+
+impl Frame {
+    /// Whether insignificant whitespace may appear at this point
+    fn skips_whitespace(self) -> bool {
+        match self {
+            Frame::Value(_) | Frame::Array { .. } => true,
+            Frame::Object { state, .. } => !matches!(state, ObjectState::KeyByte(_)),
+            Frame::String(_) | Frame::Number { .. } => false,
+        }
+    }
+}
+
+/// What to do with a byte after looking at the innermost frame
+enum Step {
+    Accept,
+    Reject,
+    /// The frame was replaced or popped; offer the byte to the new top
+    Refeed,
+}
+
+/// No token in the vocabulary can continue the output
+#[derive(Debug, thiserror::Error)]
+#[error("No token in the vocabulary can continue the structured output")]
+pub struct DeadEnd;
+
+/// Incremental recogniser for JSON matching a [`Schema`]
+///
+/// Grammars compare equal when they are in the same state, which is what
+/// lets callers cache work per state.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct JsonGrammar {
+    stack: Vec<Frame>,
+    whitespace_run: usize,
+}
+
+impl JsonGrammar {
+    pub fn new(schema: Schema) -> Self {
+        Self {
+            stack: vec![Frame::Value(schema)],
+            whitespace_run: 0,
+        }
+    }
+
+    /// Whether a complete document has been recognised
+    pub fn is_complete(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    /// Whether `bytes` could follow the text recognised so far
+    pub fn accepts(&self, bytes: &[u8]) -> bool {
+        self.clone().advance(bytes)
+    }
+
+    /// Which of `tokens` could follow the text recognised so far
+    ///
+    /// Empty tokens never can. One scratch copy of the grammar is reset
+    /// for each token, so checking a whole vocabulary does not allocate
+    /// per token.
+    pub fn allowed_tokens(&self, tokens: &[Vec<u8>]) -> Vec<bool> {
+        let mut scratch = self.clone();
+        tokens
+            .iter()
+            .map(|bytes| {
+                scratch.stack.clone_from(&self.stack);
+                scratch.whitespace_run = self.whitespace_run;
+                !bytes.is_empty() && scratch.advance(bytes)
+            })
+            .collect()
+    }
+
+    /// Consume `bytes`, returning false (and leaving the grammar in an
+    /// unspecified state) if they cannot follow the text so far
+    pub fn advance(&mut self, bytes: &[u8]) -> bool {
+        bytes.iter().all(|&b| self.feed(b))
+    }
+
+    fn feed(&mut self, byte: u8) -> bool {
+        let whitespace = matches!(byte, b' ' | b'\n' | b'\r' | b'\t');
+        if !whitespace {
+            self.whitespace_run = 0;
+        }
+
+        loop {
+            let Some(&top) = self.stack.last() else {
+                return false;
+            };
+
+            let step = match top {
+                _ if whitespace && top.skips_whitespace() => {
+                    self.whitespace_run += 1;
+                    return self.whitespace_run <= MAX_WHITESPACE_RUN;
+                }
+                Frame::Value(schema) => self.start_value(schema, byte),
+                Frame::Object {
+                    fields,
+                    index,
+                    state,
+                } => self.feed_object(fields, index, state, byte),
+                Frame::Array { item, state } => self.feed_array(item, state, byte),
+                Frame::String(state) => self.feed_string(state, byte),
+                Frame::Number {
+                    state,
+                    integer_only,
+                } => match state.next(byte, integer_only) {
+                    Some(state) => self.replace(Frame::Number {
+                        state,
+                        integer_only,
+                    }),
+                    None if state.is_complete() => {
+                        self.stack.pop();
+                        Step::Refeed
+                    }
+                    None => Step::Reject,
+                },
+            };
+
+            match step {
+                Step::Accept => return true,
+                Step::Reject => return false,
+                Step::Refeed => continue,
+            }
+        }
+    }
+
+    fn start_value(&mut self, schema: Schema, byte: u8) -> Step {
+        let frame = match (schema, byte) {
+            (Schema::Object(fields), b'{') => Frame::Object {
+                fields,
+                index: 0,
+                state: ObjectState::Key,
+            },
+            (Schema::Array(item), b'[') => Frame::Array {
+                item: *item,
+                state: ArrayState::First,
+            },
+            (Schema::String, b'"') => Frame::String(StringState::Normal),
+            (Schema::Integer | Schema::Number, b'0'..=b'9') => Frame::Number {
+                state: if byte == b'0' {
+                    NumberState::Zero
+                } else {
+                    NumberState::Integer
+                },
+                integer_only: schema == Schema::Integer,
+            },
+            _ => return Step::Reject,
+        };
+
+        self.replace(frame)
+    }
+
+    fn feed_object(
+        &mut self,
+        fields: &'static [(&'static str, Schema)],
+        index: usize,
+        state: ObjectState,
+        byte: u8,
+    ) -> Step {
+        let (key, schema) = fields[index];
+        let object = |state| Frame::Object {
+            fields,
+            index,
+            state,
+        };
+
+        match (state, byte) {
+            (ObjectState::Key, b'"') => self.replace(object(ObjectState::KeyByte(0))),
+            (ObjectState::KeyByte(matched), _) if matched < key.len() => {
+                if key.as_bytes()[matched] == byte {
+                    self.replace(object(ObjectState::KeyByte(matched + 1)))
+                } else {
+                    Step::Reject
+                }
+            }
+            (ObjectState::KeyByte(_), b'"') => self.replace(object(ObjectState::Colon)),
+            (ObjectState::Colon, b':') => {
+                self.replace(object(ObjectState::AfterValue));
+                self.stack.push(Frame::Value(schema));
+                Step::Accept
+            }
+            (ObjectState::AfterValue, b',') if index + 1 < fields.len() => {
+                self.replace(Frame::Object {
+                    fields,
+                    index: index + 1,
+                    state: ObjectState::Key,
+                })
+            }
+            (ObjectState::AfterValue, b'}') if index + 1 == fields.len() => {
+                self.stack.pop();
+                Step::Accept
+            }
+            _ => Step::Reject,
+        }
+    }
+
+    fn feed_array(&mut self, item: Schema, state: ArrayState, byte: u8) -> Step {
+        match (state, byte) {
+            (ArrayState::First | ArrayState::AfterItem, b']') => {
+                self.stack.pop();
+                Step::Accept
+            }
+            (ArrayState::AfterItem, b',') => self.replace(Frame::Array {
+                item,
+                state: ArrayState::Item,
+            }),
+            (ArrayState::First | ArrayState::Item, _) => {
+                self.replace(Frame::Array {
+                    item,
+                    state: ArrayState::AfterItem,
+                });
+                self.stack.push(Frame::Value(item));
+                Step::Refeed
+            }
+            _ => Step::Reject,
+        }
+    }
+
+    fn feed_string(&mut self, state: StringState, byte: u8) -> Step {
+        let next = match (state, byte) {
+            (StringState::Normal, b'"') => {
+                self.stack.pop();
+                return Step::Accept;
+            }
+            (StringState::Normal, b'\\') => StringState::Escape,
+            (StringState::Normal, 0x00..=0x1f) => return Step::Reject,
+            (StringState::Normal, _) => StringState::Normal,
+            (StringState::Escape, b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => {
+                StringState::Normal
+            }
+            (StringState::Escape, b'u') => StringState::Unicode(4),
+            (StringState::Unicode(1), b) if b.is_ascii_hexdigit() => StringState::Normal,
+            (StringState::Unicode(n), b) if b.is_ascii_hexdigit() => StringState::Unicode(n - 1),
+            _ => return Step::Reject,
+        };
+
+        self.replace(Frame::String(next))
+    }
+
+    fn replace(&mut self, frame: Frame) -> Step {
+        if let Some(top) = self.stack.last_mut() {
+            *top = frame;
+        }
+        Step::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"{
+  "criterion_scores": [
+    {"criterion_number": 1, "criterion_text": "Base case", "score": 7.5, "max_score": 10, "feedback": "Mostly \"right\"\n"}
+  ],
+  "strengths": ["Clear"],
+  "suggestions": [],
+  "overall_grade": 75
+}"#;
+
+    #[test]
+    fn test_accepts_valid_document() {
+        let mut grammar = JsonGrammar::new(RUBRIC_FEEDBACK);
+
+        assert!(grammar.advance(EXAMPLE.as_bytes()));
+        assert!(grammar.is_complete());
+        assert!(!grammar.accepts(b" "));
+    }
+
+    #[test]
+    fn test_every_prefix_is_accepted_incomplete() {
+        let grammar = JsonGrammar::new(RUBRIC_FEEDBACK);
+
+        for end in 0..EXAMPLE.len() - 1 {
+            let mut prefix = grammar.clone();
+            assert!(prefix.advance(&EXAMPLE.as_bytes()[..end]), "prefix {}", end);
+            assert!(!prefix.is_complete());
+        }
+    }
+
+    #[test]
+    fn test_allowed_tokens_match_accepts() {
+        let mut grammar = JsonGrammar::new(RUBRIC_FEEDBACK);
+        assert!(grammar.advance(b"{\"criterion_scores\": [{\"criterion_number\": 1"));
+        let tokens: Vec<Vec<u8>> = ["", "2", ".5", ",", " ,", "}", "\"x"]
+            .iter()
+            .map(|t| t.as_bytes().to_vec())
+            .collect();
+
+        let expected: Vec<bool> = tokens
+            .iter()
+            .map(|t| !t.is_empty() && grammar.accepts(t))
+            .collect();
+        assert_eq!(grammar.allowed_tokens(&tokens), expected);
+        assert_eq!(expected, [false, true, false, true, true, false, false]);
+    }
+
+    #[test]
+    fn test_rejects_off_schema_text() {
+        let grammar = JsonGrammar::new(RUBRIC_FEEDBACK);
+
+        assert!(!grammar.accepts(b"Good work"));
+        assert!(!grammar.accepts(b"{\"strengths\""));
+        assert!(!grammar.accepts(b"{\" criterion_scores\""));
+        assert!(!grammar.accepts(b"{\"criterion_scores\": {"));
+        assert!(!grammar.accepts(b"{\"criterion_scores\": [{\"criterion_number\": 1.5"));
+        assert!(!grammar.accepts(b"{\"criterion_scores\": [{\"criterion_number\": -1"));
+        assert!(!grammar.accepts(b"{\"criterion_scores\": [], \"strengths\": [01"));
+    }
+
+    #[test]
+    fn test_number_ends_at_next_token() {
+        let mut grammar = JsonGrammar::new(Schema::Array(&Schema::Number));
+
+        assert!(grammar.advance(b"[1"));
+        assert!(grammar.advance(b"2.5,"));
+        assert!(!grammar.accepts(b"]"));
+        assert!(grammar.advance(b"0]"));
+        assert!(grammar.is_complete());
+    }
+
+    #[test]
+    fn test_whitespace_run_is_bounded() {
+        let grammar = JsonGrammar::new(RUBRIC_FEEDBACK);
+
+        assert!(grammar.accepts(&[b' '; MAX_WHITESPACE_RUN]));
+        assert!(!grammar.accepts(&[b' '; MAX_WHITESPACE_RUN + 1]));
+    }
+
+    #[test]
+    fn test_unicode_escape() {
+        let mut grammar = JsonGrammar::new(Schema::String);
+
+        assert!(!grammar.accepts(b"\"\\u12g"));
+        assert!(grammar.advance("\"caf\\u00e9 ☕\"".as_bytes()));
+        assert!(grammar.is_complete());
+    }
+}
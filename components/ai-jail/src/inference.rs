@@ -4,11 +4,22 @@
 //! TMA feedback using the loaded Mistral model.
 
 use anyhow::{Context, Result};
-use candle_core::{DType, Device, Tensor};
+use aws_ipc_protocol::{ContextUsage, GenerationParams, ModelInfo};
+use candle_core::{DType, Tensor};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Instant;
 
-use crate::model::LoadedModel;
-use crate::protocol::{InferenceRequest, InferenceResponse};
+use crate::backend::InferenceBackend;
+use crate::confidence::{self, TokenStat, LOW_CONFIDENCE_THRESHOLD};
+use crate::context::{self, MAX_SECTIONS};
+use crate::grammar::{DeadEnd, JsonGrammar, RUBRIC_FEEDBACK};
+use crate::prompt::TemplateRegistry;
+use crate::protocol::{InferenceRequest, InferenceResponse, ResponseFormat, StructuredFeedback};
+use crate::stream::{StopSequenceMatcher, TokenStream};
+use crate::watchdog::Deadline;
 
 /// Sampling parameters for text generation
 #[derive(Debug, Clone)]
@@ -19,14 +30,33 @@ pub struct SamplingParams {
     /// Top-p (nucleus) sampling threshold
     pub top_p: f64,
 
+    /// Top-k cutoff (0 disables)
+    pub top_k: usize,
+
+    /// Min-p cutoff relative to the most likely token (0.0 disables)
+    pub min_p: f64,
+
     /// Maximum tokens to generate
     pub max_tokens: usize,
 
-    /// Repetition penalty
+    /// Repetition penalty: divides the logit of every token already
+    /// generated (1.0 disables)
     pub repetition_penalty: f32,
 
+    /// Subtracted from a token's logit per time it was generated
+    pub frequency_penalty: f32,
+
+    /// Subtracted from a token's logit if it was generated at all
+    pub presence_penalty: f32,
+
     /// Stop sequences
     pub stop_sequences: Vec<String>,
+
+    /// Shape the output is constrained to
+    pub response_format: ResponseFormat,
+
+    /// Seed for the sampler's random number generator
+    pub seed: u64,
 }
 
 impl Default for SamplingParams {
@@ -34,33 +64,101 @@ fn default() -> Self {
         Self {
             temperature: 0.7,
             top_p: 0.9,
+            top_k: 0,
+            min_p: 0.0,
             max_tokens: 512,
             repetition_penalty: 1.1,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
             stop_sequences: vec![
                 "</s>".to_string(),
                 "<|im_end|>".to_string(),
             ],
+            response_format: ResponseFormat::Text,
+            seed: 0,
         }
     }
 }
 
 impl From<&InferenceRequest> for SamplingParams {
+    /// Requests without a seed get a fresh random one, which the response
+    /// records so the output can still be replayed. The request's stop
+    /// sequences are added to the default end-of-turn markers.
     fn from(req: &InferenceRequest) -> Self {
+        let defaults = Self::default();
+        let mut stop_sequences = defaults.stop_sequences;
+        stop_sequences.extend(req.stop_sequences.iter().cloned());
+
         Self {
             temperature: req.temperature,
             top_p: req.top_p,
+            top_k: req.top_k,
+            min_p: req.min_p,
             max_tokens: req.max_tokens,
-            ..Default::default()
+            repetition_penalty: req.repetition_penalty,
+            frequency_penalty: req.frequency_penalty,
+            presence_penalty: req.presence_penalty,
+            stop_sequences,
+            response_format: req.response_format,
+            seed: req.seed.unwrap_or_else(rand::random),
         }
     }
 }
 
+/// Grammar that sampled tokens must keep satisfying
+struct GrammarConstraint {
+    grammar: JsonGrammar,
+    /// Bytes of every token, indexed by token ID
+    vocab: Arc<[Vec<u8>]>,
+    eos_token: u32,
+    /// Tokens allowed in each grammar state seen so far, indexed by token ID
+    allowed: HashMap<JsonGrammar, Vec<bool>>,
+}
+
+impl GrammarConstraint {
+    /// Tokens allowed next, worked out once per grammar state
+    ///
+    /// Most steps land in a state already seen (inside a string, say), so
+    /// the vocabulary is only walked for new ones.
+    fn allowed(&mut self) -> &[bool] {
+        let Self {
+            grammar,
+            vocab,
+            eos_token,
+            allowed,
+        } = self;
+
+        allowed.entry(grammar.clone()).or_insert_with(|| {
+            let mut allowed = grammar.allowed_tokens(vocab);
+            let eos = *eos_token as usize;
+            if allowed.len() <= eos {
+                allowed.resize(eos + 1, false);
+            }
+            allowed[eos] = grammar.is_complete();
+            allowed
+        })
+    }
+}
+
 /// Logits processor for sampling
+///
+/// All randomness comes from a ChaCha20 generator seeded from
+/// [`SamplingParams::seed`], so the same seed and logits always yield the
+/// same tokens.
 pub struct LogitsProcessor {
     temperature: f64,
     top_p: f64,
+    top_k: usize,
+    min_p: f64,
     repetition_penalty: f32,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    rng: ChaCha20Rng,
     generated_tokens: Vec<u32>,
+    /// How often each generated token has appeared
+    token_counts: HashMap<u32, usize>,
+    token_stats: Vec<TokenStat>,
+    constraint: Option<GrammarConstraint>,
 }
 
 impl LogitsProcessor {
@@ -68,18 +166,62 @@ pub fn new(params: &SamplingParams) -> Self {
         Self {
             temperature: params.temperature,
             top_p: params.top_p,
+            top_k: params.top_k,
+            min_p: params.min_p,
             repetition_penalty: params.repetition_penalty,
+            frequency_penalty: params.frequency_penalty,
+            presence_penalty: params.presence_penalty,
+            rng: ChaCha20Rng::seed_from_u64(params.seed),
             generated_tokens: Vec::new(),
+            token_counts: HashMap::new(),
+            token_stats: Vec::new(),
+            constraint: None,
         }
     }
 
+    /// Only sample tokens that keep the output a prefix of `grammar`, and
+    /// end of sequence once the grammar is complete
+    ///
+    /// `vocab` holds the bytes of each token as returned by
+    /// [`InferenceBackend::token_bytes`].
+    pub fn with_grammar(
+        mut self,
+        grammar: JsonGrammar,
+        vocab: Arc<[Vec<u8>]>,
+        eos_token: u32,
+    ) -> Self {
+        self.constraint = Some(GrammarConstraint {
+            grammar,
+            vocab,
+            eos_token,
+            allowed: HashMap::new(),
+        });
+        self
+    }
+
+    /// Probability and entropy of every token sampled so far
+    pub fn token_stats(&self) -> &[TokenStat] {
+        &self.token_stats
+    }
+
     /// Process logits and sample next token
+    ///
+    /// Penalties and the grammar mask adjust the logits; temperature
+    /// scales them; top-k, min-p and top-p then trim the distribution, in
+    /// that order, before a token is drawn. The token's recorded
+    /// probability comes from the logits as the model gave them.
     pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
         let logits = logits.to_dtype(DType::F32)?;
-        let logits = logits.to_vec1::<f32>()?;
+        let mut logits = logits.to_vec1::<f32>()?;
+
+        // Confidence reflects the model's own distribution, not the one
+        // penalties, the grammar and temperature make the token drawn from
+        let log_probs = log_softmax(&logits);
 
-        // Apply repetition penalty
-        let mut logits = self.apply_repetition_penalty(logits);
+        self.apply_penalties(&mut logits);
+        self.apply_grammar(&mut logits)?;
+        let forced = self.constraint.is_some()
+            && logits.iter().filter(|l| l.is_finite()).count() == 1;
 
         // Apply temperature
         if self.temperature > 0.0 {
@@ -93,200 +235,877 @@ pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
             .map(|&l| (l - max_logit).exp())
             .collect();
 
-        let sum: f32 = probs.iter().sum();
-        probs.iter_mut().for_each(|p| *p /= sum);
+        normalize(&mut probs);
 
-        // Apply top-p sampling
-        let token = if self.top_p < 1.0 {
-            self.sample_top_p(&probs)
-        } else {
-            self.sample_multinomial(&probs)
-        };
+        // Trim the tail of the distribution
+        if self.top_k > 0 {
+            filter_top_k(&mut probs, self.top_k);
+        }
+        if self.min_p > 0.0 {
+            filter_min_p(&mut probs, self.min_p as f32);
+        }
+        if self.top_p < 1.0 {
+            normalize(&mut probs);
+            filter_top_p(&mut probs, self.top_p as f32);
+        }
+        normalize(&mut probs);
+
+        let token = self.sample_multinomial(&probs);
+
+        let entropy = -log_probs
+            .iter()
+            .filter(|lp| lp.is_finite())
+            .map(|&lp| lp.exp() * lp)
+            .sum::<f32>();
+        self.token_stats.push(TokenStat {
+            logprob: log_probs[token as usize],
+            entropy,
+            forced,
+        });
+
+        if let Some(constraint) = &mut self.constraint {
+            if token != constraint.eos_token {
+                constraint.grammar.advance(&constraint.vocab[token as usize]);
+            }
+        }
 
         self.generated_tokens.push(token);
+        *self.token_counts.entry(token).or_default() += 1;
         Ok(token)
     }
 
-    fn apply_repetition_penalty(&self, mut logits: Vec<f32>) -> Vec<f32> {
-        if self.repetition_penalty == 1.0 {
-            return logits;
-        }
+    /// Mask out tokens the grammar does not allow next
+    ///
+    /// Fails with [`DeadEnd`] if that leaves nothing to sample.
+    fn apply_grammar(&mut self, logits: &mut [f32]) -> Result<()> {
+        let Some(constraint) = &mut self.constraint else {
+            return Ok(());
+        };
 
-        for &token in &self.generated_tokens {
-            let token_idx = token as usize;
-            if token_idx < logits.len() {
-                if logits[token_idx] < 0.0 {
-                    logits[token_idx] *= self.repetition_penalty;
-                } else {
-                    logits[token_idx] /= self.repetition_penalty;
-                }
+        let allowed = constraint.allowed();
+        for (token, logit) in logits.iter_mut().enumerate() {
+            if !allowed.get(token).copied().unwrap_or(false) {
+                *logit = f32::NEG_INFINITY;
             }
         }
 
-        logits
-    }
+        if logits.iter().all(|&l| l == f32::NEG_INFINITY) {
+            return Err(DeadEnd.into());
+        }
 
-    fn sample_top_p(&self, probs: &[f32]) -> u32 {
-        // Create (index, prob) pairs and sort by probability descending
-        let mut indexed_probs: Vec<(usize, f32)> = probs
-            .iter()
-            .enumerate()
-            .map(|(i, &p)| (i, p))
-            .collect();
+        Ok(())
+    }
 
-        indexed_probs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    /// Penalise tokens that have already been generated
+    ///
+    /// The repetition penalty applies once per distinct token; the
+    /// frequency penalty scales with how often the token appeared.
+    fn apply_penalties(&self, logits: &mut [f32]) {
+        for (&token, &count) in &self.token_counts {
+            let Some(logit) = logits.get_mut(token as usize) else {
+                continue;
+            };
+
+            if *logit < 0.0 {
+                *logit *= self.repetition_penalty;
+            } else {
+                *logit /= self.repetition_penalty;
+            }
+            *logit -= count as f32 * self.frequency_penalty + self.presence_penalty;
+        }
+    }
 
-        // Accumulate probabilities until we reach top_p
+    fn sample_multinomial(&mut self, probs: &[f32]) -> u32 {
+        let random: f32 = self.rng.random();
         let mut cumsum = 0.0;
-        let mut top_p_probs = vec![0.0; probs.len()];
 
-        for (idx, prob) in indexed_probs {
+        for (i, &prob) in probs.iter().enumerate() {
             cumsum += prob;
-            top_p_probs[idx] = prob;
+            if random < cumsum {
+                return i as u32;
+            }
+        }
 
-            if cumsum >= self.top_p {
-                break;
+        // Fallback to last possible token if rounding errors occur
+        probs.iter().rposition(|&p| p > 0.0).unwrap_or(probs.len() - 1) as u32
+    }
+}
+
+fn normalize(probs: &mut [f32]) {
+    let sum: f32 = probs.iter().sum();
+    if sum > 0.0 {
+        probs.iter_mut().for_each(|p| *p /= sum);
+    }
+}
+
+/// Token indices ordered from most to least likely
+fn by_probability(probs: &[f32]) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..probs.len()).collect();
+    indices.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+    indices
+}
+
+/// Zero all but the `k` most likely tokens
+fn filter_top_k(probs: &mut [f32], k: usize) {
+    if k >= probs.len() {
+        return;
+    }
+
+    for index in by_probability(probs).into_iter().skip(k) {
+        probs[index] = 0.0;
+    }
+}
+
+/// Zero tokens less likely than `min_p` times the most likely token
+fn filter_min_p(probs: &mut [f32], min_p: f32) {
+    let threshold = probs.iter().cloned().fold(0.0, f32::max) * min_p;
+    probs.iter_mut().filter(|p| **p < threshold).for_each(|p| *p = 0.0);
+}
+
+/// Keep the smallest set of most likely tokens whose probabilities sum to
+/// at least `top_p`, and zero the rest
+fn filter_top_p(probs: &mut [f32], top_p: f32) {
+    let mut cumsum = 0.0;
+    let mut kept = true;
+
+    for index in by_probability(probs) {
+        if kept {
+            cumsum += probs[index];
+            kept = cumsum < top_p;
+        } else {
+            probs[index] = 0.0;
+        }
+    }
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max_logit = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits
+        .iter()
+        .map(|&l| (l - max_logit).exp())
+        .sum::<f32>()
+        .ln();
+
+    logits.iter().map(|&l| l - max_logit - log_sum).collect()
+}
+
+/// Tokens produced by one generation and the text they decode to
+#[derive(Debug)]
+struct Generation {
+    tokens: Vec<u32>,
+    /// Decoded text, cut before any stop sequence
+    text: String,
+    /// Probability facts for each token in `tokens`
+    stats: Vec<TokenStat>,
+    /// Text length and token count at each decoded fragment
+    fragments: Vec<(usize, usize)>,
+}
+
+/// Decoding state of one sequence in a batch
+struct Sequence<'a> {
+    params: &'a SamplingParams,
+    eos_token: u32,
+    logits_processor: LogitsProcessor,
+    token_stream: TokenStream,
+    stop_matcher: StopSequenceMatcher,
+    generated: Vec<u32>,
+    text: String,
+    fragments: Vec<(usize, usize)>,
+    /// Bytes of `text` already passed to the chunk callback
+    emitted: usize,
+    /// Whether a stop sequence ended the sequence
+    stopped: bool,
+    /// Why the sequence was cut off before it finished, if it was
+    interrupted: Option<anyhow::Error>,
+    /// Whether the sequence needs no more tokens
+    done: bool,
+}
+
+impl<'a> Sequence<'a> {
+    fn new(params: &'a SamplingParams, vocab: Option<Arc<[Vec<u8>]>>, eos_token: u32) -> Self {
+        let mut logits_processor = LogitsProcessor::new(params);
+
+        // Under a grammar the output ends where the grammar does, and a
+        // stop sequence inside a JSON string must not cut it short
+        let stop_matcher = match (params.response_format, vocab) {
+            (ResponseFormat::RubricJson, Some(vocab)) => {
+                logits_processor = logits_processor.with_grammar(
+                    JsonGrammar::new(RUBRIC_FEEDBACK),
+                    vocab,
+                    eos_token,
+                );
+                StopSequenceMatcher::new(&[])
             }
+            _ => StopSequenceMatcher::new(&params.stop_sequences),
+        };
+
+        Self {
+            params,
+            eos_token,
+            logits_processor,
+            token_stream: TokenStream::new(),
+            stop_matcher,
+            generated: Vec::new(),
+            text: String::new(),
+            fragments: Vec::new(),
+            emitted: 0,
+            stopped: false,
+            interrupted: None,
+            done: false,
         }
+    }
+
+    /// Stop the sequence where it is; it finishes as an error
+    fn interrupt(&mut self, reason: impl Into<anyhow::Error>) {
+        let reason = reason.into();
+        tracing::info!("Generation stopped after {} tokens: {}", self.generated.len(), reason);
+        self.interrupted = Some(reason);
+        self.done = true;
+    }
+
+    /// Sample the next token from `logits` and check the stop conditions
+    fn step(
+        &mut self,
+        backend: &dyn InferenceBackend,
+        logits: &Tensor,
+        on_chunk: &mut dyn FnMut(&str, usize) -> Result<()>,
+    ) -> Result<()> {
+        let next_token = match self.logits_processor.sample(logits) {
+            Ok(token) => token,
+            // Only this sequence's grammar is stuck; the rest of the batch
+            // carries on
+            Err(e) if e.is::<DeadEnd>() => {
+                self.interrupt(e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
 
-        // Renormalize
-        let sum: f32 = top_p_probs.iter().sum();
-        if sum > 0.0 {
-            top_p_probs.iter_mut().for_each(|p| *p /= sum);
+        // Check for stop conditions
+        if next_token == self.eos_token {
+            tracing::debug!("EOS token generated after {} tokens", self.generated.len());
+            self.done = true;
+            return Ok(());
         }
 
-        self.sample_multinomial(&top_p_probs)
+        self.generated.push(next_token);
+        self.done = self.generated.len() >= self.params.max_tokens;
+
+        // Check for stop sequences in the newly decoded text
+        if let Some(fragment) = self.token_stream.next_token(backend, next_token)? {
+            self.text.push_str(&fragment);
+            self.fragments.push((self.text.len(), self.generated.len()));
+            if let Some(stop_at) = self.stop_matcher.push(&fragment) {
+                tracing::debug!("Stop sequence detected after {} tokens", self.generated.len());
+                self.text.truncate(stop_at);
+                self.stopped = true;
+                self.done = true;
+                return Ok(());
+            }
+
+            let safe_end = self.text.len() - self.stop_matcher.partial_match_len();
+            if safe_end > self.emitted {
+                on_chunk(&self.text[self.emitted..safe_end], self.generated.len())?;
+                self.emitted = safe_end;
+            }
+        }
+
+        Ok(())
     }
 
-    fn sample_multinomial(&self, probs: &[f32]) -> u32 {
-        let random: f32 = rand::random();
-        let mut cumsum = 0.0;
+    /// Token to feed on the next step, if the sequence is still going
+    fn next_input(&self) -> Option<u32> {
+        if self.done {
+            None
+        } else {
+            self.generated.last().copied()
+        }
+    }
 
-        for (i, &prob) in probs.iter().enumerate() {
-            cumsum += prob;
-            if random < cumsum {
-                return i as u32;
+    /// Flush any remaining text and return the generation
+    fn finish(
+        mut self,
+        backend: &dyn InferenceBackend,
+        on_chunk: &mut dyn FnMut(&str, usize) -> Result<()>,
+    ) -> Result<Generation> {
+        if let Some(reason) = self.interrupted {
+            return Err(reason);
+        }
+
+        if !self.stopped {
+            if let Some(rest) = self.token_stream.finish(backend)? {
+                self.text.push_str(&rest);
+                self.fragments.push((self.text.len(), self.generated.len()));
+                if let Some(stop_at) = self.stop_matcher.push(&rest) {
+                    self.text.truncate(stop_at);
+                }
             }
         }
 
-        // Fallback to last token if rounding errors occur
-        (probs.len() - 1) as u32
+        if self.text.len() > self.emitted {
+            on_chunk(&self.text[self.emitted..], self.generated.len())?;
+        }
+
+        // The EOS token is sampled but not part of the generation
+        let mut stats = self.logits_processor.token_stats().to_vec();
+        stats.truncate(self.generated.len());
+
+        Ok(Generation {
+            tokens: self.generated,
+            text: self.text,
+            stats,
+            fragments: self.fragments,
+        })
     }
 }
 
+/// One prompt decoded for a request: its whole answer or one section
+struct Section {
+    /// The request with its content cut down to the section
+    request: InferenceRequest,
+    tokens: Vec<u32>,
+    params: SamplingParams,
+}
+
+/// Prompts a request is decoded as, and how its answer was fitted in
+struct Plan {
+    sections: Vec<Section>,
+    context: ContextUsage,
+}
+
 /// Inference engine for text generation
 pub struct InferenceEngine {
-    model: LoadedModel,
+    backend: Box<dyn InferenceBackend>,
+    /// Bytes of every token, built the first time a grammar needs them
+    vocab: Option<Arc<[Vec<u8>]>>,
+    templates: TemplateRegistry,
+    /// Reported in every response
+    model: ModelInfo,
 }
 
 impl InferenceEngine {
-    pub fn new(model: LoadedModel) -> Self {
-        Self { model }
+    pub fn new(backend: Box<dyn InferenceBackend>) -> Self {
+        tracing::info!("Using {} inference backend", backend.name());
+        let model = ModelInfo {
+            id: backend.name().to_string(),
+            revision: String::new(),
+            hash: String::new(),
+        };
+
+        Self {
+            backend,
+            vocab: None,
+            templates: TemplateRegistry::default(),
+            model,
+        }
+    }
+
+    /// Report responses as coming from `model` instead of the backend name
+    pub fn with_model(mut self, model: ModelInfo) -> Self {
+        self.model = model;
+        self
+    }
+
+    /// Model this engine generates with
+    pub fn model(&self) -> &ModelInfo {
+        &self.model
+    }
+
+    /// Memory held by the backend's weights, in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.backend.memory_usage()
+    }
+
+    /// Use prompt templates loaded from disk instead of the built-in one
+    pub fn with_templates(mut self, templates: TemplateRegistry) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// Check a request's parameters and that its prompt template exists
+    pub fn validate(&self, request: &InferenceRequest) -> Result<(), String> {
+        request.validate()?;
+        self.templates
+            .get(request.prompt.template.as_deref())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Most requests decoded together by [`generate_batch`](Self::generate_batch)
+    pub fn max_batch_size(&self) -> usize {
+        self.backend.max_batch_size().max(1)
     }
 
     /// Generate feedback for a TMA question
     pub fn generate(&mut self, request: &InferenceRequest) -> Result<InferenceResponse> {
-        let start_time = Instant::now();
+        self.generate_streaming(request, &mut |_, _| Ok(()))
+    }
 
-        // Validate request
-        request.validate()
+    /// Generate feedback, passing text to `on_chunk` as it is produced
+    ///
+    /// `on_chunk` receives each new piece of text and the number of tokens
+    /// generated so far. Text that might be the start of a stop sequence is
+    /// held back until it is known not to be, so the chunks concatenate to
+    /// the final text before trimming. An answer too long for the context
+    /// window is marked in sections, and its merged feedback arrives as a
+    /// single chunk once every section is done.
+    pub fn generate_streaming(
+        &mut self,
+        request: &InferenceRequest,
+        on_chunk: &mut dyn FnMut(&str, usize) -> Result<()>,
+    ) -> Result<InferenceResponse> {
+        self.generate_batch(std::slice::from_ref(request), &mut |_, text, tokens| {
+            on_chunk(text, tokens)
+        })
+        .pop()
+        .context("No generation for request")?
+    }
+
+    /// Generate feedback for several requests, decoding up to
+    /// [`max_batch_size`](Self::max_batch_size) of them together
+    ///
+    /// Results are in request order. Requests are grouped by prompt length
+    /// to keep padding down, except that a request that sets its own seed
+    /// is only decoded with its own sections: batching changes the logits
+    /// slightly, so only a request decoded alone can be replayed exactly.
+    /// Responses say whether they were batched. `on_chunk` works as for
+    /// [`generate_streaming`](Self::generate_streaming) and also receives
+    /// the index of the request the text belongs to. A failure while
+    /// decoding fails every request in the same batch, but a request that
+    /// times out or is cancelled stops on its own, without its sections
+    /// that have not started yet.
+    pub fn generate_batch(
+        &mut self,
+        requests: &[InferenceRequest],
+        on_chunk: &mut dyn FnMut(usize, &str, usize) -> Result<()>,
+    ) -> Vec<Result<InferenceResponse>> {
+        let mut results: Vec<Option<Result<InferenceResponse>>> =
+            requests.iter().map(|_| None).collect();
+        let mut contexts = vec![ContextUsage::default(); requests.len()];
+        // Response for each section of each request, once decoded
+        let mut parts: Vec<Vec<Option<InferenceResponse>>> = vec![Vec::new(); requests.len()];
+
+        let deadlines: Vec<Deadline> = requests.iter().map(Deadline::start).collect();
+
+        let mut sections = Vec::new();
+        for (index, request) in requests.iter().enumerate() {
+            match self.plan(request) {
+                Ok(plan) => {
+                    parts[index] = plan.sections.iter().map(|_| None).collect();
+                    contexts[index] = plan.context;
+                    sections.extend(
+                        plan.sections
+                            .into_iter()
+                            .enumerate()
+                            .map(|(part, section)| (index, part, section)),
+                    );
+                }
+                Err(e) => results[index] = Some(Err(e)),
+            }
+        }
+        sections.sort_by_key(|(_, _, section)| section.tokens.len());
+
+        // Seeded requests each form a group of their own; the rest share one
+        let mut groups: Vec<Vec<(usize, usize, Section)>> = vec![Vec::new()];
+        let mut group_of = HashMap::new();
+        for section in sections {
+            let index = section.0;
+            let group = if requests[index].seed.is_some() {
+                *group_of.entry(index).or_insert_with(|| {
+                    groups.push(Vec::new());
+                    groups.len() - 1
+                })
+            } else {
+                0
+            };
+            groups[group].push(section);
+        }
+
+        let max_batch_size = self.max_batch_size();
+        for batch in groups.iter().flat_map(|group| group.chunks(max_batch_size)) {
+            // Requests that already failed or ran out are not decoded further
+            let batch: Vec<&(usize, usize, Section)> = batch
+                .iter()
+                .filter(|(index, _, _)| {
+                    if results[*index].is_some() {
+                        return false;
+                    }
+                    match deadlines[*index].check() {
+                        Some(reason) => {
+                            results[*index] = Some(Err(reason.into()));
+                            false
+                        }
+                        None => true,
+                    }
+                })
+                .collect();
+            if batch.is_empty() {
+                continue;
+            }
+
+            let start_time = Instant::now();
+            let prompts: Vec<&[u32]> = batch.iter().map(|(_, _, s)| &s.tokens[..]).collect();
+            let params: Vec<&SamplingParams> = batch.iter().map(|(_, _, s)| &s.params).collect();
+            let row_deadlines: Vec<&Deadline> =
+                batch.iter().map(|(index, _, _)| &deadlines[*index]).collect();
+            tracing::info!("Decoding a batch of {} prompts", batch.len());
+
+            // Sections of a split answer are only streamed once merged
+            let generations =
+                self.generate_tokens(&prompts, &params, &row_deadlines, &mut |row, text, tokens| {
+                    let index = batch[row].0;
+                    if parts[index].len() == 1 {
+                        on_chunk(index, text, tokens)
+                    } else {
+                        Ok(())
+                    }
+                });
+
+            match generations {
+                Ok(generations) => {
+                    for ((index, part, section), generation) in batch.iter().zip(generations) {
+                        let batched = batch.iter().any(|(other, _, _)| other != index);
+                        let response = generation.and_then(|generation| {
+                            self.finish(&section.request, &section.params, generation, start_time)
+                        });
+                        let response = response.map(|response| InferenceResponse {
+                            batched,
+                            ..response
+                        });
+                        match response {
+                            Ok(response) => parts[*index][*part] = Some(response),
+                            Err(e) => {
+                                results[*index].get_or_insert(Err(e));
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    for (index, _, _) in &batch {
+                        results[*index].get_or_insert_with(|| Err(anyhow::anyhow!("{:#}", e)));
+                    }
+                }
+            }
+        }
+
+        for (index, (parts, context)) in parts.into_iter().zip(contexts).enumerate() {
+            if results[index].is_some() {
+                continue;
+            }
+
+            let mut parts: Vec<InferenceResponse> = parts
+                .into_iter()
+                .map(|part| part.expect("every section was decoded"))
+                .collect();
+            let response = if parts.len() == 1 {
+                let mut response = parts.remove(0);
+                response.context = context;
+                Ok(response)
+            } else {
+                let response = context::merge_sections(parts, context);
+                on_chunk(index, &response.feedback, response.tokens_generated).map(|()| response)
+            };
+            results[index] = Some(response);
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.expect("every request has a result"))
+            .collect()
+    }
+
+    /// Validate a request and render the prompts it is decoded as
+    ///
+    /// The prompt must leave `max_tokens` of the context window free for
+    /// output. An answer too long for that is split into sections, each
+    /// marked against the criteria it mentions; beyond [`MAX_SECTIONS`] the
+    /// rest of the answer is left out and reported as truncated.
+    fn plan(&self, request: &InferenceRequest) -> Result<Plan> {
+        self.validate(request)
+            .map_err(anyhow::Error::msg)
             .context("Invalid inference request")?;
 
-        // Create prompt
-        let prompt = request.to_prompt();
-        tracing::debug!("Prompt: {}", prompt);
+        // Every section samples with the same seed, so a merged response
+        // can still report the one seed that replays it
+        let mut request = request.clone();
+        request.seed.get_or_insert_with(rand::random);
+
+        let context_length = self.backend.context_length();
+        let limit = context_length.saturating_sub(request.max_tokens);
+
+        let tokens = self.encode_prompt(&request)?;
+        tracing::info!("Input tokens: {}", tokens.len());
+        if tokens.len() <= limit {
+            let context = ContextUsage {
+                context_length,
+                prompt_tokens: tokens.len(),
+                sections: 1,
+                ..Default::default()
+            };
+            let params = SamplingParams::from(&request);
+            return Ok(Plan {
+                sections: vec![Section { request, tokens, params }],
+                context,
+            });
+        }
 
-        // Encode prompt
-        let input_tokens = self.model.encode(&prompt, true)?;
-        tracing::info!("Input tokens: {}", input_tokens.len());
+        // Room left for the answer once the rest of the prompt is in place
+        let mut skeleton = request.clone();
+        skeleton.tma_content = context::section_header(MAX_SECTIONS, MAX_SECTIONS);
+        let overhead = self.encode_prompt(&skeleton)?.len();
+        if overhead >= limit {
+            anyhow::bail!(
+                "Prompt needs {} tokens before the answer, but only {} of the {} token \
+                 context window are left after max_tokens",
+                overhead,
+                limit,
+                context_length
+            );
+        }
+        let budget = limit - overhead;
+
+        let mut pieces = context::split_sections(&request.tma_content, budget, &|text| {
+            Ok(self.backend.encode(text, false)?.len())
+        })?;
+        pieces.retain(|piece| !piece.trim().is_empty());
+
+        let kept = pieces.len().min(MAX_SECTIONS);
+        let omitted_bytes: usize = pieces[kept..].iter().map(String::len).sum();
+        pieces.truncate(kept);
+        if omitted_bytes > 0 {
+            tracing::warn!(
+                "Answer does not fit in {} sections; leaving out the last {} bytes",
+                MAX_SECTIONS,
+                omitted_bytes
+            );
+        }
+        tracing::info!("Answer split into {} sections of up to {} tokens", kept, budget);
+
+        let criteria = context::assign_criteria(&pieces, &request.criteria);
+        let mut sections = Vec::with_capacity(kept);
+        for (i, (piece, criteria)) in pieces.iter().zip(criteria).enumerate() {
+            let mut section = request.clone();
+            let header = context::section_header(i + 1, kept);
+            section.tma_content = format!("{}{}", header, piece.trim());
+            section.criteria = criteria;
+
+            let tokens = self.encode_prompt(&section)?;
+            let params = SamplingParams::from(&section);
+            sections.push(Section {
+                request: section,
+                tokens,
+                params,
+            });
+        }
 
-        // Generate text
-        let sampling_params = SamplingParams::from(request);
-        let generated_tokens = self.generate_tokens(&input_tokens, &sampling_params)?;
+        let context = ContextUsage {
+            context_length,
+            prompt_tokens: sections.iter().map(|s| s.tokens.len()).max().unwrap_or(0),
+            sections: kept,
+            truncated: omitted_bytes > 0,
+            omitted_bytes,
+        };
+
+        Ok(Plan { sections, context })
+    }
 
-        // Decode output
-        let feedback = self.model.decode(&generated_tokens, true)?;
+    /// Render a request's prompt and encode it
+    fn encode_prompt(&self, request: &InferenceRequest) -> Result<Vec<u32>> {
+        let prompt = self.templates.render(request, self.backend.chat_format())?;
+        tracing::debug!("Prompt: {}", prompt);
+
+        self.backend.encode(&prompt, true)
+    }
 
+    /// Build the response for a finished generation
+    fn finish(
+        &self,
+        request: &InferenceRequest,
+        sampling_params: &SamplingParams,
+        generation: Generation,
+        start_time: Instant,
+    ) -> Result<InferenceResponse> {
         // Calculate metrics
-        let confidence = self.calculate_confidence(&generated_tokens);
-        let rubric_alignment = self.calculate_rubric_alignment(&feedback, &request.rubric);
+        let confidence = confidence::confidence(&generation.stats);
+        let rubric_alignment = self.calculate_rubric_alignment(&generation.text, &request.rubric);
+
+        let (feedback, low_confidence_spans, structured) = match sampling_params.response_format {
+            ResponseFormat::Text => {
+                // Span offsets refer to the feedback after trimming
+                let leading = generation.text.len() - generation.text.trim_start().len();
+                let spans = confidence::low_confidence_spans(
+                    &generation.text,
+                    &generation.fragments,
+                    &generation.stats,
+                    LOW_CONFIDENCE_THRESHOLD,
+                )
+                .into_iter()
+                .map(|mut span| {
+                    span.start -= leading;
+                    span.end -= leading;
+                    span
+                })
+                .collect();
+
+                (generation.text.trim().to_string(), spans, None)
+            }
+            ResponseFormat::RubricJson => {
+                // The grammar guarantees this parses unless generation ran
+                // out of tokens first
+                let structured: StructuredFeedback = serde_json::from_str(&generation.text)
+                    .with_context(|| {
+                        format!(
+                            "Structured output incomplete after {} tokens; increase max_tokens",
+                            generation.tokens.len()
+                        )
+                    })?;
+
+                (structured.to_text(), Vec::new(), Some(structured))
+            }
+        };
 
         let inference_time_ms = start_time.elapsed().as_millis() as u64;
 
         tracing::info!(
             "Generated {} tokens in {}ms",
-            generated_tokens.len(),
+            generation.tokens.len(),
             inference_time_ms
         );
 
         Ok(InferenceResponse {
-            feedback: feedback.trim().to_string(),
+            feedback,
             confidence,
+            low_confidence_spans,
             rubric_alignment,
-            tokens_generated: generated_tokens.len(),
+            tokens_generated: generation.tokens.len(),
             inference_time_ms,
+            structured,
+            params: GenerationParams {
+                model: Some(self.model.id.clone()),
+                ..request.generation_params(sampling_params.seed)
+            },
+            batched: false,
+            context: ContextUsage::default(),
+            model: self.model.clone(),
         })
     }
 
-    /// Generate tokens using the model
+    /// Generate tokens for a batch of prompts
+    ///
+    /// Prompts are left-padded to a common length and fed once; after that
+    /// each step feeds one token per row and relies on the backend's KV
+    /// cache for the rest. Rows that have finished are fed padding until
+    /// the whole batch is done. A batch of one is fed without padding
+    /// through the plain forward pass.
+    ///
+    /// Each row's deadline is checked before every step; a row that runs
+    /// out or is cancelled stops there and its generation is an error,
+    /// while the other rows carry on.
     fn generate_tokens(
         &mut self,
-        input_tokens: &[u32],
-        params: &SamplingParams,
-    ) -> Result<Vec<u32>> {
-        let mut generated = Vec::new();
-        let mut logits_processor = LogitsProcessor::new(params);
-
-        let eos_token = self.model.eos_token_id().unwrap_or(2); // Default to </s> token ID
-
-        // Convert input tokens to tensor
-        let mut tokens = input_tokens.to_vec();
-        let device = &self.model.device;
-
-        for step in 0..params.max_tokens {
-            // Create input tensor for current tokens
-            let input_tensor = Tensor::new(&tokens[..], device)?
-                .unsqueeze(0)?; // Add batch dimension
-
-            // Forward pass
-            let logits = self.model.forward(&input_tensor, tokens.len() - 1)?;
+        prompts: &[&[u32]],
+        params: &[&SamplingParams],
+        deadlines: &[&Deadline],
+        on_chunk: &mut dyn FnMut(usize, &str, usize) -> Result<()>,
+    ) -> Result<Vec<Result<Generation>>> {
+        let eos_token = self.backend.eos_token_id().unwrap_or(2); // Default to </s> token ID
+
+        let vocab = if params.iter().any(|p| p.response_format == ResponseFormat::RubricJson) {
+            Some(self.vocab()?)
+        } else {
+            None
+        };
+        let mut sequences: Vec<Sequence> = params
+            .iter()
+            .map(|p| Sequence::new(p, vocab.clone(), eos_token))
+            .collect();
+        let device = self.backend.device().clone();
 
-            // Get logits for last token
-            let last_logits = logits.get(0)?.get(tokens.len() - 1)?;
+        self.backend.reset();
 
-            // Sample next token
-            let next_token = logits_processor.sample(&last_logits)?;
+        let batch_size = prompts.len();
+        let width = prompts.iter().map(|p| p.len()).max().unwrap_or(0);
+        let padding: Vec<usize> = prompts.iter().map(|p| width - p.len()).collect();
+        let mut pending: Vec<u32> = prompts
+            .iter()
+            .zip(&padding)
+            .flat_map(|(prompt, &pad)| {
+                std::iter::repeat_n(eos_token, pad).chain(prompt.iter().copied())
+            })
+            .collect();
+        let mut seqlen_offset = 0;
+        let max_steps = params.iter().map(|p| p.max_tokens).max().unwrap_or(0);
 
-            // Check for stop conditions
-            if next_token == eos_token {
-                tracing::debug!("EOS token generated at step {}", step);
+        for step in 0..max_steps {
+            for (sequence, deadline) in sequences.iter_mut().zip(deadlines) {
+                if let Some(reason) = deadline.check().filter(|_| !sequence.done) {
+                    sequence.interrupt(reason);
+                }
+            }
+            if sequences.iter().all(|s| s.done) {
                 break;
             }
 
-            // Check for stop sequences
-            let generated_text = self.model.decode(&generated, true)?;
-            if params.stop_sequences.iter().any(|seq| generated_text.ends_with(seq)) {
-                tracing::debug!("Stop sequence detected at step {}", step);
-                break;
+            // Feed only the tokens the cache has not seen yet
+            let step_len = pending.len() / batch_size;
+            let input_tensor = Tensor::from_vec(pending, (batch_size, step_len), &device)?;
+
+            let logits = if batch_size == 1 {
+                self.backend.forward(&input_tensor, seqlen_offset)?
+            } else {
+                self.backend.forward_padded(&input_tensor, &padding, seqlen_offset)?
+            };
+            seqlen_offset += step_len;
+
+            pending = Vec::with_capacity(batch_size);
+            for (row, sequence) in sequences.iter_mut().enumerate() {
+                if !sequence.done {
+                    // Logits for the row's last position
+                    let last_logits = logits.get(row)?.get(0)?;
+                    let generated = sequence.generated.len();
+                    sequence.step(self.backend.as_ref(), &last_logits, &mut |text, tokens| {
+                        on_chunk(row, text, tokens)
+                    })?;
+                    if sequence.generated.len() > generated {
+                        deadlines[row].count_token();
+                    }
+                }
+                pending.push(sequence.next_input().unwrap_or(eos_token));
             }
 
-            generated.push(next_token);
-            tokens.push(next_token);
+            if sequences.iter().all(|s| s.done) {
+                tracing::debug!("Batch finished at step {}", step);
+                break;
+            }
 
             if step % 50 == 0 {
                 tracing::debug!("Generated {} tokens", step);
             }
         }
 
-        Ok(generated)
+        Ok(sequences
+            .into_iter()
+            .enumerate()
+            .map(|(row, sequence)| {
+                sequence.finish(self.backend.as_ref(), &mut |text, tokens| {
+                    on_chunk(row, text, tokens)
+                })
+            })
+            .collect())
     }
 
-    /// Calculate confidence score based on token probabilities
-    fn calculate_confidence(&self, _tokens: &[u32]) -> f32 {
-        // Simplified confidence calculation
-        // In production, this would analyze the probability distribution
-        // of generated tokens
-        0.85 // Placeholder
+    /// Bytes of every token in the backend's vocabulary
+    fn vocab(&mut self) -> Result<Arc<[Vec<u8>]>> {
+        if let Some(vocab) = &self.vocab {
+            return Ok(Arc::clone(vocab));
+        }
+
+        let vocab: Arc<[Vec<u8>]> = (0..self.backend.vocab_size() as u32)
+            .map(|token| self.backend.token_bytes(token))
+            .collect::<Result<_>>()?;
+        self.vocab = Some(Arc::clone(&vocab));
+
+        Ok(vocab)
     }
 
     /// Calculate rubric alignment score
@@ -317,29 +1136,11 @@ fn calculate_rubric_alignment(&self, feedback: &str, rubric: &str) -> f32 {
     }
 }
 
-// Add rand dependency for sampling
-mod rand {
-    use std::cell::Cell;
-
-    thread_local! {
-        static RNG_STATE: Cell<u64> = Cell::new(0x4d595df4d0f33173);
-    }
-
-    pub fn random() -> f32 {
-        RNG_STATE.with(|state| {
-            let mut x = state.get();
-            x ^= x << 13;
-            x ^= x >> 7;
-            x ^= x << 17;
-            state.set(x);
-            (x as f32) / (u64::MAX as f32)
-        })
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::{EchoBackend, ScriptedBackend};
+    use crate::watchdog::Interrupted;
 
     #[test]
     fn test_sampling_params_default() {
@@ -359,6 +1160,20 @@ fn test_sampling_params_from_request() {
             max_tokens: 256,
             temperature: 0.5,
             top_p: 0.95,
+            criteria: vec![],
+            response_format: ResponseFormat::Text,
+            seed: None,
+            top_k: 0,
+            min_p: 0.0,
+            repetition_penalty: 1.1,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            stop_sequences: vec![],
+            prompt: Default::default(),
+            model: None,
+            timeout_ms: None,
+            token_limit: None,
+            cancel: Default::default(),
         };
 
         let params = SamplingParams::from(&req);
@@ -367,13 +1182,826 @@ fn test_sampling_params_from_request() {
         assert_eq!(params.max_tokens, 256);
     }
 
+    #[test]
+    fn test_request_stop_sequences_extend_defaults() {
+        let mut req = test_request("Rubric");
+        req.stop_sequences = vec!["\n###".to_string()];
+
+        let params = SamplingParams::from(&req);
+        assert_eq!(params.stop_sequences, vec!["</s>", "<|im_end|>", "\n###"]);
+    }
+
+    fn processor(params: SamplingParams) -> LogitsProcessor {
+        LogitsProcessor::new(&params)
+    }
+
+    fn logits(values: &[f32]) -> Tensor {
+        Tensor::new(values, &candle_core::Device::Cpu).unwrap()
+    }
+
+    #[test]
+    fn test_filter_top_k() {
+        let mut probs = [0.1, 0.4, 0.2, 0.3];
+        filter_top_k(&mut probs, 2);
+        assert_eq!(probs, [0.0, 0.4, 0.0, 0.3]);
+    }
+
+    #[test]
+    fn test_filter_min_p() {
+        let mut probs = [0.5, 0.3, 0.15, 0.05];
+        filter_min_p(&mut probs, 0.4);
+        assert_eq!(probs, [0.5, 0.3, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_filter_top_p() {
+        let mut probs = [0.15, 0.5, 0.05, 0.3];
+        filter_top_p(&mut probs, 0.75);
+        assert_eq!(probs, [0.0, 0.5, 0.0, 0.3]);
+    }
+
+    #[test]
+    fn test_top_k_restricts_sampling() {
+        let logits = logits(&[1.0, 3.0, 2.9, 0.5, 2.8]);
+
+        for seed in 0..50 {
+            let mut processor = processor(SamplingParams {
+                top_k: 2,
+                temperature: 2.0,
+                top_p: 1.0,
+                seed,
+                ..Default::default()
+            });
+            let token = processor.sample(&logits).unwrap();
+            assert!(token == 1 || token == 2, "seed {} sampled {}", seed, token);
+        }
+    }
+
+    #[test]
+    fn test_min_p_restricts_sampling() {
+        // Token 0 has ~0.67 of the mass and token 1 ~0.25; min-p of 0.3
+        // keeps both but drops the rest
+        let logits = logits(&[2.0, 1.0, -2.0, -2.0]);
+
+        for seed in 0..50 {
+            let mut processor = processor(SamplingParams {
+                min_p: 0.3,
+                temperature: 1.0,
+                top_p: 1.0,
+                seed,
+                ..Default::default()
+            });
+            assert!(processor.sample(&logits).unwrap() < 2);
+        }
+    }
+
+    #[test]
+    fn test_penalties_adjust_logits() {
+        let mut processor = processor(SamplingParams {
+            repetition_penalty: 2.0,
+            frequency_penalty: 0.5,
+            presence_penalty: 0.25,
+            ..Default::default()
+        });
+        processor.token_counts = HashMap::from([(0, 2), (1, 1)]);
+
+        let mut values = [-1.0, 2.0, 1.0];
+        processor.apply_penalties(&mut values);
+
+        // Token 0: -1.0 * 2.0 - 2 * 0.5 - 0.25; token 1: 2.0 / 2.0 - 0.5 - 0.25
+        assert_eq!(values, [-3.25, 0.25, 1.0]);
+    }
+
+    #[test]
+    fn test_penalties_do_not_lower_recorded_probability() {
+        let mut processor = processor(SamplingParams {
+            top_k: 1,
+            repetition_penalty: 1.5,
+            frequency_penalty: 1.0,
+            presence_penalty: 1.0,
+            ..Default::default()
+        });
+        let logits = logits(&[3.0, 0.0]);
+
+        assert_eq!(processor.sample(&logits).unwrap(), 0);
+        assert_eq!(processor.sample(&logits).unwrap(), 0);
+
+        let stats = processor.token_stats();
+        assert_eq!(stats[0], stats[1]);
+        assert!(!stats[0].forced);
+    }
+
+    #[test]
+    fn test_presence_penalty_changes_choice() {
+        let mut processor = processor(SamplingParams {
+            top_k: 1,
+            repetition_penalty: 1.0,
+            presence_penalty: 2.0,
+            ..Default::default()
+        });
+        let logits = logits(&[1.0, 0.9]);
+
+        assert_eq!(processor.sample(&logits).unwrap(), 0);
+        assert_eq!(processor.sample(&logits).unwrap(), 1);
+    }
+
     #[test]
     fn test_logits_processor() {
         let params = SamplingParams::default();
-        let mut processor = LogitsProcessor::new(&params);
+        let processor = LogitsProcessor::new(&params);
 
         // Test that processor can be created
         assert_eq!(processor.temperature, 0.7);
         assert_eq!(processor.generated_tokens.len(), 0);
     }
+
+    fn test_request(rubric: &str) -> InferenceRequest {
+        InferenceRequest {
+            tma_content: "Explain recursion.".to_string(),
+            rubric: rubric.to_string(),
+            question_number: 2,
+            student_answer: None,
+            max_tokens: 256,
+            temperature: 0.7,
+            top_p: 0.9,
+            criteria: vec![],
+            response_format: ResponseFormat::Text,
+            seed: None,
+            top_k: 0,
+            min_p: 0.0,
+            repetition_penalty: 1.1,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            stop_sequences: vec![],
+            prompt: Default::default(),
+            model: None,
+            timeout_ms: None,
+            token_limit: None,
+            cancel: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_scripted_engine_returns_script() {
+        let backend = ScriptedBackend::new(vec!["Good base case.".to_string()]);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+
+        let response = engine.generate(&test_request("Award marks for a base case")).unwrap();
+
+        assert_eq!(response.feedback, "Good base case.");
+        assert_eq!(response.tokens_generated, "Good base case.".len());
+    }
+
+    #[test]
+    fn test_scripted_engine_is_deterministic() {
+        let mut engine = InferenceEngine::new(Box::new(ScriptedBackend::default()));
+        let request = test_request("Award marks for a base case");
+
+        let first = engine.generate(&request).unwrap();
+        let second = engine.generate(&request).unwrap();
+
+        assert_eq!(first.feedback, second.feedback);
+        assert_eq!(first.tokens_generated, second.tokens_generated);
+    }
+
+    #[test]
+    fn test_echo_engine_respects_max_tokens() {
+        let mut engine = InferenceEngine::new(Box::new(EchoBackend::new()));
+        let mut request = test_request("Award marks for a base case");
+        request.max_tokens = 10;
+
+        let response = engine.generate(&request).unwrap();
+
+        assert_eq!(response.tokens_generated, 10);
+        assert_eq!(response.feedback, "system\nYou");
+    }
+
+    #[test]
+    fn test_stop_sequence_truncates_feedback() {
+        let backend = ScriptedBackend::new(vec!["Clear answer.\n###\nIgnore this".to_string()]);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let params = SamplingParams {
+            stop_sequences: vec!["\n###".to_string()],
+            ..Default::default()
+        };
+
+        let input = engine.backend.encode("prompt", true).unwrap();
+        let deadline = Deadline::default();
+        let generation = engine
+            .generate_tokens(&[&input], &[&params], &[&deadline], &mut |_, _, _| Ok(()))
+            .unwrap()
+            .remove(0)
+            .unwrap();
+
+        assert_eq!(generation.text, "Clear answer.");
+        assert_eq!(generation.tokens.len(), "Clear answer.\n###".len());
+    }
+
+    #[test]
+    fn test_streamed_chunks_match_feedback() {
+        let backend = ScriptedBackend::new(vec!["Clear answer.\n###\nIgnore this".to_string()]);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let params = SamplingParams {
+            stop_sequences: vec!["\n###".to_string()],
+            ..Default::default()
+        };
+
+        let mut chunks = Vec::new();
+        let input = engine.backend.encode("prompt", true).unwrap();
+        let deadline = Deadline::default();
+        let generation = engine
+            .generate_tokens(&[&input], &[&params], &[&deadline], &mut |_, text, tokens| {
+                chunks.push((text.to_string(), tokens));
+                Ok(())
+            })
+            .unwrap()
+            .remove(0)
+            .unwrap();
+
+        // No part of the stop sequence is ever streamed
+        let streamed: String = chunks.iter().map(|(text, _)| text.as_str()).collect();
+        assert_eq!(streamed, generation.text);
+        assert!(chunks.windows(2).all(|w| w[0].1 <= w[1].1));
+    }
+
+    #[test]
+    fn test_prompt_fed_once() {
+        /// Records the shape and offset of every forward call
+        struct Recording {
+            inner: EchoBackend,
+            calls: std::rc::Rc<std::cell::RefCell<Vec<(usize, usize)>>>,
+        }
+
+        impl InferenceBackend for Recording {
+            fn name(&self) -> &'static str {
+                "recording"
+            }
+            fn device(&self) -> &candle_core::Device {
+                self.inner.device()
+            }
+            fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+                self.inner.encode(text, add_special_tokens)
+            }
+            fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+                self.inner.decode(tokens, skip_special_tokens)
+            }
+            fn eos_token_id(&self) -> Option<u32> {
+                self.inner.eos_token_id()
+            }
+            fn vocab_size(&self) -> usize {
+                self.inner.vocab_size()
+            }
+            fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+                self.calls.borrow_mut().push((input_ids.dim(1)?, seqlen_offset));
+                self.inner.forward(input_ids, seqlen_offset)
+            }
+            fn reset(&mut self) {
+                self.inner.reset()
+            }
+        }
+
+        let calls = std::rc::Rc::default();
+        let backend = Recording {
+            inner: EchoBackend::new(),
+            calls: std::rc::Rc::clone(&calls),
+        };
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let params = SamplingParams {
+            max_tokens: 3,
+            ..Default::default()
+        };
+
+        let deadline = Deadline::default();
+        engine
+            .generate_tokens(&[&[1, 50, 51, 52, 53]], &[&params], &[&deadline], &mut |_, _, _| {
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(*calls.borrow(), vec![(5, 0), (1, 5), (1, 6)]);
+    }
+
+    #[test]
+    fn test_logits_processor_records_token_stats() {
+        let params = SamplingParams {
+            repetition_penalty: 1.0,
+            ..Default::default()
+        };
+        let mut processor = LogitsProcessor::new(&params);
+
+        // Four equally likely tokens
+        let logits = Tensor::zeros(4, DType::F32, &candle_core::Device::Cpu).unwrap();
+        processor.sample(&logits).unwrap();
+
+        let stat = processor.token_stats()[0];
+        assert!((stat.logprob - 0.25f32.ln()).abs() < 1e-5);
+        assert!((stat.entropy - 4f32.ln()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_forced_tokens_are_confident() {
+        let backend = ScriptedBackend::new(vec!["Good base case. Check edge cases.".to_string()]);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+
+        let response = engine.generate(&test_request("Award marks for a base case")).unwrap();
+
+        assert!(response.confidence > 0.99);
+        assert!(response.low_confidence_spans.is_empty());
+    }
+
+    #[test]
+    fn test_grammar_masks_disallowed_tokens() {
+        let backend = EchoBackend::new();
+        let vocab: Arc<[Vec<u8>]> = (0..backend.vocab_size() as u32)
+            .map(|t| backend.token_bytes(t).unwrap())
+            .collect();
+        let eos = backend.eos_token_id().unwrap();
+        let mut processor = LogitsProcessor::new(&SamplingParams::default()).with_grammar(
+            JsonGrammar::new(RUBRIC_FEEDBACK),
+            vocab,
+            eos,
+        );
+
+        // The model strongly prefers "G" and EOS, but only "{" or leading
+        // whitespace can start the document
+        let token_for = |text: &str| backend.encode(text, false).unwrap()[0];
+        let mut logits = vec![0.0f32; backend.vocab_size()];
+        logits[token_for("G") as usize] = 1.0e4;
+        logits[eos as usize] = 1.0e4;
+        let logits = Tensor::new(logits, &candle_core::Device::Cpu).unwrap();
+
+        let token = processor.sample(&logits).unwrap();
+        let text = backend.decode(&[token], false).unwrap();
+        assert!(text == "{" || text.trim().is_empty(), "sampled {:?}", text);
+    }
+
+    #[test]
+    fn test_structured_output_parsed() {
+        let json = r#"{"criterion_scores": [{"criterion_number": 1, "criterion_text": "Base case", "score": 8, "max_score": 10, "feedback": "Correct"}], "strengths": ["Concise"], "suggestions": ["Test the empty list"], "overall_grade": 80}"#;
+        let backend = ScriptedBackend::new(vec![format!("{}</s> trailing", json)]);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let mut request = test_request("Award marks for a base case");
+        request.response_format = ResponseFormat::RubricJson;
+
+        let response = engine.generate(&request).unwrap();
+
+        let structured = response.structured.unwrap();
+        assert_eq!(structured.criterion_scores[0].score, 8.0);
+        assert_eq!(structured.suggestions, vec!["Test the empty list".to_string()]);
+        assert_eq!(structured.overall_grade, 80.0);
+        assert!(response.feedback.starts_with("Criterion 1 (8/10): Correct"));
+        assert_eq!(response.tokens_generated, json.len());
+    }
+
+    #[test]
+    fn test_structured_output_cut_off_is_an_error() {
+        let json = r#"{"criterion_scores": [], "strengths": [], "suggestions": [], "overall_grade": 50}"#;
+        let backend = ScriptedBackend::new(vec![json.to_string()]);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let mut request = test_request("Rubric");
+        request.response_format = ResponseFormat::RubricJson;
+        request.max_tokens = 10;
+
+        let err = engine.generate(&request).unwrap_err();
+        assert!(format!("{:#}", err).contains("increase max_tokens"));
+    }
+
+    /// Echo backend whose logits are flat, so every token is equally
+    /// likely and the output depends only on the sampler's seed
+    struct Uniform(EchoBackend);
+
+    impl InferenceBackend for Uniform {
+        fn name(&self) -> &'static str {
+            "uniform"
+        }
+        fn device(&self) -> &candle_core::Device {
+            self.0.device()
+        }
+        fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+            self.0.encode(text, add_special_tokens)
+        }
+        fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+            self.0.decode(tokens, skip_special_tokens)
+        }
+        fn eos_token_id(&self) -> Option<u32> {
+            self.0.eos_token_id()
+        }
+        fn vocab_size(&self) -> usize {
+            self.0.vocab_size()
+        }
+        fn forward(&mut self, _input_ids: &Tensor, _seqlen_offset: usize) -> Result<Tensor> {
+            Ok(Tensor::zeros((1, 1, self.vocab_size()), DType::F32, self.device())?)
+        }
+        fn reset(&mut self) {}
+    }
+
+    #[test]
+    fn test_same_seed_reproduces_output() {
+        let mut engine = InferenceEngine::new(Box::new(Uniform(EchoBackend::new())));
+        let mut request = test_request("Rubric");
+        request.max_tokens = 64;
+        request.seed = Some(42);
+
+        let first = engine.generate(&request).unwrap();
+        let second = engine.generate(&request).unwrap();
+        request.seed = Some(43);
+        let other = engine.generate(&request).unwrap();
+
+        assert_eq!(first.feedback, second.feedback);
+        assert_eq!(first.tokens_generated, second.tokens_generated);
+        assert_ne!(first.feedback, other.feedback);
+    }
+
+    #[test]
+    fn test_response_records_seed_for_replay() {
+        let mut engine = InferenceEngine::new(Box::new(Uniform(EchoBackend::new())));
+        let mut request = test_request("Rubric");
+        request.max_tokens = 64;
+
+        let original = engine.generate(&request).unwrap();
+        assert_eq!(original.params.temperature, request.temperature);
+        assert_eq!(original.params.max_tokens, 64);
+
+        // Replaying with the recorded parameters gives the same output
+        request.seed = original.params.seed;
+        let replay = engine.generate(&request).unwrap();
+        assert_eq!(replay.feedback, original.feedback);
+    }
+
+    #[test]
+    fn test_engine_validates_request() {
+        let mut engine = InferenceEngine::new(Box::new(ScriptedBackend::default()));
+        let mut request = test_request("Rubric");
+        request.tma_content = String::new();
+
+        assert!(engine.generate(&request).is_err());
+    }
+
+    #[test]
+    fn test_batch_matches_sequential() {
+        let mut requests = Vec::new();
+        let cases = [("Short", 40), ("A much longer answer to echo", 60), ("Mid", 8)];
+        for (content, max_tokens) in cases {
+            let mut request = test_request("Rubric");
+            request.tma_content = content.to_string();
+            request.max_tokens = max_tokens;
+            requests.push(request);
+        }
+
+        let mut engine = InferenceEngine::new(Box::new(EchoBackend::new()));
+        let sequential: Vec<String> = requests
+            .iter()
+            .map(|r| engine.generate(r).unwrap().feedback)
+            .collect();
+
+        let batched = engine.generate_batch(&requests, &mut |_, _, _| Ok(()));
+        let batched: Vec<String> = batched.into_iter().map(|r| r.unwrap().feedback).collect();
+
+        assert_eq!(batched, sequential);
+    }
+
+    #[test]
+    fn test_batch_chunks_tagged_by_request() {
+        let backend = ScriptedBackend::new(vec!["First".to_string(), "Second".to_string()]);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let requests = vec![test_request("Rubric"), test_request("Rubric")];
+
+        let mut streamed = vec![String::new(); requests.len()];
+        let results = engine.generate_batch(&requests, &mut |index, text, _| {
+            streamed[index].push_str(text);
+            Ok(())
+        });
+
+        let feedback: Vec<String> = results.into_iter().map(|r| r.unwrap().feedback).collect();
+        assert_eq!(feedback, vec!["First", "Second"]);
+        assert_eq!(streamed, feedback);
+    }
+
+    #[test]
+    fn test_batch_reports_invalid_request_alone() {
+        let mut engine = InferenceEngine::new(Box::new(ScriptedBackend::default()));
+        let mut invalid = test_request("Rubric");
+        invalid.temperature = 5.0;
+        let requests = vec![test_request("Rubric"), invalid, test_request("Rubric")];
+
+        let results = engine.generate_batch(&requests, &mut |_, _, _| Ok(()));
+
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+    }
+
+    #[test]
+    fn test_grammar_dead_end_fails_only_its_request() {
+        /// Scripted backend that gives every token but the scripted one
+        /// zero probability
+        struct Strict(ScriptedBackend);
+
+        impl Strict {
+            fn only_best(logits: Tensor) -> Result<Tensor> {
+                let (shape, device) = (logits.shape().clone(), logits.device().clone());
+                let mut rows = logits.flatten_to(1)?.to_vec2::<f32>()?;
+                for row in &mut rows {
+                    let best = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+                    for logit in row.iter_mut().filter(|l| **l < best) {
+                        *logit = f32::NEG_INFINITY;
+                    }
+                }
+                let logits = rows.concat();
+                Ok(Tensor::from_vec(logits, shape, &device)?)
+            }
+        }
+
+        impl InferenceBackend for Strict {
+            fn name(&self) -> &'static str {
+                "strict"
+            }
+            fn device(&self) -> &candle_core::Device {
+                self.0.device()
+            }
+            fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+                self.0.encode(text, add_special_tokens)
+            }
+            fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+                self.0.decode(tokens, skip_special_tokens)
+            }
+            fn eos_token_id(&self) -> Option<u32> {
+                self.0.eos_token_id()
+            }
+            fn vocab_size(&self) -> usize {
+                self.0.vocab_size()
+            }
+            fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+                Self::only_best(self.0.forward(input_ids, seqlen_offset)?)
+            }
+            fn max_batch_size(&self) -> usize {
+                self.0.max_batch_size()
+            }
+            fn forward_padded(
+                &mut self,
+                input_ids: &Tensor,
+                padding: &[usize],
+                seqlen_offset: usize,
+            ) -> Result<Tensor> {
+                Self::only_best(self.0.forward_padded(input_ids, padding, seqlen_offset)?)
+            }
+            fn reset(&mut self) {
+                self.0.reset()
+            }
+        }
+
+        // The script cannot be written as JSON, so the grammar masks out
+        // the only token the backend allows
+        let backend = ScriptedBackend::new(vec!["Not JSON".to_string()]);
+        let mut engine = InferenceEngine::new(Box::new(Strict(backend)));
+        let mut structured = test_request("Rubric");
+        structured.response_format = ResponseFormat::RubricJson;
+        let requests = vec![structured, test_request("Rubric")];
+
+        let results = engine.generate_batch(&requests, &mut |_, _, _| Ok(()));
+
+        let err = results[0].as_ref().unwrap_err();
+        assert!(format!("{:#}", err).contains("No token in the vocabulary"));
+        assert_eq!(results[1].as_ref().unwrap().feedback, "Not JSON");
+    }
+
+    #[test]
+    fn test_seeded_request_decodes_the_same_beside_any_neighbours() {
+        /// Samples letters from logits that shift with the batch shape, as
+        /// padded matrix products do on a real model
+        struct ShapeSensitive {
+            inner: EchoBackend,
+            letters: Vec<u32>,
+        }
+
+        impl ShapeSensitive {
+            fn logits(&self, rows: usize, width: usize) -> Result<Tensor> {
+                let mut row = vec![f32::NEG_INFINITY; self.inner.vocab_size()];
+                for (i, &letter) in self.letters.iter().enumerate() {
+                    row[letter as usize] = (i * rows * width % 7) as f32 * 0.5;
+                }
+                let logits = row.repeat(rows);
+                Ok(Tensor::from_vec(logits, (rows, 1, row.len()), self.inner.device())?)
+            }
+        }
+
+        impl InferenceBackend for ShapeSensitive {
+            fn name(&self) -> &'static str {
+                "shape-sensitive"
+            }
+            fn device(&self) -> &candle_core::Device {
+                self.inner.device()
+            }
+            fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+                self.inner.encode(text, add_special_tokens)
+            }
+            fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+                self.inner.decode(tokens, skip_special_tokens)
+            }
+            fn eos_token_id(&self) -> Option<u32> {
+                self.inner.eos_token_id()
+            }
+            fn vocab_size(&self) -> usize {
+                self.inner.vocab_size()
+            }
+            fn forward(&mut self, _input_ids: &Tensor, _seqlen_offset: usize) -> Result<Tensor> {
+                self.logits(1, 1)
+            }
+            fn max_batch_size(&self) -> usize {
+                8
+            }
+            fn forward_padded(
+                &mut self,
+                input_ids: &Tensor,
+                padding: &[usize],
+                _seqlen_offset: usize,
+            ) -> Result<Tensor> {
+                let width = input_ids.dim(1)? + padding.iter().max().unwrap_or(&0);
+                self.logits(input_ids.dim(0)?, width)
+            }
+            fn reset(&mut self) {}
+        }
+
+        let inner = EchoBackend::new();
+        let letters = inner.encode("abcdefgh", false).unwrap();
+        let mut engine = InferenceEngine::new(Box::new(ShapeSensitive { inner, letters }));
+
+        let mut seeded = test_request("Rubric");
+        seeded.max_tokens = 32;
+        seeded.seed = Some(11);
+        let neighbour = |content: &str| InferenceRequest {
+            tma_content: content.to_string(),
+            ..seeded.clone()
+        };
+        let unseeded = |content: &str| InferenceRequest {
+            seed: None,
+            ..neighbour(content)
+        };
+
+        let alone = engine.generate(&seeded).unwrap();
+        let beside_seeded = engine.generate_batch(
+            &[neighbour("A longer neighbouring answer"), seeded.clone()],
+            &mut |_, _, _| Ok(()),
+        );
+        let beside_unseeded = engine.generate_batch(
+            &[unseeded("Short"), seeded.clone(), unseeded("Another, much longer, answer")],
+            &mut |_, _, _| Ok(()),
+        );
+
+        let beside_seeded = beside_seeded[1].as_ref().unwrap();
+        assert_eq!(beside_seeded.feedback, alone.feedback);
+        assert!(!beside_seeded.batched);
+
+        let beside_unseeded: Vec<&InferenceResponse> =
+            beside_unseeded.iter().map(|r| r.as_ref().unwrap()).collect();
+        assert_eq!(beside_unseeded[1].feedback, alone.feedback);
+        assert!(!beside_unseeded[1].batched);
+        // Unseeded requests still share a batch, and say so
+        assert!(beside_unseeded[0].batched && beside_unseeded[2].batched);
+    }
+
+    /// An answer of `paragraphs` paragraphs of about 100 bytes each
+    fn long_answer(paragraphs: usize) -> String {
+        (1..=paragraphs)
+            .map(|i| {
+                let filler = "x".repeat(40);
+                format!("Paragraph {} discusses the algorithm and its testing. {}", i, filler)
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    #[test]
+    fn test_short_answer_is_one_section() {
+        let mut engine = InferenceEngine::new(Box::new(ScriptedBackend::default()));
+
+        let response = engine.generate(&test_request("Rubric")).unwrap();
+
+        assert_eq!(response.context.sections, 1);
+        assert!(!response.context.truncated);
+        assert_eq!(response.context.context_length, crate::backend::DEFAULT_CONTEXT_LENGTH);
+        assert!(response.context.prompt_tokens > 0);
+    }
+
+    #[test]
+    fn test_long_answer_split_to_fit_context() {
+        let backend = EchoBackend::new().with_context_length(700);
+        let engine = InferenceEngine::new(Box::new(backend));
+        let mut request = test_request("Rubric");
+        request.tma_content = long_answer(12);
+        request.max_tokens = 64;
+
+        let plan = engine.plan(&request).unwrap();
+
+        assert!(plan.sections.len() > 1);
+        assert!(!plan.context.truncated);
+        for section in &plan.sections {
+            assert!(section.tokens.len() + request.max_tokens <= 700);
+            assert_eq!(section.params.seed, plan.sections[0].params.seed);
+        }
+        // Every paragraph is marked in exactly one section
+        for i in 1..=12 {
+            let needle = format!("Paragraph {} ", i);
+            let found = plan
+                .sections
+                .iter()
+                .filter(|s| s.request.tma_content.contains(&needle))
+                .count();
+            assert_eq!(found, 1, "paragraph {}", i);
+        }
+    }
+
+    #[test]
+    fn test_cancelled_request_stops_alone() {
+        let mut engine = InferenceEngine::new(Box::new(ScriptedBackend::default()));
+        let cancelled = test_request("Rubric");
+        cancelled.cancel.cancel();
+        let requests = [cancelled, test_request("Rubric")];
+
+        let mut results = engine.generate_batch(&requests, &mut |_, _, _| Ok(()));
+
+        assert!(results.pop().unwrap().is_ok());
+        let err = results.pop().unwrap().unwrap_err();
+        assert_eq!(err.downcast_ref::<Interrupted>(), Some(&Interrupted::Cancelled));
+    }
+
+    #[test]
+    fn test_token_limit_spans_sections() {
+        let backend = ScriptedBackend::new(vec!["Part feedback.".to_string()])
+            .with_context_length(700);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let mut request = test_request("Rubric");
+        request.tma_content = long_answer(12);
+        request.max_tokens = 64;
+
+        // Each section alone is well within the limit
+        request.token_limit = Some("Part feedback.".len() + 1);
+        let err = engine.generate(&request).unwrap_err();
+        assert_eq!(err.downcast_ref::<Interrupted>(), Some(&Interrupted::TokenLimit(15)));
+
+        request.token_limit = Some(1000);
+        assert!(engine.generate(&request).is_ok());
+    }
+
+    #[test]
+    fn test_split_answer_feedback_merged() {
+        let backend = ScriptedBackend::new(vec!["Part feedback.".to_string()])
+            .with_context_length(700);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let mut request = test_request("Rubric");
+        request.tma_content = long_answer(12);
+        request.max_tokens = 64;
+
+        let mut streamed = String::new();
+        let response = engine
+            .generate_streaming(&request, &mut |text, _| {
+                streamed.push_str(text);
+                Ok(())
+            })
+            .unwrap();
+
+        let sections = response.context.sections;
+        assert!(sections > 1);
+        assert!(response.feedback.starts_with(&format!("Part 1 of {}:\nPart feedback.", sections)));
+        assert_eq!(response.tokens_generated, sections * "Part feedback.".len());
+        assert_eq!(streamed, response.feedback);
+    }
+
+    #[test]
+    fn test_overlong_answer_reports_truncation() {
+        let backend = ScriptedBackend::default().with_context_length(700);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let mut request = test_request("Rubric");
+        request.tma_content = long_answer(200);
+        request.max_tokens = 64;
+
+        let response = engine.generate(&request).unwrap();
+
+        assert_eq!(response.context.sections, MAX_SECTIONS);
+        assert!(response.context.truncated);
+        assert!(response.context.omitted_bytes > 0);
+        assert!(response.context.omitted_bytes < request.tma_content.len());
+    }
+
+    #[test]
+    fn test_max_tokens_larger_than_context_rejected() {
+        let backend = ScriptedBackend::default().with_context_length(300);
+        let mut engine = InferenceEngine::new(Box::new(backend));
+        let mut request = test_request("Rubric");
+        request.max_tokens = 300;
+
+        let err = engine.generate(&request).unwrap_err();
+        assert!(format!("{:#}", err).contains("context window"));
+    }
+
+    #[test]
+    fn test_engine_rejects_unknown_template() {
+        let mut engine = InferenceEngine::new(Box::new(ScriptedBackend::default()));
+        let mut request = test_request("Rubric");
+        request.prompt.template = Some("missing".to_string());
+
+        let err = engine.validate(&request).unwrap_err();
+        assert!(err.contains("Unknown prompt template 'missing'"));
+        assert!(engine.generate(&request).is_err());
+    }
 }
@@ -0,0 +1,18 @@
+//! AI Jail inference library
+//!
+//! The model loading, inference and protocol code behind the `ai-jail`
+//! binary. It is exposed as a library so benchmarks can drive the
+//! inference engine directly instead of through the jail's stdin/stdout.
+
+pub mod backend;
+pub mod confidence;
+pub mod context;
+pub mod grammar;
+pub mod inference;
+pub mod manifest;
+pub mod model;
+pub mod prompt;
+pub mod protocol;
+pub mod registry;
+pub mod stream;
+pub mod watchdog;
@@ -6,29 +6,143 @@
 //!
 //! # Architecture
 //!
-//! - Reads JSON requests from stdin
+//! - Reads `aws-ipc-protocol` messages from stdin, one JSON object per line
 //! - Loads Mistral 7B model from local storage
 //! - Generates feedback using Candle ML framework
-//! - Writes JSON responses to stdout
+//! - Writes `aws-ipc-protocol` messages to stdout
 //! - Runs in Podman container with network disabled
 //!
+//! # Backends
+//!
+//! The backend is chosen with `--backend <name>` or `AI_JAIL_BACKEND`:
+//!
+//! - `candle` (default): Mistral 7B from `MODEL_PATH`/`TOKENIZER_PATH`
+//! - `echo`: repeats the prompt back, no weights needed
+//! - `scripted`: replays responses from `AI_JAIL_MOCK_SCRIPT` (separated by
+//!   `---` lines), or a built-in response if unset, taking
+//!   `AI_JAIL_MOCK_TOKEN_DELAY_MS` per token
+//!
+//! The mock backends are deterministic and run on CPU, so the whole
+//! orchestrator-to-jail pipeline can be exercised in CI.
+//!
+//! # Models
+//!
+//! `MODEL_REGISTRY` names a JSON file listing the models the jail may
+//! serve (see [`ai_jail::registry`]). Requests pick one by id with
+//! `params.model`; models are loaded on first use and unloaded least
+//! recently used first to stay within `MODEL_MEMORY_BUDGET_MB`. Without a
+//! registry the jail serves the single model described by `MODEL_PATH`,
+//! registered as `MODEL_ID` at `MODEL_REVISION`. The default model is
+//! loaded at startup either way.
+//!
+//! # Model integrity
+//!
+//! With `MODEL_MANIFEST_KEY_FILE` naming a file that holds a hex encoded
+//! public key (ed25519, or Dilithium5 with `MODEL_MANIFEST_ALGORITHM=dilithium`),
+//! every model's weights and tokenizer are checked against a signed manifest
+//! of BLAKE3 hashes (`MODEL_MANIFEST`, or `manifest` in the registry) before
+//! they are loaded. The jail refuses to start if any model fails the check, and
+//! reports the verified hash of the weights in every response.
+//!
+//! Without a key the Candle backend refuses to start, unless
+//! `ALLOW_UNVERIFIED_MODELS=1` accepts unverified weights. The mock backends
+//! load no weights and start either way.
+//!
+//! # Batching
+//!
+//! Feedback requests that queue up on stdin while a generation is running
+//! are decoded together, up to the backend's batch size, and answered in
+//! the order they arrived. Backends that cannot mask padding (the quantized
+//! models) decode them one at a time.
+//!
+//! # Context window
+//!
+//! Prompts must leave `max_tokens` of the model's context window
+//! (`CONTEXT_LENGTH`, 8192 tokens by default) free for output. Answers too
+//! long for that are split into sections that are marked separately and
+//! merged; past the section limit the rest of the answer is left out, and
+//! the response says so.
+//!
+//! # Deadlines and cancellation
+//!
+//! A request stops with a `timeout` error once it has run for
+//! `params.timeout_ms` or generated `params.token_limit` tokens, capped by
+//! `REQUEST_TIMEOUT_SECS` (300 by default) and `REQUEST_TOKEN_LIMIT` (none
+//! by default); 0 lifts either cap. A `Cancel` message stops a request
+//! with a `cancelled` error, whether it is running or still queued. See
+//! [`ai_jail::watchdog`].
+//!
+//! # Prompts
+//!
+//! Requests pick a prompt template by name from the built-in `default` and
+//! any `.txt` files in `PROMPT_TEMPLATE_DIR`. The chat format comes from
+//! `CHAT_FORMAT` (`chatml` or `mistral`), or is guessed from `MODEL_PATH`.
+//!
+//! # Reproducibility
+//!
+//! Sampling is seeded from `params.seed`, or from a random seed that is
+//! then reported in the response metadata. The same model, request and
+//! parameters give bit-identical output on the same device only when the
+//! request is decoded alone: a batch pads its prompts and changes the
+//! shape of the model's matrix products. Requests that set `params.seed`
+//! are never batched with other requests, so a replay is decoded alone;
+//! it matches the original exactly when `metadata.batched` was false for
+//! that too. CUDA kernels are not guaranteed to match across GPU models.
+//!
+//! # Handshake
+//!
+//! A `Hello` is answered with the jail's protocol version and what it
+//! supports: streaming, the largest batch any loaded model decodes, rubric
+//! JSON output, and the ids of the models in the registry. The jail serves
+//! orchestrators that skip the handshake just the same.
+//!
+//! # Framing
+//!
+//! Messages are read and written one per line until the orchestrator asks
+//! for length-prefixed frames with `SetFraming`. Frames larger than
+//! `IPC_MAX_FRAME_SIZE` bytes (16 MiB by default), or the smaller limit the
+//! orchestrator asks for, are skipped and answered with an error.
+//!
+//! # Logging
+//!
+//! Logs go to stderr, filtered by `RUST_LOG` (`info` by default). With
+//! `AI_JAIL_LOG_FORMAT=json` each line is a JSON object, which is how the
+//! orchestrator reads them back; otherwise they are plain text.
+//!
 //! # Usage
 //!
 //! ```bash
-//! echo '{"tma_content":"...","rubric":"...","question_number":1}' | ai-jail
+//! echo '{"type":"Ping","payload":{"timestamp":0}}' | ai-jail --backend echo
 //! ```
 
 use anyhow::{Context, Result};
+use aws_ipc_protocol::{
+    Capabilities, ErrorKind, FrameCodec, FrameDecoder, FrameError, IPCMessage,
+    DEFAULT_MAX_FRAME_SIZE, PROTOCOL_VERSION,
+};
+use std::collections::BTreeMap;
 use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
 use tracing_subscriber::EnvFilter;
 
-mod inference;
-mod model;
-mod protocol;
-
-use inference::InferenceEngine;
-use model::{LoadedModel, ModelConfig};
-use protocol::{ErrorResponse, InferenceRequest, Response};
+use ai_jail::backend::{
+    context_length_from_env, BackendKind, EchoBackend, InferenceBackend, ScriptedBackend,
+};
+use ai_jail::manifest::ManifestVerifier;
+use ai_jail::model::{LoadedModel, ModelConfig};
+use ai_jail::prompt::TemplateRegistry;
+use ai_jail::protocol::InferenceRequest;
+use ai_jail::registry::{ModelLoader, ModelRegistry, ModelSpec, UNVERSIONED};
+use ai_jail::watchdog::{error_kind, Limits, Watchdog};
+
+/// Whether the main loop should keep reading after a message
+#[derive(Debug, PartialEq)]
+enum Control {
+    Continue,
+    Shutdown,
+}
 
 /// Main entry point
 fn main() {
@@ -42,13 +156,13 @@ fn main() {
 
     // Run the main loop
     if let Err(e) = run() {
-        let error_response = Response::Error(ErrorResponse {
-            error_type: "initialization_error".to_string(),
-            message: e.to_string(),
-            details: Some(format!("{:?}", e)),
-        });
+        let error_response = IPCMessage::Error {
+            request_id: None,
+            kind: ErrorKind::InitializationError,
+            message: format!("{:#}", e),
+        };
 
-        if let Err(e) = write_response(&error_response) {
+        if let Err(e) = write_message(&error_response) {
             eprintln!("Failed to write error response: {}", e);
         }
 
@@ -63,31 +177,179 @@ fn init_logging() -> Result<()> {
     let filter = EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| EnvFilter::new("info"));
 
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_writer(io::stderr) // Write logs to stderr, keep stdout for IPC
         .with_target(false)
         .with_thread_ids(false)
         .with_file(true)
-        .with_line_number(true)
-        .init();
+        .with_line_number(true);
+
+    match std::env::var("AI_JAIL_LOG_FORMAT").as_deref() {
+        Ok("json") => subscriber.json().init(),
+        Ok("text") | Err(_) => subscriber.init(),
+        Ok(other) => {
+            anyhow::bail!("Unknown AI_JAIL_LOG_FORMAT '{}' (expected json or text)", other)
+        }
+    }
 
     Ok(())
 }
 
 /// Main execution loop
 fn run() -> Result<()> {
-    // Load model configuration
-    tracing::info!("Loading model configuration...");
-    let config = ModelConfig::from_env()
-        .context("Failed to load model configuration")?;
+    let kind = backend_kind(std::env::args().skip(1))?;
+    let loader = model_loader(kind)?;
+
+    if kind != BackendKind::Candle {
+        tracing::warn!("Running with a mock backend; feedback is not model-generated");
+    }
+
+    let mut registry = match std::env::var("MODEL_REGISTRY") {
+        Ok(path) => ModelRegistry::from_file(&path, loader)
+            .context("Failed to load model registry")?,
+        Err(_) => ModelRegistry::new(vec![default_model_spec(kind)?], loader)?,
+    };
+
+    if let Ok(mb) = std::env::var("MODEL_MEMORY_BUDGET_MB") {
+        let mb: usize = mb
+            .parse()
+            .with_context(|| format!("Invalid MODEL_MEMORY_BUDGET_MB '{}'", mb))?;
+        registry = registry.with_memory_budget(mb * 1024 * 1024);
+    }
+
+    match ManifestVerifier::from_env()? {
+        Some(verifier) => registry = registry.with_verifier(verifier),
+        None if kind != BackendKind::Candle => {}
+        None if allow_unverified_models()? => {
+            tracing::warn!("MODEL_MANIFEST_KEY_FILE is not set; model files are not verified")
+        }
+        None => anyhow::bail!(
+            "MODEL_MANIFEST_KEY_FILE is not set; refusing to load unverified model files \
+             (set ALLOW_UNVERIFIED_MODELS=1 to load them anyway)"
+        ),
+    }
+
+    if let Ok(dir) = std::env::var("PROMPT_TEMPLATE_DIR") {
+        let templates = TemplateRegistry::load_dir(&dir)
+            .context("Failed to load prompt templates")?;
+        registry = registry.with_templates(templates);
+    }
+
+    // Fail at startup rather than on the first request if the default
+    // model cannot be loaded or any model's files have been tampered with
+    registry.engine(None)?;
+    registry.verify()?;
 
+    let watchdog = Watchdog::new(Limits::from_env()?);
+    let max_frame_size = match std::env::var("IPC_MAX_FRAME_SIZE") {
+        Ok(size) => size
+            .parse()
+            .with_context(|| format!("Invalid IPC_MAX_FRAME_SIZE '{}'", size))?,
+        Err(_) => DEFAULT_MAX_FRAME_SIZE,
+    };
+
+    // Process requests from stdin
+    tracing::info!("Ready to process requests");
+    process_requests(&mut registry, &watchdog, max_frame_size)?;
+
+    Ok(())
+}
+
+/// How models are turned into backends for the selected backend kind
+///
+/// The mock backends ignore the model files, so every registered model
+/// id gets its own mock.
+fn model_loader(kind: BackendKind) -> Result<ModelLoader> {
+    let context_length = context_length_from_env()?;
+
+    Ok(match kind {
+        BackendKind::Candle => Box::new(load_model),
+        BackendKind::Echo => Box::new(move |_| {
+            Ok(Box::new(EchoBackend::new().with_context_length(context_length)))
+        }),
+        BackendKind::Scripted => {
+            let script = std::env::var("AI_JAIL_MOCK_SCRIPT").ok();
+            let token_delay = match std::env::var("AI_JAIL_MOCK_TOKEN_DELAY_MS") {
+                Ok(ms) => Duration::from_millis(
+                    ms.parse()
+                        .with_context(|| format!("Invalid AI_JAIL_MOCK_TOKEN_DELAY_MS '{}'", ms))?,
+                ),
+                Err(_) => Duration::ZERO,
+            };
+            Box::new(move |_| {
+                let backend = match &script {
+                    Some(path) => ScriptedBackend::from_file(path)?,
+                    None => ScriptedBackend::default(),
+                };
+                Ok(Box::new(
+                    backend
+                        .with_context_length(context_length)
+                        .with_token_delay(token_delay),
+                ))
+            })
+        }
+    })
+}
+
+/// The single model served when no registry file is given
+fn default_model_spec(kind: BackendKind) -> Result<ModelSpec> {
+    let default_id = match kind {
+        BackendKind::Candle => "mistral-7b",
+        BackendKind::Echo => "echo",
+        BackendKind::Scripted => "scripted",
+    };
+
+    Ok(ModelSpec {
+        id: std::env::var("MODEL_ID").unwrap_or_else(|_| default_id.to_string()),
+        revision: std::env::var("MODEL_REVISION").unwrap_or_else(|_| UNVERSIONED.to_string()),
+        config: ModelConfig::from_env().context("Failed to load model configuration")?,
+        manifest: std::env::var_os("MODEL_MANIFEST").map(PathBuf::from),
+    })
+}
+
+/// Whether `ALLOW_UNVERIFIED_MODELS` opts out of model verification
+fn allow_unverified_models() -> Result<bool> {
+    match std::env::var("ALLOW_UNVERIFIED_MODELS").as_deref() {
+        Ok("1") | Ok("true") => Ok(true),
+        Ok("0") | Ok("false") | Ok("") | Err(_) => Ok(false),
+        Ok(other) => anyhow::bail!(
+            "Invalid ALLOW_UNVERIFIED_MODELS '{}' (expected 1 or 0)",
+            other
+        ),
+    }
+}
+
+/// Select the inference backend
+///
+/// A `--backend` flag takes precedence over `AI_JAIL_BACKEND`; the default
+/// is the Candle model.
+fn backend_kind(mut args: impl Iterator<Item = String>) -> Result<BackendKind> {
+    let mut flag = None;
+    while let Some(arg) = args.next() {
+        if let Some(value) = arg.strip_prefix("--backend=") {
+            flag = Some(value.to_string());
+        } else if arg == "--backend" {
+            flag = Some(args.next().context("--backend requires a value")?);
+        } else {
+            anyhow::bail!("Unknown argument: {}", arg);
+        }
+    }
+
+    match flag.or_else(|| std::env::var("AI_JAIL_BACKEND").ok()) {
+        Some(name) => name.parse(),
+        None => Ok(BackendKind::Candle),
+    }
+}
+
+/// Load a model after checking its files exist
+fn load_model(config: &ModelConfig) -> Result<Box<dyn InferenceBackend>> {
     // Validate model files exist
-    validate_model_files(&config)?;
+    validate_model_files(config)?;
 
     // Load model
     tracing::info!("Loading model (this may take a few minutes)...");
-    let model = LoadedModel::load(config)
+    let model = LoadedModel::load(config.clone())
         .context("Failed to load model")?;
 
     let memory_usage = model.estimate_memory_usage();
@@ -96,14 +358,7 @@ fn run() -> Result<()> {
         memory_usage as f64 / 1_073_741_824.0
     );
 
-    // Create inference engine
-    let mut engine = InferenceEngine::new(model);
-
-    // Process requests from stdin
-    tracing::info!("Ready to process requests");
-    process_requests(&mut engine)?;
-
-    Ok(())
+    Ok(Box::new(model))
 }
 
 /// Validate that required model files exist
@@ -127,81 +382,377 @@ fn validate_model_files(config: &ModelConfig) -> Result<()> {
 }
 
 /// Process inference requests from stdin
-fn process_requests(engine: &mut InferenceEngine) -> Result<()> {
-    let stdin = io::stdin();
-    let mut reader = stdin.lock();
-    let mut line = String::new();
+///
+/// Messages are read on a separate thread, so feedback requests that
+/// arrive while a generation is running queue up and are decoded together
+/// as one batch once it finishes, and a `Cancel` reaches the request it
+/// names straight away.
+fn process_requests(
+    registry: &mut ModelRegistry,
+    watchdog: &Watchdog,
+    max_frame_size: usize,
+) -> Result<()> {
+    let messages = spawn_message_reader(watchdog.clone(), max_frame_size);
+    let mut held = None;
 
     loop {
-        line.clear();
+        let Some(message) = held.take().or_else(|| messages.recv().ok()) else {
+            tracing::info!("EOF reached, shutting down");
+            break;
+        };
 
-        // Read line from stdin
-        match reader.read_line(&mut line) {
-            Ok(0) => {
-                // EOF reached
-                tracing::info!("EOF reached, shutting down");
-                break;
-            }
-            Ok(_) => {
-                // Process the request
-                if let Err(e) = process_single_request(engine, &line) {
-                    tracing::error!("Error processing request: {}", e);
-
-                    let error_response = Response::Error(ErrorResponse {
-                        error_type: "processing_error".to_string(),
-                        message: e.to_string(),
-                        details: Some(format!("{:?}", e)),
-                    });
-
-                    write_response(&error_response)?;
+        let result = match message {
+            Ok(first @ IPCMessage::FeedbackRequest { .. }) => {
+                let mut batch = vec![first];
+                while batch.len() < registry.max_batch_size() {
+                    let Ok(next) = messages.try_recv() else {
+                        break;
+                    };
+                    match next {
+                        Ok(message @ IPCMessage::FeedbackRequest { .. }) => batch.push(message),
+                        // Anything else waits until the batch is answered
+                        other => {
+                            held = Some(other);
+                            break;
+                        }
+                    }
                 }
+                process_feedback_batch(registry, watchdog, batch).map(|()| Control::Continue)
             }
-            Err(e) => {
-                tracing::error!("Error reading from stdin: {}", e);
+            Ok(message) => process_message(registry, watchdog, message),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(Control::Continue) => {}
+            Ok(Control::Shutdown) => {
+                tracing::info!("Shutdown requested");
                 break;
             }
+            Err(e) => {
+                tracing::error!("Error processing request: {:#}", e);
+
+                let error_response = IPCMessage::Error {
+                    request_id: None,
+                    kind: ErrorKind::InvalidRequest,
+                    message: format!("{:#}", e),
+                };
+
+                write_message(&error_response)?;
+            }
         }
     }
 
     Ok(())
 }
 
-/// Process a single inference request
-fn process_single_request(engine: &mut InferenceEngine, line: &str) -> Result<()> {
-    let line = line.trim();
+/// Read and parse stdin on a background thread
+///
+/// Feedback requests are registered with the watchdog as they are read,
+/// and `Cancel` and `SetFraming` messages are handled here rather than
+/// queued behind the requests before them. Frames that fail to parse or
+/// are larger than `max_frame_size` are passed on as errors. The channel
+/// closes at EOF or on a read error.
+fn spawn_message_reader(
+    watchdog: Watchdog,
+    max_frame_size: usize,
+) -> mpsc::Receiver<Result<IPCMessage>> {
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin().lock();
+        let mut decoder = FrameDecoder::new(FrameCodec {
+            max_frame_size,
+            ..FrameCodec::default()
+        });
+
+        loop {
+            let frame = match read_frame(&mut stdin, &mut decoder) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    tracing::error!("Error reading from stdin: {}", e);
+                    break;
+                }
+            };
+
+            let message = match frame.map_err(anyhow::Error::new).and_then(|f| parse_message(&f)) {
+                Ok(Some(IPCMessage::Cancel { request_id })) => {
+                    if watchdog.cancel(&request_id) {
+                        tracing::info!("Cancelling request {}", request_id);
+                    } else {
+                        tracing::debug!("Ignoring cancel for finished {}", request_id);
+                    }
+                    continue;
+                }
+                Ok(Some(IPCMessage::SetFraming {
+                    framing,
+                    max_frame_size: requested,
+                })) => {
+                    let codec = FrameCodec::new(framing, requested.min(max_frame_size));
+                    if let Err(e) = switch_framing(codec) {
+                        tracing::error!("Failed to switch framing: {:#}", e);
+                        break;
+                    }
+                    tracing::info!("Switched to {:?} framing", codec);
+                    decoder.set_codec(codec);
+                    continue;
+                }
+                Ok(Some(message)) => {
+                    if let IPCMessage::FeedbackRequest { request_id, .. } = &message {
+                        watchdog.register(request_id);
+                    }
+                    Ok(message)
+                }
+                Ok(None) => continue,
+                Err(e) => Err(e),
+            };
+
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+/// Read the next frame, or `None` at EOF
+fn read_frame(
+    reader: &mut impl BufRead,
+    decoder: &mut FrameDecoder,
+) -> io::Result<Option<std::result::Result<Vec<u8>, FrameError>>> {
+    loop {
+        let input = reader.fill_buf()?;
+        if input.is_empty() {
+            return Ok(decoder.finish().err().map(Err));
+        }
+
+        let (consumed, frame) = decoder.decode(input);
+        reader.consume(consumed);
+        if frame.is_some() {
+            return Ok(frame);
+        }
+    }
+}
+
+/// Parse one frame of input; blank lines carry no message
+fn parse_message(frame: &[u8]) -> Result<Option<IPCMessage>> {
+    let frame = frame.trim_ascii();
 
     // Skip empty lines
-    if line.is_empty() {
-        return Ok(());
+    if frame.is_empty() {
+        return Ok(None);
+    }
+
+    serde_json::from_slice(frame)
+        .map(Some)
+        .context("Failed to parse IPC message")
+}
+
+/// Process a single message from the orchestrator
+///
+/// Errors returned from here are protocol-level (a failed write);
+/// inference failures are reported to the orchestrator as `Error` messages
+/// tagged with the request ID.
+fn process_message(
+    registry: &mut ModelRegistry,
+    watchdog: &Watchdog,
+    message: IPCMessage,
+) -> Result<Control> {
+    match message {
+        IPCMessage::Ping { timestamp } => {
+            write_message(&IPCMessage::Pong { timestamp })?;
+        }
+        IPCMessage::Hello { protocol_version, .. } => {
+            if protocol_version != PROTOCOL_VERSION {
+                tracing::warn!(
+                    "Orchestrator speaks protocol version {}, jail speaks {}",
+                    protocol_version,
+                    PROTOCOL_VERSION
+                );
+            }
+            write_message(&IPCMessage::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: capabilities(registry),
+            })?;
+        }
+        IPCMessage::Shutdown => return Ok(Control::Shutdown),
+        message @ IPCMessage::FeedbackRequest { .. } => {
+            process_feedback_batch(registry, watchdog, vec![message])?;
+        }
+        other => {
+            write_message(&IPCMessage::Error {
+                request_id: other.request_id().map(str::to_string),
+                kind: ErrorKind::UnexpectedMessage,
+                message: "AI jail does not accept this message type".to_string(),
+            })?;
+        }
     }
 
-    tracing::info!("Processing request");
+    Ok(Control::Continue)
+}
 
-    // Parse request
-    let request: InferenceRequest = serde_json::from_str(line)
-        .context("Failed to parse JSON request")?;
+/// What this jail advertises in its `Hello`
+fn capabilities(registry: &ModelRegistry) -> Capabilities {
+    Capabilities {
+        streaming: true,
+        max_batch_size: registry.max_batch_size(),
+        grammar: true,
+        models: registry.ids().map(str::to_string).collect(),
+    }
+}
 
-    tracing::debug!("Request: question {}", request.question_number);
+/// Answer a batch of `FeedbackRequest` messages
+///
+/// Requests that fail validation are answered straight away. The rest are
+/// decoded together, one group per model, and answered in the order they
+/// arrived, each tagged with its request ID; streamed chunks from
+/// different requests interleave. Requests that time out or are cancelled
+/// are answered with a `timeout` or `cancelled` error.
+fn process_feedback_batch(
+    registry: &mut ModelRegistry,
+    watchdog: &Watchdog,
+    messages: Vec<IPCMessage>,
+) -> Result<()> {
+    let mut jobs = Vec::with_capacity(messages.len());
+    // Requests for each model, with their position in `jobs`
+    let mut groups: BTreeMap<String, Vec<(usize, InferenceRequest)>> = BTreeMap::new();
+
+    for message in messages {
+        let IPCMessage::FeedbackRequest {
+            request_id,
+            content,
+            rubric,
+            criteria,
+            question_number,
+            params,
+            prompt,
+        } = message
+        else {
+            continue;
+        };
+        tracing::info!("Processing request {}", request_id);
+
+        let stream = params.stream;
+        let mut request = InferenceRequest::from_feedback(
+            content,
+            rubric,
+            criteria,
+            question_number,
+            params,
+            prompt,
+        );
 
-    // Generate feedback
-    let response = engine.generate(&request)
-        .context("Inference failed")?;
+        if let Err(e) = registry.validate(&request) {
+            watchdog.finish(&request_id);
+            write_message(&IPCMessage::Error {
+                request_id: Some(request_id),
+                kind: ErrorKind::InvalidRequest,
+                message: e,
+            })?;
+            continue;
+        }
+        watchdog.prepare(&request_id, &mut request);
 
-    // Write response
-    let success_response = Response::Success(response);
-    write_response(&success_response)?;
+        let model = registry.resolve(request.model.as_deref())?.to_string();
+        groups.entry(model).or_default().push((jobs.len(), request));
+        jobs.push((request_id, stream));
+    }
+
+    let mut results: Vec<Option<Result<_>>> = jobs.iter().map(|_| None).collect();
+    for (model, group) in groups {
+        let (positions, requests): (Vec<usize>, Vec<InferenceRequest>) = group.into_iter().unzip();
+
+        let engine = match registry.engine(Some(&model)) {
+            Ok(engine) => engine,
+            Err(e) => {
+                for &position in &positions {
+                    results[position] = Some(Err(anyhow::anyhow!("{:#}", e)));
+                }
+                continue;
+            }
+        };
+
+        let group_results = engine.generate_batch(&requests, &mut |index, text, tokens_generated| {
+            let (request_id, stream) = &jobs[positions[index]];
+            if !stream {
+                return Ok(());
+            }
+
+            write_message(&IPCMessage::FeedbackChunk {
+                request_id: request_id.clone(),
+                text: text.to_string(),
+                tokens_generated,
+            })
+        });
+
+        for (position, result) in positions.into_iter().zip(group_results) {
+            results[position] = Some(result);
+        }
+    }
+
+    for ((request_id, _), result) in jobs.into_iter().zip(results) {
+        watchdog.finish(&request_id);
+        let reply = match result.expect("every request has a result") {
+            Ok(response) => response.into_message(request_id),
+            Err(e) => {
+                tracing::error!("Inference failed for {}: {:#}", request_id, e);
+                IPCMessage::Error {
+                    request_id: Some(request_id),
+                    kind: error_kind(&e, ErrorKind::ProcessingError),
+                    message: format!("{:#}", e),
+                }
+            }
+        };
+
+        write_message(&reply)?;
+    }
 
     Ok(())
 }
 
-/// Write a response to stdout
-fn write_response(response: &Response) -> Result<()> {
-    let json = serde_json::to_string(response)
+/// Write a message to stdout
+fn write_message(message: &IPCMessage) -> Result<()> {
+    let codec = lock_output();
+    write_framed(&codec, message)
+}
+
+/// Framing for messages written to stdout, shared by every thread that
+/// writes them
+static OUTPUT_CODEC: Mutex<FrameCodec> = Mutex::new(FrameCodec {
+    framing: aws_ipc_protocol::Framing::Lines,
+    max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+});
+
+fn lock_output() -> std::sync::MutexGuard<'static, FrameCodec> {
+    OUTPUT_CODEC.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Confirm a `SetFraming` in the old framing, then switch to `codec`
+///
+/// Holding the lock throughout keeps other threads from writing between
+/// the confirmation and the switch.
+fn switch_framing(codec: FrameCodec) -> Result<()> {
+    let mut output = lock_output();
+    write_framed(
+        &output,
+        &IPCMessage::FramingSet {
+            framing: codec.framing,
+            max_frame_size: codec.max_frame_size,
+        },
+    )?;
+    *output = codec;
+    Ok(())
+}
+
+fn write_framed(codec: &FrameCodec, message: &IPCMessage) -> Result<()> {
+    let json = serde_json::to_vec(message)
         .context("Failed to serialize response")?;
+    let frame = codec.encode(&json).context("Response too large to send")?;
 
-    // Write to stdout with newline
     let mut stdout = io::stdout();
-    writeln!(stdout, "{}", json)
+    stdout.write_all(&frame)
         .context("Failed to write to stdout")?;
 
     stdout.flush()
@@ -213,6 +764,7 @@ fn write_response(response: &Response) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ai_jail::inference::InferenceEngine;
 
     #[test]
     fn test_init_logging() {
@@ -225,11 +777,70 @@ fn test_validate_model_files_missing() {
         let config = ModelConfig {
             model_path: "/nonexistent/model.safetensors".into(),
             tokenizer_path: "/nonexistent/tokenizer.json".into(),
-            quantization: model::QuantizationMode::Q4,
+            quantization: ai_jail::model::QuantizationMode::Q4,
             device: candle_core::Device::Cpu,
             use_flash_attn: false,
+            chat_format: ai_jail::prompt::ChatFormat::MistralInstruct,
+            context_length: ai_jail::backend::DEFAULT_CONTEXT_LENGTH,
         };
 
         assert!(validate_model_files(&config).is_err());
     }
+
+    #[test]
+    fn test_default_model_spec() {
+        let spec = default_model_spec(BackendKind::Echo).unwrap();
+        assert_eq!(spec.id, "echo");
+        assert_eq!(spec.revision, UNVERSIONED);
+    }
+
+    #[test]
+    fn test_backend_flag() {
+        let args = ["--backend", "echo"].map(String::from).into_iter();
+        assert_eq!(backend_kind(args).unwrap(), BackendKind::Echo);
+
+        let args = ["--backend=scripted"].map(String::from).into_iter();
+        assert_eq!(backend_kind(args).unwrap(), BackendKind::Scripted);
+    }
+
+    #[test]
+    fn test_backend_flag_errors() {
+        assert!(backend_kind(["--backend"].map(String::from).into_iter()).is_err());
+        assert!(backend_kind(["--backend", "gpt"].map(String::from).into_iter()).is_err());
+        assert!(backend_kind(["--verbose"].map(String::from).into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_stops_loop() {
+        let engine = InferenceEngine::new(Box::new(ScriptedBackend::default()));
+        let mut registry = ModelRegistry::with_engine(engine);
+        let message = parse_message(br#"{"type":"Shutdown"}"#).unwrap().unwrap();
+        let control = process_message(&mut registry, &Watchdog::default(), message).unwrap();
+
+        assert_eq!(control, Control::Shutdown);
+    }
+
+    #[test]
+    fn test_empty_line_is_ignored() {
+        assert!(parse_message(b"  \n").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_invalid_json_is_rejected() {
+        assert!(parse_message(b"{invalid json}").is_err());
+    }
+
+    #[test]
+    fn test_oversized_frame_is_skipped() {
+        let codec = FrameCodec::new(aws_ipc_protocol::Framing::Lines, 32);
+        let mut decoder = FrameDecoder::new(codec);
+        let mut input = io::Cursor::new(format!("{}\n{{\"type\":\"Shutdown\"}}\n", "x".repeat(64)));
+
+        let first = read_frame(&mut input, &mut decoder).unwrap().unwrap();
+        assert_eq!(first, Err(FrameError::TooLarge { size: 64, max: 32 }));
+
+        let second = read_frame(&mut input, &mut decoder).unwrap().unwrap().unwrap();
+        assert!(matches!(parse_message(&second).unwrap(), Some(IPCMessage::Shutdown)));
+        assert_eq!(read_frame(&mut input, &mut decoder).unwrap(), None);
+    }
 }
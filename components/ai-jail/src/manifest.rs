@@ -0,0 +1,307 @@
+//! Integrity checks for model files
+//!
+//! The jail runs third-party weights on student data, so before a model is
+//! loaded its files are checked against a signed manifest listing their
+//! BLAKE3 hashes:
+//!
+//! ```json
+//! {
+//!   "files": {
+//!     "model-q4_k.gguf": "5f0c0e5b2b6a…",
+//!     "tokenizer.json": "a81d9e44c3f7…"
+//!   }
+//! }
+//! ```
+//!
+//! Paths are relative to the manifest's directory, and the manifest may
+//! list files the model does not use. The signature over the manifest's
+//! bytes sits next to it in `<manifest>.sig`, hex encoded, and the public
+//! key it is checked with is given to the jail separately, so whoever can
+//! swap the weights cannot also vouch for them.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::model::ModelConfig;
+
+/// Signature scheme a manifest is signed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+    Ed25519,
+    /// Dilithium5 (ML-DSA-87), post-quantum
+    Dilithium,
+}
+
+impl FromStr for SignatureAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ed25519" => Ok(SignatureAlgorithm::Ed25519),
+            "dilithium" | "dilithium5" => Ok(SignatureAlgorithm::Dilithium),
+            other => anyhow::bail!(
+                "Unknown signature algorithm '{}' (expected ed25519 or dilithium)",
+                other
+            ),
+        }
+    }
+}
+
+/// Manifest file layout
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// BLAKE3 hash of each file, hex encoded
+    files: BTreeMap<PathBuf, String>,
+}
+
+/// Checks model files against manifests signed with one public key
+#[derive(Debug, Clone)]
+pub struct ManifestVerifier {
+    algorithm: SignatureAlgorithm,
+    public_key: Vec<u8>,
+}
+
+impl ManifestVerifier {
+    pub fn new(algorithm: SignatureAlgorithm, public_key: Vec<u8>) -> Self {
+        Self {
+            algorithm,
+            public_key,
+        }
+    }
+
+    /// Verifier for the hex encoded public key in the file named by
+    /// `MODEL_MANIFEST_KEY_FILE`, using `MODEL_MANIFEST_ALGORITHM` (ed25519
+    /// by default), or `None` if no key is configured
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(path) = std::env::var("MODEL_MANIFEST_KEY_FILE") else {
+            return Ok(None);
+        };
+        let algorithm = match std::env::var("MODEL_MANIFEST_ALGORITHM") {
+            Ok(name) => name.parse()?,
+            Err(_) => SignatureAlgorithm::Ed25519,
+        };
+
+        let public_key = read_hex(Path::new(&path))
+            .with_context(|| format!("Failed to read manifest key {}", path))?;
+
+        Ok(Some(Self::new(algorithm, public_key)))
+    }
+
+    /// Check the manifest's signature, then the model's weights and
+    /// tokenizer against it, returning the hash of the weights
+    pub fn verify(&self, manifest_path: &Path, config: &ModelConfig) -> Result<String> {
+        let bytes = std::fs::read(manifest_path)
+            .with_context(|| format!("Failed to read manifest {}", manifest_path.display()))?;
+        let signature_path = signature_path(manifest_path);
+        let signature = read_hex(&signature_path).with_context(|| {
+            format!("Failed to read manifest signature {}", signature_path.display())
+        })?;
+
+        let valid = match self.algorithm {
+            SignatureAlgorithm::Ed25519 => {
+                academic_shared::crypto::ed25519_verify(&bytes, &signature, &self.public_key)
+            }
+            SignatureAlgorithm::Dilithium => {
+                academic_shared::crypto::dilithium_verify(&bytes, &signature, &self.public_key)
+            }
+        }
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+        if !valid {
+            anyhow::bail!("Manifest {} has an invalid signature", manifest_path.display());
+        }
+
+        let manifest: Manifest = serde_json::from_slice(&bytes)
+            .with_context(|| format!("Invalid manifest {}", manifest_path.display()))?;
+        let base = manifest_path.parent().unwrap_or(Path::new("."));
+
+        let model_hash = check_file(&manifest, base, &config.model_path)?;
+        check_file(&manifest, base, &config.tokenizer_path)?;
+
+        tracing::info!(
+            "Model files match manifest {} (weights {})",
+            manifest_path.display(),
+            model_hash
+        );
+        Ok(model_hash)
+    }
+}
+
+/// Where the signature for a manifest is kept
+pub fn signature_path(manifest_path: &Path) -> PathBuf {
+    let mut path = manifest_path.as_os_str().to_owned();
+    path.push(".sig");
+    PathBuf::from(path)
+}
+
+/// BLAKE3 hash of a file's contents, hex encoded
+///
+/// The file is streamed, so multi-gigabyte weights are not read into
+/// memory first.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_reader(file)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Hash `path` and compare it with its manifest entry
+fn check_file(manifest: &Manifest, base: &Path, path: &Path) -> Result<String> {
+    let canonical = path
+        .canonicalize()
+        .with_context(|| format!("Model file not found: {}", path.display()))?;
+    let expected = manifest
+        .files
+        .iter()
+        .find(|(listed, _)| base.join(listed).canonicalize().ok().as_ref() == Some(&canonical))
+        .map(|(_, hash)| hash)
+        .with_context(|| format!("{} is not listed in the manifest", path.display()))?;
+
+    let actual = hash_file(path)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        anyhow::bail!(
+            "Hash mismatch for {}: manifest has {}, file is {}",
+            path.display(),
+            expected,
+            actual
+        );
+    }
+
+    Ok(actual)
+}
+
+fn read_hex(path: &Path) -> Result<Vec<u8>> {
+    let text = std::fs::read_to_string(path)?;
+    hex::decode(text.trim()).context("Not valid hex")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::DEFAULT_CONTEXT_LENGTH;
+    use crate::model::QuantizationMode;
+    use crate::prompt::ChatFormat;
+    use academic_shared::crypto::{dilithium_keypair, dilithium_sign, ed25519_keypair, ed25519_sign};
+    use candle_core::Device;
+
+    fn config(dir: &Path) -> ModelConfig {
+        let model_path = dir.join("model.gguf");
+        let tokenizer_path = dir.join("tokenizer.json");
+        std::fs::write(&model_path, b"weights").unwrap();
+        std::fs::write(&tokenizer_path, b"{}").unwrap();
+
+        ModelConfig {
+            model_path,
+            tokenizer_path,
+            quantization: QuantizationMode::Q4,
+            device: Device::Cpu,
+            use_flash_attn: false,
+            chat_format: ChatFormat::MistralInstruct,
+            context_length: DEFAULT_CONTEXT_LENGTH,
+        }
+    }
+
+    /// Write a manifest for the config's files, signed with `sign`
+    fn write_manifest(config: &ModelConfig, sign: impl Fn(&[u8]) -> Vec<u8>) -> PathBuf {
+        let dir = config.model_path.parent().unwrap();
+        let manifest = serde_json::json!({
+            "files": {
+                "model.gguf": hash_file(&config.model_path).unwrap(),
+                "tokenizer.json": hash_file(&config.tokenizer_path).unwrap(),
+            }
+        });
+        let path = dir.join("manifest.json");
+        let bytes = serde_json::to_vec(&manifest).unwrap();
+        std::fs::write(&path, &bytes).unwrap();
+        std::fs::write(signature_path(&path), hex::encode(sign(&bytes))).unwrap();
+        path
+    }
+
+    fn ed25519_setup(dir: &Path) -> (ManifestVerifier, ModelConfig, PathBuf) {
+        let (public_key, secret_key) = ed25519_keypair();
+        let config = config(dir);
+        let manifest = write_manifest(&config, |bytes| ed25519_sign(bytes, &secret_key).unwrap());
+        let verifier = ManifestVerifier::new(SignatureAlgorithm::Ed25519, public_key);
+        (verifier, config, manifest)
+    }
+
+    #[test]
+    fn test_verified_files_report_weights_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let (verifier, config, manifest) = ed25519_setup(dir.path());
+
+        let hash = verifier.verify(&manifest, &config).unwrap();
+
+        assert_eq!(hash, blake3::hash(b"weights").to_hex().to_string());
+    }
+
+    #[test]
+    fn test_tampered_weights_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (verifier, config, manifest) = ed25519_setup(dir.path());
+
+        std::fs::write(&config.model_path, b"weightz").unwrap();
+
+        let err = verifier.verify(&manifest, &config).unwrap_err();
+        assert!(err.to_string().contains("Hash mismatch for"));
+    }
+
+    #[test]
+    fn test_edited_manifest_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (verifier, config, manifest) = ed25519_setup(dir.path());
+
+        // Re-pointing the manifest at swapped weights breaks the signature
+        std::fs::write(&config.model_path, b"weightz").unwrap();
+        let text = std::fs::read_to_string(&manifest).unwrap().replace(
+            blake3::hash(b"weights").to_hex().as_str(),
+            blake3::hash(b"weightz").to_hex().as_str(),
+        );
+        std::fs::write(&manifest, text).unwrap();
+
+        let err = verifier.verify(&manifest, &config).unwrap_err();
+        assert!(err.to_string().contains("invalid signature"));
+    }
+
+    #[test]
+    fn test_manifest_signed_with_other_key_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, config, manifest) = ed25519_setup(dir.path());
+        let (other_key, _) = ed25519_keypair();
+
+        let verifier = ManifestVerifier::new(SignatureAlgorithm::Ed25519, other_key);
+
+        assert!(verifier.verify(&manifest, &config).is_err());
+    }
+
+    #[test]
+    fn test_unlisted_tokenizer_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (verifier, mut config, manifest) = ed25519_setup(dir.path());
+
+        config.tokenizer_path = dir.path().join("other-tokenizer.json");
+        std::fs::write(&config.tokenizer_path, b"{}").unwrap();
+
+        let err = verifier.verify(&manifest, &config).unwrap_err();
+        assert!(err.to_string().contains("not listed in the manifest"));
+    }
+
+    #[test]
+    fn test_dilithium_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let (public_key, secret_key) = dilithium_keypair();
+        let config = config(dir.path());
+        let manifest =
+            write_manifest(&config, |bytes| dilithium_sign(bytes, &secret_key).unwrap());
+
+        let verifier = ManifestVerifier::new(SignatureAlgorithm::Dilithium, public_key);
+
+        assert!(verifier.verify(&manifest, &config).is_ok());
+    }
+}
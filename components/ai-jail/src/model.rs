@@ -2,14 +2,32 @@
 //!
 //! This module handles loading Mistral 7B models from local storage,
 //! with support for quantization to fit within 8GB VRAM constraints.
+//!
+//! Full precision weights are read from safetensors. Quantized weights are
+//! read from GGUF files: llama.cpp exports (`token_embd.weight`,
+//! `blk.N.*` tensor names) load through candle's quantized Llama model,
+//! which also covers Mistral, while candle's own Mistral conversions
+//! (`model.layers.N.*`) load through its quantized Mistral model.
 
 use anyhow::{Context, Result};
+use candle_core::quantized::{gguf_file, GgmlDType};
+use candle_core::safetensors::MmapedSafetensors;
 use candle_core::{DType, Device, Tensor};
-use candle_nn::VarBuilder;
+use candle_nn::{Module, VarBuilder};
 use candle_transformers::models::mistral::{Config as MistralConfig, Model as MistralModel};
+use candle_transformers::models::quantized_llama::ModelWeights as QuantizedLlamaModel;
+use candle_transformers::models::quantized_mistral::{
+    Model as QuantizedMistralModel, VarBuilder as QuantizedVarBuilder,
+};
+use std::collections::HashMap;
+use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tokenizers::Tokenizer;
 
+use crate::backend::{context_length_from_env, InferenceBackend, DEFAULT_CONTEXT_LENGTH};
+use crate::prompt::ChatFormat;
+
 /// Quantization mode for model weights
 #[derive(Debug, Clone, Copy)]
 pub enum QuantizationMode {
@@ -21,6 +39,46 @@ pub enum QuantizationMode {
     Q4,
 }
 
+impl FromStr for QuantizationMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" | "fp16" => Ok(QuantizationMode::None),
+            "q8" => Ok(QuantizationMode::Q8),
+            "q4" => Ok(QuantizationMode::Q4),
+            other => anyhow::bail!(
+                "Unknown quantization '{}' (expected none, fp16, q8 or q4)",
+                other
+            ),
+        }
+    }
+}
+
+impl QuantizationMode {
+    /// Weights file used when `MODEL_PATH` is not set
+    pub fn default_weights_file(&self) -> &'static str {
+        match self {
+            QuantizationMode::None => "model.safetensors",
+            QuantizationMode::Q8 => "model-q8_0.gguf",
+            QuantizationMode::Q4 => "model-q4_k.gguf",
+        }
+    }
+
+    /// Whether GGUF weights stored as `dtype` satisfy this mode
+    pub fn accepts(&self, dtype: GgmlDType) -> bool {
+        match self {
+            QuantizationMode::None => false,
+            QuantizationMode::Q8 => {
+                matches!(dtype, GgmlDType::Q8_0 | GgmlDType::Q8_1 | GgmlDType::Q8K)
+            }
+            QuantizationMode::Q4 => {
+                matches!(dtype, GgmlDType::Q4_0 | GgmlDType::Q4_1 | GgmlDType::Q4K)
+            }
+        }
+    }
+}
+
 /// Configuration for model loading
 #[derive(Debug, Clone)]
 pub struct ModelConfig {
@@ -38,54 +96,150 @@ pub struct ModelConfig {
 
     /// Use flash attention (if available)
     pub use_flash_attn: bool,
+
+    /// Prompt layout the model was instruction-tuned with
+    pub chat_format: ChatFormat,
+
+    /// Tokens the model attends to at once: prompt plus generated text
+    pub context_length: usize,
 }
 
 impl ModelConfig {
     /// Create a new model configuration with defaults for RTX 3080 (8GB)
     pub fn new_default() -> Result<Self> {
         let model_dir = PathBuf::from("/models/mistral-7b");
+        let quantization = QuantizationMode::Q4; // Default to 4-bit for 8GB VRAM
 
         Ok(Self {
-            model_path: model_dir.join("model.safetensors"),
+            model_path: model_dir.join(quantization.default_weights_file()),
             tokenizer_path: model_dir.join("tokenizer.json"),
-            quantization: QuantizationMode::Q4, // Default to 4-bit for 8GB VRAM
+            quantization,
             device: Device::cuda_if_available(0)?,
             use_flash_attn: true,
+            chat_format: ChatFormat::MistralInstruct,
+            context_length: DEFAULT_CONTEXT_LENGTH,
         })
     }
 
     /// Create configuration from environment variables
     pub fn from_env() -> Result<Self> {
-        let model_path = std::env::var("MODEL_PATH")
-            .unwrap_or_else(|_| "/models/mistral-7b/model.safetensors".to_string());
+        let quantization = std::env::var("QUANTIZATION")
+            .ok()
+            .map(|name| name.parse())
+            .transpose()?
+            .unwrap_or(QuantizationMode::Q4);
+
+        let model_path = std::env::var("MODEL_PATH").unwrap_or_else(|_| {
+            format!("/models/mistral-7b/{}", quantization.default_weights_file())
+        });
 
         let tokenizer_path = std::env::var("TOKENIZER_PATH")
             .unwrap_or_else(|_| "/models/mistral-7b/tokenizer.json".to_string());
 
-        let quantization = match std::env::var("QUANTIZATION").as_deref() {
-            Ok("none") | Ok("fp16") => QuantizationMode::None,
-            Ok("q8") => QuantizationMode::Q8,
-            Ok("q4") | _ => QuantizationMode::Q4,
+        let model_path = PathBuf::from(model_path);
+        let chat_format = match std::env::var("CHAT_FORMAT") {
+            Ok(name) => name.parse()?,
+            Err(_) => ChatFormat::for_model(&model_path),
         };
 
+        let context_length = context_length_from_env()?;
+
         let device = Device::cuda_if_available(0)?;
 
         Ok(Self {
-            model_path: PathBuf::from(model_path),
+            model_path,
             tokenizer_path: PathBuf::from(tokenizer_path),
             quantization,
             device,
             use_flash_attn: true,
+            chat_format,
+            context_length,
         })
     }
 }
 
+/// Rows the full precision model decodes together
+///
+/// Each row carries its own KV cache, so this trades VRAM for throughput.
+const MAX_BATCH_SIZE: usize = 4;
+
+/// Model weights in the form selected by the quantization mode
+pub enum ModelWeights {
+    /// Full precision Mistral loaded from safetensors
+    Full(MistralModel),
+    /// Quantized Mistral from a GGUF file with Hugging Face tensor names
+    QuantizedMistral(QuantizedMistralModel),
+    /// Quantized Llama-architecture model from a llama.cpp GGUF file
+    QuantizedLlama(QuantizedLlamaModel),
+}
+
+impl ModelWeights {
+    /// Forward pass returning logits of shape (batch, 1, vocab)
+    fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> candle_core::Result<Tensor> {
+        match self {
+            ModelWeights::Full(model) => model.forward(input_ids, seqlen_offset),
+            ModelWeights::QuantizedMistral(model) => model.forward(input_ids, seqlen_offset),
+            // The Llama model drops the sequence dimension from its logits
+            ModelWeights::QuantizedLlama(model) => {
+                model.forward(input_ids, seqlen_offset)?.unsqueeze(1)
+            }
+        }
+    }
+
+    /// Rows `forward_padded` accepts; candle's quantized models take no
+    /// attention mask, so they decode one sequence at a time
+    fn max_batch_size(&self) -> usize {
+        match self {
+            ModelWeights::Full(_) => MAX_BATCH_SIZE,
+            ModelWeights::QuantizedMistral(_) | ModelWeights::QuantizedLlama(_) => 1,
+        }
+    }
+
+    /// Forward pass over left-padded rows, masking each row's padding
+    fn forward_padded(
+        &mut self,
+        input_ids: &Tensor,
+        padding: &[usize],
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        match self {
+            ModelWeights::Full(model) => {
+                let (_, q_len) = input_ids.dims2()?;
+                let config = LoadedModel::get_mistral_config();
+                let mask = padding_mask(
+                    padding,
+                    q_len,
+                    seqlen_offset,
+                    config.sliding_window,
+                    DType::F16,
+                    input_ids.device(),
+                )?;
+                let embeds = model.embed_tokens().forward(input_ids)?;
+                Ok(model.forward_embeds(&embeds, Some(&mask), seqlen_offset)?)
+            }
+            _ if padding.iter().all(|&p| p == 0) => Ok(self.forward(input_ids, seqlen_offset)?),
+            _ => anyhow::bail!("Quantized models cannot mask padding"),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match self {
+            ModelWeights::Full(model) => model.clear_kv_cache(),
+            ModelWeights::QuantizedMistral(model) => model.clear_kv_cache(),
+            // The Llama model replaces its cache whenever a pass starts at offset 0
+            ModelWeights::QuantizedLlama(_) => {}
+        }
+    }
+}
+
 /// Loaded model with tokenizer
 pub struct LoadedModel {
-    pub model: MistralModel,
+    pub model: ModelWeights,
     pub tokenizer: Tokenizer,
     pub device: Device,
-    pub config: MistralConfig,
+    pub chat_format: ChatFormat,
+    pub context_length: usize,
+    weights_bytes: usize,
 }
 
 impl LoadedModel {
@@ -99,27 +253,7 @@ pub fn load(config: ModelConfig) -> Result<Self> {
         let tokenizer = Tokenizer::from_file(&config.tokenizer_path)
             .map_err(|e| anyhow::anyhow!("Failed to load tokenizer: {}", e))?;
 
-        // Load model configuration
-        let model_config = Self::get_mistral_config();
-
-        // Determine dtype based on quantization
-        let dtype = match config.quantization {
-            QuantizationMode::None => DType::F16,
-            QuantizationMode::Q8 => DType::U8,
-            QuantizationMode::Q4 => DType::U8, // GGUF Q4 uses U8 storage
-        };
-
-        // Load model weights
-        let vb = unsafe {
-            VarBuilder::from_mmaped_safetensors(
-                &[config.model_path.clone()],
-                dtype,
-                &config.device,
-            )?
-        };
-
-        // Build model
-        let model = MistralModel::new(&model_config, vb)?;
+        let (model, weights_bytes) = Self::load_weights(&config)?;
 
         tracing::info!("Model loaded successfully");
 
@@ -127,10 +261,79 @@ pub fn load(config: ModelConfig) -> Result<Self> {
             model,
             tokenizer,
             device: config.device,
-            config: model_config,
+            chat_format: config.chat_format,
+            context_length: config.context_length,
+            weights_bytes,
         })
     }
 
+    /// Load weights in the format the quantization mode calls for,
+    /// returning them with their size in bytes
+    fn load_weights(config: &ModelConfig) -> Result<(ModelWeights, usize)> {
+        match config.quantization {
+            QuantizationMode::None => Self::load_safetensors(&config.model_path, &config.device),
+            mode => Self::load_gguf(&config.model_path, mode, &config.device),
+        }
+    }
+
+    /// Load full precision Mistral weights from safetensors
+    fn load_safetensors(path: &Path, device: &Device) -> Result<(ModelWeights, usize)> {
+        let dtype = DType::F16;
+
+        // Weights are converted to `dtype` on load, so size them at that width
+        let safetensors = unsafe { MmapedSafetensors::new(path)? };
+        let weights_bytes = safetensors
+            .tensors()
+            .iter()
+            .map(|(_, view)| view.shape().iter().product::<usize>() * dtype.size_in_bytes())
+            .sum();
+
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[path], dtype, device)? };
+        let model = MistralModel::new(&Self::get_mistral_config(), vb)?;
+
+        Ok((ModelWeights::Full(model), weights_bytes))
+    }
+
+    /// Load quantized weights from a GGUF file, checking that the file's
+    /// quantization matches `mode`
+    fn load_gguf(
+        path: &Path,
+        mode: QuantizationMode,
+        device: &Device,
+    ) -> Result<(ModelWeights, usize)> {
+        let mut file = File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let content = gguf_file::Content::read(&mut file)
+            .map_err(|e| e.with_path(path))
+            .context("Failed to read GGUF header")?;
+
+        let dtype = dominant_dtype(&content.tensor_infos)
+            .context("GGUF file contains no weight matrices")?;
+        if !mode.accepts(dtype) {
+            anyhow::bail!(
+                "Quantization mode {:?} does not match {}, which stores weights as {:?}",
+                mode,
+                path.display(),
+                dtype
+            );
+        }
+        tracing::info!("GGUF weights stored as {:?}", dtype);
+
+        let weights_bytes = gguf_size_in_bytes(&content.tensor_infos);
+
+        let model = if content.tensor_infos.contains_key("token_embd.weight") {
+            ModelWeights::QuantizedLlama(QuantizedLlamaModel::from_gguf(content, &mut file, device)?)
+        } else {
+            let vb = QuantizedVarBuilder::from_gguf(path, device)?;
+            ModelWeights::QuantizedMistral(QuantizedMistralModel::new(
+                &Self::get_mistral_config(),
+                vb,
+            )?)
+        };
+
+        Ok((model, weights_bytes))
+    }
+
     /// Get Mistral 7B model configuration
     fn get_mistral_config() -> MistralConfig {
         MistralConfig {
@@ -139,13 +342,16 @@ fn get_mistral_config() -> MistralConfig {
             intermediate_size: 14336,
             num_hidden_layers: 32,
             num_attention_heads: 32,
+            head_dim: None,
             num_key_value_heads: 8,
             hidden_act: candle_nn::Activation::Silu,
             max_position_embeddings: 32768,
             rms_norm_eps: 1e-5,
             rope_theta: 10000.0,
             sliding_window: Some(4096),
-            use_flash_attn: true,
+            // candle-transformers is built without flash-attn, which would
+            // also ignore the padding mask batched decoding relies on
+            use_flash_attn: false,
         }
     }
 
@@ -168,10 +374,34 @@ pub fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String
     /// Get the end-of-sequence token ID
     pub fn eos_token_id(&self) -> Option<u32> {
         self.tokenizer
-            .get_vocab(true)
-            .get("</s>")
-            .or_else(|| self.tokenizer.get_vocab(true).get("<|im_end|>"))
-            .copied()
+            .token_to_id("</s>")
+            .or_else(|| self.tokenizer.token_to_id("<|im_end|>"))
+    }
+
+    /// Bytes a token contributes when it follows other text
+    ///
+    /// Mistral's SentencePiece vocabulary marks a leading space with `▁` and
+    /// stores raw bytes as `<0xNN>` pieces, and neither survives decoding the
+    /// token on its own, so the piece itself is translated instead.
+    pub fn token_bytes(&self, token: u32) -> Result<Vec<u8>> {
+        // Special tokens decode to nothing once skipped
+        if self.decode(&[token], true)?.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let Some(piece) = self.tokenizer.id_to_token(token) else {
+            return Ok(Vec::new());
+        };
+
+        let byte = piece
+            .strip_prefix("<0x")
+            .and_then(|p| p.strip_suffix('>'))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        Ok(match byte {
+            Some(byte) => vec![byte],
+            None => piece.replace('\u{2581}', " ").into_bytes(),
+        })
     }
 
     /// Forward pass through the model
@@ -182,21 +412,140 @@ pub fn forward(&mut self, input_ids: &Tensor, position_ids: usize) -> Result<Ten
     }
 
     /// Get estimated memory usage in bytes
+    ///
+    /// This is the size of the weights as held on the device, which for
+    /// GGUF files is their quantized size. The KV cache is not included.
     pub fn estimate_memory_usage(&self) -> usize {
-        // Rough estimate for Mistral 7B
-        let params = 7_000_000_000u64; // 7B parameters
-
-        let bytes_per_param = match self.device {
-            Device::Cpu => 2, // FP16
-            Device::Cuda(_) => match std::env::var("QUANTIZATION").as_deref() {
-                Ok("q4") => 1, // 4-bit quantized (0.5 bytes but rounded up)
-                Ok("q8") => 1, // 8-bit quantized
-                _ => 2, // FP16
-            },
-            _ => 2,
-        };
+        self.weights_bytes
+    }
+}
+
+/// Attention mask for a pass over left-padded rows
+///
+/// The mask has shape `(batch, 1, q_len, seqlen_offset + q_len)` and is
+/// added to the attention scores. Row `i`'s query at absolute position `p`
+/// may attend to keys from `padding[i]` up to `p`, within the sliding
+/// window. Pad positions attend only to themselves: a fully masked query
+/// would produce NaNs that later leak through the KV cache.
+fn padding_mask(
+    padding: &[usize],
+    q_len: usize,
+    seqlen_offset: usize,
+    sliding_window: Option<usize>,
+    dtype: DType,
+    device: &Device,
+) -> candle_core::Result<Tensor> {
+    let kv_len = seqlen_offset + q_len;
+    let window = sliding_window.unwrap_or(kv_len + 1);
+
+    let mask: Vec<f32> = padding
+        .iter()
+        .flat_map(|&pad| {
+            (seqlen_offset..kv_len).flat_map(move |pos| {
+                (0..kv_len).map(move |k| {
+                    let visible = k <= pos && k + window >= pos && (k >= pad || k == pos);
+                    if visible {
+                        0.0
+                    } else {
+                        f32::NEG_INFINITY
+                    }
+                })
+            })
+        })
+        .collect();
+
+    Tensor::from_vec(mask, (padding.len(), 1, q_len, kv_len), device)?.to_dtype(dtype)
+}
+
+/// The quantized type holding most of the weight matrix elements
+///
+/// Mixed files such as Q4_K_M keep a few tensors at higher precision, so
+/// the mode is judged by the type covering the most elements.
+fn dominant_dtype(tensors: &HashMap<String, gguf_file::TensorInfo>) -> Option<GgmlDType> {
+    let mut elements: HashMap<GgmlDType, usize> = HashMap::new();
+
+    for info in tensors.values().filter(|info| info.shape.rank() == 2) {
+        *elements.entry(info.ggml_dtype).or_default() += info.shape.elem_count();
+    }
+
+    elements
+        .into_iter()
+        .max_by_key(|(_, count)| *count)
+        .map(|(dtype, _)| dtype)
+}
+
+/// Total storage taken by the tensors of a GGUF file
+fn gguf_size_in_bytes(tensors: &HashMap<String, gguf_file::TensorInfo>) -> usize {
+    tensors
+        .values()
+        .map(|info| {
+            info.shape.elem_count() / info.ggml_dtype.block_size() * info.ggml_dtype.type_size()
+        })
+        .sum()
+}
+
+impl InferenceBackend for LoadedModel {
+    fn name(&self) -> &'static str {
+        "candle"
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        LoadedModel::encode(self, text, add_special_tokens)
+    }
+
+    fn decode(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String> {
+        LoadedModel::decode(self, tokens, skip_special_tokens)
+    }
+
+    fn eos_token_id(&self) -> Option<u32> {
+        LoadedModel::eos_token_id(self)
+    }
+
+    fn chat_format(&self) -> ChatFormat {
+        self.chat_format
+    }
+
+    fn context_length(&self) -> usize {
+        self.context_length
+    }
 
-        (params * bytes_per_param) as usize
+    fn vocab_size(&self) -> usize {
+        self.tokenizer.get_vocab_size(true)
+    }
+
+    fn token_bytes(&self, token: u32) -> Result<Vec<u8>> {
+        LoadedModel::token_bytes(self, token)
+    }
+
+    fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        LoadedModel::forward(self, input_ids, seqlen_offset)
+    }
+
+    fn max_batch_size(&self) -> usize {
+        self.model.max_batch_size()
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.estimate_memory_usage()
+    }
+
+    fn forward_padded(
+        &mut self,
+        input_ids: &Tensor,
+        padding: &[usize],
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        self.model
+            .forward_padded(input_ids, padding, seqlen_offset)
+            .context("Model forward pass failed")
+    }
+
+    fn reset(&mut self) {
+        self.model.clear_kv_cache();
     }
 }
 
@@ -205,6 +554,8 @@ pub struct ModelBuilder {
     model_path: Option<PathBuf>,
     tokenizer_path: Option<PathBuf>,
     quantization: QuantizationMode,
+    chat_format: Option<ChatFormat>,
+    context_length: usize,
 }
 
 impl ModelBuilder {
@@ -213,6 +564,8 @@ pub fn new() -> Self {
             model_path: None,
             tokenizer_path: None,
             quantization: QuantizationMode::Q4,
+            chat_format: None,
+            context_length: DEFAULT_CONTEXT_LENGTH,
         }
     }
 
@@ -231,6 +584,17 @@ pub fn quantization(mut self, quant: QuantizationMode) -> Self {
         self
     }
 
+    /// Override the chat format guessed from the model path
+    pub fn chat_format(mut self, format: ChatFormat) -> Self {
+        self.chat_format = Some(format);
+        self
+    }
+
+    pub fn context_length(mut self, tokens: usize) -> Self {
+        self.context_length = tokens;
+        self
+    }
+
     pub fn build(self) -> Result<ModelConfig> {
         let model_path = self.model_path
             .ok_or_else(|| anyhow::anyhow!("Model path not specified"))?;
@@ -238,12 +602,18 @@ pub fn build(self) -> Result<ModelConfig> {
         let tokenizer_path = self.tokenizer_path
             .ok_or_else(|| anyhow::anyhow!("Tokenizer path not specified"))?;
 
+        let chat_format = self
+            .chat_format
+            .unwrap_or_else(|| ChatFormat::for_model(&model_path));
+
         Ok(ModelConfig {
             model_path,
             tokenizer_path,
             quantization: self.quantization,
             device: Device::cuda_if_available(0)?,
             use_flash_attn: true,
+            chat_format,
+            context_length: self.context_length,
         })
     }
 }
@@ -257,9 +627,37 @@ fn default() -> Self {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Mutex;
+
+    /// Serialises the tests that set environment variables
+    static ENV: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_padding_mask() {
+        let ninf = f32::NEG_INFINITY;
+
+        // Prefill of two rows, the second padded by one token
+        let mask = padding_mask(&[0, 1], 3, 0, None, DType::F32, &Device::Cpu).unwrap();
+        let mask = mask.squeeze(1).unwrap().to_vec3::<f32>().unwrap();
+        assert_eq!(mask[0][2], vec![0.0, 0.0, 0.0]);
+        assert_eq!(mask[1][0], vec![0.0, ninf, ninf]);
+        assert_eq!(mask[1][1], vec![ninf, 0.0, ninf]);
+        assert_eq!(mask[1][2], vec![ninf, 0.0, 0.0]);
+
+        // A later single-token step still hides the padding
+        let mask = padding_mask(&[0, 1], 1, 3, None, DType::F32, &Device::Cpu).unwrap();
+        let mask = mask.squeeze(1).unwrap().to_vec3::<f32>().unwrap();
+        assert_eq!(mask[1][0], vec![ninf, 0.0, 0.0, 0.0]);
+
+        // Keys beyond the sliding window are hidden
+        let mask = padding_mask(&[0], 1, 3, Some(2), DType::F32, &Device::Cpu).unwrap();
+        let mask = mask.squeeze(1).unwrap().to_vec3::<f32>().unwrap();
+        assert_eq!(mask[0][0], vec![ninf, 0.0, 0.0, 0.0]);
+    }
 
     #[test]
     fn test_config_from_env() {
+        let _env = ENV.lock().unwrap();
         std::env::set_var("MODEL_PATH", "/test/model.safetensors");
         std::env::set_var("TOKENIZER_PATH", "/test/tokenizer.json");
         std::env::set_var("QUANTIZATION", "q4");
@@ -267,21 +665,153 @@ fn test_config_from_env() {
         let config = ModelConfig::from_env().unwrap();
         assert_eq!(config.model_path, PathBuf::from("/test/model.safetensors"));
         assert_eq!(config.tokenizer_path, PathBuf::from("/test/tokenizer.json"));
+        assert_eq!(config.chat_format, ChatFormat::ChatMl);
 
         std::env::remove_var("MODEL_PATH");
         std::env::remove_var("TOKENIZER_PATH");
         std::env::remove_var("QUANTIZATION");
     }
 
+    #[test]
+    fn test_config_rejects_unknown_quantization() {
+        let _env = ENV.lock().unwrap();
+        std::env::set_var("QUANTIZATION", "q3");
+
+        let err = ModelConfig::from_env().unwrap_err();
+        assert!(err.to_string().contains("Unknown quantization 'q3'"));
+
+        std::env::remove_var("QUANTIZATION");
+    }
+
     #[test]
     fn test_model_builder() {
         let config = ModelBuilder::new()
             .model_path("/test/model.safetensors")
             .tokenizer_path("/test/tokenizer.json")
             .quantization(QuantizationMode::Q8)
+            .context_length(4096)
             .build()
             .unwrap();
 
         assert_eq!(config.model_path, PathBuf::from("/test/model.safetensors"));
+        assert_eq!(config.context_length, 4096);
+    }
+
+    #[test]
+    fn test_quantization_accepts() {
+        assert!(QuantizationMode::Q4.accepts(GgmlDType::Q4K));
+        assert!(QuantizationMode::Q4.accepts(GgmlDType::Q4_0));
+        assert!(!QuantizationMode::Q4.accepts(GgmlDType::Q8_0));
+        assert!(QuantizationMode::Q8.accepts(GgmlDType::Q8_0));
+        assert!(!QuantizationMode::Q8.accepts(GgmlDType::Q4K));
+        assert!(!QuantizationMode::None.accepts(GgmlDType::F16));
+    }
+
+    /// Write a one-layer Llama-architecture GGUF with random weights
+    fn write_tiny_gguf(path: &Path, dtype: GgmlDType) {
+        use candle_core::quantized::QTensor;
+        use gguf_file::Value;
+
+        const HIDDEN: usize = 256;
+        const FFN: usize = 512;
+        const VOCAB: usize = 64;
+        const HEADS: u32 = 4;
+
+        let device = Device::Cpu;
+        let quantized = |shape: (usize, usize)| {
+            let weights = Tensor::randn(0f32, 0.02, shape, &device).unwrap();
+            QTensor::quantize(&weights, dtype).unwrap()
+        };
+        let norm = || {
+            let weights = Tensor::ones(HIDDEN, DType::F32, &device).unwrap();
+            QTensor::quantize(&weights, GgmlDType::F32).unwrap()
+        };
+
+        let tensors = [
+            ("token_embd.weight", quantized((VOCAB, HIDDEN))),
+            ("output_norm.weight", norm()),
+            ("output.weight", quantized((VOCAB, HIDDEN))),
+            ("blk.0.attn_q.weight", quantized((HIDDEN, HIDDEN))),
+            ("blk.0.attn_k.weight", quantized((HIDDEN, HIDDEN))),
+            ("blk.0.attn_v.weight", quantized((HIDDEN, HIDDEN))),
+            ("blk.0.attn_output.weight", quantized((HIDDEN, HIDDEN))),
+            ("blk.0.ffn_gate.weight", quantized((FFN, HIDDEN))),
+            ("blk.0.ffn_down.weight", quantized((HIDDEN, FFN))),
+            ("blk.0.ffn_up.weight", quantized((FFN, HIDDEN))),
+            ("blk.0.attn_norm.weight", norm()),
+            ("blk.0.ffn_norm.weight", norm()),
+        ];
+
+        let metadata = [
+            ("llama.attention.head_count", Value::U32(HEADS)),
+            ("llama.attention.head_count_kv", Value::U32(HEADS)),
+            ("llama.block_count", Value::U32(1)),
+            ("llama.embedding_length", Value::U32(HIDDEN as u32)),
+            ("llama.rope.dimension_count", Value::U32(HIDDEN as u32 / HEADS)),
+            ("llama.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+        ];
+
+        let metadata: Vec<_> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let tensors: Vec<_> = tensors.iter().map(|(k, t)| (*k, t)).collect();
+        let mut file = File::create(path).unwrap();
+        gguf_file::write(&mut file, &metadata, &tensors).unwrap();
+    }
+
+    fn gguf_config(path: &Path, quantization: QuantizationMode) -> ModelConfig {
+        ModelConfig {
+            model_path: path.to_path_buf(),
+            tokenizer_path: PathBuf::from("/unused/tokenizer.json"),
+            quantization,
+            device: Device::Cpu,
+            use_flash_attn: false,
+            chat_format: ChatFormat::MistralInstruct,
+            context_length: DEFAULT_CONTEXT_LENGTH,
+        }
+    }
+
+    #[test]
+    fn test_load_q8_gguf() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiny-q8_0.gguf");
+        write_tiny_gguf(&path, GgmlDType::Q8_0);
+
+        let (mut model, bytes) =
+            LoadedModel::load_weights(&gguf_config(&path, QuantizationMode::Q8)).unwrap();
+        assert!(matches!(model, ModelWeights::QuantizedLlama(_)));
+
+        // Q8_0 packs 32 weights into 34 bytes; the three norms stay F32
+        let matrices = 2 * 64 * 256 + 4 * 256 * 256 + 3 * 512 * 256;
+        assert_eq!(bytes, matrices / 32 * 34 + 3 * 256 * 4);
+
+        let input = Tensor::new(&[[1u32, 2, 3]], &Device::Cpu).unwrap();
+        let logits = model.forward(&input, 0).unwrap();
+        assert_eq!(logits.dims(), &[1, 1, 64]);
+    }
+
+    #[test]
+    fn test_q4_footprint_smaller_than_q8() {
+        let dir = tempfile::tempdir().unwrap();
+        let q4 = dir.path().join("tiny-q4_k.gguf");
+        let q8 = dir.path().join("tiny-q8_0.gguf");
+        write_tiny_gguf(&q4, GgmlDType::Q4K);
+        write_tiny_gguf(&q8, GgmlDType::Q8_0);
+
+        let (_, q4_bytes) =
+            LoadedModel::load_weights(&gguf_config(&q4, QuantizationMode::Q4)).unwrap();
+        let (_, q8_bytes) =
+            LoadedModel::load_weights(&gguf_config(&q8, QuantizationMode::Q8)).unwrap();
+
+        assert!(q4_bytes < q8_bytes);
+    }
+
+    #[test]
+    fn test_quantization_mismatch_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiny-q8_0.gguf");
+        write_tiny_gguf(&path, GgmlDType::Q8_0);
+
+        let result = LoadedModel::load_weights(&gguf_config(&path, QuantizationMode::Q4));
+        let message = result.err().unwrap().to_string();
+        assert!(message.contains("Q8_0"), "unexpected error: {}", message);
     }
 }
@@ -0,0 +1,508 @@
+//! Prompt templates and chat formats
+//!
+//! A prompt is built in two steps. A [`PromptTemplate`] renders the system
+//! and user messages from the request, then the model's [`ChatFormat`]
+//! wraps them in the control tokens the model was instruction-tuned with.
+//! Keeping the two apart lets one template serve every model.
+//!
+//! Templates are text files in the directory named by
+//! `PROMPT_TEMPLATE_DIR`, one per template, named after the file stem. The
+//! system message comes first; a line containing only `---` starts the user
+//! message. A file without one is a system message used with the built-in
+//! user message. Placeholders are written `{{name}}`, and
+//! `{{#name}}...{{/name}}` keeps its contents only when `name` has a
+//! value. The variables are:
+//!
+//! - `question`: the anonymized TMA content
+//! - `question_number`
+//! - `rubric`
+//! - `answer`: the student's answer, if sent separately
+//! - `module_code`
+//! - `word_limit`
+//!
+//! Instructions for the requested response format are appended to the user
+//! message by the jail, so every template works with every format.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::protocol::{InferenceRequest, ResponseFormat};
+
+/// Name of the template used when a request does not pick one
+pub const DEFAULT_TEMPLATE: &str = "default";
+
+/// Separator between the system and user messages in a template file
+const SEPARATOR: &str = "---";
+
+const DEFAULT_SYSTEM: &str = "You are an expert academic grader assistant. \
+    Your task is to provide constructive feedback on student answers \
+    based on the provided rubric. Be objective, specific, and helpful.";
+
+const DEFAULT_USER: &str = "Question {{question_number}}\
+    {{#module_code}} ({{module_code}}){{/module_code}}\n\n\
+    TMA Context:\n{{question}}\n\n\
+    Grading Rubric:\n{{rubric}}\
+    {{#answer}}\n\nStudent Answer:\n{{answer}}{{/answer}}\
+    {{#word_limit}}\n\nWord limit: {{word_limit}} words{{/word_limit}}";
+
+/// Control-token layout a model expects its conversation in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChatFormat {
+    /// `<|im_start|>role ... <|im_end|>` blocks
+    #[default]
+    ChatMl,
+    /// Mistral Instruct's `[INST] ... [/INST]`, which has no system role,
+    /// so the system message opens the instruction
+    MistralInstruct,
+}
+
+impl ChatFormat {
+    /// Guess the format from the model's file path, for when
+    /// `CHAT_FORMAT` is not set
+    pub fn for_model(path: &Path) -> Self {
+        if path.to_string_lossy().to_lowercase().contains("mistral") {
+            ChatFormat::MistralInstruct
+        } else {
+            ChatFormat::ChatMl
+        }
+    }
+
+    /// Lay out a system and user message, leaving the model to write the
+    /// assistant turn
+    ///
+    /// The beginning-of-sequence token is left to the tokenizer.
+    pub fn wrap(&self, system: &str, user: &str) -> String {
+        match self {
+            ChatFormat::ChatMl => format!(
+                "<|im_start|>system\n{}\n<|im_end|>\n\
+                 <|im_start|>user\n{}<|im_end|>\n\
+                 <|im_start|>assistant\n",
+                system, user
+            ),
+            ChatFormat::MistralInstruct => format!("[INST] {}\n\n{} [/INST]", system, user),
+        }
+    }
+}
+
+impl FromStr for ChatFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "chatml" => Ok(ChatFormat::ChatMl),
+            "mistral" => Ok(ChatFormat::MistralInstruct),
+            other => anyhow::bail!(
+                "Unknown chat format '{}' (expected chatml or mistral)",
+                other
+            ),
+        }
+    }
+}
+
+/// Request values a template can refer to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variable {
+    Question,
+    QuestionNumber,
+    Rubric,
+    Answer,
+    ModuleCode,
+    WordLimit,
+}
+
+impl Variable {
+    /// The variable's value for `request`, if it has a non-empty one
+    fn value(&self, request: &InferenceRequest) -> Option<String> {
+        let value = match self {
+            Variable::Question => Some(request.tma_content.clone()),
+            Variable::QuestionNumber => Some(request.question_number.to_string()),
+            Variable::Rubric => Some(request.rubric.clone()),
+            Variable::Answer => request.student_answer.clone(),
+            Variable::ModuleCode => request.prompt.module_code.clone(),
+            Variable::WordLimit => request.prompt.word_limit.map(|n| n.to_string()),
+        };
+
+        value.filter(|v| !v.is_empty())
+    }
+}
+
+impl FromStr for Variable {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "question" => Ok(Variable::Question),
+            "question_number" => Ok(Variable::QuestionNumber),
+            "rubric" => Ok(Variable::Rubric),
+            "answer" => Ok(Variable::Answer),
+            "module_code" => Ok(Variable::ModuleCode),
+            "word_limit" => Ok(Variable::WordLimit),
+            other => anyhow::bail!("Unknown template variable '{}'", other),
+        }
+    }
+}
+
+/// Piece of a parsed message template
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable(Variable),
+    /// Rendered only when the variable has a value
+    Section(Variable, Vec<Segment>),
+}
+
+/// Parse `{{name}}` placeholders and `{{#name}}...{{/name}}` sections
+fn parse_segments(text: &str) -> Result<Vec<Segment>> {
+    // Open sections, innermost last; the first entry is the top level
+    let mut stack: Vec<(Option<Variable>, Vec<Segment>)> = vec![(None, Vec::new())];
+    let mut rest = text;
+
+    while let Some(open) = rest.find("{{") {
+        let segments = &mut stack.last_mut().expect("top level is never popped").1;
+        if open > 0 {
+            segments.push(Segment::Text(rest[..open].to_string()));
+        }
+
+        let after = &rest[open + 2..];
+        let close = after.find("}}").context("Unclosed '{{' in template")?;
+        let tag = after[..close].trim();
+        rest = &after[close + 2..];
+
+        if let Some(name) = tag.strip_prefix('#') {
+            stack.push((Some(name.trim().parse()?), Vec::new()));
+        } else if let Some(name) = tag.strip_prefix('/') {
+            let variable: Variable = name.trim().parse()?;
+            if stack.len() < 2 || stack.last().map(|(v, _)| *v) != Some(Some(variable)) {
+                anyhow::bail!("'{{{{/{}}}}}' does not close an open section", name.trim());
+            }
+            let (_, body) = stack.pop().expect("checked above");
+            let parent = &mut stack.last_mut().expect("top level is never popped").1;
+            parent.push(Segment::Section(variable, body));
+        } else {
+            segments.push(Segment::Variable(tag.parse()?));
+        }
+    }
+
+    if stack.len() > 1 {
+        anyhow::bail!("Unclosed section in template");
+    }
+
+    let mut segments = stack.pop().expect("top level is never popped").1;
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest.to_string()));
+    }
+
+    Ok(segments)
+}
+
+fn render_segments(segments: &[Segment], request: &InferenceRequest, out: &mut String) {
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => out.push_str(text),
+            Segment::Variable(variable) => {
+                if let Some(value) = variable.value(request) {
+                    out.push_str(&value);
+                }
+            }
+            Segment::Section(variable, body) => {
+                if variable.value(request).is_some() {
+                    render_segments(body, request, out);
+                }
+            }
+        }
+    }
+}
+
+/// System and user messages with placeholders for request values
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    system: Vec<Segment>,
+    user: Vec<Segment>,
+}
+
+impl PromptTemplate {
+    /// Parse a template file's contents
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut system = Vec::new();
+        let mut user = None;
+        for line in text.lines() {
+            match &mut user {
+                None if line.trim() == SEPARATOR => user = Some(Vec::new()),
+                None => system.push(line),
+                Some(user) => user.push(line),
+            }
+        }
+
+        let system = system.join("\n");
+        let user = user.map(|lines| lines.join("\n"));
+
+        Ok(Self {
+            system: parse_segments(system.trim()).context("Invalid system message")?,
+            user: parse_segments(user.as_deref().unwrap_or(DEFAULT_USER).trim())
+                .context("Invalid user message")?,
+        })
+    }
+
+    /// Render the prompt for `request` in `format`
+    pub fn render(&self, request: &InferenceRequest, format: ChatFormat) -> String {
+        let mut system = String::new();
+        render_segments(&self.system, request, &mut system);
+
+        let mut user = String::new();
+        render_segments(&self.user, request, &mut user);
+        push_format_instructions(request, &mut user);
+
+        format.wrap(&system, &user)
+    }
+}
+
+impl Default for PromptTemplate {
+    fn default() -> Self {
+        Self {
+            system: vec![Segment::Text(DEFAULT_SYSTEM.to_string())],
+            user: parse_segments(DEFAULT_USER).expect("built-in template is valid"),
+        }
+    }
+}
+
+/// Tell the model what shape of response the request asked for
+fn push_format_instructions(request: &InferenceRequest, prompt: &mut String) {
+    match request.response_format {
+        ResponseFormat::Text => {
+            prompt.push_str("\n\nProvide detailed feedback based on the rubric:\n");
+        }
+        ResponseFormat::RubricJson => {
+            if !request.criteria.is_empty() {
+                prompt.push_str("\n\nCriteria:\n");
+                for criterion in &request.criteria {
+                    let line = format!("{}. {}", criterion.number, criterion.description);
+                    prompt.push_str(&line);
+                    if let Some(max_marks) = criterion.max_marks {
+                        prompt.push_str(&format!(" ({} marks)", max_marks));
+                    }
+                    prompt.push('\n');
+                }
+            }
+
+            prompt.push_str("\n\nRespond with a JSON object with the keys ");
+            prompt.push_str("\"criterion_scores\" (one object per criterion with ");
+            prompt.push_str("\"criterion_number\", \"criterion_text\", \"score\", ");
+            prompt.push_str("\"max_score\" and \"feedback\"), \"strengths\" and ");
+            prompt.push_str("\"suggestions\" (lists of strings) and \"overall_grade\" ");
+            prompt.push_str("(0-100), in that order:\n");
+        }
+    }
+}
+
+/// Named prompt templates, always including [`DEFAULT_TEMPLATE`]
+#[derive(Debug, Clone)]
+pub struct TemplateRegistry {
+    templates: BTreeMap<String, PromptTemplate>,
+}
+
+impl TemplateRegistry {
+    /// Load every `.txt` file in `dir` on top of the built-in default
+    ///
+    /// A `default.txt` replaces the built-in default template.
+    pub fn load_dir<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut registry = Self::default();
+
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read template directory {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("txt") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read template {}", path.display()))?;
+            let template = PromptTemplate::parse(&text)
+                .with_context(|| format!("Invalid template {}", path.display()))?;
+            registry.templates.insert(name.to_string(), template);
+        }
+
+        tracing::info!(
+            "Loaded prompt templates: {}",
+            registry.names().collect::<Vec<_>>().join(", ")
+        );
+        Ok(registry)
+    }
+
+    /// Template names in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(String::as_str)
+    }
+
+    /// The named template, or the default when `name` is `None`
+    pub fn get(&self, name: Option<&str>) -> Result<&PromptTemplate> {
+        let name = name.unwrap_or(DEFAULT_TEMPLATE);
+        self.templates.get(name).with_context(|| {
+            format!(
+                "Unknown prompt template '{}' (available: {})",
+                name,
+                self.names().collect::<Vec<_>>().join(", ")
+            )
+        })
+    }
+
+    /// Render the prompt for `request` with the template it selects
+    pub fn render(&self, request: &InferenceRequest, format: ChatFormat) -> Result<String> {
+        let template = self.get(request.prompt.template.as_deref())?;
+        Ok(template.render(request, format))
+    }
+}
+
+impl Default for TemplateRegistry {
+    fn default() -> Self {
+        let mut templates = BTreeMap::new();
+        templates.insert(DEFAULT_TEMPLATE.to_string(), PromptTemplate::default());
+        Self { templates }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_ipc_protocol::{GenerationParams, PromptOptions, RubricCriterion};
+
+    fn request(prompt: PromptOptions) -> InferenceRequest {
+        InferenceRequest::from_feedback(
+            "Discuss the impact of climate change.".to_string(),
+            "Award 10 marks for comprehensive discussion.".to_string(),
+            vec![],
+            1,
+            GenerationParams::default(),
+            prompt,
+        )
+    }
+
+    #[test]
+    fn test_default_template() {
+        let mut req = request(PromptOptions::default());
+        req.student_answer = Some("Climate change affects weather patterns.".to_string());
+
+        let prompt = TemplateRegistry::default()
+            .render(&req, ChatFormat::ChatMl)
+            .unwrap();
+
+        assert!(prompt.starts_with("<|im_start|>system\nYou are an expert"));
+        assert!(prompt.contains("Question 1\n\nTMA Context:\nDiscuss the impact"));
+        assert!(prompt.contains("Student Answer:\nClimate change affects weather patterns."));
+        assert!(!prompt.contains("Word limit"));
+        assert!(prompt.ends_with("<|im_start|>assistant\n"));
+    }
+
+    #[test]
+    fn test_chat_formats() {
+        let req = request(PromptOptions::default());
+        let template = PromptTemplate::default();
+
+        let mistral = template.render(&req, ChatFormat::MistralInstruct);
+        assert!(mistral.starts_with("[INST] You are an expert"));
+        assert!(mistral.ends_with(" [/INST]"));
+        assert!(!mistral.contains("<|im_start|>"));
+
+        assert_eq!(
+            "mistral".parse::<ChatFormat>().unwrap(),
+            ChatFormat::MistralInstruct
+        );
+        assert!("llama".parse::<ChatFormat>().is_err());
+        assert_eq!(
+            ChatFormat::for_model(Path::new("/models/mistral-7b/model-q4_k.gguf")),
+            ChatFormat::MistralInstruct
+        );
+    }
+
+    #[test]
+    fn test_variables_and_sections() {
+        let template = PromptTemplate::parse(
+            "Marking {{ module_code }}.\n---\n{{question}}{{#word_limit}} (max {{word_limit}}){{/word_limit}}",
+        )
+        .unwrap();
+
+        let req = request(PromptOptions {
+            module_code: Some("M250".to_string()),
+            word_limit: Some(500),
+            ..Default::default()
+        });
+        let prompt = template.render(&req, ChatFormat::ChatMl);
+        assert!(prompt.contains("system\nMarking M250.\n"));
+        assert!(prompt.contains("user\nDiscuss the impact of climate change. (max 500)"));
+
+        let prompt = template.render(&request(PromptOptions::default()), ChatFormat::ChatMl);
+        assert!(prompt.contains("user\nDiscuss the impact of climate change.\n\nProvide"));
+    }
+
+    #[test]
+    fn test_invalid_templates_rejected() {
+        assert!(PromptTemplate::parse("Hello {{name}}").is_err());
+        assert!(PromptTemplate::parse("Hello {{question").is_err());
+        assert!(PromptTemplate::parse("{{#answer}}unclosed").is_err());
+        assert!(PromptTemplate::parse("{{#answer}}x{{/rubric}}").is_err());
+        assert!(PromptTemplate::parse("{{/answer}}").is_err());
+    }
+
+    #[test]
+    fn test_json_prompt_lists_criteria() {
+        let params = GenerationParams {
+            response_format: ResponseFormat::RubricJson,
+            ..Default::default()
+        };
+        let req = InferenceRequest::from_feedback(
+            "Answer".to_string(),
+            "Rubric".to_string(),
+            vec![RubricCriterion {
+                number: 1,
+                description: "Identifies the base case".to_string(),
+                max_marks: Some(10.0),
+            }],
+            1,
+            params,
+            PromptOptions::default(),
+        );
+
+        let prompt = PromptTemplate::default().render(&req, ChatFormat::ChatMl);
+        assert!(prompt.contains("1. Identifies the base case (10 marks)"));
+        assert!(prompt.contains("\"criterion_scores\""));
+        assert!(!prompt.contains("Provide detailed feedback"));
+    }
+
+    #[test]
+    fn test_registry_loads_directory() {
+        let dir = std::env::temp_dir().join(format!("ai-jail-templates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("essay.txt"), "Mark this essay.").unwrap();
+        std::fs::write(dir.join("notes.md"), "ignored").unwrap();
+
+        let registry = TemplateRegistry::load_dir(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            vec!["default", "essay"]
+        );
+
+        let req = request(PromptOptions {
+            template: Some("essay".to_string()),
+            ..Default::default()
+        });
+        let prompt = registry.render(&req, ChatFormat::ChatMl).unwrap();
+        // A system-only file keeps the built-in user message
+        assert!(prompt.contains("system\nMark this essay.\n"));
+        assert!(prompt.contains("Grading Rubric:\nAward 10 marks"));
+
+        let req = request(PromptOptions {
+            template: Some("missing".to_string()),
+            ..Default::default()
+        });
+        let err = registry.render(&req, ChatFormat::ChatMl).unwrap_err();
+        assert!(err.to_string().contains("available: default, essay"));
+    }
+}
@@ -1,11 +1,20 @@
 //! IPC protocol definitions for stdin/stdout communication
 //!
-//! This module defines the request/response protocol used for communication
-//! between the orchestrator and the AI jail container.
-
+//! The wire format lives in the shared `aws-ipc-protocol` crate. This module
+//! maps `FeedbackRequest` messages onto the jail's internal inference request
+//! and turns inference results back into `FeedbackResponse` messages.
+
+use aws_ipc_protocol::{
+    ConfidenceSpan, ContextUsage, CriterionScore, GenerationParams, IPCMessage,
+    InferenceMetadata, ModelInfo, PromptOptions, RubricCriterion,
+};
 use serde::{Deserialize, Serialize};
 
-/// Request sent from orchestrator to AI jail via stdin
+use crate::watchdog::CancelFlag;
+
+pub use aws_ipc_protocol::ResponseFormat;
+
+/// Inference request decoded from a `FeedbackRequest` message
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceRequest {
     /// Anonymized TMA content (student answers removed)
@@ -32,17 +41,78 @@ pub struct InferenceRequest {
     /// Optional: Top-p sampling threshold
     #[serde(default = "default_top_p")]
     pub top_p: f64,
+
+    /// Optional: Top-k cutoff (0 disables)
+    #[serde(default)]
+    pub top_k: usize,
+
+    /// Optional: Min-p cutoff relative to the most likely token (0.0 disables)
+    #[serde(default)]
+    pub min_p: f64,
+
+    /// Optional: Repetition penalty (1.0 disables)
+    #[serde(default = "default_repetition_penalty")]
+    pub repetition_penalty: f32,
+
+    /// Optional: Frequency penalty (-2.0 to 2.0)
+    #[serde(default)]
+    pub frequency_penalty: f32,
+
+    /// Optional: Presence penalty (-2.0 to 2.0)
+    #[serde(default)]
+    pub presence_penalty: f32,
+
+    /// Optional: Stop sequences in addition to the end-of-turn markers
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+
+    /// Structured rubric criteria, if the orchestrator parsed any
+    #[serde(default)]
+    pub criteria: Vec<RubricCriterion>,
+
+    /// Shape the output is constrained to
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+
+    /// Optional: Sampler seed; a random one is used when absent
+    #[serde(default)]
+    pub seed: Option<u64>,
+
+    /// Prompt template and the module details it may refer to
+    #[serde(default)]
+    pub prompt: PromptOptions,
+
+    /// Optional: Registry id of the model to use; the default model when
+    /// absent
+    #[serde(default)]
+    pub model: Option<String>,
+
+    /// Optional: Wall-clock milliseconds the request may run for
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+
+    /// Optional: Tokens the request may generate over all its sections
+    #[serde(default)]
+    pub token_limit: Option<usize>,
+
+    /// Set when the orchestrator cancels the request
+    #[serde(skip)]
+    pub cancel: CancelFlag,
 }
 
-/// Response sent from AI jail to orchestrator via stdout
+/// Result of running inference for one request
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceResponse {
     /// Generated feedback text
     pub feedback: String,
 
-    /// Model confidence score (0.0-1.0)
+    /// Geometric mean token probability (0.0-1.0)
     pub confidence: f32,
 
+    /// Sentences the model was least sure of
+    #[serde(default)]
+    pub low_confidence_spans: Vec<ConfidenceSpan>,
+
     /// How well the feedback aligns with the rubric (0.0-1.0)
     pub rubric_alignment: f32,
 
@@ -51,31 +121,67 @@ pub struct InferenceResponse {
 
     /// Inference time in milliseconds
     pub inference_time_ms: u64,
-}
 
-/// Error response when inference fails
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ErrorResponse {
-    /// Error type
-    pub error_type: String,
+    /// Parsed output, when the request asked for structured output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub structured: Option<StructuredFeedback>,
 
-    /// Human-readable error message
-    pub message: String,
+    /// Parameters the output was sampled with, including the seed
+    #[serde(default)]
+    pub params: GenerationParams,
 
-    /// Optional detailed error information
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub details: Option<String>,
+    /// Whether other requests were decoded in the same batch, in which
+    /// case replaying the seed may not reproduce the output exactly
+    #[serde(default)]
+    pub batched: bool,
+
+    /// How the answer was fitted into the context window, including
+    /// whether any of it had to be left out
+    #[serde(default)]
+    pub context: ContextUsage,
+
+    /// Model that generated the feedback
+    #[serde(default)]
+    pub model: ModelInfo,
 }
 
-/// Wrapper for all responses
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "status")]
-pub enum Response {
-    #[serde(rename = "success")]
-    Success(InferenceResponse),
+/// Output generated for `ResponseFormat::RubricJson`
+///
+/// The grammar in `grammar::RUBRIC_FEEDBACK` mirrors these fields, so any
+/// complete constrained generation deserializes into this type.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StructuredFeedback {
+    pub criterion_scores: Vec<CriterionScore>,
+    pub strengths: Vec<String>,
+    pub suggestions: Vec<String>,
+    pub overall_grade: f32,
+}
+
+impl StructuredFeedback {
+    /// Render as plain-text feedback for display
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        for score in &self.criterion_scores {
+            text.push_str(&format!(
+                "Criterion {} ({}/{}): {}\n",
+                score.criterion_number, score.score, score.max_score, score.feedback
+            ));
+        }
 
-    #[serde(rename = "error")]
-    Error(ErrorResponse),
+        let lists = [("Strengths", &self.strengths), ("Suggestions", &self.suggestions)];
+        for (heading, items) in lists {
+            if !items.is_empty() {
+                text.push_str(&format!("\n{}:\n", heading));
+                for item in items {
+                    text.push_str(&format!("- {}\n", item));
+                }
+            }
+        }
+
+        text.push_str(&format!("\nOverall grade: {}/100", self.overall_grade));
+        text
+    }
 }
 
 fn default_max_tokens() -> usize {
@@ -90,7 +196,73 @@ fn default_top_p() -> f64 {
     0.9
 }
 
+fn default_repetition_penalty() -> f32 {
+    1.1
+}
+
+/// Most extra stop sequences a request may set
+pub const MAX_STOP_SEQUENCES: usize = 8;
+
+/// Longest stop sequence a request may set, in bytes
+pub const MAX_STOP_SEQUENCE_LEN: usize = 64;
+
 impl InferenceRequest {
+    /// Build a request from the payload of a `FeedbackRequest` message
+    pub fn from_feedback(
+        content: String,
+        rubric: String,
+        criteria: Vec<RubricCriterion>,
+        question_number: u32,
+        params: GenerationParams,
+        prompt: PromptOptions,
+    ) -> Self {
+        Self {
+            tma_content: content,
+            rubric,
+            question_number,
+            student_answer: None,
+            max_tokens: params.max_tokens,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            top_k: params.top_k,
+            min_p: params.min_p,
+            repetition_penalty: params.repetition_penalty,
+            frequency_penalty: params.frequency_penalty,
+            presence_penalty: params.presence_penalty,
+            stop_sequences: params.stop_sequences,
+            criteria,
+            response_format: params.response_format,
+            seed: params.seed,
+            prompt,
+            model: params.model,
+            timeout_ms: params.timeout_ms,
+            token_limit: params.token_limit,
+            cancel: CancelFlag::default(),
+        }
+    }
+
+    /// Generation parameters that reproduce this request's output when
+    /// sent back with the same content, rubric and model
+    pub fn generation_params(&self, seed: u64) -> GenerationParams {
+        GenerationParams {
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            min_p: self.min_p,
+            repetition_penalty: self.repetition_penalty,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            stop_sequences: self.stop_sequences.clone(),
+            response_format: self.response_format,
+            seed: Some(seed),
+            model: self.model.clone(),
+            timeout_ms: self.timeout_ms,
+            token_limit: self.token_limit,
+            ..Default::default()
+        }
+    }
+
     /// Validate request parameters
     pub fn validate(&self) -> Result<(), String> {
         if self.tma_content.is_empty() {
@@ -113,36 +285,81 @@ pub fn validate(&self) -> Result<(), String> {
             return Err("Max tokens must be between 1 and 4096".to_string());
         }
 
-        Ok(())
-    }
+        if self.timeout_ms == Some(0) {
+            return Err("Timeout must be at least 1 ms".to_string());
+        }
+
+        if self.token_limit == Some(0) {
+            return Err("Token limit must be at least 1".to_string());
+        }
 
-    /// Format the request into a prompt for the model
-    pub fn to_prompt(&self) -> String {
-        let mut prompt = String::new();
+        if !(0.0..=1.0).contains(&self.min_p) {
+            return Err("Min-p must be between 0.0 and 1.0".to_string());
+        }
 
-        prompt.push_str("<|im_start|>system\n");
-        prompt.push_str("You are an expert academic grader assistant. ");
-        prompt.push_str("Your task is to provide constructive feedback on student answers ");
-        prompt.push_str("based on the provided rubric. Be objective, specific, and helpful.\n");
-        prompt.push_str("<|im_end|>\n");
+        if self.repetition_penalty <= 0.0 || self.repetition_penalty > 2.0 {
+            return Err("Repetition penalty must be above 0.0 and at most 2.0".to_string());
+        }
 
-        prompt.push_str("<|im_start|>user\n");
-        prompt.push_str(&format!("Question {}\n\n", self.question_number));
-        prompt.push_str("TMA Context:\n");
-        prompt.push_str(&self.tma_content);
-        prompt.push_str("\n\nGrading Rubric:\n");
-        prompt.push_str(&self.rubric);
+        if !(-2.0..=2.0).contains(&self.frequency_penalty) {
+            return Err("Frequency penalty must be between -2.0 and 2.0".to_string());
+        }
+
+        if !(-2.0..=2.0).contains(&self.presence_penalty) {
+            return Err("Presence penalty must be between -2.0 and 2.0".to_string());
+        }
+
+        if self.stop_sequences.len() > MAX_STOP_SEQUENCES {
+            return Err(format!("At most {} stop sequences are allowed", MAX_STOP_SEQUENCES));
+        }
 
-        if let Some(answer) = &self.student_answer {
-            prompt.push_str("\n\nStudent Answer:\n");
-            prompt.push_str(answer);
+        if self
+            .stop_sequences
+            .iter()
+            .any(|s| s.is_empty() || s.len() > MAX_STOP_SEQUENCE_LEN)
+        {
+            return Err(format!(
+                "Stop sequences must be between 1 and {} bytes",
+                MAX_STOP_SEQUENCE_LEN
+            ));
         }
 
-        prompt.push_str("\n\nProvide detailed feedback based on the rubric:\n");
-        prompt.push_str("<|im_end|>\n");
-        prompt.push_str("<|im_start|>assistant\n");
+        Ok(())
+    }
+}
 
-        prompt
+impl InferenceResponse {
+    /// Wrap this result in a `FeedbackResponse` for the given request
+    pub fn into_message(self, request_id: String) -> IPCMessage {
+        let (scores, overall_grade, strengths, suggestions) = match self.structured {
+            Some(s) => (
+                s.criterion_scores,
+                Some(s.overall_grade),
+                s.strengths,
+                s.suggestions,
+            ),
+            None => Default::default(),
+        };
+
+        IPCMessage::FeedbackResponse {
+            request_id,
+            feedback: self.feedback,
+            scores,
+            overall_grade,
+            strengths,
+            suggestions,
+            metadata: InferenceMetadata {
+                confidence: self.confidence,
+                low_confidence_spans: self.low_confidence_spans,
+                rubric_alignment: self.rubric_alignment,
+                tokens_generated: self.tokens_generated,
+                inference_time_ms: self.inference_time_ms,
+                params: self.params,
+                batched: self.batched,
+                context: self.context,
+                model: self.model,
+            },
+        }
     }
 }
 
@@ -160,6 +377,20 @@ fn test_request_validation() {
             max_tokens: 512,
             temperature: 0.7,
             top_p: 0.9,
+            criteria: vec![],
+            response_format: ResponseFormat::Text,
+            seed: None,
+            top_k: 0,
+            min_p: 0.0,
+            repetition_penalty: 1.1,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            stop_sequences: vec![],
+            prompt: PromptOptions::default(),
+            model: None,
+            timeout_ms: None,
+            token_limit: None,
+            cancel: Default::default(),
         };
 
         assert!(req.validate().is_ok());
@@ -176,21 +407,32 @@ fn test_request_validation() {
     }
 
     #[test]
-    fn test_prompt_formatting() {
-        let req = InferenceRequest {
-            tma_content: "Discuss the impact of climate change.".to_string(),
-            rubric: "Award 10 marks for comprehensive discussion.".to_string(),
-            question_number: 1,
-            student_answer: Some("Climate change affects weather patterns.".to_string()),
-            max_tokens: 512,
-            temperature: 0.7,
-            top_p: 0.9,
-        };
-
-        let prompt = req.to_prompt();
-        assert!(prompt.contains("Question 1"));
-        assert!(prompt.contains("climate change"));
-        assert!(prompt.contains("weather patterns"));
+    fn test_sampling_option_validation() {
+        let valid = InferenceRequest::from_feedback(
+            "Content".to_string(),
+            "Rubric".to_string(),
+            vec![],
+            1,
+            GenerationParams::default(),
+            PromptOptions::default(),
+        );
+        assert!(valid.validate().is_ok());
+
+        let invalid: [fn(&mut InferenceRequest); 7] = [
+            |r| r.min_p = 1.5,
+            |r| r.repetition_penalty = 0.0,
+            |r| r.repetition_penalty = 2.5,
+            |r| r.frequency_penalty = -2.5,
+            |r| r.presence_penalty = 3.0,
+            |r| r.stop_sequences = vec![String::new()],
+            |r| r.stop_sequences = vec!["END".to_string(); MAX_STOP_SEQUENCES + 1],
+        ];
+
+        for (i, make_invalid) in invalid.iter().enumerate() {
+            let mut req = valid.clone();
+            make_invalid(&mut req);
+            assert!(req.validate().is_err(), "case {} should be rejected", i);
+        }
     }
 
     #[test]
@@ -203,6 +445,20 @@ fn test_serde_roundtrip() {
             max_tokens: 512,
             temperature: 0.7,
             top_p: 0.9,
+            criteria: vec![],
+            response_format: ResponseFormat::Text,
+            seed: None,
+            top_k: 0,
+            min_p: 0.0,
+            repetition_penalty: 1.1,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            stop_sequences: vec![],
+            prompt: PromptOptions::default(),
+            model: None,
+            timeout_ms: None,
+            token_limit: None,
+            cancel: Default::default(),
         };
 
         let json = serde_json::to_string(&req).unwrap();
@@ -212,4 +468,127 @@ fn test_serde_roundtrip() {
         assert_eq!(req.rubric, decoded.rubric);
         assert_eq!(req.question_number, decoded.question_number);
     }
+
+    #[test]
+    fn test_from_feedback_uses_params() {
+        let params = GenerationParams {
+            max_tokens: 128,
+            temperature: 0.2,
+            top_p: 0.5,
+            ..Default::default()
+        };
+        let req = InferenceRequest::from_feedback(
+            "Answer".to_string(),
+            "Rubric".to_string(),
+            vec![],
+            3,
+            params,
+            PromptOptions::default(),
+        );
+
+        assert_eq!(req.tma_content, "Answer");
+        assert_eq!(req.question_number, 3);
+        assert_eq!(req.max_tokens, 128);
+        assert_eq!(req.temperature, 0.2);
+        assert_eq!(req.top_p, 0.5);
+        assert!(req.validate().is_ok());
+    }
+
+    #[test]
+    fn test_response_into_message() {
+        let response = InferenceResponse {
+            feedback: "Well argued".to_string(),
+            confidence: 0.8,
+            low_confidence_spans: vec![ConfidenceSpan {
+                start: 0,
+                end: 11,
+                text: "Well argued".to_string(),
+                confidence: 0.3,
+                entropy: 2.0,
+            }],
+            rubric_alignment: 0.6,
+            tokens_generated: 3,
+            inference_time_ms: 10,
+            structured: None,
+            params: GenerationParams::default(),
+            batched: false,
+            context: ContextUsage {
+                sections: 3,
+                truncated: true,
+                omitted_bytes: 120,
+                ..Default::default()
+            },
+            model: ModelInfo {
+                id: "mistral-7b".to_string(),
+                revision: "v0.2".to_string(),
+                hash: String::new(),
+            },
+        };
+
+        match response.into_message("req-1".to_string()) {
+            IPCMessage::FeedbackResponse {
+                request_id,
+                feedback,
+                metadata,
+                ..
+            } => {
+                assert_eq!(request_id, "req-1");
+                assert_eq!(feedback, "Well argued");
+                assert_eq!(metadata.tokens_generated, 3);
+                assert_eq!(metadata.low_confidence_spans.len(), 1);
+                assert!(metadata.context.truncated);
+                assert_eq!(metadata.context.omitted_bytes, 120);
+                assert_eq!(metadata.model.id, "mistral-7b");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_structured_response_into_message() {
+        let structured = StructuredFeedback {
+            criterion_scores: vec![CriterionScore {
+                criterion_number: 1,
+                criterion_text: "Base case".to_string(),
+                score: 7.0,
+                max_score: 10.0,
+                feedback: "Handles the empty list".to_string(),
+            }],
+            strengths: vec!["Clear".to_string()],
+            suggestions: vec!["Add tests".to_string()],
+            overall_grade: 70.0,
+        };
+        let response = InferenceResponse {
+            feedback: structured.to_text(),
+            confidence: 0.9,
+            low_confidence_spans: vec![],
+            rubric_alignment: 0.5,
+            tokens_generated: 40,
+            inference_time_ms: 10,
+            structured: Some(structured),
+            params: GenerationParams::default(),
+            batched: false,
+            context: ContextUsage::default(),
+            model: ModelInfo::default(),
+        };
+
+        match response.into_message("req-1".to_string()) {
+            IPCMessage::FeedbackResponse {
+                feedback,
+                scores,
+                overall_grade,
+                strengths,
+                suggestions,
+                ..
+            } => {
+                assert!(feedback.starts_with("Criterion 1 (7/10): Handles the empty list"));
+                assert!(feedback.contains("Suggestions:\n- Add tests"));
+                assert_eq!(scores.len(), 1);
+                assert_eq!(overall_grade, Some(70.0));
+                assert_eq!(strengths, vec!["Clear".to_string()]);
+                assert_eq!(suggestions, vec!["Add tests".to_string()]);
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
 }
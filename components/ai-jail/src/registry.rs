@@ -0,0 +1,731 @@
+//! Registry of the models a jail can serve
+//!
+//! Requests name a model by id, e.g. a small model for quick formative
+//! feedback and a larger one for summative marking. Models are loaded the
+//! first time they are asked for and kept resident while they fit in the
+//! memory budget; loading one that does not fit unloads the least recently
+//! used models first.
+//!
+//! The registry file is JSON:
+//!
+//! ```json
+//! {
+//!   "default": "mistral-7b",
+//!   "memory_budget_mb": 7500,
+//!   "models": [
+//!     {
+//!       "id": "mistral-7b",
+//!       "revision": "v0.2",
+//!       "model_path": "mistral-7b/model-q4_k.gguf",
+//!       "tokenizer_path": "mistral-7b/tokenizer.json",
+//!       "quantization": "q4",
+//!       "chat_format": "mistral",
+//!       "manifest": "mistral-7b/manifest.json"
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Relative paths are resolved against the registry file's directory.
+//!
+//! With a [`ManifestVerifier`], every model needs a signed manifest (see
+//! [`crate::manifest`]) and its files are checked against it before each
+//! load; the verified hash of the weights is reported in responses.
+
+use anyhow::{Context, Result};
+use aws_ipc_protocol::ModelInfo;
+use candle_core::Device;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::backend::{InferenceBackend, DEFAULT_CONTEXT_LENGTH};
+use crate::inference::InferenceEngine;
+use crate::manifest::ManifestVerifier;
+use crate::model::{ModelConfig, QuantizationMode};
+use crate::prompt::{ChatFormat, TemplateRegistry};
+use crate::protocol::InferenceRequest;
+
+/// Revision reported for models registered without one
+pub const UNVERSIONED: &str = "unversioned";
+
+const BYTES_PER_MB: usize = 1024 * 1024;
+
+/// Builds a backend from a model's configuration
+pub type ModelLoader = Box<dyn FnMut(&ModelConfig) -> Result<Box<dyn InferenceBackend>>>;
+
+/// A model the registry can load
+#[derive(Debug, Clone)]
+pub struct ModelSpec {
+    /// Id requests refer to the model by
+    pub id: String,
+    /// Revision of the weights, reported in responses
+    pub revision: String,
+    pub config: ModelConfig,
+    /// Signed manifest the model's files are checked against
+    pub manifest: Option<PathBuf>,
+}
+
+impl ModelSpec {
+    fn info(&self) -> ModelInfo {
+        ModelInfo {
+            id: self.id.clone(),
+            revision: self.revision.clone(),
+            hash: String::new(),
+        }
+    }
+
+    /// Memory the model is expected to need: the size of its weights file
+    fn estimated_bytes(&self) -> usize {
+        std::fs::metadata(&self.config.model_path)
+            .map(|m| m.len() as usize)
+            .unwrap_or(0)
+    }
+}
+
+/// Registry file layout
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryFile {
+    default: Option<String>,
+    memory_budget_mb: Option<usize>,
+    models: Vec<ModelFileEntry>,
+}
+
+/// One model in the registry file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelFileEntry {
+    id: String,
+    revision: Option<String>,
+    model_path: PathBuf,
+    tokenizer_path: PathBuf,
+    quantization: Option<String>,
+    chat_format: Option<String>,
+    context_length: Option<usize>,
+    manifest: Option<PathBuf>,
+}
+
+impl ModelFileEntry {
+    fn into_spec(self, base: &Path, device: &Device) -> Result<ModelSpec> {
+        let model_path = base.join(self.model_path);
+        let quantization = match self.quantization {
+            Some(name) => name.parse()?,
+            None => QuantizationMode::Q4,
+        };
+        let chat_format = match self.chat_format {
+            Some(name) => name.parse()?,
+            None => ChatFormat::for_model(&model_path),
+        };
+
+        Ok(ModelSpec {
+            id: self.id,
+            revision: self.revision.unwrap_or_else(|| UNVERSIONED.to_string()),
+            config: ModelConfig {
+                model_path,
+                tokenizer_path: base.join(self.tokenizer_path),
+                quantization,
+                device: device.clone(),
+                use_flash_attn: true,
+                chat_format,
+                context_length: self.context_length.unwrap_or(DEFAULT_CONTEXT_LENGTH),
+            },
+            manifest: self.manifest.map(|manifest| base.join(manifest)),
+        })
+    }
+}
+
+/// A registered model and, while loaded, its engine
+struct Entry {
+    info: ModelInfo,
+    /// `None` for models registered already loaded; they cannot be
+    /// reloaded, so they are never unloaded
+    spec: Option<ModelSpec>,
+    engine: Option<InferenceEngine>,
+    /// Memory charged against the budget while loaded
+    bytes: usize,
+    last_used: u64,
+}
+
+/// Named models, loaded on demand within a memory budget
+pub struct ModelRegistry {
+    entries: BTreeMap<String, Entry>,
+    default_model: String,
+    memory_budget: Option<usize>,
+    templates: TemplateRegistry,
+    loader: ModelLoader,
+    verifier: Option<ManifestVerifier>,
+    /// Counts engine lookups, to find the least recently used model
+    clock: u64,
+}
+
+impl ModelRegistry {
+    /// Registry of models loaded with `loader`; the first is the default
+    pub fn new(specs: Vec<ModelSpec>, loader: ModelLoader) -> Result<Self> {
+        let default_model = specs
+            .first()
+            .map(|spec| spec.id.clone())
+            .context("Model registry has no models")?;
+
+        let mut entries = BTreeMap::new();
+        for spec in specs {
+            let entry = Entry {
+                info: spec.info(),
+                spec: Some(spec),
+                engine: None,
+                bytes: 0,
+                last_used: 0,
+            };
+            let id = entry.info.id.clone();
+            if entries.insert(id.clone(), entry).is_some() {
+                anyhow::bail!("Model '{}' is registered twice", id);
+            }
+        }
+
+        Ok(Self {
+            entries,
+            default_model,
+            memory_budget: None,
+            templates: TemplateRegistry::default(),
+            loader,
+            verifier: None,
+            clock: 0,
+        })
+    }
+
+    /// Registry serving a single engine that is already loaded
+    pub fn with_engine(engine: InferenceEngine) -> Self {
+        let info = engine.model().clone();
+        let default_model = info.id.clone();
+        let entry = Entry {
+            info,
+            spec: None,
+            bytes: engine.memory_usage(),
+            engine: Some(engine),
+            last_used: 0,
+        };
+
+        Self {
+            entries: BTreeMap::from([(default_model.clone(), entry)]),
+            default_model,
+            memory_budget: None,
+            templates: TemplateRegistry::default(),
+            loader: Box::new(|_| anyhow::bail!("Registry has no model loader")),
+            verifier: None,
+            clock: 0,
+        }
+    }
+
+    /// Read a registry file
+    pub fn from_file<P: AsRef<Path>>(path: P, loader: ModelLoader) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read model registry {}", path.display()))?;
+        let file: RegistryFile = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid model registry {}", path.display()))?;
+
+        let base = path.parent().unwrap_or(Path::new("."));
+        let device = Device::cuda_if_available(0)?;
+        let specs = file
+            .models
+            .into_iter()
+            .map(|entry| entry.into_spec(base, &device))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut registry = Self::new(specs, loader)?;
+        if let Some(id) = file.default {
+            registry = registry.with_default(&id)?;
+        }
+        if let Some(mb) = file.memory_budget_mb {
+            registry = registry.with_memory_budget(mb * BYTES_PER_MB);
+        }
+
+        Ok(registry)
+    }
+
+    /// Serve requests that name no model with `id`
+    pub fn with_default(mut self, id: &str) -> Result<Self> {
+        self.default_model = self.resolve(Some(id))?.to_string();
+        Ok(self)
+    }
+
+    /// Most memory, in bytes, the loaded models may hold together
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Check every model's files against its signed manifest before
+    /// loading it
+    pub fn with_verifier(mut self, verifier: ManifestVerifier) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Prompt templates for every model, loaded now or later
+    pub fn with_templates(mut self, templates: TemplateRegistry) -> Self {
+        for entry in self.entries.values_mut() {
+            entry.engine = entry
+                .engine
+                .take()
+                .map(|engine| engine.with_templates(templates.clone()));
+        }
+        self.templates = templates;
+        self
+    }
+
+    /// Ids of every registered model
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// Ids of the models currently loaded
+    pub fn loaded(&self) -> impl Iterator<Item = &str> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.engine.is_some())
+            .map(|(id, _)| id.as_str())
+    }
+
+    /// Memory held by the loaded models, in bytes
+    pub fn memory_in_use(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| entry.engine.is_some())
+            .map(|entry| entry.bytes)
+            .sum()
+    }
+
+    /// Registered id for `id`, or the default model's when `None`
+    pub fn resolve(&self, id: Option<&str>) -> Result<&str> {
+        let id = id.unwrap_or(&self.default_model);
+        match self.entries.get_key_value(id) {
+            Some((id, _)) => Ok(id),
+            None => {
+                let available: Vec<&str> = self.ids().collect();
+                anyhow::bail!("Unknown model '{}' (available: {})", id, available.join(", "))
+            }
+        }
+    }
+
+    /// Check a request's parameters, model and prompt template
+    pub fn validate(&self, request: &InferenceRequest) -> Result<(), String> {
+        self.resolve(request.model.as_deref())
+            .map_err(|e| e.to_string())?;
+        request.validate()?;
+        self.templates
+            .get(request.prompt.template.as_deref())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Check the files of every model that is not loaded against its
+    /// manifest, so a tampered model is found at startup rather than by the
+    /// first request for it
+    ///
+    /// Loaded models were checked when they were loaded.
+    pub fn verify(&mut self) -> Result<()> {
+        for entry in self.entries.values_mut() {
+            if let (Some(spec), None) = (&entry.spec, &entry.engine) {
+                entry.info.hash = verify_files(self.verifier.as_ref(), spec)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Largest batch any loaded model decodes at once
+    pub fn max_batch_size(&self) -> usize {
+        self.entries
+            .values()
+            .filter_map(|entry| entry.engine.as_ref())
+            .map(InferenceEngine::max_batch_size)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Engine for model `id` (the default when `None`), loading it first
+    /// if needed
+    pub fn engine(&mut self, id: Option<&str>) -> Result<&mut InferenceEngine> {
+        let id = self.resolve(id)?.to_string();
+        if self.entries[&id].engine.is_none() {
+            self.load(&id)?;
+        }
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(&id).expect("model is registered");
+        entry.last_used = self.clock;
+
+        Ok(entry.engine.as_mut().expect("model is loaded"))
+    }
+
+    /// Unload model `id`, returning whether it was loaded
+    ///
+    /// Models registered already loaded stay loaded.
+    pub fn unload(&mut self, id: &str) -> bool {
+        match self.entries.get_mut(id) {
+            Some(entry) if entry.spec.is_some() && entry.engine.is_some() => {
+                tracing::info!("Unloading model '{}'", id);
+                entry.engine = None;
+                entry.bytes = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Load model `id`, unloading least recently used models until it
+    /// fits in the memory budget
+    fn load(&mut self, id: &str) -> Result<()> {
+        let spec = self.entries[id]
+            .spec
+            .clone()
+            .with_context(|| format!("Model '{}' cannot be reloaded", id))?;
+        // Checked before anything is unloaded to make room, so a tampered
+        // model cannot evict good ones
+        let hash = verify_files(self.verifier.as_ref(), &spec)?;
+        let estimate = spec.estimated_bytes();
+
+        if let Some(budget) = self.memory_budget {
+            if estimate > budget {
+                anyhow::bail!(
+                    "Model '{}' needs about {} MB, more than the {} MB memory budget",
+                    id,
+                    estimate / BYTES_PER_MB,
+                    budget / BYTES_PER_MB
+                );
+            }
+
+            while self.memory_in_use() + estimate > budget {
+                let victim = self
+                    .entries
+                    .iter()
+                    .filter(|(_, entry)| entry.engine.is_some() && entry.spec.is_some())
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(victim, _)| victim.clone());
+
+                match victim {
+                    Some(victim) => {
+                        self.unload(&victim);
+                    }
+                    None => anyhow::bail!(
+                        "Not enough of the {} MB memory budget is free to load model '{}'",
+                        budget / BYTES_PER_MB,
+                        id
+                    ),
+                }
+            }
+        }
+
+        tracing::info!("Loading model '{}' (revision {})", id, spec.revision);
+        let backend = (self.loader)(&spec.config)
+            .with_context(|| format!("Failed to load model '{}'", id))?;
+        let info = ModelInfo { hash, ..spec.info() };
+        let engine = InferenceEngine::new(backend)
+            .with_templates(self.templates.clone())
+            .with_model(info.clone());
+
+        let bytes = match engine.memory_usage() {
+            0 => estimate,
+            bytes => bytes,
+        };
+        tracing::info!("Model '{}' loaded, using about {} MB", id, bytes / BYTES_PER_MB);
+
+        let entry = self.entries.get_mut(id).expect("model is registered");
+        entry.info = info;
+        entry.engine = Some(engine);
+        entry.bytes = bytes;
+
+        Ok(())
+    }
+}
+
+/// Check a model's files against its manifest, returning the hash of its
+/// weights, or an empty hash if the registry does not verify models
+fn verify_files(verifier: Option<&ManifestVerifier>, spec: &ModelSpec) -> Result<String> {
+    match (verifier, &spec.manifest) {
+        (Some(verifier), Some(manifest)) => verifier
+            .verify(manifest, &spec.config)
+            .with_context(|| format!("Model '{}' failed verification", spec.id)),
+        (Some(_), None) => anyhow::bail!("Model '{}' has no signed manifest", spec.id),
+        (None, Some(_)) => anyhow::bail!(
+            "Model '{}' has a manifest but no key to verify it with",
+            spec.id
+        ),
+        (None, None) => Ok(String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::ScriptedBackend;
+    use crate::manifest::{hash_file, signature_path, SignatureAlgorithm};
+    use academic_shared::crypto::{ed25519_keypair, ed25519_sign};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Spec whose weights file is `mb` megabytes of zeros in `dir`
+    fn spec(dir: &Path, id: &str, mb: usize) -> ModelSpec {
+        let model_path = dir.join(format!("{}.gguf", id));
+        std::fs::write(&model_path, vec![0u8; mb * BYTES_PER_MB]).unwrap();
+
+        ModelSpec {
+            id: id.to_string(),
+            revision: format!("{}-rev", id),
+            config: ModelConfig {
+                model_path,
+                tokenizer_path: dir.join("tokenizer.json"),
+                quantization: QuantizationMode::Q4,
+                device: Device::Cpu,
+                use_flash_attn: false,
+                chat_format: ChatFormat::ChatMl,
+                context_length: DEFAULT_CONTEXT_LENGTH,
+            },
+            manifest: None,
+        }
+    }
+
+    /// Loader that answers with the weights file name and counts loads
+    fn loader(loads: Rc<RefCell<Vec<String>>>) -> ModelLoader {
+        Box::new(move |config| {
+            let name = config.model_path.file_stem().unwrap().to_string_lossy().to_string();
+            loads.borrow_mut().push(name.clone());
+            Ok(Box::new(ScriptedBackend::new(vec![format!("Feedback from {}", name)])))
+        })
+    }
+
+    fn request(model: Option<&str>) -> InferenceRequest {
+        let mut request = InferenceRequest::from_feedback(
+            "Answer".to_string(),
+            "Rubric".to_string(),
+            vec![],
+            1,
+            Default::default(),
+            Default::default(),
+        );
+        request.model = model.map(str::to_string);
+        request
+    }
+
+    #[test]
+    fn test_models_load_on_demand() {
+        let dir = tempfile::tempdir().unwrap();
+        let loads = Rc::new(RefCell::new(Vec::new()));
+        let specs = vec![spec(dir.path(), "small", 1), spec(dir.path(), "large", 2)];
+        let mut registry = ModelRegistry::new(specs, loader(loads.clone())).unwrap();
+
+        assert_eq!(registry.loaded().count(), 0);
+
+        let response = registry.engine(None).unwrap().generate(&request(None)).unwrap();
+        assert_eq!(response.feedback, "Feedback from small");
+        assert_eq!(response.model.id, "small");
+        assert_eq!(response.model.revision, "small-rev");
+        assert_eq!(response.params.model.as_deref(), Some("small"));
+
+        let response = registry
+            .engine(Some("large"))
+            .unwrap()
+            .generate(&request(Some("large")))
+            .unwrap();
+        assert_eq!(response.model.id, "large");
+
+        registry.engine(Some("small")).unwrap();
+        assert_eq!(*loads.borrow(), vec!["small", "large"]);
+        assert_eq!(registry.memory_in_use(), 3 * BYTES_PER_MB);
+    }
+
+    #[test]
+    fn test_least_recently_used_model_unloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let loads = Rc::new(RefCell::new(Vec::new()));
+        let specs = vec![
+            spec(dir.path(), "a", 2),
+            spec(dir.path(), "b", 2),
+            spec(dir.path(), "c", 2),
+        ];
+        let mut registry = ModelRegistry::new(specs, loader(loads.clone()))
+            .unwrap()
+            .with_memory_budget(5 * BYTES_PER_MB);
+
+        registry.engine(Some("a")).unwrap();
+        registry.engine(Some("b")).unwrap();
+        registry.engine(Some("a")).unwrap();
+        registry.engine(Some("c")).unwrap();
+
+        let loaded: Vec<&str> = registry.loaded().collect();
+        assert_eq!(loaded, vec!["a", "c"]);
+        assert!(registry.memory_in_use() <= 5 * BYTES_PER_MB);
+    }
+
+    #[test]
+    fn test_model_over_budget_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let loads = Rc::new(RefCell::new(Vec::new()));
+        let specs = vec![spec(dir.path(), "huge", 3)];
+        let mut registry = ModelRegistry::new(specs, loader(loads.clone()))
+            .unwrap()
+            .with_memory_budget(2 * BYTES_PER_MB);
+
+        let err = registry.engine(None).err().unwrap();
+        assert!(err.to_string().contains("memory budget"));
+        assert!(loads.borrow().is_empty());
+    }
+
+    /// Sign a manifest for the spec's files and return its verifier
+    fn sign_manifest(spec: &mut ModelSpec) -> ManifestVerifier {
+        let (public_key, secret_key) = ed25519_keypair();
+        let dir = spec.config.model_path.parent().unwrap();
+        std::fs::write(&spec.config.tokenizer_path, b"{}").unwrap();
+
+        let manifest = serde_json::json!({"files": {
+            spec.config.model_path.file_name().unwrap().to_str().unwrap():
+                hash_file(&spec.config.model_path).unwrap(),
+            "tokenizer.json": hash_file(&spec.config.tokenizer_path).unwrap(),
+        }});
+        let path = dir.join(format!("{}.manifest.json", spec.id));
+        let bytes = serde_json::to_vec(&manifest).unwrap();
+        std::fs::write(&path, &bytes).unwrap();
+        let signature = ed25519_sign(&bytes, &secret_key).unwrap();
+        std::fs::write(signature_path(&path), hex::encode(signature)).unwrap();
+
+        spec.manifest = Some(path);
+        ManifestVerifier::new(SignatureAlgorithm::Ed25519, public_key)
+    }
+
+    #[test]
+    fn test_verified_model_reports_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let loads = Rc::new(RefCell::new(Vec::new()));
+        let mut small = spec(dir.path(), "small", 1);
+        let verifier = sign_manifest(&mut small);
+        let hash = hash_file(&small.config.model_path).unwrap();
+        let mut registry = ModelRegistry::new(vec![small], loader(loads))
+            .unwrap()
+            .with_verifier(verifier);
+
+        let response = registry.engine(None).unwrap().generate(&request(None)).unwrap();
+
+        assert_eq!(response.model.hash, hash);
+    }
+
+    #[test]
+    fn test_tampered_model_not_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let loads = Rc::new(RefCell::new(Vec::new()));
+        let mut small = spec(dir.path(), "small", 1);
+        let verifier = sign_manifest(&mut small);
+        let mut large = spec(dir.path(), "large", 1);
+        large.manifest = small.manifest.clone();
+        let mut registry = ModelRegistry::new(vec![small, large], loader(loads.clone()))
+            .unwrap()
+            .with_verifier(verifier);
+
+        registry.engine(None).unwrap();
+        std::fs::write(dir.path().join("small.gguf"), b"swapped").unwrap();
+        registry.unload("small");
+
+        let err = registry.engine(None).err().unwrap();
+        assert!(format!("{:#}", err).contains("Hash mismatch"));
+        // Not listed in the manifest it points at
+        assert!(registry.verify().is_err());
+        assert_eq!(*loads.borrow(), vec!["small"]);
+    }
+
+    #[test]
+    fn test_verifier_requires_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let loads = Rc::new(RefCell::new(Vec::new()));
+        let mut signed = spec(dir.path(), "signed", 1);
+        let verifier = sign_manifest(&mut signed);
+        let unsigned = spec(dir.path(), "unsigned", 1);
+
+        let mut registry = ModelRegistry::new(vec![unsigned], loader(loads.clone()))
+            .unwrap()
+            .with_verifier(verifier);
+        let err = registry.engine(None).err().unwrap();
+        assert!(err.to_string().contains("has no signed manifest"));
+
+        // A manifest nobody can check is refused as well
+        let mut registry = ModelRegistry::new(vec![signed], loader(loads.clone())).unwrap();
+        assert!(registry.verify().is_err());
+        assert!(loads.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_model_is_invalid() {
+        let registry = ModelRegistry::with_engine(InferenceEngine::new(Box::new(
+            ScriptedBackend::default(),
+        )));
+
+        assert!(registry.validate(&request(None)).is_ok());
+        assert!(registry.validate(&request(Some("scripted"))).is_ok());
+
+        let err = registry.validate(&request(Some("gpt"))).unwrap_err();
+        assert_eq!(err, "Unknown model 'gpt' (available: scripted)");
+    }
+
+    #[test]
+    fn test_registry_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("models.json");
+        std::fs::write(
+            &path,
+            r#"{
+                "default": "large",
+                "memory_budget_mb": 4,
+                "models": [
+                    {"id": "small", "model_path": "small.gguf", "tokenizer_path": "t.json",
+                     "quantization": "q8", "context_length": 4096},
+                    {"id": "large", "revision": "v2", "model_path": "/abs/large.gguf",
+                     "tokenizer_path": "t.json", "chat_format": "mistral"}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let loads = Rc::new(RefCell::new(Vec::new()));
+        let mut registry = ModelRegistry::from_file(&path, loader(loads)).unwrap();
+
+        assert_eq!(registry.ids().collect::<Vec<_>>(), vec!["large", "small"]);
+        assert_eq!(registry.resolve(None).unwrap(), "large");
+        assert_eq!(registry.memory_budget, Some(4 * BYTES_PER_MB));
+
+        let small = registry.entries["small"].spec.clone().unwrap();
+        assert_eq!(small.config.model_path, dir.path().join("small.gguf"));
+        assert_eq!(small.config.context_length, 4096);
+        assert_eq!(small.revision, UNVERSIONED);
+
+        let large = registry.entries["large"].spec.clone().unwrap();
+        assert_eq!(large.config.model_path, PathBuf::from("/abs/large.gguf"));
+        assert_eq!(large.config.chat_format, ChatFormat::MistralInstruct);
+
+        let response = registry.engine(None).unwrap().generate(&request(None)).unwrap();
+        assert_eq!(response.model.revision, "v2");
+    }
+
+    #[test]
+    fn test_invalid_registry_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("models.json");
+        let loads = Rc::new(RefCell::new(Vec::new()));
+
+        std::fs::write(&path, r#"{"models": []}"#).unwrap();
+        assert!(ModelRegistry::from_file(&path, loader(loads.clone())).is_err());
+
+        std::fs::write(
+            &path,
+            r#"{"models": [{"id": "a", "model_path": "a", "tokenizer_path": "t",
+                            "quantization": "q3"}]}"#,
+        )
+        .unwrap();
+        assert!(ModelRegistry::from_file(&path, loader(loads.clone())).is_err());
+
+        std::fs::write(
+            &path,
+            r#"{"default": "b",
+                "models": [{"id": "a", "model_path": "a", "tokenizer_path": "t"}]}"#,
+        )
+        .unwrap();
+        assert!(ModelRegistry::from_file(&path, loader(loads)).is_err());
+    }
+}
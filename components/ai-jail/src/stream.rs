@@ -0,0 +1,238 @@
+//! Incremental decoding of generated tokens
+//!
+//! Decoding the whole generation after every step makes the loop quadratic.
+//! [`TokenStream`] decodes only a short window of recent tokens per step and
+//! [`StopSequenceMatcher`] searches only the newly decoded text plus enough
+//! of the previous text to catch a stop sequence spanning the boundary.
+
+use anyhow::Result;
+
+use crate::backend::InferenceBackend;
+
+/// Turns a stream of token IDs into a stream of text fragments
+///
+/// Tokenizers merge adjacent tokens when decoding (leading spaces, multi
+/// byte characters split across tokens), so each step decodes the tokens
+/// since the last emitted fragment and emits only the text they added.
+#[derive(Debug, Default)]
+pub struct TokenStream {
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl TokenStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a token and return any text it completes
+    pub fn next_token(
+        &mut self,
+        backend: &dyn InferenceBackend,
+        token: u32,
+    ) -> Result<Option<String>> {
+        let prev_text = if self.tokens.is_empty() {
+            String::new()
+        } else {
+            backend.decode(&self.tokens[self.prev_index..self.current_index], true)?
+        };
+
+        self.tokens.push(token);
+        let text = backend.decode(&self.tokens[self.prev_index..], true)?;
+
+        // Hold back text ending in a partial character until it completes
+        if text.len() > prev_text.len() && !text.ends_with(char::REPLACEMENT_CHARACTER) {
+            self.prev_index = self.current_index;
+            self.current_index = self.tokens.len();
+            Ok(Some(text[prev_text.len()..].to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Return text from tokens not yet emitted, once generation has ended
+    pub fn finish(&mut self, backend: &dyn InferenceBackend) -> Result<Option<String>> {
+        let prev_text = backend.decode(&self.tokens[self.prev_index..self.current_index], true)?;
+        let text = backend.decode(&self.tokens[self.prev_index..], true)?;
+
+        self.prev_index = self.tokens.len();
+        self.current_index = self.tokens.len();
+
+        if text.len() > prev_text.len() {
+            Ok(Some(text[prev_text.len()..].to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Finds stop sequences in text that arrives one fragment at a time
+#[derive(Debug)]
+pub struct StopSequenceMatcher {
+    sequences: Vec<String>,
+    /// Trailing text that could still be the start of a stop sequence
+    window: String,
+    /// Bytes of the stream that have already left the window
+    consumed: usize,
+    /// Bytes of text to keep in the window between fragments
+    keep: usize,
+}
+
+impl StopSequenceMatcher {
+    pub fn new(sequences: &[String]) -> Self {
+        let sequences: Vec<String> = sequences.iter().filter(|s| !s.is_empty()).cloned().collect();
+        let keep = sequences.iter().map(|s| s.len()).max().unwrap_or(1) - 1;
+
+        Self {
+            sequences,
+            window: String::new(),
+            consumed: 0,
+            keep,
+        }
+    }
+
+    /// Feed the next fragment of text
+    ///
+    /// Returns the byte offset in the whole stream where the earliest stop
+    /// sequence begins, once one has been seen.
+    pub fn push(&mut self, text: &str) -> Option<usize> {
+        if self.sequences.is_empty() {
+            return None;
+        }
+
+        self.window.push_str(text);
+
+        let found = self
+            .sequences
+            .iter()
+            .filter_map(|seq| self.window.find(seq.as_str()))
+            .min();
+        if let Some(index) = found {
+            return Some(self.consumed + index);
+        }
+
+        // Keep only the tail that could begin a stop sequence
+        if self.window.len() > self.keep {
+            let mut cut = self.window.len() - self.keep;
+            while !self.window.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            self.window.drain(..cut);
+            self.consumed += cut;
+        }
+
+        None
+    }
+
+    /// Length of the longest suffix of the text seen so far that could
+    /// still grow into a stop sequence
+    ///
+    /// Streamed text should hold this many trailing bytes back, since they
+    /// are cut from the output if the stop sequence completes.
+    pub fn partial_match_len(&self) -> usize {
+        self.sequences
+            .iter()
+            .flat_map(|seq| {
+                (1..seq.len())
+                    .rev()
+                    .filter(|&len| seq.is_char_boundary(len))
+                    .find(|&len| self.window.ends_with(&seq[..len]))
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::EchoBackend;
+
+    fn stream_text(backend: &dyn InferenceBackend, tokens: &[u32]) -> String {
+        let mut stream = TokenStream::new();
+        let mut text = String::new();
+
+        for &token in tokens {
+            if let Some(fragment) = stream.next_token(backend, token).unwrap() {
+                text.push_str(&fragment);
+            }
+        }
+        if let Some(rest) = stream.finish(backend).unwrap() {
+            text.push_str(&rest);
+        }
+
+        text
+    }
+
+    #[test]
+    fn test_stream_matches_full_decode() {
+        let backend = EchoBackend::new();
+        let tokens = backend.encode("Recursion needs a base case.", false).unwrap();
+
+        assert_eq!(stream_text(&backend, &tokens), "Recursion needs a base case.");
+    }
+
+    #[test]
+    fn test_stream_holds_back_partial_characters() {
+        let backend = EchoBackend::new();
+        let tokens = backend.encode("naïve café", false).unwrap();
+
+        let mut stream = TokenStream::new();
+        let fragments: Vec<String> = tokens
+            .iter()
+            .filter_map(|&t| stream.next_token(&backend, t).unwrap())
+            .collect();
+
+        assert!(fragments.iter().all(|f| !f.contains(char::REPLACEMENT_CHARACTER)));
+        assert_eq!(fragments.concat(), "naïve café");
+    }
+
+    #[test]
+    fn test_stop_sequence_within_fragment() {
+        let mut matcher = StopSequenceMatcher::new(&["STOP".to_string()]);
+
+        assert_eq!(matcher.push("Good work. "), None);
+        assert_eq!(matcher.push("STOP here"), Some(11));
+    }
+
+    #[test]
+    fn test_stop_sequence_across_fragments() {
+        let mut matcher = StopSequenceMatcher::new(&["\n\n###".to_string()]);
+
+        let mut found = None;
+        for fragment in ["Well argued", ".\n", "\n#", "##", " Next"] {
+            found = matcher.push(fragment);
+            if found.is_some() {
+                break;
+            }
+        }
+
+        assert_eq!(found, Some("Well argued.".len()));
+    }
+
+    #[test]
+    fn test_earliest_stop_sequence_wins() {
+        let mut matcher = StopSequenceMatcher::new(&["END".to_string(), "--".to_string()]);
+
+        assert_eq!(matcher.push("ab--cdEND"), Some(2));
+    }
+
+    #[test]
+    fn test_partial_match_len() {
+        let mut matcher = StopSequenceMatcher::new(&["\n###".to_string()]);
+
+        matcher.push("Well argued.\n#");
+        assert_eq!(matcher.partial_match_len(), 2);
+
+        matcher.push(" then more");
+        assert_eq!(matcher.partial_match_len(), 0);
+    }
+
+    #[test]
+    fn test_no_stop_sequences() {
+        let mut matcher = StopSequenceMatcher::new(&[]);
+
+        assert_eq!(matcher.push("anything"), None);
+    }
+}
@@ -0,0 +1,308 @@
+//! Deadlines and cancellation for running requests
+//!
+//! Each request may run for a limited wall-clock time and generate a
+//! limited number of tokens, counted across every section of a split
+//! answer. The limits come from the request's `params.timeout_ms` and
+//! `params.token_limit`, capped by the jail's own `REQUEST_TIMEOUT_SECS`
+//! and `REQUEST_TOKEN_LIMIT`. The orchestrator can also cancel a request
+//! it no longer wants.
+//!
+//! Limits are checked between decoding steps, so a request stops within
+//! one forward pass of running out. A stopped request is answered with an
+//! `Error` of kind `timeout` or `cancelled`, so every request still gets
+//! exactly one final reply.
+
+use anyhow::{Context, Result};
+use aws_ipc_protocol::ErrorKind;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::protocol::InferenceRequest;
+
+/// Wall-clock time a request may take when `REQUEST_TIMEOUT_SECS` is unset
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Why a request was stopped before it finished
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Interrupted {
+    #[error("Request timed out after {0} ms")]
+    TimedOut(u64),
+    #[error("Request reached its limit of {0} generated tokens")]
+    TokenLimit(usize),
+    #[error("Request cancelled")]
+    Cancelled,
+}
+
+impl Interrupted {
+    /// Error kind the request is answered with
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Interrupted::TimedOut(_) | Interrupted::TokenLimit(_) => ErrorKind::Timeout,
+            Interrupted::Cancelled => ErrorKind::Cancelled,
+        }
+    }
+}
+
+/// Error kind to report `error` as: `timeout` or `cancelled` if it
+/// interrupted a request, `default` otherwise
+pub fn error_kind(error: &anyhow::Error, default: ErrorKind) -> ErrorKind {
+    error
+        .downcast_ref::<Interrupted>()
+        .map_or(default, Interrupted::kind)
+}
+
+/// Set when the orchestrator cancels a request; shared by every copy
+#[derive(Debug, Clone, Default)]
+pub struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The jail's caps on every request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    pub token_limit: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            timeout: Some(DEFAULT_REQUEST_TIMEOUT),
+            token_limit: None,
+        }
+    }
+}
+
+impl Limits {
+    /// Limits from `REQUEST_TIMEOUT_SECS` and `REQUEST_TOKEN_LIMIT`; a
+    /// value of 0 lifts the limit
+    pub fn from_env() -> Result<Self> {
+        let mut limits = Self::default();
+
+        if let Ok(value) = std::env::var("REQUEST_TIMEOUT_SECS") {
+            let secs: u64 = value
+                .parse()
+                .with_context(|| format!("Invalid REQUEST_TIMEOUT_SECS '{}'", value))?;
+            limits.timeout = (secs > 0).then(|| Duration::from_secs(secs));
+        }
+
+        if let Ok(value) = std::env::var("REQUEST_TOKEN_LIMIT") {
+            let tokens: usize = value
+                .parse()
+                .with_context(|| format!("Invalid REQUEST_TOKEN_LIMIT '{}'", value))?;
+            limits.token_limit = (tokens > 0).then_some(tokens);
+        }
+
+        Ok(limits)
+    }
+
+    /// Lower the request's own limits to the jail's
+    pub fn apply(&self, request: &mut InferenceRequest) {
+        if let Some(timeout) = self.timeout {
+            let ms = timeout.as_millis() as u64;
+            request.timeout_ms = Some(request.timeout_ms.map_or(ms, |own| own.min(ms)));
+        }
+        if let Some(tokens) = self.token_limit {
+            request.token_limit = Some(request.token_limit.map_or(tokens, |own| own.min(tokens)));
+        }
+    }
+}
+
+/// Limits and cancel flags for the requests a jail is serving
+///
+/// Requests are registered as they are read, so a `Cancel` that arrives
+/// while a request is still queued stops it before it starts.
+#[derive(Debug, Clone, Default)]
+pub struct Watchdog {
+    limits: Limits,
+    pending: Arc<Mutex<HashMap<String, CancelFlag>>>,
+}
+
+impl Watchdog {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            pending: Arc::default(),
+        }
+    }
+
+    /// Start tracking a request the jail has received
+    pub fn register(&self, request_id: &str) {
+        self.lock().entry(request_id.to_string()).or_default();
+    }
+
+    /// Cancel a request, returning whether it was still waiting for its
+    /// reply
+    pub fn cancel(&self, request_id: &str) -> bool {
+        match self.lock().get(request_id) {
+            Some(flag) => {
+                flag.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Apply the jail's limits to a request and attach its cancel flag
+    pub fn prepare(&self, request_id: &str, request: &mut InferenceRequest) {
+        self.limits.apply(request);
+        request.cancel = self.lock().entry(request_id.to_string()).or_default().clone();
+    }
+
+    /// Stop tracking a request once it has been answered
+    pub fn finish(&self, request_id: &str) {
+        self.lock().remove(request_id);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, CancelFlag>> {
+        // The map stays consistent even if a holder panicked
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A request's limits while it is being decoded
+#[derive(Debug, Default)]
+pub struct Deadline {
+    expires: Option<Instant>,
+    timeout_ms: u64,
+    token_limit: Option<usize>,
+    /// Tokens generated so far, over every section of the request
+    tokens: Cell<usize>,
+    cancel: CancelFlag,
+}
+
+impl Deadline {
+    /// Start the clock on a request
+    pub fn start(request: &InferenceRequest) -> Self {
+        let timeout_ms = request.timeout_ms.unwrap_or(0);
+
+        Self {
+            expires: request
+                .timeout_ms
+                .map(|ms| Instant::now() + Duration::from_millis(ms)),
+            timeout_ms,
+            token_limit: request.token_limit,
+            tokens: Cell::new(0),
+            cancel: request.cancel.clone(),
+        }
+    }
+
+    /// Why the request must stop before its next token, if it must
+    pub fn check(&self) -> Option<Interrupted> {
+        if self.cancel.is_cancelled() {
+            return Some(Interrupted::Cancelled);
+        }
+        if self.expires.is_some_and(|expires| Instant::now() >= expires) {
+            return Some(Interrupted::TimedOut(self.timeout_ms));
+        }
+        match self.token_limit {
+            Some(limit) if self.tokens.get() >= limit => Some(Interrupted::TokenLimit(limit)),
+            _ => None,
+        }
+    }
+
+    /// Count a token generated for the request
+    pub fn count_token(&self) {
+        self.tokens.set(self.tokens.get() + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> InferenceRequest {
+        InferenceRequest::from_feedback(
+            "Answer".to_string(),
+            "Rubric".to_string(),
+            vec![],
+            1,
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    #[test]
+    fn test_token_limit() {
+        let mut request = request();
+        request.token_limit = Some(2);
+        let deadline = Deadline::start(&request);
+
+        deadline.count_token();
+        assert_eq!(deadline.check(), None);
+        deadline.count_token();
+        assert_eq!(deadline.check(), Some(Interrupted::TokenLimit(2)));
+    }
+
+    #[test]
+    fn test_wall_clock_deadline() {
+        let mut request = request();
+        request.timeout_ms = Some(0);
+
+        assert_eq!(Deadline::start(&request).check(), Some(Interrupted::TimedOut(0)));
+
+        request.timeout_ms = Some(60_000);
+        assert_eq!(Deadline::start(&request).check(), None);
+    }
+
+    #[test]
+    fn test_cancel_reaches_running_request() {
+        let watchdog = Watchdog::default();
+        watchdog.register("r1");
+
+        let mut request = request();
+        watchdog.prepare("r1", &mut request);
+        let deadline = Deadline::start(&request);
+        assert_eq!(deadline.check(), None);
+
+        assert!(watchdog.cancel("r1"));
+        assert_eq!(deadline.check(), Some(Interrupted::Cancelled));
+
+        // Answered requests can no longer be cancelled
+        watchdog.finish("r1");
+        assert!(!watchdog.cancel("r1"));
+    }
+
+    #[test]
+    fn test_jail_limits_cap_request() {
+        let limits = Limits {
+            timeout: Some(Duration::from_secs(10)),
+            token_limit: Some(100),
+        };
+
+        let mut request = request();
+        limits.apply(&mut request);
+        assert_eq!(request.timeout_ms, Some(10_000));
+        assert_eq!(request.token_limit, Some(100));
+
+        let mut request = self::request();
+        request.timeout_ms = Some(500);
+        request.token_limit = Some(1000);
+        limits.apply(&mut request);
+        assert_eq!(request.timeout_ms, Some(500));
+        assert_eq!(request.token_limit, Some(100));
+    }
+
+    #[test]
+    fn test_error_kind() {
+        let timed_out = anyhow::Error::new(Interrupted::TimedOut(5)).context("Section 2");
+        assert_eq!(error_kind(&timed_out, ErrorKind::ProcessingError), ErrorKind::Timeout);
+
+        let cancelled = anyhow::Error::new(Interrupted::Cancelled);
+        assert_eq!(error_kind(&cancelled, ErrorKind::ProcessingError), ErrorKind::Cancelled);
+
+        let other = anyhow::anyhow!("Backend failed");
+        assert_eq!(error_kind(&other, ErrorKind::ProcessingError), ErrorKind::ProcessingError);
+    }
+}
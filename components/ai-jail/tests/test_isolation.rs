@@ -19,11 +19,11 @@ fn test_binary_exists() {
 }
 
 #[test]
-#[ignore] // Requires model files to be present
 fn test_stdin_stdout_protocol() {
-    // Test basic stdin/stdout communication
-    let mut child = Command::new("cargo")
-        .args(&["run", "--bin", "ai-jail"])
+    // Test basic stdin/stdout communication using the scripted backend,
+    // which needs no model files
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ai-jail"))
+        .args(&["--backend", "scripted"])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -34,20 +34,37 @@ fn test_stdin_stdout_protocol() {
 
     // Send a test request
     let request = json!({
-        "tma_content": "Discuss the impact of climate change on biodiversity.",
-        "rubric": "Award 10 marks for comprehensive discussion covering at least 3 ecosystems.",
-        "question_number": 1,
-        "student_answer": "Climate change affects many animals and plants.",
-        "max_tokens": 100,
-        "temperature": 0.7,
-        "top_p": 0.9
+        "type": "FeedbackRequest",
+        "payload": {
+            "request_id": "isolation-1",
+            "content": "Climate change affects many animals and plants.",
+            "rubric": "Award 10 marks for comprehensive discussion covering at least 3 ecosystems.",
+            "criteria": [],
+            "question_number": 1,
+            "params": {
+                "max_tokens": 100,
+                "temperature": 0.7,
+                "top_p": 0.9
+            }
+        }
     });
 
     let request_json = serde_json::to_string(&request).unwrap();
     writeln!(stdin, "{}", request_json).expect("Failed to write to stdin");
 
-    // Note: In a real test, we would read from stdout and verify the response
-    // This is a basic structure test
+    // Closing stdin makes the jail exit after answering
+    drop(child.stdin.take());
+    let output = child.wait_with_output().expect("Failed to wait for process");
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let response: serde_json::Value =
+        serde_json::from_str(stdout.lines().next().expect("No response"))
+            .expect("Response is not JSON");
+
+    assert_eq!(response["type"], "FeedbackResponse");
+    assert_eq!(response["payload"]["request_id"], "isolation-1");
+    assert!(!response["payload"]["feedback"].as_str().unwrap().is_empty());
 }
 
 #[test]
@@ -93,7 +110,7 @@ fn test_container_network_isolation() {
             "ai-jail-test:latest",
             "-f",
             "Containerfile",
-            ".",
+            "..",
         ])
         .output();
 
@@ -195,39 +212,34 @@ fn test_request_validation() {
     // Test protocol request validation
     use serde_json::json;
 
+    let feedback_request = |content: &str, rubric: &str, params: serde_json::Value| {
+        json!({
+            "type": "FeedbackRequest",
+            "payload": {
+                "request_id": "validation",
+                "content": content,
+                "rubric": rubric,
+                "criteria": [],
+                "params": params
+            }
+        })
+    };
+
     let test_cases = vec![
         (
-            json!({
-                "tma_content": "",
-                "rubric": "Test rubric",
-                "question_number": 1
-            }),
+            feedback_request("", "Test rubric", json!({})),
             false, // Should fail - empty TMA content
         ),
         (
-            json!({
-                "tma_content": "Test content",
-                "rubric": "",
-                "question_number": 1
-            }),
+            feedback_request("Test content", "", json!({})),
             false, // Should fail - empty rubric
         ),
         (
-            json!({
-                "tma_content": "Test content",
-                "rubric": "Test rubric",
-                "question_number": 1,
-                "temperature": 5.0
-            }),
+            feedback_request("Test content", "Test rubric", json!({"temperature": 5.0})),
             false, // Should fail - invalid temperature
         ),
         (
-            json!({
-                "tma_content": "Test content",
-                "rubric": "Test rubric",
-                "question_number": 1,
-                "max_tokens": 0
-            }),
+            feedback_request("Test content", "Test rubric", json!({"max_tokens": 0})),
             false, // Should fail - invalid max_tokens
         ),
     ];
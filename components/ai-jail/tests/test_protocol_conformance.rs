@@ -0,0 +1,579 @@
+//! Protocol conformance tests
+//!
+//! These tests spawn the jail binary with the scripted backend and drive it
+//! with the shared `aws-ipc-protocol` messages, checking that every reply is
+//! a well-formed protocol message the orchestrator can decode.
+
+use aws_ipc_protocol::{
+    ErrorKind, FrameCodec, FrameDecoder, Framing, GenerationParams, IPCMessage, PromptOptions,
+    RubricCriterion, PROTOCOL_VERSION,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// A running jail process with line-oriented access to its pipes
+struct Jail {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Jail {
+    fn spawn() -> Self {
+        Self::spawn_with_env(&[])
+    }
+
+    fn spawn_with_env(vars: &[(&str, &str)]) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ai-jail"))
+            .env("AI_JAIL_BACKEND", "scripted")
+            .envs(vars.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to spawn ai-jail");
+
+        let stdin = child.stdin.take().expect("Failed to open stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("Failed to open stdout"));
+
+        Self { child, stdin, stdout }
+    }
+
+    fn send_line(&mut self, line: &str) {
+        writeln!(self.stdin, "{}", line).expect("Failed to write to stdin");
+        self.stdin.flush().expect("Failed to flush stdin");
+    }
+
+    fn send(&mut self, message: &IPCMessage) {
+        let json = serde_json::to_string(message).unwrap();
+        self.send_line(&json);
+    }
+
+    fn receive(&mut self) -> IPCMessage {
+        let mut line = String::new();
+        let read = self.stdout.read_line(&mut line).expect("Failed to read stdout");
+        assert!(read > 0, "ai-jail closed stdout unexpectedly");
+
+        serde_json::from_str(&line).expect("Reply is not a protocol message")
+    }
+}
+
+fn feedback_request(request_id: &str) -> IPCMessage {
+    IPCMessage::FeedbackRequest {
+        request_id: request_id.to_string(),
+        content: "Water evaporates from oceans, forms clouds, and falls as rain.".to_string(),
+        rubric: "1. Evaporation\n2. Condensation\n3. Precipitation".to_string(),
+        criteria: vec![RubricCriterion {
+            number: 1,
+            description: "1. Evaporation".to_string(),
+            max_marks: Some(2.0),
+        }],
+        question_number: 1,
+        params: GenerationParams::default(),
+        prompt: PromptOptions::default(),
+    }
+}
+
+#[test]
+fn test_ping_pong() {
+    let mut jail = Jail::spawn();
+
+    jail.send(&IPCMessage::Ping { timestamp: 1234 });
+
+    match jail.receive() {
+        IPCMessage::Pong { timestamp } => assert_eq!(timestamp, 1234),
+        other => panic!("Expected Pong, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_hello_advertises_capabilities() {
+    let mut jail = Jail::spawn();
+
+    jail.send(&IPCMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Default::default(),
+    });
+
+    match jail.receive() {
+        IPCMessage::Hello {
+            protocol_version,
+            capabilities,
+        } => {
+            assert_eq!(protocol_version, PROTOCOL_VERSION);
+            assert!(capabilities.streaming);
+            assert!(capabilities.grammar);
+            assert!(capabilities.max_batch_size >= 1);
+            assert_eq!(capabilities.models, vec!["scripted"]);
+        }
+        other => panic!("Expected Hello, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_feedback_roundtrip() {
+    let mut jail = Jail::spawn();
+
+    jail.send(&feedback_request("req-1"));
+
+    match jail.receive() {
+        IPCMessage::FeedbackResponse {
+            request_id,
+            feedback,
+            metadata,
+            ..
+        } => {
+            assert_eq!(request_id, "req-1");
+            assert!(!feedback.is_empty());
+            assert!(metadata.tokens_generated > 0);
+            assert_eq!(metadata.model.id, "scripted");
+        }
+        other => panic!("Expected FeedbackResponse, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_requests_routed_to_named_model() {
+    let dir = tempfile::tempdir().unwrap();
+    let registry = dir.path().join("models.json");
+    std::fs::write(
+        &registry,
+        r#"{"default": "formative", "models": [
+            {"id": "formative", "revision": "small-1",
+             "model_path": "s.gguf", "tokenizer_path": "t"},
+            {"id": "summative", "revision": "large-3",
+             "model_path": "l.gguf", "tokenizer_path": "t"}
+        ]}"#,
+    )
+    .unwrap();
+    let mut jail = Jail::spawn_with_env(&[("MODEL_REGISTRY", registry.to_str().unwrap())]);
+
+    let models = [None, Some("summative"), Some("marking")];
+    for (i, model) in models.iter().enumerate() {
+        let mut request = feedback_request(&format!("req-{}", i));
+        if let IPCMessage::FeedbackRequest { params, .. } = &mut request {
+            params.model = model.map(str::to_string);
+        }
+        jail.send(&request);
+    }
+
+    let mut served = Vec::new();
+    for _ in 0..models.len() {
+        match jail.receive() {
+            IPCMessage::FeedbackResponse { metadata, .. } => {
+                assert_eq!(metadata.params.model.as_ref(), Some(&metadata.model.id));
+                served.push(format!("{}@{}", metadata.model.id, metadata.model.revision));
+            }
+            IPCMessage::Error { request_id, kind, message } => {
+                assert_eq!(request_id.as_deref(), Some("req-2"));
+                assert_eq!(kind, ErrorKind::InvalidRequest);
+                assert!(message.contains("Unknown model 'marking'"));
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    assert_eq!(served, vec!["formative@small-1", "summative@large-3"]);
+}
+
+#[test]
+fn test_model_files_verified_against_manifest() {
+    use academic_shared::crypto::{ed25519_keypair, ed25519_sign};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    std::fs::write(path("model.gguf"), b"weights").unwrap();
+    std::fs::write(path("tokenizer.json"), b"{}").unwrap();
+
+    let hash = blake3::hash(b"weights").to_hex().to_string();
+    let manifest = format!(
+        r#"{{"files": {{"model.gguf": "{}", "tokenizer.json": "{}"}}}}"#,
+        hash,
+        blake3::hash(b"{}").to_hex()
+    );
+    let (public_key, secret_key) = ed25519_keypair();
+    std::fs::write(path("manifest.json"), &manifest).unwrap();
+    std::fs::write(
+        path("manifest.json.sig"),
+        hex::encode(ed25519_sign(manifest.as_bytes(), &secret_key).unwrap()),
+    )
+    .unwrap();
+    std::fs::write(path("key.pub"), hex::encode(public_key)).unwrap();
+
+    let env = [
+        ("MODEL_PATH", path("model.gguf")),
+        ("TOKENIZER_PATH", path("tokenizer.json")),
+        ("MODEL_MANIFEST", path("manifest.json")),
+        ("MODEL_MANIFEST_KEY_FILE", path("key.pub")),
+    ];
+    let env: Vec<(&str, &str)> = env.iter().map(|(k, v)| (*k, v.as_str())).collect();
+
+    let mut jail = Jail::spawn_with_env(&env);
+    jail.send(&feedback_request("req-verified"));
+    match jail.receive() {
+        IPCMessage::FeedbackResponse { metadata, .. } => assert_eq!(metadata.model.hash, hash),
+        other => panic!("Expected FeedbackResponse, got {:?}", other),
+    }
+
+    // Swapped weights stop the jail before it serves anything
+    std::fs::write(path("model.gguf"), b"weightz").unwrap();
+    let mut jail = Jail::spawn_with_env(&env);
+    match jail.receive() {
+        IPCMessage::Error { kind, message, .. } => {
+            assert_eq!(kind, ErrorKind::InitializationError);
+            assert!(message.contains("Hash mismatch"));
+        }
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert!(!jail.child.wait().unwrap().success());
+}
+
+#[test]
+fn test_unverified_model_refused_without_opt_out() {
+    let spawn = |vars: &[(&str, &str)]| {
+        Command::new(env!("CARGO_BIN_EXE_ai-jail"))
+            .env("AI_JAIL_BACKEND", "candle")
+            .env("MODEL_PATH", "/nonexistent/model.gguf")
+            .env_remove("MODEL_MANIFEST_KEY_FILE")
+            .env_remove("ALLOW_UNVERIFIED_MODELS")
+            .envs(vars.iter().copied())
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .expect("Failed to run ai-jail")
+    };
+    let first_error = |stdout: &[u8]| -> (ErrorKind, String) {
+        let line = stdout.split(|&b| b == b'\n').next().unwrap();
+        match serde_json::from_slice(line).expect("Reply is not a protocol message") {
+            IPCMessage::Error { kind, message, .. } => (kind, message),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    };
+
+    let output = spawn(&[]);
+    assert!(!output.status.success());
+    let (kind, message) = first_error(&output.stdout);
+    assert_eq!(kind, ErrorKind::InitializationError);
+    assert!(message.contains("MODEL_MANIFEST_KEY_FILE is not set"));
+
+    // With the opt-out it gets as far as loading the (missing) weights
+    let output = spawn(&[("ALLOW_UNVERIFIED_MODELS", "1")]);
+    assert!(!output.status.success());
+    let (_, message) = first_error(&output.stdout);
+    assert!(!message.contains("MODEL_MANIFEST_KEY_FILE"));
+}
+
+#[test]
+fn test_streamed_chunks_precede_response() {
+    let mut jail = Jail::spawn();
+
+    let mut request = feedback_request("req-stream");
+    if let IPCMessage::FeedbackRequest { params, .. } = &mut request {
+        params.stream = true;
+    }
+    jail.send(&request);
+
+    let mut streamed = String::new();
+    let mut last_count = 0;
+    loop {
+        match jail.receive() {
+            IPCMessage::FeedbackChunk {
+                request_id,
+                text,
+                tokens_generated,
+            } => {
+                assert_eq!(request_id, "req-stream");
+                assert!(tokens_generated >= last_count);
+                last_count = tokens_generated;
+                streamed.push_str(&text);
+            }
+            IPCMessage::FeedbackResponse {
+                request_id,
+                feedback,
+                metadata,
+                ..
+            } => {
+                assert_eq!(request_id, "req-stream");
+                assert_eq!(streamed.trim(), feedback);
+                assert_eq!(last_count, metadata.tokens_generated);
+                break;
+            }
+            other => panic!("Expected FeedbackChunk or FeedbackResponse, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_seed_recorded_in_response() {
+    let mut jail = Jail::spawn();
+
+    let mut request = feedback_request("req-seeded");
+    if let IPCMessage::FeedbackRequest { params, .. } = &mut request {
+        params.seed = Some(1234);
+    }
+    jail.send(&request);
+    jail.send(&feedback_request("req-unseeded"));
+
+    for expected in [Some(1234), None] {
+        match jail.receive() {
+            IPCMessage::FeedbackResponse { metadata, .. } => match expected {
+                Some(seed) => assert_eq!(metadata.params.seed, Some(seed)),
+                None => assert!(metadata.params.seed.is_some()),
+            },
+            other => panic!("Expected FeedbackResponse, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_no_chunks_unless_requested() {
+    let mut jail = Jail::spawn();
+
+    jail.send(&feedback_request("req-quiet"));
+
+    assert!(matches!(jail.receive(), IPCMessage::FeedbackResponse { .. }));
+}
+
+#[test]
+fn test_requests_answered_in_order() {
+    let mut jail = Jail::spawn();
+
+    jail.send(&feedback_request("req-a"));
+    jail.send(&IPCMessage::Ping { timestamp: 7 });
+    jail.send(&feedback_request("req-b"));
+
+    assert_eq!(jail.receive().request_id(), Some("req-a"));
+    assert!(matches!(jail.receive(), IPCMessage::Pong { timestamp: 7 }));
+    assert_eq!(jail.receive().request_id(), Some("req-b"));
+}
+
+#[test]
+fn test_pipelined_requests_each_answered() {
+    let mut jail = Jail::spawn();
+
+    // Requests queued while the jail is busy are decoded as a batch
+    let ids: Vec<String> = (0..5).map(|i| format!("req-{}", i)).collect();
+    for id in &ids {
+        jail.send(&feedback_request(id));
+    }
+
+    for id in &ids {
+        match jail.receive() {
+            IPCMessage::FeedbackResponse {
+                request_id,
+                feedback,
+                ..
+            } => {
+                assert_eq!(&request_id, id);
+                assert!(!feedback.is_empty());
+            }
+            other => panic!("Expected FeedbackResponse, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_long_answer_reports_sections() {
+    let mut jail = Jail::spawn_with_env(&[("CONTEXT_LENGTH", "2048")]);
+
+    let mut request = feedback_request("long");
+    if let IPCMessage::FeedbackRequest { content, .. } = &mut request {
+        *content = "Evaporation turns sea water into vapour. ".repeat(60);
+    }
+    jail.send(&request);
+
+    match jail.receive() {
+        IPCMessage::FeedbackResponse { request_id, feedback, metadata, .. } => {
+            assert_eq!(request_id, "long");
+            assert!(metadata.context.sections > 1);
+            assert!(!metadata.context.truncated);
+            assert_eq!(metadata.context.context_length, 2048);
+            assert!(feedback.starts_with("Part 1 of"));
+        }
+        other => panic!("Expected FeedbackResponse, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_request_past_deadline_times_out() {
+    let mut jail = Jail::spawn_with_env(&[("AI_JAIL_MOCK_TOKEN_DELAY_MS", "20")]);
+
+    let mut request = feedback_request("req-slow");
+    if let IPCMessage::FeedbackRequest { params, .. } = &mut request {
+        params.timeout_ms = Some(200);
+    }
+    jail.send(&request);
+    let mut request = feedback_request("req-long");
+    if let IPCMessage::FeedbackRequest { params, .. } = &mut request {
+        params.token_limit = Some(5);
+    }
+    jail.send(&request);
+
+    for expected in ["req-slow", "req-long"] {
+        match jail.receive() {
+            IPCMessage::Error { request_id, kind, .. } => {
+                assert_eq!(request_id.as_deref(), Some(expected));
+                assert_eq!(kind, ErrorKind::Timeout);
+            }
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_cancelled_request_answered_once() {
+    let mut jail = Jail::spawn_with_env(&[("AI_JAIL_MOCK_TOKEN_DELAY_MS", "20")]);
+
+    jail.send(&feedback_request("req-running"));
+    jail.send(&IPCMessage::Cancel { request_id: "req-running".to_string() });
+    jail.send(&IPCMessage::Ping { timestamp: 9 });
+
+    match jail.receive() {
+        IPCMessage::Error { request_id, kind, .. } => {
+            assert_eq!(request_id.as_deref(), Some("req-running"));
+            assert_eq!(kind, ErrorKind::Cancelled);
+        }
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // Cancelling an answered request sends nothing, so the next reply
+    // is the pong
+    jail.send(&IPCMessage::Cancel { request_id: "req-running".to_string() });
+    assert!(matches!(jail.receive(), IPCMessage::Pong { timestamp: 9 }));
+}
+
+#[test]
+fn test_invalid_request_reports_request_id() {
+    let mut jail = Jail::spawn();
+
+    let mut request = feedback_request("req-bad");
+    if let IPCMessage::FeedbackRequest { params, .. } = &mut request {
+        params.temperature = 5.0;
+    }
+    jail.send(&request);
+
+    match jail.receive() {
+        IPCMessage::Error {
+            request_id, kind, ..
+        } => {
+            assert_eq!(request_id.as_deref(), Some("req-bad"));
+            assert_eq!(kind, ErrorKind::InvalidRequest);
+        }
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_unknown_template_is_invalid_request() {
+    let mut jail = Jail::spawn();
+
+    let mut request = feedback_request("req-template");
+    if let IPCMessage::FeedbackRequest { prompt, .. } = &mut request {
+        prompt.template = Some("no-such-template".to_string());
+    }
+    jail.send(&request);
+
+    match jail.receive() {
+        IPCMessage::Error {
+            request_id,
+            kind,
+            message,
+        } => {
+            assert_eq!(request_id.as_deref(), Some("req-template"));
+            assert_eq!(kind, ErrorKind::InvalidRequest);
+            assert!(message.contains("no-such-template"));
+        }
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_malformed_json_yields_error() {
+    let mut jail = Jail::spawn();
+
+    jail.send_line("{invalid json}");
+
+    match jail.receive() {
+        IPCMessage::Error { kind, .. } => assert_eq!(kind, ErrorKind::InvalidRequest),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // The jail keeps serving after a bad line
+    jail.send(&IPCMessage::Ping { timestamp: 1 });
+    assert!(matches!(jail.receive(), IPCMessage::Pong { .. }));
+}
+
+#[test]
+fn test_unexpected_message_yields_error() {
+    let mut jail = Jail::spawn();
+
+    jail.send(&IPCMessage::Pong { timestamp: 1 });
+
+    match jail.receive() {
+        IPCMessage::Error { kind, .. } => assert_eq!(kind, ErrorKind::UnexpectedMessage),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_shutdown_exits_cleanly() {
+    let mut jail = Jail::spawn();
+
+    jail.send(&IPCMessage::Shutdown);
+
+    let status = jail.child.wait().expect("Failed to wait for ai-jail");
+    assert!(status.success());
+}
+
+#[test]
+fn test_length_prefixed_framing_after_handshake() {
+    let mut jail = Jail::spawn_with_env(&[("IPC_MAX_FRAME_SIZE", "4096")]);
+
+    // The jail never agrees to frames larger than its own limit
+    jail.send(&IPCMessage::SetFraming {
+        framing: Framing::LengthPrefixed,
+        max_frame_size: 1 << 20,
+    });
+    match jail.receive() {
+        IPCMessage::FramingSet {
+            framing,
+            max_frame_size,
+        } => {
+            assert_eq!(framing, Framing::LengthPrefixed);
+            assert_eq!(max_frame_size, 4096);
+        }
+        other => panic!("Expected FramingSet, got {:?}", other),
+    }
+
+    let codec = FrameCodec::new(Framing::LengthPrefixed, 4096);
+    let send = |jail: &mut Jail, payload: &[u8]| {
+        let frame = FrameCodec::new(Framing::LengthPrefixed, usize::MAX).encode(payload).unwrap();
+        jail.stdin.write_all(&frame).unwrap();
+        jail.stdin.flush().unwrap();
+    };
+    let receive = |jail: &mut Jail| {
+        let mut decoder = FrameDecoder::new(codec);
+        loop {
+            let input = jail.stdout.fill_buf().unwrap();
+            assert!(!input.is_empty(), "ai-jail closed stdout unexpectedly");
+            let (consumed, frame) = decoder.decode(input);
+            jail.stdout.consume(consumed);
+            if let Some(frame) = frame {
+                return serde_json::from_slice::<IPCMessage>(&frame.unwrap()).unwrap();
+            }
+        }
+    };
+
+    // An oversized frame is skipped and reported, and the stream stays usable
+    send(&mut jail, &vec![b' '; 8192]);
+    match receive(&mut jail) {
+        IPCMessage::Error { kind, message, .. } => {
+            assert_eq!(kind, ErrorKind::InvalidRequest);
+            assert!(message.contains("exceeds"));
+        }
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    send(&mut jail, br#"{"type":"Ping","payload":{"timestamp":11}}"#);
+    assert!(matches!(receive(&mut jail), IPCMessage::Pong { timestamp: 11 }));
+}
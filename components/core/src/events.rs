@@ -4,12 +4,36 @@
 //! All state changes are persisted as events in LMDB for complete audit trail.
 
 use anyhow::{Context, Result};
-use chrono::{DateTime, Utc};
-use heed::{Database, Env, EnvOpenOptions};
+use chrono::{DateTime, SecondsFormat, Utc};
+use heed::byteorder::BigEndian;
+use heed::{Database, Env, EnvOpenOptions, RoTxn, RwTxn};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ops::Bound;
 use std::path::Path;
+use thiserror::Error;
+use tokio::sync::broadcast;
 use uuid::Uuid;
 
+/// Errors from appending to an event store
+#[derive(Error, Debug, PartialEq)]
+pub enum EventStoreError {
+    #[error("Version conflict on {aggregate_id}: expected version {expected}, found {actual}")]
+    VersionConflict {
+        aggregate_id: String,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("Event {event_id} does not follow version {expected} of {aggregate_id}")]
+    OutOfSequence {
+        event_id: Uuid,
+        aggregate_id: String,
+        expected: u64,
+    },
+}
+
 /// Event types in the system
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "type", content = "data")]
@@ -39,6 +63,29 @@ pub enum EventType {
         anonymized_id: String,
         timestamp: DateTime<Utc>,
     },
+    /// Anonymized TMA has been sent for AI feedback
+    FeedbackRequested {
+        tma_id: Uuid,
+    },
+    /// TMA processing has failed
+    TMAFailed {
+        tma_id: Uuid,
+        reason: String,
+    },
+}
+
+impl EventType {
+    /// Name of the variant, as accepted by [`EventStore::get_events_by_type`]
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventType::TMASubmitted { .. } => "TMASubmitted",
+            EventType::FeedbackGenerated { .. } => "FeedbackGenerated",
+            EventType::GradeAssigned { .. } => "GradeAssigned",
+            EventType::StudentAnonymized { .. } => "StudentAnonymized",
+            EventType::FeedbackRequested { .. } => "FeedbackRequested",
+            EventType::TMAFailed { .. } => "TMAFailed",
+        }
+    }
 }
 
 /// Rubric scoring component
@@ -63,6 +110,10 @@ pub struct Event {
     pub aggregate_id: String,
     /// Event version for ordering
     pub version: u64,
+    /// Position in the global log, assigned by the store on append
+    /// (0 until then)
+    #[serde(default)]
+    pub position: u64,
 }
 
 impl Event {
@@ -74,31 +125,109 @@ pub fn new(event_type: EventType, aggregate_id: String, version: u64) -> Self {
             event_type,
             aggregate_id,
             version,
+            position: 0,
         }
     }
 }
 
+/// Saved state of an aggregate as of one of its versions
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Snapshot {
+    /// Aggregate the state belongs to
+    pub aggregate_id: String,
+    /// Version of the last event applied to the state
+    pub version: u64,
+    /// Serialized aggregate state
+    pub state: serde_json::Value,
+    /// When the snapshot was taken
+    pub taken_at: DateTime<Utc>,
+}
+
+/// State rebuilt by applying an aggregate's events in version order
+pub trait Aggregate: Default + Serialize + DeserializeOwned {
+    /// Fold one event into the state
+    fn apply(&mut self, event: &Event);
+}
+
 /// Trait for event storage implementations
 pub trait EventStore: Send + Sync {
     /// Append an event to the store
+    ///
+    /// The event's version is not checked; writers that may race should
+    /// use [`EventStore::append_expected`].
     fn append(&self, event: Event) -> Result<()>;
 
+    /// Append events to an aggregate whose latest version is
+    /// `expected_version` (0 for a new aggregate)
+    ///
+    /// The events must belong to `aggregate_id` and be numbered on from
+    /// `expected_version` without gaps. The check and the write are one
+    /// transaction: if another writer has appended first, nothing is
+    /// written and [`EventStoreError::VersionConflict`] is returned.
+    /// Returns the aggregate's new version.
+    fn append_expected(
+        &self,
+        aggregate_id: &str,
+        expected_version: u64,
+        events: Vec<Event>,
+    ) -> Result<u64>;
+
     /// Get all events for an aggregate
-    fn get_events(&self, aggregate_id: &str) -> Result<Vec<Event>>;
+    fn get_events(&self, aggregate_id: &str) -> Result<Vec<Event>> {
+        self.get_events_after(aggregate_id, 0)
+    }
+
+    /// Get the events of an aggregate with versions after `version`
+    fn get_events_after(&self, aggregate_id: &str, version: u64) -> Result<Vec<Event>>;
 
-    /// Get all events in the system
+    /// Get the latest version of an aggregate, or 0 if it has no events
+    fn get_version(&self, aggregate_id: &str) -> Result<u64>;
+
+    /// Get all events in the system, in global log order
     fn get_all_events(&self) -> Result<Vec<Event>>;
 
+    /// Get up to `limit` events from the global log, starting at `position`
+    fn read_from(&self, position: u64, limit: usize) -> Result<Vec<Event>>;
+
     /// Get events by type
     fn get_events_by_type(&self, event_type_name: &str) -> Result<Vec<Event>>;
+
+    /// Get events with `from <= timestamp < to`, oldest first
+    fn get_events_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Event>>;
+
+    /// Store a snapshot, replacing any earlier one for the aggregate
+    fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()>;
+
+    /// Get the latest snapshot of an aggregate
+    fn load_snapshot(&self, aggregate_id: &str) -> Result<Option<Snapshot>>;
 }
 
 /// LMDB-based event store implementation
+///
+/// Events are keyed `aggregate::version::id` with the version zero-padded,
+/// so an aggregate's events are one contiguous, ordered key range. Two
+/// secondary databases map `type::timestamp::id` and `timestamp::id` back
+/// to the event key for type and time queries, and the log maps each
+/// global position to the event key. Snapshots live in their own database,
+/// one per aggregate.
+#[derive(Clone)]
 pub struct LmdbEventStore {
     env: Env,
     db: Database<heed::types::Str, heed::types::SerdeJson<Event>>,
+    by_type: Database<heed::types::Str, heed::types::Str>,
+    by_time: Database<heed::types::Str, heed::types::Str>,
+    log: Database<heed::types::U64<BigEndian>, heed::types::Str>,
+    snapshots: Database<heed::types::Str, heed::types::SerdeJson<Snapshot>>,
+    committed: broadcast::Sender<Event>,
 }
 
+/// Committed events a subscriber may fall behind by before it has to
+/// catch up from the log
+const SUBSCRIPTION_CAPACITY: usize = 1024;
+
+/// Events read from the log per catch-up step
+const CATCH_UP_BATCH: usize = 256;
+
 impl LmdbEventStore {
     /// Create a new LMDB event store
     ///
@@ -113,7 +242,7 @@ pub fn new<P: AsRef<Path>>(path: P, max_size: Option<usize>) -> Result<Self> {
         let env = unsafe {
             EnvOpenOptions::new()
                 .map_size(max_size.unwrap_or(1024 * 1024 * 1024)) // 1GB default
-                .max_dbs(3)
+                .max_dbs(5)
                 .open(path)
                 .context("Failed to open LMDB environment")?
         };
@@ -122,97 +251,393 @@ pub fn new<P: AsRef<Path>>(path: P, max_size: Option<usize>) -> Result<Self> {
             .context("Failed to create write transaction")?;
         let db = env.create_database(&mut wtxn, Some("events"))
             .context("Failed to create events database")?;
+        let by_type = env.create_database(&mut wtxn, Some("events_by_type"))
+            .context("Failed to create event type index")?;
+        let by_time = env.create_database(&mut wtxn, Some("events_by_time"))
+            .context("Failed to create event time index")?;
+        let log = env.create_database(&mut wtxn, Some("event_log"))
+            .context("Failed to create event log")?;
+        let snapshots = env.create_database(&mut wtxn, Some("snapshots"))
+            .context("Failed to create snapshots database")?;
         wtxn.commit()
             .context("Failed to commit database creation")?;
 
-        Ok(Self { env, db })
+        let (committed, _) = broadcast::channel(SUBSCRIPTION_CAPACITY);
+
+        let store = Self { env, db, by_type, by_time, log, snapshots, committed };
+        store.upgrade()?;
+
+        Ok(store)
+    }
+
+    /// Bring a store written by an earlier layout up to date
+    ///
+    /// Older stores keyed events `aggregate::id` and had no indexes or log.
+    /// Every event not yet in the log is re-keyed, indexed and given a log
+    /// position, oldest first. This runs in one transaction, so an upgrade
+    /// that fails leaves the store as it was.
+    fn upgrade(&self) -> Result<()> {
+        let mut wtxn = self.env.write_txn()
+            .context("Failed to create write transaction")?;
+
+        if self.log.len(&wtxn)? == self.db.len(&wtxn)? {
+            return Ok(());
+        }
+
+        let mut stale = Vec::new();
+        for result in self.db.iter(&wtxn)? {
+            let (key, event) = result?;
+            if event.position == 0 {
+                stale.push((key.to_string(), event));
+            }
+        }
+        stale.sort_by(|(_, a), (_, b)| {
+            (a.timestamp, &a.aggregate_id, a.version).cmp(&(b.timestamp, &b.aggregate_id, b.version))
+        });
+
+        tracing::info!("Upgrading {} events to the current store layout", stale.len());
+        for (key, mut event) in stale {
+            self.db.delete(&mut wtxn, &key)
+                .context("Failed to remove event under its old key")?;
+            self.put_event(&mut wtxn, &mut event)?;
+        }
+
+        wtxn.commit()
+            .context("Failed to commit store upgrade")?;
+
+        Ok(())
+    }
+
+    /// Follow the global log from the next event committed
+    pub fn subscribe(&self) -> Result<Subscription> {
+        let rtxn = self.env.read_txn()
+            .context("Failed to create read transaction")?;
+        let head = self.log.last(&rtxn)?.map(|(position, _)| position).unwrap_or(0);
+
+        Ok(self.subscribe_from(head + 1))
+    }
+
+    /// Follow the global log from `position`, catching up on events already
+    /// committed before yielding new ones
+    pub fn subscribe_from(&self, position: u64) -> Subscription {
+        Subscription {
+            store: self.clone(),
+            receiver: self.committed.subscribe(),
+            pending: VecDeque::new(),
+            next: position.max(1),
+            live: false,
+        }
+    }
+
+    /// Tell subscribers about committed events
+    fn publish(&self, events: Vec<Event>) {
+        for event in events {
+            // No receivers is not an error; the log has the event
+            let _ = self.committed.send(event);
+        }
     }
 
     /// Generate a unique key for an event
+    ///
+    /// The id keeps keys unique when unchecked appends reuse a version.
     fn event_key(event: &Event) -> String {
-        format!("{}::{}", event.aggregate_id, event.id)
+        format!("{}::{:020}::{}", event.aggregate_id, event.version, event.id)
+    }
+
+    /// Fixed-width timestamp that sorts the same as the time it encodes
+    fn timestamp_key(timestamp: &DateTime<Utc>) -> String {
+        timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)
+    }
+
+    /// Key of an event in the time index
+    fn time_key(event: &Event) -> String {
+        format!("{}::{}", Self::timestamp_key(&event.timestamp), event.id)
+    }
+
+    /// Key of an event in the type index
+    fn type_key(event: &Event) -> String {
+        format!("{}::{}", event.event_type.name(), Self::time_key(event))
+    }
+
+    /// Give an event the next log position, then write it and its index
+    /// entries
+    fn put_event(&self, wtxn: &mut RwTxn, event: &mut Event) -> Result<()> {
+        let key = Self::event_key(event);
+        let head = self.log.last(wtxn)?.map(|(position, _)| position).unwrap_or(0);
+        event.position = head + 1;
+
+        self.db.put(wtxn, &key, event)
+            .context("Failed to write event to LMDB")?;
+        self.log.put(wtxn, &event.position, &key)
+            .context("Failed to append event to log")?;
+        self.by_type.put(wtxn, &Self::type_key(event), &key)
+            .context("Failed to index event by type")?;
+        self.by_time.put(wtxn, &Self::time_key(event), &key)
+            .context("Failed to index event by time")?;
+
+        Ok(())
+    }
+
+    /// Look up the events an index range points at
+    fn resolve<'txn, K, I>(&self, txn: &'txn RoTxn, index: I) -> Result<Vec<Event>>
+    where
+        I: Iterator<Item = heed::Result<(K, &'txn str)>>,
+    {
+        let mut events = Vec::new();
+
+        for result in index {
+            let (_, key) = result?;
+            let event = self.db.get(txn, key)?
+                .with_context(|| format!("Index points at missing event {}", key))?;
+            events.push(event);
+        }
+
+        Ok(events)
+    }
+
+    /// Latest version of an aggregate, or 0 if it has no events
+    fn head_version(&self, txn: &RoTxn, aggregate_id: &str) -> Result<u64> {
+        let prefix = format!("{}::", aggregate_id);
+
+        // Keys sort by version, so the last one under the prefix is the head
+        for result in self.db.rev_prefix_iter(txn, &prefix)? {
+            let (_, event) = result?;
+            if event.aggregate_id == aggregate_id {
+                return Ok(event.version);
+            }
+        }
+
+        Ok(0)
     }
 }
 
 impl EventStore for LmdbEventStore {
-    fn append(&self, event: Event) -> Result<()> {
+    fn append(&self, mut event: Event) -> Result<()> {
         let mut wtxn = self.env.write_txn()
             .context("Failed to create write transaction")?;
 
-        let key = Self::event_key(&event);
-        self.db.put(&mut wtxn, &key, &event)
-            .context("Failed to write event to LMDB")?;
+        self.put_event(&mut wtxn, &mut event)?;
 
         wtxn.commit()
             .context("Failed to commit event")?;
 
+        self.publish(vec![event]);
+
         Ok(())
     }
 
-    fn get_events(&self, aggregate_id: &str) -> Result<Vec<Event>> {
+    fn append_expected(
+        &self,
+        aggregate_id: &str,
+        expected_version: u64,
+        mut events: Vec<Event>,
+    ) -> Result<u64> {
+        // LMDB allows one write transaction at a time, so no other writer
+        // can move the head between the check and the commit
+        let mut wtxn = self.env.write_txn()
+            .context("Failed to create write transaction")?;
+
+        let actual = self.head_version(&wtxn, aggregate_id)?;
+        if actual != expected_version {
+            return Err(EventStoreError::VersionConflict {
+                aggregate_id: aggregate_id.to_string(),
+                expected: expected_version,
+                actual,
+            }
+            .into());
+        }
+
+        let mut version = expected_version;
+        for event in &mut events {
+            if event.aggregate_id != aggregate_id || event.version != version + 1 {
+                return Err(EventStoreError::OutOfSequence {
+                    event_id: event.id,
+                    aggregate_id: aggregate_id.to_string(),
+                    expected: version,
+                }
+                .into());
+            }
+            version = event.version;
+
+            self.put_event(&mut wtxn, event)?;
+        }
+
+        wtxn.commit()
+            .context("Failed to commit events")?;
+
+        self.publish(events);
+
+        Ok(version)
+    }
+
+    fn get_events_after(&self, aggregate_id: &str, version: u64) -> Result<Vec<Event>> {
         let rtxn = self.env.read_txn()
             .context("Failed to create read transaction")?;
 
         let mut events = Vec::new();
         let prefix = format!("{}::", aggregate_id);
+        let start = format!("{}{:020}", prefix, version.saturating_add(1));
+        let range = (Bound::Included(start.as_str()), Bound::Unbounded);
 
-        for result in self.db.iter(&rtxn)? {
+        // Keys are already in version order
+        for result in self.db.range(&rtxn, &range)? {
             let (key, event) = result?;
-            if key.starts_with(&prefix) {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            if event.aggregate_id == aggregate_id {
                 events.push(event);
             }
         }
 
-        // Sort by version
-        events.sort_by_key(|e| e.version);
-
         Ok(events)
     }
 
+    fn get_version(&self, aggregate_id: &str) -> Result<u64> {
+        let rtxn = self.env.read_txn()
+            .context("Failed to create read transaction")?;
+
+        self.head_version(&rtxn, aggregate_id)
+    }
+
     fn get_all_events(&self) -> Result<Vec<Event>> {
         let rtxn = self.env.read_txn()
             .context("Failed to create read transaction")?;
 
-        let mut events = Vec::new();
-        for result in self.db.iter(&rtxn)? {
-            let (_, event) = result?;
-            events.push(event);
-        }
+        let events = self.resolve(&rtxn, self.log.iter(&rtxn)?)?;
 
-        // Sort by timestamp
-        events.sort_by_key(|e| e.timestamp);
+        Ok(events)
+    }
+
+    fn read_from(&self, position: u64, limit: usize) -> Result<Vec<Event>> {
+        let rtxn = self.env.read_txn()
+            .context("Failed to create read transaction")?;
+
+        let range = (Bound::Included(position), Bound::Unbounded);
+        let events = self.resolve(&rtxn, self.log.range(&rtxn, &range)?.take(limit))?;
 
         Ok(events)
     }
 
     fn get_events_by_type(&self, event_type_name: &str) -> Result<Vec<Event>> {
-        let all_events = self.get_all_events()?;
-
-        let filtered = all_events.into_iter()
-            .filter(|event| {
-                match (&event.event_type, event_type_name) {
-                    (EventType::TMASubmitted { .. }, "TMASubmitted") => true,
-                    (EventType::FeedbackGenerated { .. }, "FeedbackGenerated") => true,
-                    (EventType::GradeAssigned { .. }, "GradeAssigned") => true,
-                    (EventType::StudentAnonymized { .. }, "StudentAnonymized") => true,
-                    _ => false,
+        let rtxn = self.env.read_txn()
+            .context("Failed to create read transaction")?;
+
+        let prefix = format!("{}::", event_type_name);
+        let events = self.resolve(&rtxn, self.by_type.prefix_iter(&rtxn, &prefix)?)?;
+
+        Ok(events)
+    }
+
+    fn get_events_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<Event>> {
+        let rtxn = self.env.read_txn()
+            .context("Failed to create read transaction")?;
+
+        let from = Self::timestamp_key(&from);
+        let to = Self::timestamp_key(&to);
+        let range = (Bound::Included(from.as_str()), Bound::Excluded(to.as_str()));
+        let events = self.resolve(&rtxn, self.by_time.range(&rtxn, &range)?)?;
+
+        Ok(events)
+    }
+
+    fn save_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        let mut wtxn = self.env.write_txn()
+            .context("Failed to create write transaction")?;
+
+        self.snapshots.put(&mut wtxn, &snapshot.aggregate_id, snapshot)
+            .context("Failed to write snapshot to LMDB")?;
+
+        wtxn.commit()
+            .context("Failed to commit snapshot")?;
+
+        Ok(())
+    }
+
+    fn load_snapshot(&self, aggregate_id: &str) -> Result<Option<Snapshot>> {
+        let rtxn = self.env.read_txn()
+            .context("Failed to create read transaction")?;
+
+        Ok(self.snapshots.get(&rtxn, aggregate_id)?)
+    }
+}
+
+/// A reader following the global log of an [`LmdbEventStore`]
+///
+/// Yields every event from its starting position exactly once and in log
+/// order. Events are pushed as they are committed; if the subscriber falls
+/// behind or misses one, it reads the gap back from the log.
+pub struct Subscription {
+    store: LmdbEventStore,
+    receiver: broadcast::Receiver<Event>,
+    pending: VecDeque<Event>,
+    next: u64,
+    live: bool,
+}
+
+impl Subscription {
+    /// Wait for the next event in the log
+    pub async fn next(&mut self) -> Result<Event> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                self.next = event.position + 1;
+                return Ok(event);
+            }
+
+            if !self.live {
+                self.pending.extend(self.store.read_from(self.next, CATCH_UP_BATCH)?);
+                self.live = self.pending.is_empty();
+                continue;
+            }
+
+            match self.receiver.recv().await {
+                // Already read from the log while catching up
+                Ok(event) if event.position < self.next => {}
+                Ok(event) if event.position == self.next => self.pending.push_back(event),
+                // Writers publish after commit, so a later event can arrive
+                // first; the log has both
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => self.live = false,
+                Err(broadcast::error::RecvError::Closed) => {
+                    anyhow::bail!("Event store was closed")
                 }
-            })
-            .collect();
+            }
+        }
+    }
 
-        Ok(filtered)
+    /// Position of the next event this subscription will yield
+    pub fn position(&self) -> u64 {
+        self.next
     }
 }
 
+/// Events applied since the last snapshot before a new one is taken
+pub const DEFAULT_SNAPSHOT_FREQUENCY: u64 = 100;
+
 /// Event projection for rebuilding state from events
 pub struct EventProjection {
     store: Box<dyn EventStore>,
+    snapshot_frequency: u64,
 }
 
 impl EventProjection {
     /// Create a new event projection
     pub fn new(store: Box<dyn EventStore>) -> Self {
-        Self { store }
+        Self {
+            store,
+            snapshot_frequency: DEFAULT_SNAPSHOT_FREQUENCY,
+        }
+    }
+
+    /// Set how many events may be replayed on top of a snapshot before a
+    /// fresh one is taken (0 never snapshots)
+    pub fn with_snapshot_frequency(mut self, frequency: u64) -> Self {
+        self.snapshot_frequency = frequency;
+        self
+    }
+
+    /// The store events are read from
+    pub fn store(&self) -> &dyn EventStore {
+        self.store.as_ref()
     }
 
     /// Replay all events for an aggregate
@@ -222,8 +647,43 @@ pub fn replay(&self, aggregate_id: &str) -> Result<Vec<Event>> {
 
     /// Get the current version for an aggregate
     pub fn get_version(&self, aggregate_id: &str) -> Result<u64> {
-        let events = self.store.get_events(aggregate_id)?;
-        Ok(events.last().map(|e| e.version).unwrap_or(0))
+        self.store.get_version(aggregate_id)
+    }
+
+    /// Rebuild an aggregate from its latest snapshot and the events after it
+    ///
+    /// Returns the state and the version it reflects. Snapshots are a
+    /// cache: one that no longer deserializes is ignored and the aggregate
+    /// is replayed from its first event.
+    pub fn load_aggregate<A: Aggregate>(&self, aggregate_id: &str) -> Result<(A, u64)> {
+        let (mut state, snapshot_version) = match self.store.load_snapshot(aggregate_id)? {
+            Some(snapshot) => match serde_json::from_value(snapshot.state) {
+                Ok(state) => (state, snapshot.version),
+                Err(e) => {
+                    tracing::warn!("Ignoring unreadable snapshot of {}: {}", aggregate_id, e);
+                    (A::default(), 0)
+                }
+            },
+            None => (A::default(), 0),
+        };
+
+        let mut version = snapshot_version;
+        for event in self.store.get_events_after(aggregate_id, snapshot_version)? {
+            state.apply(&event);
+            version = event.version;
+        }
+
+        if self.snapshot_frequency > 0 && version - snapshot_version >= self.snapshot_frequency {
+            self.store.save_snapshot(&Snapshot {
+                aggregate_id: aggregate_id.to_string(),
+                version,
+                state: serde_json::to_value(&state)
+                    .context("Failed to serialize aggregate state")?,
+                taken_at: Utc::now(),
+            })?;
+        }
+
+        Ok((state, version))
     }
 }
 
@@ -356,6 +816,236 @@ fn test_get_events_by_type() {
         drop(_temp_dir);
     }
 
+    fn submitted(aggregate_id: &str, version: u64) -> Event {
+        Event::new(
+            EventType::TMASubmitted {
+                student_id: "student123".to_string(),
+                module_code: "TM112".to_string(),
+                question_number: 1,
+                content_hash: "abc123".to_string(),
+            },
+            aggregate_id.to_string(),
+            version,
+        )
+    }
+
+    #[test]
+    fn test_append_expected_detects_conflict() {
+        let (store, _temp_dir) = create_test_store();
+
+        let version = store
+            .append_expected("tma-001", 0, vec![submitted("tma-001", 1), submitted("tma-001", 2)])
+            .expect("Failed to append events");
+        assert_eq!(version, 2);
+
+        // A writer that read the aggregate before those events loses
+        let error = store
+            .append_expected("tma-001", 1, vec![submitted("tma-001", 2)])
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<EventStoreError>(),
+            Some(&EventStoreError::VersionConflict {
+                aggregate_id: "tma-001".to_string(),
+                expected: 1,
+                actual: 2,
+            })
+        );
+
+        let events = store.get_events("tma-001").expect("Failed to get events");
+        assert_eq!(events.len(), 2);
+    }
+
+    #[test]
+    fn test_append_expected_rejects_gaps() {
+        let (store, _temp_dir) = create_test_store();
+
+        let error = store
+            .append_expected("tma-001", 0, vec![submitted("tma-001", 1), submitted("tma-001", 3)])
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<EventStoreError>(),
+            Some(EventStoreError::OutOfSequence { expected: 1, .. })
+        ));
+
+        let error = store
+            .append_expected("tma-001", 0, vec![submitted("tma-002", 1)])
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<EventStoreError>(),
+            Some(EventStoreError::OutOfSequence { expected: 0, .. })
+        ));
+
+        // Nothing from a rejected append is kept
+        assert!(store.get_events("tma-001").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_racing_writers_append_once() {
+        let (store, _temp_dir) = create_test_store();
+        let store = std::sync::Arc::new(store);
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    store.append_expected("tma-001", 0, vec![submitted("tma-001", 1)])
+                })
+            })
+            .collect();
+        let appended = writers
+            .into_iter()
+            .map(|writer| writer.join().unwrap())
+            .filter(Result::is_ok)
+            .count();
+
+        assert_eq!(appended, 1);
+        assert_eq!(store.get_events("tma-001").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_get_events_orders_by_version() {
+        let (store, _temp_dir) = create_test_store();
+
+        // Versions past 9 would sort before 2 without zero padding
+        for version in (1..=12).rev() {
+            store.append(submitted("tma-001", version)).unwrap();
+        }
+        store.append(submitted("tma-001::retry", 1)).unwrap();
+
+        let versions: Vec<u64> = store
+            .get_events("tma-001")
+            .unwrap()
+            .iter()
+            .map(|e| e.version)
+            .collect();
+        assert_eq!(versions, (1..=12).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_old_layout_upgraded_on_open() {
+        let temp_dir = TempDir::new().unwrap();
+        let events: Vec<Event> = [("tma-001", 2), ("tma-002", 1), ("tma-001", 1)]
+            .into_iter()
+            .enumerate()
+            .map(|(offset, (aggregate_id, version))| {
+                let mut event = submitted(aggregate_id, version);
+                event.timestamp = Utc::now() + chrono::Duration::seconds(offset as i64);
+                event
+            })
+            .collect();
+
+        // Written as earlier versions did: `aggregate::id` keys, no indexes
+        {
+            let store = LmdbEventStore::new(temp_dir.path(), Some(10 * 1024 * 1024)).unwrap();
+            let mut wtxn = store.env.write_txn().unwrap();
+            for event in &events {
+                let key = format!("{}::{}", event.aggregate_id, event.id);
+                store.db.put(&mut wtxn, &key, event).unwrap();
+            }
+            wtxn.commit().unwrap();
+        }
+
+        let store = LmdbEventStore::new(temp_dir.path(), Some(10 * 1024 * 1024)).unwrap();
+
+        let versions: Vec<u64> = store.get_events("tma-001").unwrap().iter().map(|e| e.version).collect();
+        assert_eq!(versions, vec![1, 2]);
+        assert_eq!(store.get_version("tma-001").unwrap(), 2);
+        assert_eq!(store.get_events_by_type("TMASubmitted").unwrap().len(), 3);
+
+        // Positions follow the order the events happened in
+        let ids: Vec<Uuid> = store.read_from(1, 10).unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, events.iter().map(|e| e.id).collect::<Vec<_>>());
+
+        store
+            .append_expected("tma-001", 2, vec![submitted("tma-001", 3)])
+            .unwrap();
+        assert_eq!(store.read_from(4, 1).unwrap()[0].position, 4);
+    }
+
+    #[test]
+    fn test_get_events_between() {
+        let (store, _temp_dir) = create_test_store();
+
+        let start = Utc::now();
+        let mut events: Vec<Event> = (1..=3).map(|v| submitted("tma-001", v)).collect();
+        for (offset, event) in events.iter_mut().enumerate() {
+            event.timestamp = start + chrono::Duration::seconds(offset as i64);
+        }
+        for event in events.iter().rev() {
+            store.append(event.clone()).unwrap();
+        }
+
+        let found = store
+            .get_events_between(start, start + chrono::Duration::seconds(2))
+            .unwrap();
+        let versions: Vec<u64> = found.iter().map(|e| e.version).collect();
+        assert_eq!(versions, vec![1, 2]);
+
+        assert_eq!(store.get_all_events().unwrap().len(), 3);
+        assert_eq!(store.get_events_by_type("TMASubmitted").unwrap().len(), 3);
+        assert!(store.get_events_by_type("GradeAssigned").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_from_follows_append_order() {
+        let (store, _temp_dir) = create_test_store();
+
+        store.append(submitted("tma-002", 1)).unwrap();
+        store
+            .append_expected("tma-001", 0, vec![submitted("tma-001", 1), submitted("tma-001", 2)])
+            .unwrap();
+        store.append(submitted("tma-002", 2)).unwrap();
+
+        let events = store.read_from(2, 2).unwrap();
+        let order: Vec<(u64, &str, u64)> = events
+            .iter()
+            .map(|e| (e.position, e.aggregate_id.as_str(), e.version))
+            .collect();
+        assert_eq!(order, vec![(2, "tma-001", 1), (3, "tma-001", 2)]);
+
+        let positions: Vec<u64> = store.get_all_events().unwrap().iter().map(|e| e.position).collect();
+        assert_eq!(positions, vec![1, 2, 3, 4]);
+        assert!(store.read_from(5, 10).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_catches_up_then_tails() {
+        let (store, _temp_dir) = create_test_store();
+
+        store.append(submitted("tma-001", 1)).unwrap();
+        let mut tail = store.subscribe().unwrap();
+        let mut replay = store.subscribe_from(1);
+        store.append(submitted("tma-001", 2)).unwrap();
+
+        let writer = store.clone();
+        tokio::spawn(async move {
+            writer.append(submitted("tma-001", 3)).unwrap();
+        });
+
+        for expected in 1..=3 {
+            assert_eq!(replay.next().await.unwrap().position, expected);
+        }
+        for expected in 2..=3 {
+            assert_eq!(tail.next().await.unwrap().position, expected);
+        }
+        assert_eq!(tail.position(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscription_reads_gap_from_log() {
+        let (store, _temp_dir) = create_test_store();
+
+        let mut subscription = store.subscribe().unwrap();
+        let count = SUBSCRIPTION_CAPACITY as u64 + 10;
+        for version in 1..=count {
+            store.append(submitted("tma-001", version)).unwrap();
+        }
+
+        for expected in 1..=count {
+            assert_eq!(subscription.next().await.unwrap().version, expected);
+        }
+    }
+
     #[test]
     fn test_event_projection() {
         let (store, _temp_dir) = create_test_store();
@@ -380,4 +1070,61 @@ fn test_event_projection() {
         drop(projection);
         drop(_temp_dir);
     }
+
+    /// Counts the events applied, to show which ones a load replayed
+    #[derive(Default, Serialize, Deserialize)]
+    struct Counter {
+        applied: Vec<u64>,
+    }
+
+    impl Aggregate for Counter {
+        fn apply(&mut self, event: &Event) {
+            self.applied.push(event.version);
+        }
+    }
+
+    #[test]
+    fn test_load_aggregate_from_snapshot() {
+        let (store, _temp_dir) = create_test_store();
+        let events = (1..=5).map(|v| submitted("tma-001", v)).collect();
+        store.append_expected("tma-001", 0, events).unwrap();
+
+        let projection = EventProjection::new(Box::new(store.clone())).with_snapshot_frequency(3);
+        let (counter, version) = projection.load_aggregate::<Counter>("tma-001").unwrap();
+        assert_eq!(counter.applied, vec![1, 2, 3, 4, 5]);
+        assert_eq!(version, 5);
+
+        let snapshot = store.load_snapshot("tma-001").unwrap().expect("Snapshot not taken");
+        assert_eq!(snapshot.version, 5);
+
+        store
+            .append_expected("tma-001", 5, vec![submitted("tma-001", 6), submitted("tma-001", 7)])
+            .unwrap();
+
+        // Only the events after the snapshot are applied on top of it
+        let (counter, version) = projection.load_aggregate::<Counter>("tma-001").unwrap();
+        assert_eq!(counter.applied, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(version, 7);
+        assert_eq!(store.load_snapshot("tma-001").unwrap().unwrap().version, 5);
+        assert_eq!(store.get_events_after("tma-001", 5).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_load_aggregate_ignores_unreadable_snapshot() {
+        let (store, _temp_dir) = create_test_store();
+        store.append(submitted("tma-001", 1)).unwrap();
+        store
+            .save_snapshot(&Snapshot {
+                aggregate_id: "tma-001".to_string(),
+                version: 1,
+                state: serde_json::json!("not a counter"),
+                taken_at: Utc::now(),
+            })
+            .unwrap();
+
+        let projection = EventProjection::new(Box::new(store)).with_snapshot_frequency(0);
+        let (counter, version) = projection.load_aggregate::<Counter>("tma-001").unwrap();
+        assert_eq!(counter.applied, vec![1]);
+        assert_eq!(version, 1);
+    }
 }
@@ -3,24 +3,46 @@
 //! Coordinates feedback generation for TMAs, integrating with the AI jail
 //! and ensuring rubric-aligned responses.
 
-use crate::ipc::{AsyncIPCClient, IPCMessage};
+use crate::ipc::{
+    reply_error, AsyncIPCClient, Capabilities, GenerationParams, IPCError, IPCMessage, JailPool,
+    PendingReply, PromptOptions, ResponseFormat, REPLY_GRACE,
+};
 use crate::security::SecurityService;
 use crate::tma::{RubricCriterion, TMA};
 use anyhow::{Context, Result};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
 
+pub use aws_ipc_protocol::{ConfidenceSpan, CriterionScore, ModelInfo};
+
 /// Request for feedback generation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FeedbackRequest {
     /// The TMA to generate feedback for
     pub tma_id: String,
+    /// Question number within the TMA
+    pub question_number: u32,
     /// Anonymized student content
     pub content: String,
     /// Rubric criteria to evaluate against
     pub rubric: String,
     /// Rubric criteria parsed into structured form
     pub criteria: Vec<RubricCriterion>,
+    /// OU module code, available to the prompt template
+    pub module_code: String,
+    /// Word limit for the answer, if the question sets one
+    #[serde(default)]
+    pub word_limit: Option<u32>,
+    /// Jail prompt template to use instead of its default
+    #[serde(default)]
+    pub template: Option<String>,
+    /// Jail model to generate with instead of its default
+    #[serde(default)]
+    pub model: Option<String>,
     /// Maximum response time in seconds
     pub timeout_secs: u64,
 }
@@ -42,9 +64,14 @@ pub fn from_tma(tma: &TMA, security: &SecurityService) -> Result<Self> {
 
         Ok(Self {
             tma_id: tma.id.to_string(),
+            question_number: tma.question_number,
             content: sanitized_content,
             rubric: tma.rubric.clone(),
             criteria,
+            module_code: tma.module_code.clone(),
+            word_limit: None,
+            template: None,
+            model: None,
             timeout_secs: 120, // Default 2 minutes
         })
     }
@@ -54,6 +81,25 @@ pub fn with_timeout(mut self, timeout_secs: u64) -> Self {
         self.timeout_secs = timeout_secs;
         self
     }
+
+    /// Set the answer's word limit
+    pub fn with_word_limit(mut self, word_limit: u32) -> Self {
+        self.word_limit = Some(word_limit);
+        self
+    }
+
+    /// Use a named prompt template from the jail's template directory
+    pub fn with_template(mut self, template: impl Into<String>) -> Self {
+        self.template = Some(template.into());
+        self
+    }
+
+    /// Generate with a named model from the jail's model registry, e.g. a
+    /// larger one for summative marking
+    pub fn with_model(mut self, model: impl Into<String>) -> Self {
+        self.model = Some(model.into());
+        self
+    }
 }
 
 /// Response from feedback generation
@@ -71,22 +117,46 @@ pub struct FeedbackResponse {
     pub suggestions: Vec<String>,
     /// Strengths identified
     pub strengths: Vec<String>,
+    /// Model confidence in the feedback (0.0-1.0), if produced by a model
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    /// Sentences of the feedback the model was least sure of
+    #[serde(default)]
+    pub low_confidence_spans: Vec<ConfidenceSpan>,
+    /// Sampler seed the feedback was generated with, for replaying it
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Whether part of the answer was too long for the model and was
+    /// never marked, so a tutor should review the rest
+    #[serde(default)]
+    pub truncated: bool,
+    /// Id and revision of the model that generated the feedback
+    #[serde(default)]
+    pub model: Option<ModelInfo>,
 }
 
-/// Score for a single rubric criterion
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct CriterionScore {
-    pub criterion_number: u32,
-    pub criterion_text: String,
-    pub score: f32,
-    pub max_score: f32,
-    pub feedback: String,
+/// Receives streamed feedback text, and can reject it with an error
+type ChunkSink<'a> = &'a mut (dyn FnMut(&str) -> Result<()> + Send);
+
+/// Where a feedback service sends its requests
+enum Jail {
+    Client(Arc<AsyncIPCClient>),
+    Pool(Arc<JailPool>),
+}
+
+impl Jail {
+    fn client(&self) -> Result<Arc<AsyncIPCClient>> {
+        match self {
+            Jail::Client(client) => Ok(client.clone()),
+            Jail::Pool(pool) => pool.client(),
+        }
+    }
 }
 
 /// Service for coordinating feedback generation
 pub struct FeedbackService {
     security: SecurityService,
-    ipc_client: Option<AsyncIPCClient>,
+    jail: Option<Jail>,
 }
 
 impl FeedbackService {
@@ -94,7 +164,7 @@ impl FeedbackService {
     pub fn new(security: SecurityService) -> Self {
         Self {
             security,
-            ipc_client: None,
+            jail: None,
         }
     }
 
@@ -102,7 +172,16 @@ pub fn new(security: SecurityService) -> Self {
     pub fn with_ipc(security: SecurityService, ipc_client: AsyncIPCClient) -> Self {
         Self {
             security,
-            ipc_client: Some(ipc_client),
+            jail: Some(Jail::Client(Arc::new(ipc_client))),
+        }
+    }
+
+    /// Create a feedback service that takes a running jail from `pool` for
+    /// each request, or each batch
+    pub fn with_pool(security: SecurityService, pool: Arc<JailPool>) -> Self {
+        Self {
+            security,
+            jail: Some(Jail::Pool(pool)),
         }
     }
 
@@ -115,20 +194,57 @@ pub fn with_ipc(security: SecurityService, ipc_client: AsyncIPCClient) -> Self {
     /// 3. Validating the response
     /// 4. Structuring the feedback
     pub async fn generate_feedback(&mut self, tma: &TMA) -> Result<FeedbackResponse> {
+        self.generate(tma, None).await
+    }
+
+    /// Generate feedback for a TMA, passing text to `on_chunk` as the AI
+    /// jail produces it
+    ///
+    /// Chunks are for live display only; the returned response is the
+    /// authoritative feedback. Text is checked for PII before it reaches
+    /// `on_chunk`, so some of it is held back until the text that follows
+    /// shows it is not part of a match.
+    pub async fn generate_feedback_streaming(
+        &mut self,
+        tma: &TMA,
+        mut on_chunk: impl FnMut(&str) + Send,
+    ) -> Result<FeedbackResponse> {
+        self.generate(tma, Some(&mut on_chunk)).await
+    }
+
+    async fn generate(
+        &mut self,
+        tma: &TMA,
+        on_chunk: Option<&mut (dyn FnMut(&str) + Send)>,
+    ) -> Result<FeedbackResponse> {
         // Create request with sanitized content
         let request = FeedbackRequest::from_tma(tma, &self.security)?;
 
+        // Streamed text only reaches `on_chunk` once it has been checked
+        let streaming = on_chunk.is_some();
+        let mut on_chunk = on_chunk;
+        let mut validator = self.security.output_validator();
+        let mut checked = |text: &str| -> Result<()> {
+            let safe = validator.push(text).context("AI response contains PII")?;
+            if let Some(on_chunk) = on_chunk.as_mut().filter(|_| !safe.is_empty()) {
+                on_chunk(&safe);
+            }
+            Ok(())
+        };
+
         // Send to AI jail if IPC client is available
-        let response = if self.ipc_client.is_some() {
-            // Take the client temporarily to avoid double borrow
-            let mut ipc_client = self.ipc_client.take().unwrap();
-            let result = Self::send_via_ipc(&mut ipc_client, &request).await;
-            // Put it back
-            self.ipc_client = Some(ipc_client);
-            result?
+        let response = if let Some(jail) = &self.jail {
+            let ipc_client = jail.client()?;
+            let checked: Option<ChunkSink> =
+                if streaming { Some(&mut checked) } else { None };
+            Self::send_via_ipc(&ipc_client, &request, checked).await?
         } else {
             // Fallback to mock feedback for testing
-            Self::generate_mock_feedback(&request)?
+            let response = Self::generate_mock_feedback(&request)?;
+            if streaming {
+                checked(&response.feedback)?;
+            }
+            response
         };
 
         // Validate response doesn't contain PII
@@ -136,53 +252,307 @@ pub async fn generate_feedback(&mut self, tma: &TMA) -> Result<FeedbackResponse>
             .validate_output(&response.feedback)
             .context("AI response contains PII")?;
 
+        // Release what was held back for the end of the text
+        if let Some(on_chunk) = on_chunk {
+            let rest = validator.finish().context("AI response contains PII")?;
+            if !rest.is_empty() {
+                on_chunk(&rest);
+            }
+        }
+
         Ok(response)
     }
 
-    /// Generate feedback via IPC to AI jail
-    async fn send_via_ipc(
-        ipc_client: &mut AsyncIPCClient,
+    /// Generate feedback for several TMAs at once
+    ///
+    /// Every request is sent to the AI jail before any reply is read, so
+    /// the jail can decode them together in batches. Results are in the
+    /// order of `tmas`, and a TMA whose request fails does not affect the
+    /// others. The batch as a whole times out after the sum of the
+    /// requests' timeouts, and requests still unanswered then are
+    /// cancelled.
+    pub async fn generate_feedback_batch(&mut self, tmas: &[TMA]) -> Vec<Result<FeedbackResponse>> {
+        let mut results: Vec<Option<Result<FeedbackResponse>>> = Vec::with_capacity(tmas.len());
+        let mut pending = Vec::with_capacity(tmas.len());
+
+        for (index, tma) in tmas.iter().enumerate() {
+            match FeedbackRequest::from_tma(tma, &self.security) {
+                Ok(request) => {
+                    results.push(None);
+                    pending.push((index, request));
+                }
+                Err(e) => results.push(Some(Err(e))),
+            }
+        }
+
+        let requests: Vec<&FeedbackRequest> = pending.iter().map(|(_, request)| request).collect();
+        let responses = match self.jail.as_ref().map(Jail::client) {
+            Some(Ok(ipc_client)) => Self::send_batch_via_ipc(&ipc_client, &requests).await,
+            Some(Err(e)) => {
+                let message = format!("{:#}", e);
+                requests
+                    .iter()
+                    .map(|_| Err(anyhow::anyhow!("{}", message)))
+                    .collect()
+            }
+            None => requests
+                .iter()
+                .map(|request| Self::generate_mock_feedback(request))
+                .collect(),
+        };
+
+        for ((index, _), response) in pending.iter().zip(responses) {
+            results[*index] = Some(response.and_then(|response| {
+                self.security
+                    .validate_output(&response.feedback)
+                    .context("AI response contains PII")?;
+                Ok(response)
+            }));
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.expect("every TMA has a result"))
+            .collect()
+    }
+
+    /// Send all requests, then collect the replies as they arrive
+    async fn send_batch_via_ipc(
+        ipc_client: &AsyncIPCClient,
+        requests: &[&FeedbackRequest],
+    ) -> Vec<Result<FeedbackResponse>> {
+        let mut results: Vec<Option<Result<FeedbackResponse>>> =
+            requests.iter().map(|_| None).collect();
+        let mut waiting = HashMap::new();
+        let mut replies = FuturesUnordered::new();
+
+        let capabilities = Self::capabilities(ipc_client);
+        for (index, request) in requests.iter().enumerate() {
+            if let Err(e) = Self::check_model(request, &capabilities) {
+                results[index] = Some(Err(e));
+                continue;
+            }
+            let (request_id, message) = Self::feedback_message(request, false, &capabilities);
+            match ipc_client.request(&message).await {
+                Ok(reply) => {
+                    waiting.insert(index, request_id);
+                    replies.push(async move { (index, reply.response().await) });
+                }
+                Err(e) => results[index] = Some(Err(e)),
+            }
+        }
+
+        let timeout = Duration::from_secs(requests.iter().map(|r| r.timeout_secs).sum())
+            + REPLY_GRACE;
+        let receive_all = async {
+            while let Some((index, reply)) = replies.next().await {
+                waiting.remove(&index);
+                results[index] = Some(reply.and_then(|message| {
+                    Self::parse_response(requests[index], &capabilities, message)
+                }));
+            }
+        };
+
+        if tokio::time::timeout(timeout, receive_all).await.is_err() {
+            let unanswered: Vec<String> = waiting.into_values().collect();
+            if let Err(e) = ipc_client.cancel(&unanswered).await {
+                tracing::warn!("Failed to cancel requests: {:#}", e);
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    Err(IPCError::Timeout).context("Timeout waiting for AI response")
+                })
+            })
+            .collect()
+    }
+
+    /// What the jail behind `ipc_client` supports
+    ///
+    /// A client spawned without the version handshake is assumed to talk
+    /// to a jail as new as itself.
+    fn capabilities(ipc_client: &AsyncIPCClient) -> Capabilities {
+        match ipc_client.jail_info() {
+            Some(info) => info.capabilities.clone(),
+            None => Capabilities {
+                streaming: true,
+                grammar: true,
+                ..Capabilities::default()
+            },
+        }
+    }
+
+    /// Refuse a request for a model the jail says it does not serve
+    fn check_model(request: &FeedbackRequest, capabilities: &Capabilities) -> Result<()> {
+        match &request.model {
+            Some(model) if !capabilities.serves(model) => {
+                anyhow::bail!("AI jail does not serve model '{}'", model)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Build the IPC message for a request, returning it with its ID
+    fn feedback_message(
         request: &FeedbackRequest,
-    ) -> Result<FeedbackResponse> {
-        // Create IPC message
+        stream: bool,
+        capabilities: &Capabilities,
+    ) -> (String, IPCMessage) {
+        let request_id = uuid::Uuid::new_v4().to_string();
+
         let message = IPCMessage::FeedbackRequest {
-            request_id: uuid::Uuid::new_v4().to_string(),
+            request_id: request_id.clone(),
             content: request.content.clone(),
             rubric: request.rubric.clone(),
             criteria: request.criteria.clone(),
+            question_number: request.question_number,
+            params: GenerationParams {
+                stream: stream && capabilities.streaming,
+                response_format: Self::response_format(request, capabilities),
+                model: request.model.clone(),
+                timeout_ms: Some(request.timeout_secs * 1000),
+                ..Default::default()
+            },
+            prompt: PromptOptions {
+                template: request.template.clone(),
+                module_code: Some(request.module_code.clone()),
+                word_limit: request.word_limit,
+            },
         };
 
+        (request_id, message)
+    }
+
+    /// Output format to ask the jail for
+    ///
+    /// With parsed criteria the jail can return scores, strengths and
+    /// suggestions directly instead of leaving them to be guessed, if it
+    /// can constrain its output to the rubric JSON grammar.
+    fn response_format(request: &FeedbackRequest, capabilities: &Capabilities) -> ResponseFormat {
+        if request.criteria.is_empty() || !capabilities.grammar {
+            ResponseFormat::Text
+        } else {
+            ResponseFormat::RubricJson
+        }
+    }
+
+    /// Generate feedback via IPC to AI jail
+    ///
+    /// The jail is told the request's timeout and normally gives up on its
+    /// own with a typed timeout error. If it has not answered
+    /// [`REPLY_GRACE`] after that, the request is cancelled so the jail
+    /// stops spending time on it.
+    ///
+    /// A jail that cannot stream is sent an ordinary request, and the whole
+    /// feedback is passed to `on_chunk` once it arrives. If `on_chunk`
+    /// rejects a chunk the request is cancelled and its error returned.
+    async fn send_via_ipc(
+        ipc_client: &AsyncIPCClient,
+        request: &FeedbackRequest,
+        on_chunk: Option<ChunkSink<'_>>,
+    ) -> Result<FeedbackResponse> {
+        let capabilities = Self::capabilities(ipc_client);
+        Self::check_model(request, &capabilities)?;
+        let (request_id, message) =
+            Self::feedback_message(request, on_chunk.is_some(), &capabilities);
+
         // Send request
-        ipc_client.send(&message).await?;
+        let reply = ipc_client.request(&message).await?;
 
         // Wait for response with timeout
-        let timeout = Duration::from_secs(request.timeout_secs);
-        let response_msg = tokio::time::timeout(timeout, ipc_client.receive())
-            .await
-            .context("Timeout waiting for AI response")??;
+        let timeout = Duration::from_secs(request.timeout_secs) + REPLY_GRACE;
+        let mut ignore_chunks = |_: &str| Ok(());
+        let on_chunk = on_chunk.unwrap_or(&mut ignore_chunks);
+        let streamed = Self::receive_streamed(reply, &mut *on_chunk);
+        let response_msg = match tokio::time::timeout(timeout, streamed).await {
+            Ok(Ok(response_msg)) => response_msg?,
+            Ok(Err(e)) => {
+                Self::cancel(ipc_client, request_id).await;
+                return Err(e);
+            }
+            Err(_) => {
+                Self::cancel(ipc_client, request_id).await;
+                return Err(IPCError::Timeout).context("Timeout waiting for AI response");
+            }
+        };
 
-        // Parse response
+        let response = Self::parse_response(request, &capabilities, response_msg)?;
+        if !capabilities.streaming {
+            on_chunk(&response.feedback)?;
+        }
+        Ok(response)
+    }
+
+    /// Tell the jail to stop working on a request, logging any failure
+    async fn cancel(ipc_client: &AsyncIPCClient, request_id: String) {
+        if let Err(e) = ipc_client.cancel(&[request_id]).await {
+            tracing::warn!("Failed to cancel request: {:#}", e);
+        }
+    }
+
+    /// Turn the jail's reply to `request` into a feedback response
+    fn parse_response(
+        request: &FeedbackRequest,
+        capabilities: &Capabilities,
+        response_msg: IPCMessage,
+    ) -> Result<FeedbackResponse> {
         match response_msg {
             IPCMessage::FeedbackResponse {
-                request_id: _,
                 feedback,
                 scores,
                 overall_grade,
-            } => Ok(FeedbackResponse {
-                tma_id: request.tma_id.clone(),
-                feedback: feedback.clone(),
-                criterion_scores: scores,
-                overall_grade,
-                suggestions: Self::extract_suggestions(&feedback),
-                strengths: Self::extract_strengths(&feedback),
-            }),
-            IPCMessage::Error { message } => {
-                anyhow::bail!("AI processing error: {}", message)
+                strengths,
+                suggestions,
+                metadata,
+                ..
+            } => {
+                let (strengths, suggestions) = match Self::response_format(request, capabilities) {
+                    ResponseFormat::RubricJson => (strengths, suggestions),
+                    ResponseFormat::Text => (
+                        Self::extract_strengths(&feedback),
+                        Self::extract_suggestions(&feedback),
+                    ),
+                };
+
+                Ok(FeedbackResponse {
+                    tma_id: request.tma_id.clone(),
+                    feedback,
+                    overall_grade: overall_grade.unwrap_or_else(|| Self::average_grade(&scores)),
+                    criterion_scores: scores,
+                    suggestions,
+                    strengths,
+                    confidence: Some(metadata.confidence),
+                    low_confidence_spans: metadata.low_confidence_spans,
+                    seed: metadata.params.seed,
+                    truncated: metadata.context.truncated,
+                    model: Some(metadata.model),
+                })
             }
+            IPCMessage::Error { kind, message, .. } => Err(reply_error(kind, &message)),
             _ => anyhow::bail!("Unexpected response type from AI jail"),
         }
     }
 
+    /// Pass streamed chunks to `on_chunk` and return the final message
+    ///
+    /// The outer error is `on_chunk` rejecting a chunk, which leaves the
+    /// request running in the jail; the inner one is the jail failing.
+    async fn receive_streamed(
+        mut reply: PendingReply,
+        on_chunk: ChunkSink<'_>,
+    ) -> Result<Result<IPCMessage>> {
+        while let Some(chunk) = reply.next_chunk().await {
+            if let IPCMessage::FeedbackChunk { text, .. } = chunk {
+                on_chunk(&text)?;
+            }
+        }
+
+        Ok(reply.response().await)
+    }
+
     /// Generate mock feedback for testing (when no IPC client available)
     fn generate_mock_feedback(request: &FeedbackRequest) -> Result<FeedbackResponse> {
         let mut criterion_scores = Vec::new();
@@ -197,11 +567,7 @@ fn generate_mock_feedback(request: &FeedbackRequest) -> Result<FeedbackResponse>
             });
         }
 
-        let overall_grade = criterion_scores
-            .iter()
-            .map(|s| (s.score / s.max_score) * 100.0)
-            .sum::<f32>()
-            / criterion_scores.len() as f32;
+        let overall_grade = Self::average_grade(&criterion_scores);
 
         Ok(FeedbackResponse {
             tma_id: request.tma_id.clone(),
@@ -210,9 +576,29 @@ fn generate_mock_feedback(request: &FeedbackRequest) -> Result<FeedbackResponse>
             overall_grade,
             suggestions: vec!["Consider providing more examples".to_string()],
             strengths: vec!["Clear explanation of concepts".to_string()],
+            confidence: None,
+            low_confidence_spans: Vec::new(),
+            seed: None,
+            truncated: false,
+            model: None,
         })
     }
 
+    /// Average of criterion scores as a percentage
+    ///
+    /// Used when the jail does not supply an overall grade itself.
+    fn average_grade(scores: &[CriterionScore]) -> f32 {
+        if scores.is_empty() {
+            return 0.0;
+        }
+
+        scores
+            .iter()
+            .map(|s| (s.score / s.max_score) * 100.0)
+            .sum::<f32>()
+            / scores.len() as f32
+    }
+
     /// Extract suggestions from feedback text
     ///
     /// Looks for common patterns like "Consider...", "Try...", "You could..."
@@ -311,6 +697,9 @@ fn test_feedback_request_from_tma() {
 
         assert_eq!(request.tma_id, tma.id.to_string());
         assert_eq!(request.criteria.len(), 3);
+        assert_eq!(request.module_code, "TM112");
+        assert_eq!(request.template, None);
+        assert_eq!(request.model, None);
         assert_eq!(request.timeout_secs, 120);
     }
 
@@ -339,6 +728,207 @@ async fn test_generate_mock_feedback() {
         assert!(response.overall_grade > 0.0);
     }
 
+    #[tokio::test]
+    async fn test_generate_feedback_streaming_via_ipc() {
+        // A stand-in jail that answers one streaming request with two chunks
+        let script = r#"
+            read line
+            id=$(printf '%s' "$line" | sed 's/.*"request_id":"\([^"]*\)".*/\1/')
+            chunk='{"type":"FeedbackChunk","payload":{"request_id":"%s","text":"%s","tokens_generated":%d}}\n'
+            printf "$chunk" "$id" "Clear " 1
+            printf "$chunk" "$id" "answer." 2
+            printf '{"type":"FeedbackResponse","payload":{"request_id":"%s","feedback":"Clear answer.","scores":[]}}\n' "$id"
+        "#;
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script.to_string()], "jail")
+            .unwrap();
+        let mut service = FeedbackService::with_ipc(SecurityService::new(), client);
+
+        let mut chunks = Vec::new();
+        let response = service
+            .generate_feedback_streaming(&create_test_tma(), |text| chunks.push(text.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(chunks, vec!["Clear ", "answer."]);
+        assert_eq!(response.feedback, "Clear answer.");
+    }
+
+    #[tokio::test]
+    async fn test_streamed_pii_never_reaches_caller() {
+        // A stand-in jail whose feedback leaks an email split across chunks
+        let script = r#"
+            read line
+            id=$(printf '%s' "$line" | sed 's/.*"request_id":"\([^"]*\)".*/\1/')
+            chunk='{"type":"FeedbackChunk","payload":{"request_id":"%s","text":"%s","tokens_generated":%d}}\n'
+            printf "$chunk" "$id" "Email jo" 1
+            printf "$chunk" "$id" "hn@example.com" 2
+            printf "$chunk" "$id" " for help." 3
+            printf '{"type":"FeedbackResponse","payload":{"request_id":"%s","feedback":"Email john@example.com for help.","scores":[]}}\n' "$id"
+        "#;
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script.to_string()], "jail")
+            .unwrap();
+        let mut service = FeedbackService::with_ipc(SecurityService::new(), client);
+
+        let mut shown = String::new();
+        let result = service
+            .generate_feedback_streaming(&create_test_tma(), |text| shown.push_str(text))
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(shown, "Email ");
+    }
+
+    #[tokio::test]
+    async fn test_structured_feedback_via_ipc() {
+        // A stand-in jail that only answers structured requests
+        let script = r#"
+            read line
+            case "$line" in *'"response_format":"rubric_json"'*) ;; *) exit 1 ;; esac
+            case "$line" in *'"module_code":"TM112"'*) ;; *) exit 1 ;; esac
+            id=$(printf '%s' "$line" | sed 's/.*"request_id":"\([^"]*\)".*/\1/')
+            score='{"criterion_number":1,"criterion_text":"Understanding","score":6,"max_score":10,"feedback":"Partly"}'
+            printf '{"type":"FeedbackResponse","payload":{"request_id":"%s","feedback":"Criterion 1 (6/10): Partly","scores":[%s],"overall_grade":60,"strengths":["Concise"],"suggestions":["Define terms"],"metadata":{"params":{"seed":5},"context":{"sections":9,"truncated":true},"model":{"id":"large","revision":"v2"}}}}\n' "$id" "$score"
+        "#;
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script.to_string()], "jail")
+            .unwrap();
+        let mut service = FeedbackService::with_ipc(SecurityService::new(), client);
+
+        let response = service.generate_feedback(&create_test_tma()).await.unwrap();
+
+        assert_eq!(response.overall_grade, 60.0);
+        assert_eq!(response.criterion_scores[0].score, 6.0);
+        assert_eq!(response.strengths, vec!["Concise".to_string()]);
+        assert_eq!(response.suggestions, vec!["Define terms".to_string()]);
+        assert_eq!(response.seed, Some(5));
+        assert!(response.truncated);
+        assert_eq!(response.model.unwrap().revision, "v2");
+    }
+
+    #[tokio::test]
+    async fn test_old_jail_gets_plain_requests() {
+        use crate::ipc::IPCClientBuilder;
+
+        // A stand-in jail that predates the handshake, and so also streaming
+        // and structured output
+        let script = r#"
+            read line
+            printf '{"type":"Error","payload":{"kind":"invalid_request","message":"unknown variant"}}\n'
+            read line
+            case "$line" in *'"stream":true'* | *'"rubric_json"'*) exit 1 ;; esac
+            id=$(printf '%s' "$line" | sed 's/.*"request_id":"\([^"]*\)".*/\1/')
+            printf '{"type":"FeedbackResponse","payload":{"request_id":"%s","feedback":"Good clear answer.","scores":[]}}\n' "$id"
+        "#;
+        let client = IPCClientBuilder::new("jail")
+            .jail_command("sh")
+            .jail_args(vec!["-c".to_string(), script.to_string()])
+            .build_async()
+            .await
+            .unwrap();
+        let mut service = FeedbackService::with_ipc(SecurityService::new(), client);
+
+        let mut chunks = Vec::new();
+        let response = service
+            .generate_feedback_streaming(&create_test_tma(), |text| chunks.push(text.to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(chunks, vec!["Good clear ", "answer."]);
+        assert_eq!(response.strengths, vec!["Good clear answer.".to_string()]);
+    }
+
+    #[test]
+    fn test_unserved_model_refused() {
+        let security = SecurityService::new();
+        let request = FeedbackRequest::from_tma(&create_test_tma(), &security).unwrap();
+        let capabilities = Capabilities {
+            models: vec!["small".to_string()],
+            ..Capabilities::default()
+        };
+
+        let small = request.clone().with_model("small");
+        assert!(FeedbackService::check_model(&small, &capabilities).is_ok());
+        let large = request.with_model("large");
+        let error = FeedbackService::check_model(&large, &capabilities).unwrap_err();
+        assert!(error.to_string().contains("does not serve model 'large'"));
+    }
+
+    #[tokio::test]
+    async fn test_jail_timeout_is_typed() {
+        // A stand-in jail that is told the request's deadline, still has a
+        // reply to an abandoned request queued, then gives up on this one
+        let script = r#"
+            read line
+            case "$line" in *'"timeout_ms":120000'*) ;; *) exit 1 ;; esac
+            id=$(printf '%s' "$line" | sed 's/.*"request_id":"\([^"]*\)".*/\1/')
+            printf '{"type":"FeedbackResponse","payload":{"request_id":"stale","feedback":"Old","scores":[]}}\n'
+            printf '{"type":"Error","payload":{"request_id":"%s","kind":"timeout","message":"Request timed out after 120000 ms"}}\n' "$id"
+        "#;
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script.to_string()], "jail")
+            .unwrap();
+        let mut service = FeedbackService::with_ipc(SecurityService::new(), client);
+
+        let err = service.generate_feedback(&create_test_tma()).await.unwrap_err();
+
+        assert!(matches!(err.downcast_ref::<IPCError>(), Some(IPCError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_generate_feedback_via_pool() {
+        use crate::ipc::{IPCClientBuilder, PoolConfig};
+
+        // Stand-in jails that answer every request
+        let script = r#"
+            read line
+            printf '{"type":"Hello","payload":{"protocol_version":4}}\n'
+            while read line; do
+                id=$(printf '%s' "$line" | sed 's/.*"request_id":"\([^"]*\)".*/\1/')
+                printf '{"type":"FeedbackResponse","payload":{"request_id":"%s","feedback":"Pooled answer","scores":[]}}\n' "$id"
+            done
+        "#;
+        let builder = IPCClientBuilder::new("jail")
+            .jail_command("sh")
+            .jail_args(vec!["-c".to_string(), script.to_string()]);
+        let pool = JailPool::start(builder, PoolConfig::default()).await.unwrap();
+        let mut service = FeedbackService::with_pool(SecurityService::new(), pool);
+
+        for _ in 0..3 {
+            let response = service.generate_feedback(&create_test_tma()).await.unwrap();
+            assert_eq!(response.feedback, "Pooled answer");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_generate_feedback_batch_via_ipc() {
+        // A stand-in jail that reads every request before answering any,
+        // then replies in reverse order
+        let script = r#"
+            ids=""
+            for i in 1 2 3; do
+                read line
+                id=$(printf '%s' "$line" | sed 's/.*"request_id":"\([^"]*\)".*/\1/')
+                q=$(printf '%s' "$line" | sed 's/.*"question_number":\([0-9]*\).*/\1/')
+                ids="$id:$q $ids"
+            done
+            for entry in $ids; do
+                printf '{"type":"FeedbackResponse","payload":{"request_id":"%s","feedback":"Answer %s","scores":[]}}\n' "${entry%%:*}" "${entry##*:}"
+            done
+        "#;
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script.to_string()], "jail")
+            .unwrap();
+        let mut service = FeedbackService::with_ipc(SecurityService::new(), client);
+
+        let tmas: Vec<TMA> = (1..=3)
+            .map(|question_number| TMA { question_number, ..create_test_tma() })
+            .collect();
+        let responses = service.generate_feedback_batch(&tmas).await;
+
+        let feedback: Vec<String> = responses
+            .into_iter()
+            .map(|response| response.unwrap().feedback)
+            .collect();
+        assert_eq!(feedback, vec!["Answer 1", "Answer 2", "Answer 3"]);
+    }
+
     #[test]
     fn test_extract_suggestions() {
         let feedback = "Good work on your answer.\nConsider adding more examples.\nTry to explain in more detail.";
@@ -372,6 +962,11 @@ fn test_validate_feedback_valid() {
             overall_grade: 80.0,
             suggestions: vec![],
             strengths: vec![],
+            confidence: None,
+            low_confidence_spans: vec![],
+            seed: None,
+            truncated: false,
+            model: None,
         };
 
         let security = SecurityService::new();
@@ -388,6 +983,11 @@ fn test_validate_feedback_empty() {
             overall_grade: 0.0,
             suggestions: vec![],
             strengths: vec![],
+            confidence: None,
+            low_confidence_spans: vec![],
+            seed: None,
+            truncated: false,
+            model: None,
         };
 
         let security = SecurityService::new();
@@ -404,6 +1004,11 @@ fn test_validate_feedback_too_short() {
             overall_grade: 0.0,
             suggestions: vec![],
             strengths: vec![],
+            confidence: None,
+            low_confidence_spans: vec![],
+            seed: None,
+            truncated: false,
+            model: None,
         };
 
         let security = SecurityService::new();
@@ -420,6 +1025,11 @@ fn test_validate_feedback_invalid_grade() {
             overall_grade: 150.0, // Invalid
             suggestions: vec![],
             strengths: vec![],
+            confidence: None,
+            low_confidence_spans: vec![],
+            seed: None,
+            truncated: false,
+            model: None,
         };
 
         let security = SecurityService::new();
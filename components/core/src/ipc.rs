@@ -3,14 +3,25 @@
 //! Provides stdin/stdout communication protocol for interacting with
 //! the isolated AI processing jail.
 
-use crate::feedback::CriterionScore;
-use crate::tma::RubricCriterion;
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use anyhow::{Context, Result};
+use futures::stream::{self, Stream};
+use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Write};
 use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::jail_log::{self, LOG_FORMAT_ENV};
+
+pub use aws_ipc_protocol::{
+    Capabilities, ConfidenceSpan, ErrorKind, FrameCodec, FrameDecoder, Framing, GenerationParams,
+    IPCMessage, InferenceMetadata, PromptOptions, ResponseFormat, DEFAULT_MAX_FRAME_SIZE,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 
 /// Errors that can occur during IPC communication
 #[derive(Debug, Error)]
@@ -30,64 +41,75 @@ pub enum IPCError {
     #[error("Failed to deserialize message: {0}")]
     DeserializationError(String),
 
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+
     #[error("AI jail process crashed")]
     ProcessCrashed,
 
     #[error("Timeout waiting for response")]
     Timeout,
 
+    #[error("Request cancelled")]
+    Cancelled,
+
     #[error("Invalid message format")]
     InvalidMessage,
+
+    #[error("AI jail speaks protocol version {0}, which this client does not support")]
+    IncompatibleProtocol(u32),
+}
+
+/// How long to wait for the jail's reply after a deadline it was told
+/// about has passed
+pub const REPLY_GRACE: Duration = Duration::from_secs(5);
+
+/// Error for an `Error` reply from the AI jail
+///
+/// Requests the jail stopped because they ran out of time or were
+/// cancelled become [`IPCError::Timeout`] and [`IPCError::Cancelled`], so
+/// callers can tell them apart from failed inference.
+pub fn reply_error(kind: ErrorKind, message: &str) -> anyhow::Error {
+    match kind {
+        ErrorKind::Timeout => anyhow::Error::new(IPCError::Timeout).context(message.to_string()),
+        ErrorKind::Cancelled => {
+            anyhow::Error::new(IPCError::Cancelled).context(message.to_string())
+        }
+        _ => anyhow::anyhow!("AI processing error: {}", message),
+    }
+}
+
+static LAST_PING: AtomicI64 = AtomicI64::new(0);
+
+/// Timestamp for a new `Ping`, in microseconds
+///
+/// The jail echoes it in its `Pong`, so no two pings get the same one and
+/// a late `Pong` can never answer a later ping.
+fn ping_timestamp() -> i64 {
+    let now = chrono::Utc::now().timestamp_micros();
+    let last = LAST_PING
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1)))
+        .unwrap_or_else(|last| last);
+    now.max(last + 1)
 }
 
-/// IPC message types
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(tag = "type", content = "payload")]
-pub enum IPCMessage {
-    /// Request feedback generation
-    FeedbackRequest {
-        request_id: String,
-        content: String,
-        rubric: String,
-        criteria: Vec<RubricCriterion>,
-    },
-
-    /// Response with generated feedback
-    FeedbackResponse {
-        request_id: String,
-        feedback: String,
-        scores: Vec<CriterionScore>,
-        overall_grade: f32,
-    },
-
-    /// Health check ping
-    Ping {
-        timestamp: i64,
-    },
-
-    /// Health check pong
-    Pong {
-        timestamp: i64,
-    },
-
-    /// Error message
-    Error {
-        message: String,
-    },
-
-    /// Shutdown request
-    Shutdown,
-
-    /// Acknowledgment
-    Ack {
-        request_id: String,
-    },
+/// What a jail said about itself in the version handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JailInfo {
+    /// The jail's protocol version; 0 if it predates the handshake
+    pub protocol_version: u32,
+    pub capabilities: Capabilities,
 }
 
 /// Synchronous IPC client for communicating with AI jail
 pub struct IPCClient {
+    instance_id: String,
+    jail_info: Option<JailInfo>,
     stdin: Option<ChildStdin>,
     stdout: Option<BufReader<ChildStdout>>,
+    /// Framing for messages sent; replies are read with `decoder`
+    codec: FrameCodec,
+    decoder: FrameDecoder,
     process: Option<Child>,
 }
 
@@ -99,10 +121,14 @@ impl IPCClient {
     /// * `jail_command` - Command to execute the AI jail (e.g., "firejail", "bwrap")
     /// * `jail_args` - Arguments for the jail command
     /// * `ai_script` - Path to the AI processing script
+    ///
+    /// The jail's stderr is re-emitted as tracing events on a background
+    /// thread (see [`crate::jail_log`]).
     pub fn spawn(jail_command: &str, jail_args: &[String], ai_script: &str) -> Result<Self> {
         let mut cmd = Command::new(jail_command);
         cmd.args(jail_args)
             .arg(ai_script)
+            .env(LOG_FORMAT_ENV, "json")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped());
@@ -111,16 +137,49 @@ pub fn spawn(jail_command: &str, jail_args: &[String], ai_script: &str) -> Resul
             .spawn()
             .map_err(|e| IPCError::SpawnError(e.to_string()))?;
 
+        let instance_id = jail_log::next_instance_id();
+        tracing::info!(jail = %instance_id, pid = process.id(), "Started AI jail");
+
         let stdin = process.stdin.take();
         let stdout = process.stdout.take().map(BufReader::new);
+        if let Some(stderr) = process.stderr.take() {
+            jail_log::forward_blocking(instance_id.clone(), stderr);
+        }
 
         Ok(Self {
+            instance_id,
+            jail_info: None,
             stdin,
             stdout,
+            codec: FrameCodec::default(),
+            decoder: FrameDecoder::default(),
             process: Some(process),
         })
     }
 
+    /// ID that tags this jail's logs
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// The jail's version and capabilities, or `None` if no handshake has
+    /// been made
+    pub fn jail_info(&self) -> Option<&JailInfo> {
+        self.jail_info.as_ref()
+    }
+
+    /// Exchange `Hello` messages with the jail
+    ///
+    /// Must be called before any other message is sent. Fails with
+    /// [`IPCError::IncompatibleProtocol`] if the jail's protocol version is
+    /// not supported. A jail too old to know the handshake is recorded as
+    /// version 0 with no optional capabilities.
+    pub fn handshake(&mut self) -> Result<&JailInfo> {
+        self.send(&hello())?;
+        let info = hello_reply(self.receive()?)?;
+        Ok(self.jail_info.insert(info))
+    }
+
     /// Send a message to the AI jail
     pub fn send(&mut self, message: &IPCMessage) -> Result<()> {
         let stdin = self
@@ -128,10 +187,10 @@ pub fn send(&mut self, message: &IPCMessage) -> Result<()> {
             .as_mut()
             .ok_or_else(|| IPCError::WriteError("stdin not available".to_string()))?;
 
-        let json = serde_json::to_string(message)
-            .map_err(|e| IPCError::SerializationError(e.to_string()))?;
+        let frame = encode(&self.codec, message)?;
 
-        writeln!(stdin, "{}", json)
+        stdin
+            .write_all(&frame)
             .map_err(|e| IPCError::WriteError(e.to_string()))?;
 
         stdin
@@ -142,50 +201,86 @@ pub fn send(&mut self, message: &IPCMessage) -> Result<()> {
     }
 
     /// Receive a message from the AI jail (blocking)
+    ///
+    /// A frame larger than the maximum frame size is skipped and reported
+    /// as [`IPCError::InvalidFrame`]; the next call reads the frame after it.
     pub fn receive(&mut self) -> Result<IPCMessage> {
         let stdout = self
             .stdout
             .as_mut()
             .ok_or_else(|| IPCError::ReadError("stdout not available".to_string()))?;
 
-        let mut line = String::new();
-        stdout
-            .read_line(&mut line)
-            .map_err(|e| IPCError::ReadError(e.to_string()))?;
+        let frame = loop {
+            let input = stdout
+                .fill_buf()
+                .map_err(|e| IPCError::ReadError(e.to_string()))?;
 
-        if line.is_empty() {
-            return Err(IPCError::ProcessCrashed.into());
-        }
+            if input.is_empty() {
+                if let Err(e) = self.decoder.finish() {
+                    tracing::warn!("AI jail output ended badly: {}", e);
+                }
+                return Err(IPCError::ProcessCrashed.into());
+            }
+
+            let (consumed, frame) = self.decoder.decode(input);
+            stdout.consume(consumed);
+            if let Some(frame) = frame {
+                break frame;
+            }
+        };
 
-        let message: IPCMessage = serde_json::from_str(&line)
-            .map_err(|e| IPCError::DeserializationError(e.to_string()))?;
+        decode(frame)
+    }
 
-        Ok(message)
+    /// Ask the jail to switch to `framing` with frames of at most
+    /// `max_frame_size` bytes, returning the framing now in use
+    ///
+    /// Must be called before any request is sent. A jail too old to know
+    /// the handshake answers with an error, and the client keeps using
+    /// lines.
+    pub fn negotiate_framing(
+        &mut self,
+        framing: Framing,
+        max_frame_size: usize,
+    ) -> Result<FrameCodec> {
+        self.send(&IPCMessage::SetFraming {
+            framing,
+            max_frame_size,
+        })?;
+
+        if let Some(codec) = framing_reply(self.receive()?, max_frame_size)? {
+            self.codec = codec;
+            self.decoder.set_codec(codec);
+        }
+        Ok(self.codec)
     }
 
     /// Send a ping and wait for pong (health check)
+    ///
+    /// Pongs answering earlier pings are skipped.
     pub fn ping(&mut self) -> Result<()> {
-        let timestamp = chrono::Utc::now().timestamp();
+        let timestamp = ping_timestamp();
         let ping = IPCMessage::Ping { timestamp };
 
         self.send(&ping)?;
 
-        match self.receive()? {
-            IPCMessage::Pong { .. } => Ok(()),
-            IPCMessage::Error { message } => {
-                anyhow::bail!("Ping failed: {}", message)
+        loop {
+            match self.receive()? {
+                IPCMessage::Pong { timestamp: echoed } if echoed == timestamp => return Ok(()),
+                IPCMessage::Pong { .. } => continue,
+                IPCMessage::Error { message, .. } => {
+                    anyhow::bail!("Ping failed: {}", message)
+                }
+                _ => return Err(IPCError::InvalidMessage.into()),
             }
-            _ => Err(IPCError::InvalidMessage.into()),
         }
     }
 
     /// Shutdown the AI jail process
     pub fn shutdown(mut self) -> Result<()> {
         if let Some(stdin) = self.stdin.as_mut() {
-            let shutdown = IPCMessage::Shutdown;
-            let json = serde_json::to_string(&shutdown)
-                .map_err(|e| IPCError::SerializationError(e.to_string()))?;
-            let _ = writeln!(stdin, "{}", json);
+            let frame = encode(&self.codec, &IPCMessage::Shutdown)?;
+            let _ = stdin.write_all(&frame);
             let _ = stdin.flush();
         }
 
@@ -197,50 +292,263 @@ pub fn shutdown(mut self) -> Result<()> {
     }
 }
 
+/// Replies the background reader is waiting to hand out, by request ID
+///
+/// `None` once the jail's stdout has closed, so no new request can start
+/// waiting for a reply that will never come.
+type Waiters = Arc<Mutex<Option<HashMap<String, Waiter>>>>;
+
+/// Pings the background reader is waiting to answer, by the timestamp the
+/// jail echoes in its `Pong`
+///
+/// `None` once the jail's stdout has closed.
+type Pings = Arc<Mutex<Option<HashMap<i64, oneshot::Sender<()>>>>>;
+
+/// Removes a ping from [`Pings`] however waiting for it ends, so a ping
+/// that timed out is not answered by its late `Pong`
+struct PingGuard<'a> {
+    pings: &'a Pings,
+    timestamp: i64,
+}
+
+impl Drop for PingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(pending) = lock_pings(self.pings).as_mut() {
+            pending.remove(&self.timestamp);
+        }
+    }
+}
+
+/// Where to deliver the replies to one in-flight request
+struct Waiter {
+    chunks: mpsc::UnboundedSender<IPCMessage>,
+    reply: oneshot::Sender<IPCMessage>,
+}
+
+/// The jail's stdin and the framing of messages written to it
+struct Writer {
+    stdin: Option<tokio::process::ChildStdin>,
+    codec: FrameCodec,
+}
+
 /// Async IPC client for tokio-based applications
+///
+/// A background task reads every message from the jail and hands each
+/// reply to the request it answers, by request ID, so several requests can
+/// be in flight over one jail process and a late or out-of-order reply is
+/// never taken for the answer to another request. Replies with an ID that
+/// no request is waiting for are logged and dropped. Each `Pong` goes to
+/// the ping whose timestamp it echoes. Other messages that answer no
+/// request are read with [`AsyncIPCClient::receive`].
 pub struct AsyncIPCClient {
-    stdin: Option<tokio::process::ChildStdin>,
-    stdout: Option<AsyncBufReader<tokio::process::ChildStdout>>,
+    instance_id: String,
+    jail_info: Option<JailInfo>,
+    writer: tokio::sync::Mutex<Writer>,
+    waiters: Waiters,
+    pings: Pings,
+    uncorrelated: tokio::sync::Mutex<mpsc::UnboundedReceiver<Result<IPCMessage>>>,
+    reader: Option<tokio::task::JoinHandle<()>>,
     process: Option<tokio::process::Child>,
 }
 
 impl AsyncIPCClient {
     /// Create a new async IPC client by spawning the AI jail process
+    ///
+    /// Must be called from within a tokio runtime, which runs the reader.
+    /// The jail's stderr is re-emitted as tracing events (see
+    /// [`crate::jail_log`]), so it cannot fill up and block the jail, and
+    /// the jail is killed if the client is dropped without being shut down.
     pub fn spawn(jail_command: &str, jail_args: &[String], ai_script: &str) -> Result<Self> {
+        Self::spawn_with_limit(jail_command, jail_args, ai_script, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Spawn the AI jail, rejecting replies larger than `max_frame_size`
+    fn spawn_with_limit(
+        jail_command: &str,
+        jail_args: &[String],
+        ai_script: &str,
+        max_frame_size: usize,
+    ) -> Result<Self> {
         let mut cmd = tokio::process::Command::new(jail_command);
         cmd.args(jail_args)
             .arg(ai_script)
+            .env(LOG_FORMAT_ENV, "json")
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
 
         let mut process = cmd
             .spawn()
             .map_err(|e| IPCError::SpawnError(e.to_string()))?;
 
         let stdin = process.stdin.take();
-        let stdout = process.stdout.take().map(AsyncBufReader::new);
+        let stdout = process
+            .stdout
+            .take()
+            .ok_or_else(|| IPCError::SpawnError("stdout not available".to_string()))?;
+
+        let instance_id = jail_log::next_instance_id();
+        tracing::info!(jail = %instance_id, pid = process.id(), "Started AI jail");
+        if let Some(stderr) = process.stderr.take() {
+            jail_log::forward_async(instance_id.clone(), stderr);
+        }
+
+        let waiters: Waiters = Arc::new(Mutex::new(Some(HashMap::new())));
+        let pings: Pings = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (uncorrelated_tx, uncorrelated) = mpsc::unbounded_channel();
+        let decoder = FrameDecoder::new(FrameCodec {
+            max_frame_size,
+            ..FrameCodec::default()
+        });
+        let reader = tokio::spawn(Self::read_replies(
+            AsyncBufReader::new(stdout),
+            decoder,
+            waiters.clone(),
+            pings.clone(),
+            uncorrelated_tx,
+        ));
 
         Ok(Self {
-            stdin,
-            stdout,
+            instance_id,
+            jail_info: None,
+            writer: tokio::sync::Mutex::new(Writer {
+                stdin,
+                codec: FrameCodec::default(),
+            }),
+            waiters,
+            pings,
+            uncorrelated: tokio::sync::Mutex::new(uncorrelated),
+            reader: Some(reader),
             process: Some(process),
         })
     }
 
+    /// Route every message from the jail until its stdout closes
+    ///
+    /// Frames that are too large or cannot be parsed are reported through
+    /// [`AsyncIPCClient::receive`], as they cannot be tied to a request.
+    async fn read_replies(
+        mut stdout: AsyncBufReader<tokio::process::ChildStdout>,
+        mut decoder: FrameDecoder,
+        waiters: Waiters,
+        pings: Pings,
+        uncorrelated: mpsc::UnboundedSender<Result<IPCMessage>>,
+    ) {
+        loop {
+            let frame = match stdout.fill_buf().await {
+                Ok([]) => {
+                    if let Err(e) = decoder.finish() {
+                        tracing::warn!("AI jail output ended badly: {}", e);
+                    }
+                    break;
+                }
+                Ok(input) => {
+                    let (consumed, frame) = decoder.decode(input);
+                    stdout.consume(consumed);
+                    match frame {
+                        Some(frame) => frame,
+                        None => continue,
+                    }
+                }
+                Err(e) => {
+                    let _ = uncorrelated.send(Err(IPCError::ReadError(e.to_string()).into()));
+                    break;
+                }
+            };
+
+            let message = match decode(frame) {
+                Ok(message) => message,
+                Err(e) => {
+                    let _ = uncorrelated.send(Err(e));
+                    continue;
+                }
+            };
+
+            // The jail switches framing straight after confirming it
+            if let IPCMessage::FramingSet {
+                framing,
+                max_frame_size,
+            } = message
+            {
+                let max_frame_size = max_frame_size.min(decoder.codec().max_frame_size);
+                decoder.set_codec(FrameCodec::new(framing, max_frame_size));
+            }
+
+            if let IPCMessage::Pong { timestamp } = message {
+                match lock_pings(&pings).as_mut().and_then(|p| p.remove(&timestamp)) {
+                    Some(ping) => {
+                        let _ = ping.send(());
+                    }
+                    None => tracing::debug!("Dropping pong for unknown ping {}", timestamp),
+                }
+                continue;
+            }
+
+            let Some(request_id) = message.request_id().map(str::to_string) else {
+                let _ = uncorrelated.send(Ok(message));
+                continue;
+            };
+
+            let mut guard = lock(&waiters);
+            let Some(pending) = guard.as_mut() else { break };
+            match message {
+                IPCMessage::FeedbackChunk { .. } => match pending.get(&request_id) {
+                    Some(waiter) => {
+                        // The caller may only want the final reply
+                        let _ = waiter.chunks.send(message);
+                    }
+                    None => tracing::warn!("Dropping chunk for unknown request {}", request_id),
+                },
+                _ => match pending.remove(&request_id) {
+                    Some(waiter) => {
+                        // The caller may have stopped waiting, e.g. after a timeout
+                        let _ = waiter.reply.send(message);
+                    }
+                    None => tracing::warn!("Dropping reply for unknown request {}", request_id),
+                },
+            }
+        }
+
+        // Requests and pings still waiting will never be answered
+        lock(&waiters).take();
+        lock_pings(&pings).take();
+    }
+
+    /// ID that tags this jail's logs
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    /// The jail's version and capabilities, or `None` if no handshake has
+    /// been made
+    pub fn jail_info(&self) -> Option<&JailInfo> {
+        self.jail_info.as_ref()
+    }
+
+    /// Exchange `Hello` messages with the jail (async)
+    ///
+    /// Must be called before any other message is sent. Fails with
+    /// [`IPCError::IncompatibleProtocol`] if the jail's protocol version is
+    /// not supported. A jail too old to know the handshake is recorded as
+    /// version 0 with no optional capabilities.
+    pub async fn handshake(&mut self) -> Result<&JailInfo> {
+        self.send(&hello()).await?;
+        let info = hello_reply(self.receive().await?)?;
+        Ok(self.jail_info.insert(info))
+    }
+
     /// Send a message to the AI jail (async)
-    pub async fn send(&mut self, message: &IPCMessage) -> Result<()> {
-        let stdin = self
+    pub async fn send(&self, message: &IPCMessage) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        let frame = encode(&writer.codec, message)?;
+        let stdin = writer
             .stdin
             .as_mut()
             .ok_or_else(|| IPCError::WriteError("stdin not available".to_string()))?;
 
-        let json = serde_json::to_string(message)
-            .map_err(|e| IPCError::SerializationError(e.to_string()))?;
-
-        let data = format!("{}\n", json);
         stdin
-            .write_all(data.as_bytes())
+            .write_all(&frame)
             .await
             .map_err(|e| IPCError::WriteError(e.to_string()))?;
 
@@ -252,53 +560,153 @@ pub async fn send(&mut self, message: &IPCMessage) -> Result<()> {
         Ok(())
     }
 
-    /// Receive a message from the AI jail (async)
-    pub async fn receive(&mut self) -> Result<IPCMessage> {
-        let stdout = self
-            .stdout
-            .as_mut()
-            .ok_or_else(|| IPCError::ReadError("stdout not available".to_string()))?;
+    /// Send a request and return a handle to its replies
+    ///
+    /// The request must carry an ID that is not already in flight. Its
+    /// replies are routed to the handle from the moment it is sent, so
+    /// they are kept even if the jail answers before the handle is polled.
+    pub async fn request(&self, message: &IPCMessage) -> Result<PendingReply> {
+        let request_id = message
+            .request_id()
+            .ok_or(IPCError::InvalidMessage)?
+            .to_string();
+
+        let (chunks_tx, chunks) = mpsc::unbounded_channel();
+        let (reply_tx, reply) = oneshot::channel();
+        {
+            let mut guard = lock(&self.waiters);
+            let pending = guard.as_mut().ok_or(IPCError::ProcessCrashed)?;
+            if pending.contains_key(&request_id) {
+                anyhow::bail!("Request {} is already in flight", request_id);
+            }
+            pending.insert(
+                request_id.clone(),
+                Waiter {
+                    chunks: chunks_tx,
+                    reply: reply_tx,
+                },
+            );
+        }
+
+        if let Err(e) = self.send(message).await {
+            if let Some(pending) = lock(&self.waiters).as_mut() {
+                pending.remove(&request_id);
+            }
+            return Err(e);
+        }
+
+        Ok(PendingReply {
+            request_id,
+            chunks,
+            reply,
+        })
+    }
 
-        let mut line = String::new();
-        let bytes_read = stdout
-            .read_line(&mut line)
+    /// Receive the next message from the AI jail that answers no request,
+    /// such as an `Error` without a request ID (async)
+    pub async fn receive(&self) -> Result<IPCMessage> {
+        self.uncorrelated
+            .lock()
+            .await
+            .recv()
             .await
-            .map_err(|e| IPCError::ReadError(e.to_string()))?;
+            .unwrap_or_else(|| Err(IPCError::ProcessCrashed.into()))
+    }
 
-        if bytes_read == 0 {
-            return Err(IPCError::ProcessCrashed.into());
+    /// Ask the jail to switch to `framing` with frames of at most
+    /// `max_frame_size` bytes, returning the framing now in use
+    ///
+    /// Must be called before any request is sent. A jail too old to know
+    /// the handshake answers with an error, and the client keeps using
+    /// lines.
+    pub async fn negotiate_framing(
+        &self,
+        framing: Framing,
+        max_frame_size: usize,
+    ) -> Result<FrameCodec> {
+        // Nothing else may be sent until the jail has switched
+        let mut writer = self.writer.lock().await;
+        let frame = encode(
+            &writer.codec,
+            &IPCMessage::SetFraming {
+                framing,
+                max_frame_size,
+            },
+        )?;
+        let stdin = writer
+            .stdin
+            .as_mut()
+            .ok_or_else(|| IPCError::WriteError("stdin not available".to_string()))?;
+        stdin
+            .write_all(&frame)
+            .await
+            .map_err(|e| IPCError::WriteError(e.to_string()))?;
+        stdin
+            .flush()
+            .await
+            .map_err(|e| IPCError::WriteError(e.to_string()))?;
+
+        if let Some(codec) = framing_reply(self.receive().await?, max_frame_size)? {
+            writer.codec = codec;
         }
+        Ok(writer.codec)
+    }
 
-        let message: IPCMessage = serde_json::from_str(&line)
-            .map_err(|e| IPCError::DeserializationError(e.to_string()))?;
+    /// Whether the jail's stdout has closed, meaning the jail has exited
+    /// and no request sent to it will be answered
+    pub fn is_closed(&self) -> bool {
+        lock(&self.waiters).is_none()
+    }
 
-        Ok(message)
+    /// Number of requests waiting for their final reply
+    pub fn in_flight(&self) -> usize {
+        lock(&self.waiters).as_ref().map_or(0, HashMap::len)
     }
 
-    /// Send a ping and wait for pong (health check)
-    pub async fn ping(&mut self) -> Result<()> {
-        let timestamp = chrono::Utc::now().timestamp();
-        let ping = IPCMessage::Ping { timestamp };
+    /// Cancel requests the caller no longer wants
+    ///
+    /// The jail still answers each of them, with a `cancelled` error if it
+    /// had not finished; that reply goes to the request's [`PendingReply`]
+    /// and is dropped if the handle is gone.
+    pub async fn cancel(&self, request_ids: &[String]) -> Result<()> {
+        for request_id in request_ids {
+            self.send(&IPCMessage::Cancel {
+                request_id: request_id.clone(),
+            })
+            .await?;
+        }
 
-        self.send(&ping).await?;
+        Ok(())
+    }
 
-        match self.receive().await? {
-            IPCMessage::Pong { .. } => Ok(()),
-            IPCMessage::Error { message } => {
-                anyhow::bail!("Ping failed: {}", message)
-            }
-            _ => Err(IPCError::InvalidMessage.into()),
-        }
+    /// Send a ping and wait for its pong (health check)
+    ///
+    /// Only the `Pong` echoing this ping's timestamp answers it, so a pong
+    /// for a ping that timed out cannot pass a later check. Safe to call
+    /// alongside `receive` and other pings.
+    pub async fn ping(&self) -> Result<()> {
+        let timestamp = ping_timestamp();
+        let (pong_tx, pong) = oneshot::channel();
+        lock_pings(&self.pings)
+            .as_mut()
+            .ok_or(IPCError::ProcessCrashed)?
+            .insert(timestamp, pong_tx);
+        let _guard = PingGuard {
+            pings: &self.pings,
+            timestamp,
+        };
+
+        self.send(&IPCMessage::Ping { timestamp }).await?;
+
+        pong.await.map_err(|_| IPCError::ProcessCrashed.into())
     }
 
     /// Shutdown the AI jail process
     pub async fn shutdown(mut self) -> Result<()> {
-        if let Some(stdin) = self.stdin.as_mut() {
-            let shutdown = IPCMessage::Shutdown;
-            let json = serde_json::to_string(&shutdown)
-                .map_err(|e| IPCError::SerializationError(e.to_string()))?;
-            let data = format!("{}\n", json);
-            let _ = stdin.write_all(data.as_bytes()).await;
+        let writer = self.writer.get_mut();
+        if let Some(stdin) = writer.stdin.as_mut() {
+            let frame = encode(&writer.codec, &IPCMessage::Shutdown)?;
+            let _ = stdin.write_all(&frame).await;
             let _ = stdin.flush().await;
         }
 
@@ -310,11 +718,166 @@ pub async fn shutdown(mut self) -> Result<()> {
     }
 }
 
+impl Drop for AsyncIPCClient {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.abort();
+        }
+    }
+}
+
+/// The replies to one request sent with [`AsyncIPCClient::request`]
+pub struct PendingReply {
+    request_id: String,
+    chunks: mpsc::UnboundedReceiver<IPCMessage>,
+    reply: oneshot::Receiver<IPCMessage>,
+}
+
+impl PendingReply {
+    /// ID of the request the replies are for
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// Next `FeedbackChunk` for the request, or `None` once the final
+    /// reply has arrived
+    pub async fn next_chunk(&mut self) -> Option<IPCMessage> {
+        self.chunks.recv().await
+    }
+
+    /// Wait for the final reply, skipping any chunks not yet read
+    ///
+    /// `Error` replies are returned as messages, not errors. Fails if the
+    /// jail exits before answering.
+    pub async fn response(self) -> Result<IPCMessage> {
+        self.reply
+            .await
+            .map_err(|_| IPCError::ProcessCrashed.into())
+    }
+
+    /// Stream the replies to a streaming feedback request
+    ///
+    /// Yields each `FeedbackChunk`, then the final `FeedbackResponse`,
+    /// after which the stream ends. An `Error` reply is yielded as an
+    /// error and also ends the stream.
+    pub fn into_stream(self) -> impl Stream<Item = Result<IPCMessage>> {
+        stream::unfold(Some(self), |reply| async move {
+            let mut reply = reply?;
+            if let Some(chunk) = reply.next_chunk().await {
+                return Some((Ok(chunk), Some(reply)));
+            }
+
+            match reply.response().await {
+                Ok(message @ IPCMessage::FeedbackResponse { .. }) => Some((Ok(message), None)),
+                Ok(IPCMessage::Error { kind, message, .. }) => {
+                    Some((Err(reply_error(kind, &message)), None))
+                }
+                Ok(_) => Some((Err(IPCError::InvalidMessage.into()), None)),
+                Err(e) => Some((Err(e), None)),
+            }
+        })
+    }
+}
+
+/// Serialize a message and frame it for the jail
+fn encode(codec: &FrameCodec, message: &IPCMessage) -> Result<Vec<u8>> {
+    let json =
+        serde_json::to_vec(message).map_err(|e| IPCError::SerializationError(e.to_string()))?;
+    Ok(codec
+        .encode(&json)
+        .map_err(|e| IPCError::InvalidFrame(e.to_string()))?)
+}
+
+/// Parse a frame read from the jail
+fn decode(frame: std::result::Result<Vec<u8>, aws_ipc_protocol::FrameError>) -> Result<IPCMessage> {
+    let frame = frame.map_err(|e| IPCError::InvalidFrame(e.to_string()))?;
+    Ok(serde_json::from_slice(&frame)
+        .map_err(|e| IPCError::DeserializationError(e.to_string()))?)
+}
+
+/// The `Hello` that opens a connection
+fn hello() -> IPCMessage {
+    IPCMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capabilities::default(),
+    }
+}
+
+/// What a jail said about itself in reply to `Hello`
+///
+/// A jail that predates the handshake cannot parse `Hello` and answers
+/// with an `invalid_request` or `unexpected_message` error; any other
+/// error means the jail failed to start.
+fn hello_reply(reply: IPCMessage) -> Result<JailInfo> {
+    match reply {
+        IPCMessage::Hello {
+            protocol_version,
+            capabilities,
+        } => {
+            if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+                return Err(IPCError::IncompatibleProtocol(protocol_version).into());
+            }
+            Ok(JailInfo {
+                protocol_version,
+                capabilities,
+            })
+        }
+        IPCMessage::Error {
+            kind: ErrorKind::InvalidRequest | ErrorKind::UnexpectedMessage,
+            message,
+            ..
+        } => {
+            tracing::warn!(
+                "AI jail predates the version handshake, assuming no optional features: {}",
+                message
+            );
+            Ok(JailInfo {
+                protocol_version: 0,
+                capabilities: Capabilities::default(),
+            })
+        }
+        IPCMessage::Error { kind, message, .. } => {
+            Err(reply_error(kind, &message).context("AI jail failed to start"))
+        }
+        _ => Err(IPCError::InvalidMessage.into()),
+    }
+}
+
+/// The framing a jail agreed to in reply to `SetFraming`, or `None` if it
+/// does not know the handshake
+fn framing_reply(reply: IPCMessage, max_frame_size: usize) -> Result<Option<FrameCodec>> {
+    match reply {
+        IPCMessage::FramingSet {
+            framing,
+            max_frame_size: agreed,
+        } => Ok(Some(FrameCodec::new(framing, agreed.min(max_frame_size)))),
+        IPCMessage::Error { message, .. } => {
+            tracing::warn!("AI jail cannot switch framing, keeping lines: {}", message);
+            Ok(None)
+        }
+        _ => Err(IPCError::InvalidMessage.into()),
+    }
+}
+
+/// Lock the waiters, which stay consistent even if a holder panicked
+fn lock(waiters: &Waiters) -> std::sync::MutexGuard<'_, Option<HashMap<String, Waiter>>> {
+    waiters.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Lock the pings, likewise
+fn lock_pings(pings: &Pings) -> std::sync::MutexGuard<'_, Option<HashMap<i64, oneshot::Sender<()>>>> {
+    pings.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 /// Builder for creating IPC clients with custom configuration
+#[derive(Debug, Clone)]
 pub struct IPCClientBuilder {
     jail_command: String,
     jail_args: Vec<String>,
     ai_script: String,
+    framing: Framing,
+    max_frame_size: usize,
+    require_handshake: bool,
 }
 
 impl IPCClientBuilder {
@@ -328,6 +891,9 @@ pub fn new(ai_script: impl Into<String>) -> Self {
                 "--net=none".to_string(),
             ],
             ai_script: ai_script.into(),
+            framing: Framing::Lines,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            require_handshake: false,
         }
     }
 
@@ -349,15 +915,281 @@ pub fn jail_args(mut self, args: Vec<String>) -> Self {
         self
     }
 
+    /// Ask the jail for `framing` once it has started (default: lines)
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Reject messages from the jail larger than `bytes`, and ask it to do
+    /// the same (default: [`DEFAULT_MAX_FRAME_SIZE`])
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Refuse jails that predate the version handshake, instead of using
+    /// them without any optional features (default: false)
+    pub fn require_handshake(mut self, require: bool) -> Self {
+        self.require_handshake = require;
+        self
+    }
+
+    /// Check the outcome of the handshake against the builder's settings
+    fn accept(&self, info: &JailInfo) -> Result<()> {
+        if self.require_handshake && info.protocol_version == 0 {
+            return Err(IPCError::IncompatibleProtocol(0).into());
+        }
+        Ok(())
+    }
+
     /// Build a synchronous IPC client
+    ///
+    /// The client has made the version handshake (see
+    /// [`IPCClient::handshake`]) and asked for the builder's framing.
     pub fn build_sync(self) -> Result<IPCClient> {
-        IPCClient::spawn(&self.jail_command, &self.jail_args, &self.ai_script)
+        let mut client = IPCClient::spawn(&self.jail_command, &self.jail_args, &self.ai_script)?;
+        client.decoder = FrameDecoder::new(FrameCodec {
+            max_frame_size: self.max_frame_size,
+            ..FrameCodec::default()
+        });
+        self.accept(client.handshake()?)?;
+
+        if self.framing != Framing::Lines {
+            client.negotiate_framing(self.framing, self.max_frame_size)?;
+        }
+        Ok(client)
     }
 
     /// Build an async IPC client
-    pub fn build_async(self) -> Result<AsyncIPCClient> {
-        AsyncIPCClient::spawn(&self.jail_command, &self.jail_args, &self.ai_script)
+    ///
+    /// The client has made the version handshake (see
+    /// [`AsyncIPCClient::handshake`]) and asked for the builder's framing.
+    pub async fn build_async(self) -> Result<AsyncIPCClient> {
+        let mut client = AsyncIPCClient::spawn_with_limit(
+            &self.jail_command,
+            &self.jail_args,
+            &self.ai_script,
+            self.max_frame_size,
+        )?;
+        self.accept(client.handshake().await?)?;
+
+        if self.framing != Framing::Lines {
+            client
+                .negotiate_framing(self.framing, self.max_frame_size)
+                .await?;
+        }
+        Ok(client)
+    }
+}
+
+/// Settings for a [`JailPool`]
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Number of jail processes to keep running
+    pub size: usize,
+    /// How often each jail is pinged
+    pub health_interval: Duration,
+    /// How long a jail may take to answer a ping before it is restarted
+    pub ping_timeout: Duration,
+    /// How long a jail may take to start and answer the handshake before
+    /// it is killed, which includes loading its model
+    pub start_timeout: Duration,
+    /// Wait before the first restart of a failed jail, doubled after each
+    /// further failure
+    pub min_backoff: Duration,
+    /// Longest wait between restarts
+    pub max_backoff: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 2,
+            health_interval: Duration::from_secs(10),
+            ping_timeout: Duration::from_secs(5),
+            start_timeout: Duration::from_secs(120),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Wait before restarting a jail that has failed `failures` times in a
+    /// row
+    pub fn backoff(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.min_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff)
+    }
+}
+
+/// One jail process in a pool, and its restart state
+struct Slot {
+    client: Option<Arc<AsyncIPCClient>>,
+    /// Failed health checks or restarts since the jail was last healthy
+    failures: u32,
+    /// When a dead jail may next be restarted
+    retry_at: Instant,
+}
+
+/// A supervised set of warm AI jail processes
+///
+/// Every jail is started up front, so requests never wait for a model to
+/// load. A background task pings each jail every
+/// [`PoolConfig::health_interval`]; a jail that has exited or does not
+/// answer in time is killed and restarted, waiting longer after each
+/// failure in a row. A restarted jail that has not finished starting after
+/// [`PoolConfig::start_timeout`] is killed and counts as another failure.
+/// Jails with requests in flight are not pinged, since the jail only
+/// answers pings between batches. Clients are handed out round-robin and
+/// only while their jail is running.
+pub struct JailPool {
+    builder: IPCClientBuilder,
+    config: PoolConfig,
+    slots: Vec<Mutex<Slot>>,
+    next: AtomicUsize,
+}
+
+impl JailPool {
+    /// Start `config.size` jails and the task that supervises them
+    ///
+    /// Fails if any jail cannot be spawned, as that usually means the jail
+    /// command itself is misconfigured. The supervisor stops once the pool
+    /// is dropped.
+    pub async fn start(builder: IPCClientBuilder, config: PoolConfig) -> Result<Arc<Self>> {
+        if config.size == 0 {
+            anyhow::bail!("Jail pool needs at least one process");
+        }
+
+        let mut slots = Vec::with_capacity(config.size);
+        for _ in 0..config.size {
+            slots.push(Mutex::new(Slot {
+                client: Some(Arc::new(Self::launch(&builder, &config).await?)),
+                failures: 0,
+                retry_at: Instant::now(),
+            }));
+        }
+
+        let pool = Arc::new(Self {
+            builder,
+            config,
+            slots,
+            next: AtomicUsize::new(0),
+        });
+
+        let supervised = Arc::downgrade(&pool);
+        let interval = pool.config.health_interval;
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.tick().await;
+            loop {
+                ticks.tick().await;
+                let Some(pool) = supervised.upgrade() else { break };
+                pool.check().await;
+            }
+        });
+
+        Ok(pool)
+    }
+
+    /// A client for a running jail
+    pub fn client(&self) -> Result<Arc<AsyncIPCClient>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+
+        (0..self.slots.len())
+            .map(|offset| &self.slots[(start + offset) % self.slots.len()])
+            .find_map(|slot| {
+                let slot = lock_slot(slot);
+                slot.client.clone().filter(|client| !client.is_closed())
+            })
+            .ok_or(IPCError::ProcessCrashed)
+            .context("No AI jail in the pool is running")
+    }
+
+    /// Number of jails currently running
+    pub fn running(&self) -> usize {
+        self.slots
+            .iter()
+            .filter(|slot| lock_slot(slot).client.as_ref().is_some_and(|c| !c.is_closed()))
+            .count()
     }
+
+    /// Ping every jail, then restart those that failed and are due
+    ///
+    /// Run periodically by the supervisor; exposed so a caller that has
+    /// just seen a jail crash can recover without waiting for it.
+    pub async fn check(&self) {
+        for (index, slot) in self.slots.iter().enumerate() {
+            let client = lock_slot(slot).client.clone();
+
+            if let Some(client) = client {
+                // The jail answers pings between batches, so a busy jail
+                // is only checked for having exited
+                let healthy = !client.is_closed()
+                    && (client.in_flight() > 0
+                        || matches!(
+                            tokio::time::timeout(self.config.ping_timeout, client.ping()).await,
+                            Ok(Ok(()))
+                        ));
+
+                let mut slot = lock_slot(slot);
+                if healthy {
+                    slot.failures = 0;
+                    continue;
+                }
+
+                tracing::warn!(
+                    "AI jail {} ({}) failed its health check",
+                    index,
+                    client.instance_id()
+                );
+                // Dropping the last handle kills the process
+                slot.client = None;
+                self.schedule_restart(&mut slot);
+            }
+
+            {
+                let slot = lock_slot(slot);
+                if slot.client.is_some() || Instant::now() < slot.retry_at {
+                    continue;
+                }
+            }
+
+            let restarted = Self::launch(&self.builder, &self.config).await;
+            let mut slot = lock_slot(slot);
+            match restarted {
+                Ok(client) => {
+                    tracing::info!("Restarted AI jail {} as {}", index, client.instance_id());
+                    slot.client = Some(Arc::new(client));
+                }
+                Err(e) => {
+                    tracing::error!("Failed to restart AI jail {}: {:#}", index, e);
+                    self.schedule_restart(&mut slot);
+                }
+            }
+        }
+    }
+
+    /// Start a jail, giving up on it after [`PoolConfig::start_timeout`]
+    async fn launch(builder: &IPCClientBuilder, config: &PoolConfig) -> Result<AsyncIPCClient> {
+        match tokio::time::timeout(config.start_timeout, builder.clone().build_async()).await {
+            Ok(client) => client,
+            // Dropping the unfinished build kills the process
+            Err(_) => Err(IPCError::Timeout).context("AI jail did not finish starting in time"),
+        }
+    }
+
+    fn schedule_restart(&self, slot: &mut Slot) {
+        slot.failures += 1;
+        slot.retry_at = Instant::now() + self.config.backoff(slot.failures);
+    }
+}
+
+fn lock_slot(slot: &Mutex<Slot>) -> std::sync::MutexGuard<'_, Slot> {
+    slot.lock().unwrap_or_else(|e| e.into_inner())
 }
 
 #[cfg(test)]
@@ -386,6 +1218,9 @@ fn test_feedback_request_serialization() {
             content: "test content".to_string(),
             rubric: "test rubric".to_string(),
             criteria: vec![],
+            question_number: 1,
+            params: GenerationParams::default(),
+            prompt: PromptOptions::default(),
         };
 
         let json = serde_json::to_string(&msg).unwrap();
@@ -407,7 +1242,10 @@ fn test_feedback_response_serialization() {
             request_id: "req123".to_string(),
             feedback: "Good work".to_string(),
             scores: vec![],
-            overall_grade: 85.0,
+            overall_grade: Some(85.0),
+            strengths: vec![],
+            suggestions: vec![],
+            metadata: InferenceMetadata::default(),
         };
 
         let json = serde_json::to_string(&msg).unwrap();
@@ -415,7 +1253,7 @@ fn test_feedback_response_serialization() {
 
         match deserialized {
             IPCMessage::FeedbackResponse { overall_grade, .. } => {
-                assert_eq!(overall_grade, 85.0);
+                assert_eq!(overall_grade, Some(85.0));
             }
             _ => panic!("Wrong message type"),
         }
@@ -424,6 +1262,8 @@ fn test_feedback_response_serialization() {
     #[test]
     fn test_error_message_serialization() {
         let msg = IPCMessage::Error {
+            request_id: None,
+            kind: ErrorKind::ProcessingError,
             message: "Something went wrong".to_string(),
         };
 
@@ -431,7 +1271,7 @@ fn test_error_message_serialization() {
         let deserialized: IPCMessage = serde_json::from_str(&json).unwrap();
 
         match deserialized {
-            IPCMessage::Error { message } => {
+            IPCMessage::Error { message, .. } => {
                 assert_eq!(message, "Something went wrong");
             }
             _ => panic!("Wrong message type"),
@@ -450,6 +1290,482 @@ fn test_ipc_builder() {
         assert_eq!(builder.ai_script, "/path/to/ai/script.py");
     }
 
+    /// Async client whose "jail" waits for `requests` lines, replays
+    /// `lines` from a file with `cat`, then reads stdin until it closes
+    fn replay_client(
+        requests: usize,
+        lines: &[IPCMessage],
+    ) -> (AsyncIPCClient, tempfile::NamedTempFile) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        for message in lines {
+            writeln!(file, "{}", serde_json::to_string(message).unwrap()).unwrap();
+        }
+
+        let script = format!(
+            r#"for i in $(seq {}); do read line; done; cat "$0"; cat > /dev/null"#,
+            requests
+        );
+        let path = file.path().to_string_lossy().to_string();
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script], &path).unwrap();
+        (client, file)
+    }
+
+    fn uncorrelated_error(message: &str) -> IPCMessage {
+        IPCMessage::Error {
+            request_id: None,
+            kind: ErrorKind::ProcessingError,
+            message: message.to_string(),
+        }
+    }
+
+    /// Shell that answers the `Ping` in `$line`, echoing its timestamp
+    const PONG: &str = r#"ts=${line##*'"timestamp":'}; printf '{"type":"Pong","payload":{"timestamp":%s}}\n' "${ts%%[!0-9-]*}""#;
+
+    fn feedback_request(request_id: &str) -> IPCMessage {
+        IPCMessage::FeedbackRequest {
+            request_id: request_id.to_string(),
+            content: "content".to_string(),
+            rubric: "rubric".to_string(),
+            criteria: vec![],
+            question_number: 1,
+            params: GenerationParams::default(),
+            prompt: PromptOptions::default(),
+        }
+    }
+
+    fn chunk(request_id: &str, text: &str, tokens_generated: usize) -> IPCMessage {
+        IPCMessage::FeedbackChunk {
+            request_id: request_id.to_string(),
+            text: text.to_string(),
+            tokens_generated,
+        }
+    }
+
+    fn response(request_id: &str, feedback: &str) -> IPCMessage {
+        IPCMessage::FeedbackResponse {
+            request_id: request_id.to_string(),
+            feedback: feedback.to_string(),
+            scores: vec![],
+            overall_grade: None,
+            strengths: vec![],
+            suggestions: vec![],
+            metadata: InferenceMetadata::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_feedback_stream_yields_chunks_then_response() {
+        use futures::StreamExt;
+
+        let (client, _file) = replay_client(
+            1,
+            &[
+                chunk("req1", "Good ", 1),
+                uncorrelated_error("jail busy"),
+                chunk("req1", "work", 2),
+                response("req1", "Good work"),
+                chunk("req2", "never read", 1),
+            ],
+        );
+
+        let reply = client.request(&feedback_request("req1")).await.unwrap();
+        let messages: Vec<IPCMessage> = reply.into_stream().map(|m| m.unwrap()).collect().await;
+
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(&messages[0], IPCMessage::FeedbackChunk { text, .. } if text == "Good "));
+        assert!(matches!(&messages[1], IPCMessage::FeedbackChunk { text, .. } if text == "work"));
+        assert!(matches!(&messages[2], IPCMessage::FeedbackResponse { .. }));
+
+        // Messages that answer no request are left for `receive`
+        assert!(matches!(
+            client.receive().await.unwrap(),
+            IPCMessage::Error { request_id: None, message, .. } if message == "jail busy"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_feedback_stream_ends_on_error() {
+        use futures::StreamExt;
+
+        let (client, _file) = replay_client(
+            1,
+            &[
+                chunk("req1", "Partial", 1),
+                IPCMessage::Error {
+                    request_id: Some("req1".to_string()),
+                    kind: ErrorKind::ProcessingError,
+                    message: "out of memory".to_string(),
+                },
+            ],
+        );
+
+        let reply = client.request(&feedback_request("req1")).await.unwrap();
+        let results: Vec<Result<IPCMessage>> = reply.into_stream().collect().await;
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert!(results[1].as_ref().unwrap_err().to_string().contains("out of memory"));
+    }
+
+    #[tokio::test]
+    async fn test_replies_routed_by_request_id() {
+        // Answered out of order, with a stale reply to a request never sent
+        let (client, _file) = replay_client(
+            2,
+            &[
+                response("stale", "Old"),
+                response("req2", "Second"),
+                response("req1", "First"),
+            ],
+        );
+
+        let first = client.request(&feedback_request("req1")).await.unwrap();
+        let second = client.request(&feedback_request("req2")).await.unwrap();
+
+        let (first, second) = tokio::join!(first.response(), second.response());
+        let feedback = |message: IPCMessage| match message {
+            IPCMessage::FeedbackResponse { feedback, .. } => feedback,
+            other => panic!("Unexpected reply: {:?}", other),
+        };
+        assert_eq!(feedback(first.unwrap()), "First");
+        assert_eq!(feedback(second.unwrap()), "Second");
+    }
+
+    #[tokio::test]
+    async fn test_request_id_in_flight_rejected() {
+        let (client, _file) = replay_client(1, &[]);
+
+        let _pending = client.request(&feedback_request("req1")).await.unwrap();
+
+        assert!(client.request(&feedback_request("req1")).await.is_err());
+        assert!(client.request(&IPCMessage::Ping { timestamp: 0 }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jail_exit_fails_pending_requests() {
+        let script = "read line".to_string();
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script], "jail").unwrap();
+
+        let pending = client.request(&feedback_request("req1")).await.unwrap();
+
+        let err = pending.response().await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<IPCError>(), Some(IPCError::ProcessCrashed)));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_reply_goes_to_its_request() {
+        let (client, _file) = replay_client(
+            2,
+            &[
+                chunk("req1", "Partial", 1),
+                IPCMessage::Error {
+                    request_id: Some("req1".to_string()),
+                    kind: ErrorKind::Cancelled,
+                    message: "Request cancelled".to_string(),
+                },
+                uncorrelated_error("jail busy"),
+            ],
+        );
+
+        // The caller gave up on the request, dropping its handle
+        drop(client.request(&feedback_request("req1")).await.unwrap());
+        client.cancel(&["req1".to_string()]).await.unwrap();
+
+        assert!(matches!(
+            client.receive().await.unwrap(),
+            IPCMessage::Error { request_id: None, message, .. } if message == "jail busy"
+        ));
+    }
+
+    /// Builder for stand-in jails that answer pings and exit on their
+    /// first feedback request
+    fn crashing_jail() -> IPCClientBuilder {
+        let script = format!(
+            r#"
+            while read line; do
+                case "$line" in
+                    *'"Hello"'*) printf '{{"type":"Hello","payload":{{"protocol_version":4}}}}\n' ;;
+                    *'"Ping"'*) {PONG} ;;
+                    *'"FeedbackRequest"'*) exit 1 ;;
+                esac
+            done
+        "#
+        );
+        IPCClientBuilder::new("jail")
+            .jail_command("sh")
+            .jail_args(vec!["-c".to_string(), script])
+    }
+
+    fn pool_config(size: usize) -> PoolConfig {
+        PoolConfig {
+            size,
+            // Checks are driven by the tests
+            health_interval: Duration::from_secs(3600),
+            ping_timeout: Duration::from_secs(5),
+            start_timeout: Duration::from_secs(5),
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pool_restarts_crashed_jail() {
+        let pool = JailPool::start(crashing_jail(), pool_config(1)).await.unwrap();
+        pool.check().await;
+        assert_eq!(pool.running(), 1);
+
+        let client = pool.client().unwrap();
+        let pending = client.request(&feedback_request("req1")).await.unwrap();
+        assert!(pending.response().await.is_err());
+        assert!(pool.client().is_err());
+
+        // Not restarted until the backoff has passed
+        pool.check().await;
+        assert_eq!(pool.running(), 0);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        pool.check().await;
+        assert_eq!(pool.running(), 1);
+        pool.client().unwrap().ping().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pool_gives_up_on_jail_that_never_starts() {
+        // Starts normally the first time, then never answers Hello again
+        let dir = tempfile::tempdir().unwrap();
+        let started = dir.path().join("started");
+        let script = format!(
+            r#"
+            [ -e '{marker}' ] && exec sleep 3600
+            touch '{marker}'
+            while read line; do
+                case "$line" in
+                    *'"Hello"'*) printf '{{"type":"Hello","payload":{{"protocol_version":4}}}}\n' ;;
+                    *'"FeedbackRequest"'*) exit 1 ;;
+                esac
+            done
+        "#,
+            marker = started.display()
+        );
+        let builder = IPCClientBuilder::new("jail")
+            .jail_command("sh")
+            .jail_args(vec!["-c".to_string(), script]);
+        let config = PoolConfig {
+            start_timeout: Duration::from_millis(200),
+            ..pool_config(1)
+        };
+        let pool = JailPool::start(builder, config).await.unwrap();
+
+        let pending = pool.client().unwrap().request(&feedback_request("req1")).await.unwrap();
+        assert!(pending.response().await.is_err());
+
+        // The hung restart is abandoned, and retried after the backoff
+        for _ in 0..2 {
+            tokio::time::timeout(Duration::from_secs(2), pool.check())
+                .await
+                .unwrap();
+            assert_eq!(pool.running(), 0);
+            tokio::time::sleep(Duration::from_millis(60)).await;
+        }
+        assert_eq!(lock_slot(&pool.slots[0]).failures, 2);
+    }
+
+    #[tokio::test]
+    async fn test_pool_hands_out_jails_round_robin() {
+        let pool = JailPool::start(crashing_jail(), pool_config(2)).await.unwrap();
+
+        let first = pool.client().unwrap();
+        let second = pool.client().unwrap();
+
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&first, &pool.client().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_pool_fails_to_start_without_jail_command() {
+        let builder = IPCClientBuilder::new("jail").jail_command("/nonexistent/jail");
+
+        assert!(JailPool::start(builder, pool_config(1)).await.is_err());
+    }
+
+    #[test]
+    fn test_pool_backoff_doubles_up_to_max() {
+        let config = pool_config(1);
+
+        assert_eq!(config.backoff(1), Duration::from_millis(50));
+        assert_eq!(config.backoff(2), Duration::from_millis(100));
+        assert_eq!(config.backoff(3), Duration::from_millis(200));
+        assert_eq!(config.backoff(10), Duration::from_secs(1));
+        assert_eq!(config.backoff(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_late_pong_does_not_answer_next_ping() {
+        // Answers the first ping late and never answers the second
+        let script = format!("read line; sleep 0.5; {PONG}; cat > /dev/null");
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script], "jail").unwrap();
+
+        let first = tokio::time::timeout(Duration::from_millis(100), client.ping()).await;
+        assert!(first.is_err());
+
+        let second = tokio::time::timeout(Duration::from_secs(1), client.ping()).await;
+        assert!(second.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ping_alongside_receive() {
+        let script = format!(
+            r#"read line; printf '{{"type":"Error","payload":{{"kind":"processing_error","message":"jail busy"}}}}\n'; {PONG}; cat > /dev/null"#
+        );
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script], "jail").unwrap();
+
+        let (pinged, received) = tokio::join!(client.ping(), client.receive());
+
+        pinged.unwrap();
+        assert!(matches!(received.unwrap(), IPCMessage::Error { request_id: None, .. }));
+    }
+
+    #[test]
+    fn test_jail_stderr_drained() {
+        // Asks for JSON logs, then writes more than a pipe buffer of them
+        // before answering
+        let script = r#"
+            [ "$AI_JAIL_LOG_FORMAT" = json ] || exit 1
+            yes '{"level":"INFO","fields":{"message":"Loading"}}' | head -n 20000 >&2
+            read line
+        "#;
+        let script = format!("{script}{PONG}");
+        let mut client = IPCClient::spawn("sh", &["-c".to_string(), script], "jail").unwrap();
+
+        client.ping().unwrap();
+        assert!(client.instance_id().starts_with("jail-"));
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_framing_applies_to_replies() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        let shutdown = serde_json::to_vec(&IPCMessage::Shutdown).unwrap();
+        file.write_all(&FrameCodec::new(Framing::LengthPrefixed, 64).encode(&shutdown).unwrap())
+            .unwrap();
+
+        // Agrees to a smaller limit than asked for, then replays the file
+        let script = r#"
+            read line
+            printf '{"type":"FramingSet","payload":{"framing":"length_prefixed","max_frame_size":64}}\n'
+            cat "$0"
+            cat > /dev/null
+        "#;
+        let path = file.path().to_string_lossy().to_string();
+        let client = AsyncIPCClient::spawn("sh", &["-c".to_string(), script.to_string()], &path)
+            .unwrap();
+
+        let codec = client.negotiate_framing(Framing::LengthPrefixed, 1024).await.unwrap();
+
+        assert_eq!(codec, FrameCodec::new(Framing::LengthPrefixed, 64));
+        assert!(matches!(client.receive().await.unwrap(), IPCMessage::Shutdown));
+    }
+
+    /// Builder for stand-in jails that answer every message with `reply`
+    fn answering_jail(reply: &str) -> IPCClientBuilder {
+        let script = r#"while read line; do printf '%s\n' "$0"; done"#;
+        IPCClientBuilder::new(reply)
+            .jail_command("sh")
+            .jail_args(vec!["-c".to_string(), script.to_string()])
+    }
+
+    /// What a jail too old to know `Hello` or `SetFraming` answers them with
+    const UNKNOWN_VARIANT: &str =
+        r#"{"type":"Error","payload":{"kind":"invalid_request","message":"unknown variant"}}"#;
+
+    #[tokio::test]
+    async fn test_old_jail_keeps_lines() {
+        let client = answering_jail(UNKNOWN_VARIANT)
+            .framing(Framing::LengthPrefixed)
+            .build_async()
+            .await
+            .unwrap();
+
+        assert_eq!(client.writer.lock().await.codec, FrameCodec::default());
+        let info = client.jail_info().unwrap();
+        assert_eq!(info.protocol_version, 0);
+        assert_eq!(info.capabilities, Capabilities::default());
+    }
+
+    #[tokio::test]
+    async fn test_old_jail_refused_when_handshake_required() {
+        let result = answering_jail(UNKNOWN_VARIANT)
+            .require_handshake(true)
+            .build_async()
+            .await;
+
+        let error = result.err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<IPCError>(),
+            Some(IPCError::IncompatibleProtocol(0))
+        ));
+    }
+
+    #[test]
+    fn test_handshake_records_capabilities() {
+        let hello = IPCMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities {
+                streaming: true,
+                max_batch_size: 4,
+                grammar: false,
+                models: vec!["small".to_string()],
+            },
+        };
+        let client = answering_jail(&serde_json::to_string(&hello).unwrap())
+            .build_sync()
+            .unwrap();
+
+        let info = client.jail_info().unwrap();
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(info.capabilities.max_batch_size, 4);
+        assert_eq!(info.capabilities.models, vec!["small"]);
+    }
+
+    #[tokio::test]
+    async fn test_newer_jail_refused() {
+        let hello = IPCMessage::Hello {
+            protocol_version: PROTOCOL_VERSION + 1,
+            capabilities: Capabilities::default(),
+        };
+        let result = answering_jail(&serde_json::to_string(&hello).unwrap())
+            .build_async()
+            .await;
+
+        let error = result.err().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<IPCError>(),
+            Some(IPCError::IncompatibleProtocol(v)) if *v == PROTOCOL_VERSION + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_jail_failing_to_start_is_not_taken_for_old() {
+        let error = r#"{"type":"Error","payload":{"kind":"initialization_error","message":"Hash mismatch"}}"#;
+        let result = answering_jail(error).build_async().await;
+
+        let error = format!("{:#}", result.err().unwrap());
+        assert!(error.contains("failed to start"));
+        assert!(error.contains("Hash mismatch"));
+    }
+
+    #[test]
+    fn test_reply_error_is_typed() {
+        let timeout = reply_error(ErrorKind::Timeout, "Request timed out after 10 ms");
+        assert!(matches!(timeout.downcast_ref::<IPCError>(), Some(IPCError::Timeout)));
+        assert!(timeout.to_string().contains("10 ms"));
+
+        let cancelled = reply_error(ErrorKind::Cancelled, "Request cancelled");
+        assert!(matches!(cancelled.downcast_ref::<IPCError>(), Some(IPCError::Cancelled)));
+
+        let failed = reply_error(ErrorKind::ProcessingError, "out of memory");
+        assert!(failed.downcast_ref::<IPCError>().is_none());
+    }
+
     #[test]
     fn test_shutdown_message() {
         let msg = IPCMessage::Shutdown;
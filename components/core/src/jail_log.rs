@@ -0,0 +1,237 @@
+//! Logs from the AI jail
+//!
+//! The jail writes its tracing output to stderr, one JSON object per line
+//! when started with `AI_JAIL_LOG_FORMAT=json`, which the IPC clients do.
+//! Each line is re-emitted here as a tracing event with target `ai_jail`,
+//! tagged with the jail's instance ID. The jail works on student answers,
+//! so every message first goes through
+//! [`academic_shared::logging::sanitize_log_message`].
+
+use academic_shared::logging::sanitize_log_message;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+use tracing::Level;
+
+/// Environment variable that selects the jail's log format
+pub const LOG_FORMAT_ENV: &str = "AI_JAIL_LOG_FORMAT";
+
+/// Longest stderr line re-emitted; the rest of a longer line is dropped
+pub const MAX_LINE_BYTES: u64 = 16 * 1024;
+
+static NEXT_INSTANCE: AtomicU64 = AtomicU64::new(1);
+
+/// A new ID to tell a jail process's logs apart from its siblings'
+pub fn next_instance_id() -> String {
+    format!("jail-{}", NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed))
+}
+
+/// One line of the jail's JSON log output
+#[derive(Debug, Deserialize)]
+struct LogLine {
+    level: String,
+    #[serde(default)]
+    fields: BTreeMap<String, Value>,
+    filename: Option<String>,
+    line_number: Option<u32>,
+}
+
+/// A sanitized log line from the jail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JailLog {
+    pub level: Level,
+    pub message: String,
+    /// Source file and line in the jail that logged it
+    pub location: Option<String>,
+}
+
+impl JailLog {
+    /// Parse a line of the jail's stderr
+    ///
+    /// Fields other than `message` are appended as `key=value`. Lines that
+    /// are not JSON log lines, such as a panic message, are kept whole as
+    /// warnings.
+    pub fn parse(line: &str) -> Self {
+        let Ok(mut log) = serde_json::from_str::<LogLine>(line) else {
+            return Self {
+                level: Level::WARN,
+                message: sanitize_log_message(line.trim_end()),
+                location: None,
+            };
+        };
+
+        let mut message = match log.fields.remove("message") {
+            Some(Value::String(message)) => message,
+            Some(other) => other.to_string(),
+            None => String::new(),
+        };
+        for (key, value) in &log.fields {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                other => other.to_string(),
+            };
+            message.push_str(&format!(" {}={}", key, value));
+        }
+
+        Self {
+            level: log.level.parse().unwrap_or(Level::INFO),
+            message: sanitize_log_message(&message),
+            location: log.filename.map(|file| match log.line_number {
+                Some(line) => format!("{}:{}", file, line),
+                None => file,
+            }),
+        }
+    }
+
+    /// Re-emit the line as a tracing event from jail `instance`
+    pub fn emit(&self, instance: &str) {
+        let location = self.location.as_deref();
+        match self.level {
+            Level::ERROR => {
+                tracing::error!(target: "ai_jail", jail = instance, location, "{}", self.message)
+            }
+            Level::WARN => {
+                tracing::warn!(target: "ai_jail", jail = instance, location, "{}", self.message)
+            }
+            Level::INFO => {
+                tracing::info!(target: "ai_jail", jail = instance, location, "{}", self.message)
+            }
+            Level::DEBUG => {
+                tracing::debug!(target: "ai_jail", jail = instance, location, "{}", self.message)
+            }
+            Level::TRACE => {
+                tracing::trace!(target: "ai_jail", jail = instance, location, "{}", self.message)
+            }
+        }
+    }
+}
+
+/// Tracks whether a line was cut at [`MAX_LINE_BYTES`], so the rest of it
+/// is dropped instead of being logged as further lines
+#[derive(Default)]
+struct LineSplitter {
+    truncated: bool,
+}
+
+impl LineSplitter {
+    /// Take one capped read; returns the line to log, if any
+    fn next(&mut self, bytes: &[u8]) -> Option<JailLog> {
+        let log = (!self.truncated)
+            .then(|| JailLog::parse(&String::from_utf8_lossy(bytes)));
+        self.truncated = bytes.last() != Some(&b'\n');
+        log
+    }
+}
+
+/// Parse every line of `reader` until EOF
+///
+/// Lines are capped at [`MAX_LINE_BYTES`] and need not be UTF-8, so a
+/// misbehaving jail can never make us stop draining its stderr.
+fn forward_lines(mut reader: impl BufRead, mut emit: impl FnMut(JailLog)) {
+    let mut splitter = LineSplitter::default();
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        match (&mut reader).take(MAX_LINE_BYTES).read_until(b'\n', &mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+        if let Some(log) = splitter.next(&line) {
+            emit(log);
+        }
+    }
+}
+
+/// Re-emit a jail's stderr until it closes, on a background thread
+pub fn forward_blocking(instance: String, stderr: std::process::ChildStderr) {
+    std::thread::spawn(move || {
+        forward_lines(std::io::BufReader::new(stderr), |log| log.emit(&instance));
+    });
+}
+
+/// Re-emit a jail's stderr until it closes, on a tokio task
+pub fn forward_async(instance: String, stderr: tokio::process::ChildStderr) {
+    tokio::spawn(async move {
+        let mut reader = tokio::io::BufReader::new(stderr);
+        let mut splitter = LineSplitter::default();
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+            match (&mut reader).take(MAX_LINE_BYTES).read_until(b'\n', &mut line).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+            if let Some(log) = splitter.next(&line) {
+                log.emit(&instance);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_line_parsed_and_sanitized() {
+        let line = r#"{"timestamp":"2026-01-01T00:00:00Z","level":"WARN","fields":{"message":"Slow answer from student@example.com"},"filename":"src/main.rs","line_number":42}"#;
+
+        let log = JailLog::parse(line);
+
+        assert_eq!(log.level, Level::WARN);
+        assert_eq!(log.message, "Slow answer from [EMAIL_REDACTED]");
+        assert_eq!(log.location.as_deref(), Some("src/main.rs:42"));
+    }
+
+    #[test]
+    fn test_extra_fields_appended() {
+        let line = r#"{"level":"DEBUG","fields":{"message":"Batch decoded","rows":4,"model":"small"}}"#;
+
+        let log = JailLog::parse(line);
+
+        assert_eq!(log.level, Level::DEBUG);
+        assert_eq!(log.message, "Batch decoded model=small rows=4");
+        assert_eq!(log.location, None);
+    }
+
+    #[test]
+    fn test_plain_line_kept_as_warning() {
+        let log = JailLog::parse("thread 'main' panicked at 'no reply for a@b.com'\n");
+
+        assert_eq!(log.level, Level::WARN);
+        assert_eq!(log.message, "thread 'main' panicked at 'no reply for [EMAIL_REDACTED]'");
+    }
+
+    #[test]
+    fn test_forwarding_survives_bad_lines() {
+        let mut input = b"{\"level\":\"INFO\",\"fields\":{\"message\":\"first\"}}\n".to_vec();
+        input.extend_from_slice(b"bad \xff\xfe bytes\n");
+        input.extend(std::iter::repeat_n(b'x', MAX_LINE_BYTES as usize * 2 + 10));
+        input.extend_from_slice(b"\n{\"level\":\"ERROR\",\"fields\":{\"message\":\"last\"}}\n");
+
+        let mut logs = Vec::new();
+        forward_lines(&input[..], |log| logs.push(log));
+
+        let messages: Vec<&str> = logs.iter().map(|log| log.message.as_str()).collect();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0], "first");
+        assert_eq!(messages[1], "bad \u{fffd}\u{fffd} bytes");
+        assert_eq!(messages[2].len(), MAX_LINE_BYTES as usize);
+        assert_eq!(messages[3], "last");
+        assert_eq!(logs[3].level, Level::ERROR);
+    }
+
+    #[test]
+    fn test_instance_ids_unique() {
+        assert_ne!(next_instance_id(), next_instance_id());
+    }
+}
@@ -33,16 +33,24 @@
 
 pub mod events;
 pub mod tma;
+pub mod tma_aggregate;
 pub mod security;
 pub mod feedback;
 pub mod ipc;
+pub mod jail_log;
 
 // Re-export main types for convenience
-pub use events::{Event, EventStore, EventType, LmdbEventStore};
+pub use events::{
+    Aggregate, Event, EventProjection, EventStore, EventStoreError, EventType, LmdbEventStore,
+    Snapshot,
+};
 pub use tma::{TMA, TMAStatus, ValidationError};
-pub use security::{SecurityService, AnonymizationResult, PIIDetectionResult};
+pub use tma_aggregate::{TMAAggregate, TMACommand, TMARepository, TransitionError};
+pub use security::{SecurityService, AnonymizationResult, OutputValidator, PIIDetectionResult};
 pub use feedback::{FeedbackRequest, FeedbackResponse, FeedbackService};
-pub use ipc::{IPCClient, AsyncIPCClient, IPCMessage, IPCError};
+pub use ipc::{
+    IPCClient, AsyncIPCClient, IPCMessage, IPCError, JailInfo, JailPool, PendingReply, PoolConfig,
+};
 
 /// Result type used throughout the library
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
@@ -272,6 +272,16 @@ pub fn validate_output(&self, output: &str) -> Result<()> {
         Ok(())
     }
 
+    /// Validate AI output piece by piece as it is generated
+    ///
+    /// See [`OutputValidator`].
+    pub fn output_validator(&self) -> OutputValidator<'_> {
+        OutputValidator {
+            security: self,
+            held: String::new(),
+        }
+    }
+
     /// Create a redaction report for audit purposes
     pub fn create_redaction_report(&self, content: &str) -> RedactionReport {
         let detection = self.detect_pii(content);
@@ -286,6 +296,62 @@ pub fn create_redaction_report(&self, content: &str) -> RedactionReport {
     }
 }
 
+/// Checks streamed AI output for PII before any of it is shown
+///
+/// Text is handed back only once it has passed
+/// [`SecurityService::validate_output`] and nothing generated later can
+/// make it part of a match. Every pattern is either a single token without
+/// whitespace (emails, URLs, student IDs) or a run of tokens that each
+/// contain a digit (phone numbers, postal codes). So the trailing token,
+/// and any tokens with digits or `+` just before it, are held back until
+/// more text arrives.
+pub struct OutputValidator<'a> {
+    security: &'a SecurityService,
+    /// Text received but not yet handed back
+    held: String,
+}
+
+impl OutputValidator<'_> {
+    /// Add generated text and return the part that is now safe to show
+    ///
+    /// Fails if that part contains PII, in which case none of it is
+    /// returned.
+    pub fn push(&mut self, text: &str) -> Result<String> {
+        self.held.push_str(text);
+
+        let end = Self::settled_len(&self.held);
+        self.security.validate_output(&self.held[..end])?;
+
+        Ok(self.held.drain(..end).collect())
+    }
+
+    /// Check and return the text still held once generation has finished
+    pub fn finish(self) -> Result<String> {
+        self.security.validate_output(&self.held)?;
+        Ok(self.held)
+    }
+
+    /// Length of the prefix of `text` that later text cannot extend into PII
+    fn settled_len(text: &str) -> usize {
+        let is_token_char = |c: char| !c.is_whitespace();
+
+        // The last token may still be growing
+        let mut end = text.trim_end_matches(is_token_char).len();
+
+        // Digits can continue across whitespace, as in "07700 900123"
+        loop {
+            let before = text[..end].trim_end();
+            let start = before.trim_end_matches(is_token_char).len();
+            let token = &before[start..];
+
+            if token.is_empty() || !token.contains(|c: char| c.is_ascii_digit() || c == '+') {
+                return end;
+            }
+            end = start;
+        }
+    }
+}
+
 impl Default for SecurityService {
     fn default() -> Self {
         Self::new()
@@ -419,6 +485,30 @@ fn test_validate_output_with_pii() {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_output_validator_catches_pii_split_across_chunks() {
+        let security = SecurityService::new();
+        let mut validator = security.output_validator();
+
+        assert_eq!(validator.push("Good work. Email jo").unwrap(), "Good work. Email ");
+        assert_eq!(validator.push("hn@exam").unwrap(), "");
+        assert!(validator.push("ple.com for help").is_err());
+    }
+
+    #[test]
+    fn test_output_validator_holds_back_digit_runs() {
+        let security = SecurityService::new();
+        let mut validator = security.output_validator();
+
+        assert_eq!(validator.push("Call 07700 ").unwrap(), "Call ");
+        assert!(validator.push("900123 today and").is_err());
+
+        let mut validator = security.output_validator();
+        assert_eq!(validator.push("Scored 42 marks").unwrap(), "Scored ");
+        assert_eq!(validator.push(" out of 50.").unwrap(), "42 marks out of ");
+        assert_eq!(validator.finish().unwrap(), "50.");
+    }
+
     #[test]
     fn test_create_redaction_report() {
         let security = SecurityService::new();
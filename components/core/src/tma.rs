@@ -7,6 +7,8 @@
 use thiserror::Error;
 use uuid::Uuid;
 
+pub use aws_ipc_protocol::RubricCriterion;
+
 /// Errors that can occur during TMA validation
 #[derive(Debug, Error)]
 pub enum ValidationError {
@@ -37,7 +39,7 @@ pub enum ValidationError {
 pub enum TMAStatus {
     /// TMA has been submitted but not yet processed
     Submitted,
-    /// TMA is being anonymized
+    /// TMA is being anonymized, or has been and awaits feedback
     Anonymizing,
     /// TMA is being processed by AI
     Processing,
@@ -49,6 +51,26 @@ pub enum TMAStatus {
     Failed,
 }
 
+impl TMAStatus {
+    /// Whether a TMA may move from this status to `next`
+    ///
+    /// Processing may be retried after a failure; graded and failed TMAs
+    /// otherwise go no further.
+    pub fn can_transition_to(self, next: TMAStatus) -> bool {
+        use TMAStatus::*;
+
+        matches!(
+            (self, next),
+            (Submitted, Anonymizing)
+                | (Anonymizing, Processing)
+                | (Failed, Processing)
+                | (Processing, FeedbackGenerated)
+                | (FeedbackGenerated, Graded)
+                | (Submitted | Anonymizing | Processing | FeedbackGenerated, Failed)
+        )
+    }
+}
+
 /// A Tutor-Marked Assignment submission
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TMA {
@@ -185,7 +207,7 @@ fn is_valid_module_code(code: &str) -> bool {
         let mut has_digits = false;
 
         // Check for letters at the start
-        while let Some(c) = chars.next() {
+        for c in chars.by_ref() {
             if c.is_ascii_alphabetic() {
                 has_letters = true;
             } else if c.is_ascii_digit() {
@@ -269,14 +291,6 @@ pub fn sanitized_content(&self) -> String {
     }
 }
 
-/// A single criterion from a rubric
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RubricCriterion {
-    pub number: u32,
-    pub description: String,
-    pub max_marks: Option<f32>,
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -396,6 +410,17 @@ fn test_set_status() {
         assert_eq!(tma.status, TMAStatus::Processing);
     }
 
+    #[test]
+    fn test_status_transitions() {
+        assert!(TMAStatus::Submitted.can_transition_to(TMAStatus::Anonymizing));
+        assert!(TMAStatus::Processing.can_transition_to(TMAStatus::Failed));
+        assert!(TMAStatus::Failed.can_transition_to(TMAStatus::Processing));
+
+        assert!(!TMAStatus::Submitted.can_transition_to(TMAStatus::Processing));
+        assert!(!TMAStatus::Graded.can_transition_to(TMAStatus::Failed));
+        assert!(!TMAStatus::Failed.can_transition_to(TMAStatus::Anonymizing));
+    }
+
     #[test]
     fn test_set_anonymized_id() {
         let mut tma = TMA::new(
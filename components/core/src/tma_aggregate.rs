@@ -0,0 +1,416 @@
+//! Event-Sourced TMA Aggregate
+//!
+//! Commands against a TMA are checked against its current [`TMAStatus`]
+//! and recorded as events; the aggregate's state is only ever rebuilt by
+//! replaying those events from the [`EventStore`](crate::EventStore).
+
+use crate::events::{Aggregate, Event, EventProjection, EventType, RubricScore};
+use crate::tma::{TMAStatus, ValidationError, TMA};
+use anyhow::Result;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Errors from commands a TMA cannot accept in its current state
+#[derive(Debug, Error)]
+pub enum TransitionError {
+    #[error("TMA has already been submitted")]
+    AlreadySubmitted,
+
+    #[error("TMA has not been submitted")]
+    NotSubmitted,
+
+    #[error("TMA has not been anonymized")]
+    NotAnonymized,
+
+    #[error("Cannot {command} a TMA that is {status:?}")]
+    Illegal {
+        command: &'static str,
+        status: TMAStatus,
+    },
+}
+
+/// A change requested of a TMA
+#[derive(Debug, Clone)]
+pub enum TMACommand {
+    /// Submit the TMA for marking
+    Submit {
+        student_id: String,
+        module_code: String,
+        question_number: u32,
+        content_hash: String,
+    },
+    /// Record the student's anonymized ID
+    Anonymise {
+        original_hash: String,
+        anonymized_id: String,
+    },
+    /// Send the anonymized TMA for AI feedback
+    RequestFeedback,
+    /// Record the feedback the AI produced
+    RecordFeedback {
+        feedback: String,
+        rubric_scores: Vec<RubricScore>,
+    },
+    /// Assign the final grade
+    AssignGrade { grade: f32, max_grade: f32 },
+    /// Mark processing as failed
+    Fail { reason: String },
+}
+
+impl TMACommand {
+    /// Build a submit command for a validated TMA
+    ///
+    /// Only a SHA3-256 hash of the content is recorded.
+    pub fn submit(tma: &TMA) -> Result<Self, ValidationError> {
+        tma.validate()?;
+
+        Ok(TMACommand::Submit {
+            student_id: tma.student_id.clone(),
+            module_code: tma.module_code.clone(),
+            question_number: tma.question_number,
+            content_hash: hex::encode(Sha3_256::digest(tma.content.as_bytes())),
+        })
+    }
+
+    /// Status the TMA moves to when the command succeeds
+    fn status(&self) -> TMAStatus {
+        match self {
+            TMACommand::Submit { .. } => TMAStatus::Submitted,
+            TMACommand::Anonymise { .. } => TMAStatus::Anonymizing,
+            TMACommand::RequestFeedback => TMAStatus::Processing,
+            TMACommand::RecordFeedback { .. } => TMAStatus::FeedbackGenerated,
+            TMACommand::AssignGrade { .. } => TMAStatus::Graded,
+            TMACommand::Fail { .. } => TMAStatus::Failed,
+        }
+    }
+
+    /// Short name used in transition errors
+    fn name(&self) -> &'static str {
+        match self {
+            TMACommand::Submit { .. } => "submit",
+            TMACommand::Anonymise { .. } => "anonymise",
+            TMACommand::RequestFeedback => "request feedback for",
+            TMACommand::RecordFeedback { .. } => "record feedback for",
+            TMACommand::AssignGrade { .. } => "grade",
+            TMACommand::Fail { .. } => "fail",
+        }
+    }
+}
+
+/// State of a TMA rebuilt from its events
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TMAAggregate {
+    /// Version of the last event applied (0 before submission)
+    pub version: u64,
+    /// Current status, or `None` before submission
+    pub status: Option<TMAStatus>,
+    /// Student identifier
+    pub student_id: String,
+    /// Module code
+    pub module_code: String,
+    /// Question number within the TMA
+    pub question_number: u32,
+    /// SHA3-256 hash of the submitted content
+    pub content_hash: String,
+    /// Anonymized student ID
+    pub anonymized_id: Option<String>,
+    /// Generated feedback
+    pub feedback: Option<String>,
+    /// Per-criterion scores from the feedback
+    pub rubric_scores: Vec<RubricScore>,
+    /// Assigned grade and maximum grade
+    pub grade: Option<(f32, f32)>,
+    /// Reason for the most recent failure
+    pub failure: Option<String>,
+}
+
+impl TMAAggregate {
+    /// Decide which event a command produces, without changing state
+    ///
+    /// # Errors
+    ///
+    /// Returns `TransitionError` if the command would move the TMA to a
+    /// status it cannot reach from its current one.
+    pub fn handle(&self, tma_id: Uuid, command: TMACommand) -> Result<EventType, TransitionError> {
+        let next = command.status();
+        match self.status {
+            None if next == TMAStatus::Submitted => {}
+            None => return Err(TransitionError::NotSubmitted),
+            Some(_) if next == TMAStatus::Submitted => return Err(TransitionError::AlreadySubmitted),
+            Some(status) if !status.can_transition_to(next) => {
+                return Err(TransitionError::Illegal {
+                    command: command.name(),
+                    status,
+                });
+            }
+            Some(_) => {}
+        }
+
+        // Only anonymized content may reach the AI, including on retry
+        if next == TMAStatus::Processing && self.anonymized_id.is_none() {
+            return Err(TransitionError::NotAnonymized);
+        }
+
+        Ok(match command {
+            TMACommand::Submit { student_id, module_code, question_number, content_hash } => {
+                EventType::TMASubmitted { student_id, module_code, question_number, content_hash }
+            }
+            TMACommand::Anonymise { original_hash, anonymized_id } => {
+                EventType::StudentAnonymized {
+                    original_hash,
+                    anonymized_id,
+                    timestamp: Utc::now(),
+                }
+            }
+            TMACommand::RequestFeedback => EventType::FeedbackRequested { tma_id },
+            TMACommand::RecordFeedback { feedback, rubric_scores } => {
+                EventType::FeedbackGenerated { tma_id, feedback, rubric_scores }
+            }
+            TMACommand::AssignGrade { grade, max_grade } => {
+                EventType::GradeAssigned { tma_id, grade, max_grade }
+            }
+            TMACommand::Fail { reason } => EventType::TMAFailed { tma_id, reason },
+        })
+    }
+}
+
+impl Aggregate for TMAAggregate {
+    fn apply(&mut self, event: &Event) {
+        self.version = event.version;
+
+        match &event.event_type {
+            EventType::TMASubmitted { student_id, module_code, question_number, content_hash } => {
+                self.status = Some(TMAStatus::Submitted);
+                self.student_id = student_id.clone();
+                self.module_code = module_code.clone();
+                self.question_number = *question_number;
+                self.content_hash = content_hash.clone();
+            }
+            EventType::StudentAnonymized { anonymized_id, .. } => {
+                self.status = Some(TMAStatus::Anonymizing);
+                self.anonymized_id = Some(anonymized_id.clone());
+            }
+            EventType::FeedbackRequested { .. } => {
+                self.status = Some(TMAStatus::Processing);
+                self.failure = None;
+            }
+            EventType::FeedbackGenerated { feedback, rubric_scores, .. } => {
+                self.status = Some(TMAStatus::FeedbackGenerated);
+                self.feedback = Some(feedback.clone());
+                self.rubric_scores = rubric_scores.clone();
+            }
+            EventType::GradeAssigned { grade, max_grade, .. } => {
+                self.status = Some(TMAStatus::Graded);
+                self.grade = Some((*grade, *max_grade));
+            }
+            EventType::TMAFailed { reason, .. } => {
+                self.status = Some(TMAStatus::Failed);
+                self.failure = Some(reason.clone());
+            }
+        }
+    }
+}
+
+/// Loads TMA aggregates from the event store and appends the events their
+/// commands produce
+pub struct TMARepository {
+    projection: EventProjection,
+}
+
+impl TMARepository {
+    /// Create a repository over an event projection
+    pub fn new(projection: EventProjection) -> Self {
+        Self { projection }
+    }
+
+    /// Rebuild a TMA from its events
+    pub fn load(&self, tma_id: Uuid) -> Result<TMAAggregate> {
+        let (tma, _) = self.projection.load_aggregate(&tma_id.to_string())?;
+        Ok(tma)
+    }
+
+    /// Submit a TMA, keyed by its own ID
+    pub fn submit(&self, tma: &TMA) -> Result<TMAAggregate> {
+        self.execute(tma.id, TMACommand::submit(tma)?)
+    }
+
+    /// Run a command against a TMA and return its new state
+    ///
+    /// # Errors
+    ///
+    /// Returns `TransitionError` if the TMA cannot accept the command, or
+    /// [`crate::EventStoreError::VersionConflict`] if another writer
+    /// changed the TMA after it was loaded; reload and retry in that case.
+    pub fn execute(&self, tma_id: Uuid, command: TMACommand) -> Result<TMAAggregate> {
+        let aggregate_id = tma_id.to_string();
+        let (mut tma, version) = self.projection.load_aggregate::<TMAAggregate>(&aggregate_id)?;
+
+        let event = Event::new(tma.handle(tma_id, command)?, aggregate_id.clone(), version + 1);
+        self.projection
+            .store()
+            .append_expected(&aggregate_id, version, vec![event.clone()])?;
+
+        tma.apply(&event);
+        Ok(tma)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventStore, EventStoreError, LmdbEventStore};
+    use tempfile::TempDir;
+
+    fn create_test_repository() -> (TMARepository, LmdbEventStore, TempDir) {
+        let temp_dir = TempDir::new().unwrap();
+        let store = LmdbEventStore::new(temp_dir.path(), Some(10 * 1024 * 1024)).unwrap();
+        let projection = EventProjection::new(Box::new(store.clone()));
+        (TMARepository::new(projection), store, temp_dir)
+    }
+
+    fn create_test_tma() -> TMA {
+        TMA::new(
+            "student123".to_string(),
+            "TM112".to_string(),
+            1,
+            "My answer".to_string(),
+            "Rubric criteria".to_string(),
+        )
+    }
+
+    fn anonymise() -> TMACommand {
+        TMACommand::Anonymise {
+            original_hash: "abc123".to_string(),
+            anonymized_id: "anon123".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_full_lifecycle_rebuilds_from_events() {
+        let (repository, store, _temp_dir) = create_test_repository();
+        let tma = create_test_tma();
+
+        repository.submit(&tma).unwrap();
+        repository.execute(tma.id, anonymise()).unwrap();
+        repository.execute(tma.id, TMACommand::RequestFeedback).unwrap();
+        repository
+            .execute(
+                tma.id,
+                TMACommand::RecordFeedback {
+                    feedback: "Good work".to_string(),
+                    rubric_scores: vec![],
+                },
+            )
+            .unwrap();
+        let graded = repository
+            .execute(tma.id, TMACommand::AssignGrade { grade: 85.0, max_grade: 100.0 })
+            .unwrap();
+
+        assert_eq!(graded.status, Some(TMAStatus::Graded));
+        assert_eq!(graded.version, 5);
+        assert_eq!(repository.load(tma.id).unwrap(), graded);
+
+        let types: Vec<&str> = store
+            .get_events(&tma.id.to_string())
+            .unwrap()
+            .iter()
+            .map(|e| e.event_type.name())
+            .collect();
+        assert_eq!(
+            types,
+            vec!["TMASubmitted", "StudentAnonymized", "FeedbackRequested", "FeedbackGenerated", "GradeAssigned"]
+        );
+    }
+
+    #[test]
+    fn test_illegal_transitions_are_rejected() {
+        let (repository, store, _temp_dir) = create_test_repository();
+        let tma = create_test_tma();
+
+        let error = repository.execute(tma.id, TMACommand::RequestFeedback).unwrap_err();
+        assert!(matches!(error.downcast_ref::<TransitionError>(), Some(TransitionError::NotSubmitted)));
+
+        repository.submit(&tma).unwrap();
+        let error = repository.submit(&tma).unwrap_err();
+        assert!(matches!(error.downcast_ref::<TransitionError>(), Some(TransitionError::AlreadySubmitted)));
+
+        let error = repository
+            .execute(tma.id, TMACommand::AssignGrade { grade: 85.0, max_grade: 100.0 })
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TransitionError>(),
+            Some(TransitionError::Illegal { status: TMAStatus::Submitted, .. })
+        ));
+
+        // Rejected commands leave no events behind
+        assert_eq!(store.get_version(&tma.id.to_string()).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_failed_tma_can_retry_feedback() {
+        let (repository, _store, _temp_dir) = create_test_repository();
+        let tma = create_test_tma();
+
+        repository.submit(&tma).unwrap();
+        repository.execute(tma.id, anonymise()).unwrap();
+        repository.execute(tma.id, TMACommand::RequestFeedback).unwrap();
+        let failed = repository
+            .execute(tma.id, TMACommand::Fail { reason: "AI jail crashed".to_string() })
+            .unwrap();
+        assert_eq!(failed.status, Some(TMAStatus::Failed));
+        assert_eq!(failed.failure.as_deref(), Some("AI jail crashed"));
+
+        let retried = repository.execute(tma.id, TMACommand::RequestFeedback).unwrap();
+        assert_eq!(retried.status, Some(TMAStatus::Processing));
+        assert!(retried.failure.is_none());
+    }
+
+    #[test]
+    fn test_failed_before_anonymisation_cannot_reach_ai() {
+        let (repository, _store, _temp_dir) = create_test_repository();
+        let tma = create_test_tma();
+
+        repository.submit(&tma).unwrap();
+        repository
+            .execute(tma.id, TMACommand::Fail { reason: "PII found".to_string() })
+            .unwrap();
+
+        let error = repository.execute(tma.id, TMACommand::RequestFeedback).unwrap_err();
+        assert!(matches!(error.downcast_ref::<TransitionError>(), Some(TransitionError::NotAnonymized)));
+    }
+
+    #[test]
+    fn test_stale_writer_gets_version_conflict() {
+        let (repository, store, _temp_dir) = create_test_repository();
+        let tma = create_test_tma();
+        repository.submit(&tma).unwrap();
+
+        // Another worker appends between this one's load and append
+        let stale = repository.load(tma.id).unwrap();
+        repository.execute(tma.id, anonymise()).unwrap();
+
+        let event_type = stale.handle(tma.id, anonymise()).unwrap();
+        let error = store
+            .append_expected(
+                &tma.id.to_string(),
+                stale.version,
+                vec![Event::new(event_type, tma.id.to_string(), stale.version + 1)],
+            )
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<EventStoreError>(),
+            Some(EventStoreError::VersionConflict { expected: 1, actual: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_submit_validates_tma() {
+        let mut tma = create_test_tma();
+        tma.content = String::new();
+
+        assert!(matches!(TMACommand::submit(&tma), Err(ValidationError::EmptyContent)));
+    }
+}
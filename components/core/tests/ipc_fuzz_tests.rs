@@ -0,0 +1,133 @@
+//! Fuzz tests for reading replies from the AI jail.
+//!
+//! A stand-in jail replays generated bytes to `IPCClient::receive`, which
+//! must report every malformed or oversized frame as an error, never
+//! panic or buffer past the frame size limit, and keep reading the valid
+//! messages around them.
+
+use aws_core::ipc::{
+    FrameCodec, Framing, IPCClient, IPCClientBuilder, IPCError, IPCMessage, PROTOCOL_VERSION,
+};
+use proptest::prelude::*;
+use std::io::Write;
+
+/// Frame size limit the client is built with
+const MAX_FRAME_SIZE: usize = 256;
+
+/// Client whose jail answers the version and framing handshakes, then
+/// writes `output` and closes stdout
+fn replay(framing: Framing, output: &[u8]) -> (IPCClient, tempfile::NamedTempFile) {
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    let hello = IPCMessage::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Default::default(),
+    };
+    writeln!(file, "{}", serde_json::to_string(&hello).unwrap()).unwrap();
+    if framing != Framing::Lines {
+        let reply = IPCMessage::FramingSet {
+            framing,
+            max_frame_size: MAX_FRAME_SIZE,
+        };
+        writeln!(file, "{}", serde_json::to_string(&reply).unwrap()).unwrap();
+    }
+    file.write_all(output).unwrap();
+
+    let script = r#"cat "$0"; exec >&-; cat > /dev/null"#;
+    let client = IPCClientBuilder::new(file.path().to_string_lossy())
+        .jail_command("sh")
+        .jail_args(vec!["-c".to_string(), script.to_string()])
+        .framing(framing)
+        .max_frame_size(MAX_FRAME_SIZE)
+        .build_sync()
+        .unwrap();
+    (client, file)
+}
+
+fn framing() -> impl Strategy<Value = Framing> {
+    prop_oneof![Just(Framing::Lines), Just(Framing::LengthPrefixed)]
+}
+
+/// A frame the jail might send
+#[derive(Debug, Clone)]
+enum Frame {
+    /// A `Pong` with this timestamp
+    Valid(i64),
+    /// Bytes that are not a message
+    Garbage(Vec<u8>),
+    /// More bytes than the client accepts
+    Oversized(usize),
+}
+
+fn frame() -> impl Strategy<Value = Frame> {
+    prop_oneof![
+        any::<i64>().prop_map(Frame::Valid),
+        prop::collection::vec(any::<u8>(), 0..MAX_FRAME_SIZE).prop_map(Frame::Garbage),
+        (MAX_FRAME_SIZE + 1..MAX_FRAME_SIZE * 4).prop_map(Frame::Oversized),
+    ]
+}
+
+fn encode(framing: Framing, frame: &Frame) -> Vec<u8> {
+    let payload = match frame {
+        Frame::Valid(timestamp) => {
+            serde_json::to_vec(&IPCMessage::Pong { timestamp: *timestamp }).unwrap()
+        }
+        // Never valid JSON, and never split by a newline
+        Frame::Garbage(bytes) => std::iter::once(b'!')
+            .chain(bytes.iter().map(|&b| if b == b'\n' { b' ' } else { b }))
+            .take(MAX_FRAME_SIZE)
+            .collect(),
+        Frame::Oversized(size) => vec![b'x'; *size],
+    };
+    FrameCodec::new(framing, usize::MAX).encode(&payload).unwrap()
+}
+
+fn ipc_error(error: &anyhow::Error) -> Option<&IPCError> {
+    error.downcast_ref::<IPCError>()
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn prop_arbitrary_output_never_panics(
+        framing in framing(),
+        output in prop::collection::vec(any::<u8>(), 0..2048),
+    ) {
+        let (mut client, _file) = replay(framing, &output);
+
+        // Every frame consumes at least one byte, so the output runs out
+        let mut ended = false;
+        for _ in 0..=output.len() + 1 {
+            if let Err(e) = client.receive() {
+                if matches!(ipc_error(&e), Some(IPCError::ProcessCrashed)) {
+                    ended = true;
+                    break;
+                }
+            }
+        }
+        prop_assert!(ended);
+    }
+
+    #[test]
+    fn prop_valid_messages_survive_bad_frames(
+        framing in framing(),
+        frames in prop::collection::vec(frame(), 1..16),
+    ) {
+        let output: Vec<u8> = frames.iter().flat_map(|f| encode(framing, f)).collect();
+        let (mut client, _file) = replay(framing, &output);
+
+        for frame in &frames {
+            let received = client.receive();
+            let expected = match (frame, received.as_ref().map_err(ipc_error)) {
+                (Frame::Valid(timestamp), Ok(IPCMessage::Pong { timestamp: t })) => t == timestamp,
+                (Frame::Garbage(_), Err(Some(IPCError::DeserializationError(_)))) => true,
+                (Frame::Oversized(_), Err(Some(IPCError::InvalidFrame(_)))) => true,
+                _ => false,
+            };
+            prop_assert!(expected, "{:?} was read as {:?}", frame, received);
+        }
+
+        let end = client.receive().unwrap_err();
+        prop_assert!(matches!(ipc_error(&end), Some(IPCError::ProcessCrashed)));
+    }
+}
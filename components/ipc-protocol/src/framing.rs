@@ -0,0 +1,328 @@
+//! Message framing
+//!
+//! Messages start out as lines of JSON. After a `SetFraming` handshake
+//! both sides may switch to length-prefixed frames instead: a 4-byte
+//! big-endian length followed by that many bytes of JSON. Either way, a
+//! frame longer than the maximum frame size is skipped and reported rather
+//! than buffered, so a broken peer cannot exhaust the reader's memory.
+//!
+//! [`FrameDecoder`] does no I/O itself; callers feed it whatever bytes
+//! they have, which lets blocking and async readers share it.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Largest frame accepted unless the peers agree on another limit
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Bytes in a length prefix
+const PREFIX_LEN: usize = 4;
+
+/// How messages are delimited on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// One message per line
+    #[default]
+    Lines,
+    /// A 4-byte big-endian length, then the message
+    LengthPrefixed,
+}
+
+/// Why a frame could not be read or written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame is longer than the maximum frame size; it was skipped
+    TooLarge { size: usize, max: usize },
+    /// The stream ended part way through a frame
+    Truncated,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { size, max } => {
+                write!(f, "Frame of {} bytes exceeds the {} byte limit", size, max)
+            }
+            FrameError::Truncated => write!(f, "Stream ended part way through a frame"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// The framing and frame size limit a connection uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCodec {
+    pub framing: Framing,
+    pub max_frame_size: usize,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self {
+            framing: Framing::Lines,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl FrameCodec {
+    pub fn new(framing: Framing, max_frame_size: usize) -> Self {
+        Self {
+            framing,
+            max_frame_size,
+        }
+    }
+
+    /// Wrap a serialized message in a frame
+    ///
+    /// Refuses messages the peer would reject as too large. Messages sent
+    /// as lines must not contain a newline, which compact JSON never does.
+    pub fn encode(&self, payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+        if payload.len() > self.max_frame_size {
+            return Err(FrameError::TooLarge {
+                size: payload.len(),
+                max: self.max_frame_size,
+            });
+        }
+
+        let mut frame = Vec::with_capacity(payload.len() + PREFIX_LEN);
+        match self.framing {
+            Framing::Lines => {
+                frame.extend_from_slice(payload);
+                frame.push(b'\n');
+            }
+            Framing::LengthPrefixed => {
+                frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                frame.extend_from_slice(payload);
+            }
+        }
+        Ok(frame)
+    }
+}
+
+/// Splits a byte stream into frames
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    codec: FrameCodec,
+    /// The frame read so far, or the length prefix while it is incomplete
+    buffer: Vec<u8>,
+    /// Length of the current length-prefixed frame, once its prefix is read
+    expected: Option<usize>,
+    /// Bytes of an oversized frame seen so far; its bytes are dropped
+    skipped: Option<usize>,
+}
+
+impl FrameDecoder {
+    pub fn new(codec: FrameCodec) -> Self {
+        Self {
+            codec,
+            ..Self::default()
+        }
+    }
+
+    pub fn codec(&self) -> FrameCodec {
+        self.codec
+    }
+
+    /// Decode the following frames with `codec`
+    ///
+    /// Takes effect at the next frame boundary, so it should only be
+    /// called between frames.
+    pub fn set_codec(&mut self, codec: FrameCodec) {
+        self.codec = codec;
+    }
+
+    /// Consume bytes from `input` until a frame is complete
+    ///
+    /// Returns how many bytes were consumed and the frame, if one was
+    /// completed. A frame is never returned with its delimiter or length
+    /// prefix.
+    pub fn decode(&mut self, input: &[u8]) -> (usize, Option<Result<Vec<u8>, FrameError>>) {
+        match self.codec.framing {
+            Framing::Lines => self.decode_line(input),
+            Framing::LengthPrefixed => self.decode_prefixed(input),
+        }
+    }
+
+    /// Check the stream did not end mid-frame, once it has ended
+    pub fn finish(&mut self) -> Result<(), FrameError> {
+        let partial = !self.buffer.is_empty() || self.expected.is_some() || self.skipped.is_some();
+        self.buffer.clear();
+        self.expected = None;
+        self.skipped = None;
+
+        if partial {
+            Err(FrameError::Truncated)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn decode_line(&mut self, input: &[u8]) -> (usize, Option<Result<Vec<u8>, FrameError>>) {
+        let newline = input.iter().position(|&b| b == b'\n');
+        let body = &input[..newline.unwrap_or(input.len())];
+        let consumed = newline.map_or(input.len(), |i| i + 1);
+
+        match self.skipped.as_mut() {
+            Some(skipped) => *skipped += body.len(),
+            None if self.buffer.len() + body.len() > self.codec.max_frame_size => {
+                self.skipped = Some(self.buffer.len() + body.len());
+                self.buffer.clear();
+            }
+            None => self.buffer.extend_from_slice(body),
+        }
+
+        if newline.is_none() {
+            return (consumed, None);
+        }
+
+        let frame = match self.skipped.take() {
+            Some(size) => Err(FrameError::TooLarge {
+                size,
+                max: self.codec.max_frame_size,
+            }),
+            None => Ok(std::mem::take(&mut self.buffer)),
+        };
+        (consumed, Some(frame))
+    }
+
+    fn decode_prefixed(&mut self, input: &[u8]) -> (usize, Option<Result<Vec<u8>, FrameError>>) {
+        let mut consumed = 0;
+
+        let Some(expected) = self.expected else {
+            let take = (PREFIX_LEN - self.buffer.len()).min(input.len());
+            self.buffer.extend_from_slice(&input[..take]);
+            consumed += take;
+            if self.buffer.len() < PREFIX_LEN {
+                return (consumed, None);
+            }
+
+            let prefix: [u8; PREFIX_LEN] = self.buffer[..].try_into().expect("prefix is complete");
+            let expected = u32::from_be_bytes(prefix) as usize;
+            self.buffer.clear();
+            self.expected = Some(expected);
+            if expected > self.codec.max_frame_size {
+                self.skipped = Some(0);
+            }
+
+            let (rest, frame) = self.decode_prefixed(&input[consumed..]);
+            return (consumed + rest, frame);
+        };
+
+        let have = self.skipped.unwrap_or(self.buffer.len());
+        let take = (expected - have).min(input.len());
+        match self.skipped.as_mut() {
+            Some(skipped) => *skipped += take,
+            None => self.buffer.extend_from_slice(&input[..take]),
+        }
+        consumed += take;
+
+        if have + take < expected {
+            return (consumed, None);
+        }
+
+        self.expected = None;
+        let frame = match self.skipped.take() {
+            Some(_) => Err(FrameError::TooLarge {
+                size: expected,
+                max: self.codec.max_frame_size,
+            }),
+            None => Ok(std::mem::take(&mut self.buffer)),
+        };
+        (consumed, Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed `input` to `decoder` in `step`-byte pieces, collecting frames
+    fn decode_all(
+        decoder: &mut FrameDecoder,
+        input: &[u8],
+        step: usize,
+    ) -> Vec<Result<Vec<u8>, FrameError>> {
+        let mut frames = Vec::new();
+        for piece in input.chunks(step) {
+            let mut rest = piece;
+            while !rest.is_empty() {
+                let (consumed, frame) = decoder.decode(rest);
+                rest = &rest[consumed..];
+                frames.extend(frame);
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn test_round_trip_in_pieces() {
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let codec = FrameCodec::new(framing, 64);
+            let mut input = codec.encode(b"{\"a\":1}").unwrap();
+            input.extend(codec.encode(b"").unwrap());
+            input.extend(codec.encode(b"{\"b\":2}").unwrap());
+
+            for step in [1, 3, input.len()] {
+                let mut decoder = FrameDecoder::new(codec);
+                let frames = decode_all(&mut decoder, &input, step);
+
+                assert_eq!(
+                    frames,
+                    vec![Ok(b"{\"a\":1}".to_vec()), Ok(vec![]), Ok(b"{\"b\":2}".to_vec())]
+                );
+                assert_eq!(decoder.finish(), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_oversized_frame_skipped() {
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let large = FrameCodec::new(framing, 1024);
+            let mut input = large.encode(&[b'x'; 100]).unwrap();
+            input.extend(large.encode(b"ok").unwrap());
+
+            let mut decoder = FrameDecoder::new(FrameCodec::new(framing, 10));
+            let frames = decode_all(&mut decoder, &input, 7);
+
+            assert_eq!(
+                frames,
+                vec![Err(FrameError::TooLarge { size: 100, max: 10 }), Ok(b"ok".to_vec())]
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_refuses_oversized_message() {
+        let codec = FrameCodec::new(Framing::LengthPrefixed, 4);
+
+        assert_eq!(codec.encode(b"12345"), Err(FrameError::TooLarge { size: 5, max: 4 }));
+    }
+
+    #[test]
+    fn test_truncated_frame_reported() {
+        let mut decoder = FrameDecoder::new(FrameCodec::new(Framing::LengthPrefixed, 64));
+        decode_all(&mut decoder, &[0, 0, 0, 9, b'{'], 5);
+
+        assert_eq!(decoder.finish(), Err(FrameError::Truncated));
+    }
+
+    #[test]
+    fn test_codec_switched_between_frames() {
+        let lines = FrameCodec::new(Framing::Lines, 64);
+        let prefixed = FrameCodec::new(Framing::LengthPrefixed, 64);
+        let mut input = lines.encode(b"first").unwrap();
+        input.extend(prefixed.encode(b"second").unwrap());
+
+        let mut decoder = FrameDecoder::new(lines);
+        let (consumed, frame) = decoder.decode(&input);
+        assert_eq!(frame, Some(Ok(b"first".to_vec())));
+
+        decoder.set_codec(prefixed);
+        let (_, frame) = decoder.decode(&input[consumed..]);
+        assert_eq!(frame, Some(Ok(b"second".to_vec())));
+    }
+}
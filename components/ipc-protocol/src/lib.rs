@@ -0,0 +1,715 @@
+//! IPC Wire Protocol
+//!
+//! Message types exchanged between the core orchestrator and the AI jail
+//! over the jail's stdin/stdout. Both sides depend on this crate, so the
+//! wire format is defined in exactly one place.
+//!
+//! # Handshake
+//!
+//! The orchestrator opens every connection with `Hello`, giving its
+//! protocol version. The jail answers with its own `Hello`, giving its
+//! version and [`Capabilities`]. The orchestrator refuses a jail whose
+//! version is outside [`MIN_PROTOCOL_VERSION`]..=[`PROTOCOL_VERSION`], and
+//! avoids features the jail does not advertise. A jail older than the
+//! handshake answers `Hello` with an `Error`; the orchestrator may then
+//! fall back to what every jail supports: unstreamed, unbatched, free-text
+//! feedback from the default model.
+//!
+//! # Framing
+//!
+//! Every message is JSON, adjacently tagged with `type` and `payload`:
+//!
+//! ```json
+//! {"type":"Ping","payload":{"timestamp":1700000000}}
+//! ```
+//!
+//! Messages are sent one per line until the orchestrator sends
+//! `SetFraming`. The jail answers with `FramingSet`, giving the framing and
+//! maximum frame size it agreed to (never more than asked for), and both
+//! sides use them for every later message. The orchestrator sends nothing
+//! else until the answer arrives; a jail that cannot parse `SetFraming`
+//! answers with an `Error` and both sides keep using lines. See
+//! [`framing`].
+//!
+//! # Streaming
+//!
+//! A `FeedbackRequest` with `params.stream` set is answered with zero or
+//! more `FeedbackChunk` messages followed by the usual `FeedbackResponse`
+//! (or `Error`). Concatenating the chunk text gives the generated text; the
+//! response's `feedback` stays authoritative. Jails only send chunks when
+//! asked, so peers that never set `stream` never see them.
+//!
+//! # Structured output
+//!
+//! With `params.response_format` set to `rubric_json` the jail constrains
+//! generation to a JSON object of criterion scores, strengths, suggestions
+//! and an overall grade, and returns them in the `FeedbackResponse` fields
+//! instead of leaving the orchestrator to infer them from free text.
+//!
+//! # Prompt templates
+//!
+//! `prompt.template` names one of the templates the jail loaded at
+//! startup. Templates only change the wording of the prompt; the model's
+//! chat format is chosen by the jail.
+//!
+//! # Models
+//!
+//! A jail may serve several models. `params.model` picks one by id, and
+//! `metadata.model` reports the id and revision of the model that
+//! produced the feedback, and the hash of its weights if the jail checked
+//! them against a signed manifest.
+//!
+//! # Long answers
+//!
+//! An answer too long for the model's context window is split into parts
+//! that are marked separately and merged into one response.
+//! `metadata.context` says how many parts there were and whether any of
+//! the answer had to be left out.
+//!
+//! # Deadlines and cancellation
+//!
+//! The jail stops a request that runs past `params.timeout_ms` or
+//! generates more than `params.token_limit` tokens (or its own, lower
+//! limits) and answers it with an `Error` of kind `timeout`. A `Cancel`
+//! message stops a queued or running request, which is then answered with
+//! an `Error` of kind `cancelled`; cancelling a request that has already
+//! been answered does nothing. Every request still gets exactly one final
+//! reply, so an orchestrator that gives up on a request can cancel it and
+//! read past its reply rather than mistake it for the next one's.
+//!
+//! # Versioning
+//!
+//! [`PROTOCOL_VERSION`] is bumped on any change that an older peer could
+//! not parse. Optional fields added with a serde default do not require a
+//! bump.
+
+use serde::{Deserialize, Serialize};
+
+pub mod framing;
+
+pub use framing::{FrameCodec, FrameDecoder, FrameError, Framing, DEFAULT_MAX_FRAME_SIZE};
+
+/// Current version of the wire protocol
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Oldest protocol version this crate can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// IPC message types
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload")]
+pub enum IPCMessage {
+    /// Request feedback generation
+    FeedbackRequest {
+        request_id: String,
+        content: String,
+        rubric: String,
+        criteria: Vec<RubricCriterion>,
+        #[serde(default = "default_question_number")]
+        question_number: u32,
+        #[serde(default)]
+        params: GenerationParams,
+        #[serde(default)]
+        prompt: PromptOptions,
+    },
+
+    /// Feedback text generated so far, sent before the `FeedbackResponse`
+    /// when the request asked to stream
+    FeedbackChunk {
+        request_id: String,
+        /// Text generated since the previous chunk
+        text: String,
+        /// Tokens generated for this request so far
+        tokens_generated: usize,
+    },
+
+    /// Response with generated feedback
+    FeedbackResponse {
+        request_id: String,
+        feedback: String,
+        scores: Vec<CriterionScore>,
+        #[serde(default)]
+        overall_grade: Option<f32>,
+        /// Strengths identified, when the jail produced structured output
+        #[serde(default)]
+        strengths: Vec<String>,
+        /// Suggested improvements, when the jail produced structured output
+        #[serde(default)]
+        suggestions: Vec<String>,
+        #[serde(default)]
+        metadata: InferenceMetadata,
+    },
+
+    /// Health check ping
+    Ping {
+        timestamp: i64,
+    },
+
+    /// Health check pong
+    Pong {
+        timestamp: i64,
+    },
+
+    /// Error message
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        #[serde(default)]
+        kind: ErrorKind,
+        message: String,
+    },
+
+    /// Stop a queued or running `FeedbackRequest`
+    Cancel {
+        request_id: String,
+    },
+
+    /// Ask the jail to switch framing for every later message
+    SetFraming {
+        framing: Framing,
+        max_frame_size: usize,
+    },
+
+    /// The framing the jail switched to after this message
+    FramingSet {
+        framing: Framing,
+        max_frame_size: usize,
+    },
+
+    /// Protocol version and features of the sender, exchanged before any
+    /// other message
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Capabilities,
+    },
+
+    /// Shutdown request
+    Shutdown,
+
+    /// Acknowledgment
+    Ack {
+        request_id: String,
+    },
+}
+
+impl IPCMessage {
+    /// The request ID this message relates to, if any
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            IPCMessage::FeedbackRequest { request_id, .. }
+            | IPCMessage::FeedbackChunk { request_id, .. }
+            | IPCMessage::FeedbackResponse { request_id, .. }
+            | IPCMessage::Cancel { request_id }
+            | IPCMessage::Ack { request_id } => Some(request_id),
+            IPCMessage::Error { request_id, .. } => request_id.as_deref(),
+            IPCMessage::Ping { .. }
+            | IPCMessage::Pong { .. }
+            | IPCMessage::SetFraming { .. }
+            | IPCMessage::FramingSet { .. }
+            | IPCMessage::Hello { .. }
+            | IPCMessage::Shutdown => None,
+        }
+    }
+}
+
+/// Features a jail supports, advertised in its `Hello`
+///
+/// The defaults describe a jail that supports none of them, which is what
+/// the orchestrator assumes of a jail older than the handshake.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// Whether the jail sends `FeedbackChunk`s when asked to stream
+    pub streaming: bool,
+    /// Most requests the jail decodes together; 1 if it does not batch
+    pub max_batch_size: usize,
+    /// Whether the jail can constrain output to `rubric_json`
+    pub grammar: bool,
+    /// Ids of the models the jail serves; empty if it does not say
+    pub models: Vec<String>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            streaming: false,
+            max_batch_size: 1,
+            grammar: false,
+            models: Vec::new(),
+        }
+    }
+}
+
+impl Capabilities {
+    /// Whether requests naming `model` can be served; a jail that does not
+    /// list its models is trusted to know them
+    pub fn serves(&self, model: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|id| id == model)
+    }
+}
+
+/// Category of an error reported by the jail
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The jail failed to start (e.g. model files missing)
+    InitializationError,
+    /// The message could not be parsed or failed validation
+    InvalidRequest,
+    /// The message was valid but is not one the receiver handles
+    UnexpectedMessage,
+    /// Inference failed while processing a valid request
+    #[default]
+    ProcessingError,
+    /// The request ran out of time or tokens before it finished
+    Timeout,
+    /// The request was stopped by a `Cancel` message
+    Cancelled,
+}
+
+/// Sampling parameters supplied with a feedback request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationParams {
+    /// Maximum tokens to generate
+    pub max_tokens: usize,
+    /// Temperature for sampling (0.0-2.0)
+    pub temperature: f64,
+    /// Top-p (nucleus) sampling threshold
+    pub top_p: f64,
+    /// Sample only from the `top_k` most likely tokens (0 disables)
+    pub top_k: usize,
+    /// Drop tokens less likely than `min_p` times the most likely one
+    /// (0.0 disables)
+    pub min_p: f64,
+    /// Divides the logits of tokens already generated (1.0 disables)
+    pub repetition_penalty: f32,
+    /// Subtracted from a token's logit once per time it was generated
+    pub frequency_penalty: f32,
+    /// Subtracted from a token's logit if it was generated at all
+    pub presence_penalty: f32,
+    /// Extra stop sequences, on top of the model's end-of-turn markers
+    pub stop_sequences: Vec<String>,
+    /// Send `FeedbackChunk` messages while generating
+    pub stream: bool,
+    /// Shape of the generated output
+    pub response_format: ResponseFormat,
+    /// Seed for the sampler; the jail picks one at random when absent
+    pub seed: Option<u64>,
+    /// Id of the model to generate with; the jail's default when absent
+    pub model: Option<String>,
+    /// Wall-clock milliseconds the jail may spend on the request before
+    /// answering with a `timeout` error
+    pub timeout_ms: Option<u64>,
+    /// Tokens the jail may generate for the request in total, over every
+    /// part of a split answer; unlike `max_tokens`, running out is a
+    /// `timeout` error rather than a shorter answer
+    pub token_limit: Option<usize>,
+}
+
+/// Shape the jail constrains its output to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Free-text feedback
+    #[default]
+    Text,
+    /// A JSON object with `criterion_scores`, `strengths`, `suggestions`
+    /// and `overall_grade`, enforced token by token during sampling
+    RubricJson,
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        Self {
+            max_tokens: 512,
+            temperature: 0.7,
+            top_p: 0.9,
+            top_k: 0,
+            min_p: 0.0,
+            repetition_penalty: 1.1,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            stop_sequences: Vec::new(),
+            stream: false,
+            response_format: ResponseFormat::Text,
+            seed: None,
+            model: None,
+            timeout_ms: None,
+            token_limit: None,
+        }
+    }
+}
+
+/// Prompt template selection and the values it is filled with
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptOptions {
+    /// Name of the jail's prompt template; the jail's default when absent
+    pub template: Option<String>,
+    /// Module the TMA belongs to, e.g. `M250`
+    pub module_code: Option<String>,
+    /// Word limit for the answer, if the question sets one
+    pub word_limit: Option<u32>,
+}
+
+/// Facts about how a piece of feedback was produced
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InferenceMetadata {
+    /// Model confidence score (0.0-1.0): the geometric mean probability
+    /// of the generated tokens, i.e. 1 / perplexity
+    pub confidence: f32,
+    /// How well the feedback aligns with the rubric (0.0-1.0)
+    pub rubric_alignment: f32,
+    /// Tokens generated
+    pub tokens_generated: usize,
+    /// Inference time in milliseconds
+    pub inference_time_ms: u64,
+    /// Sentences of the feedback the model was least sure about
+    #[serde(default)]
+    pub low_confidence_spans: Vec<ConfidenceSpan>,
+    /// Parameters the output was sampled with, including the seed used
+    ///
+    /// Sending them back with the same content, rubric and model
+    /// reproduces the output exactly, unless `batched` is set.
+    #[serde(default)]
+    pub params: GenerationParams,
+    /// Whether the request was decoded in one batch with other requests
+    ///
+    /// A batch pads its prompts and changes the shape of the model's
+    /// matrix products, so batched output can differ from what the same
+    /// request gives decoded alone. The jail decodes requests that set
+    /// `params.seed` alone, so a replay is never batched.
+    #[serde(default)]
+    pub batched: bool,
+    /// How the answer was fitted into the model's context window
+    #[serde(default)]
+    pub context: ContextUsage,
+    /// Model that generated the feedback
+    #[serde(default)]
+    pub model: ModelInfo,
+}
+
+/// Identity of the model that generated a piece of feedback
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelInfo {
+    /// Id the model is registered under in the jail
+    pub id: String,
+    /// Revision of the weights, as given in the jail's model registry
+    pub revision: String,
+    /// BLAKE3 hash of the weights, checked against the jail's signed
+    /// manifest before loading; empty if the jail does not verify models
+    pub hash: String,
+}
+
+/// How an answer was fitted into the model's context window
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextUsage {
+    /// Size of the model's context window, in tokens
+    pub context_length: usize,
+    /// Tokens in the longest prompt sent to the model
+    pub prompt_tokens: usize,
+    /// Parts the answer was split into; 1 when it fit whole
+    pub sections: usize,
+    /// Whether part of the answer was left out and never marked
+    pub truncated: bool,
+    /// Bytes of the answer that were left out
+    pub omitted_bytes: usize,
+}
+
+/// A sentence of generated feedback with low model confidence
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfidenceSpan {
+    /// Byte offset of the sentence start in the feedback text
+    pub start: usize,
+    /// Byte offset one past the sentence end in the feedback text
+    pub end: usize,
+    /// The sentence itself
+    pub text: String,
+    /// Geometric mean probability of the sentence's tokens (0.0-1.0)
+    pub confidence: f32,
+    /// Mean entropy of the model's next-token distribution, in nats
+    pub entropy: f32,
+}
+
+/// A single criterion from a rubric
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RubricCriterion {
+    pub number: u32,
+    pub description: String,
+    pub max_marks: Option<f32>,
+}
+
+/// Score for a single rubric criterion
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CriterionScore {
+    pub criterion_number: u32,
+    pub criterion_text: String,
+    pub score: f32,
+    pub max_score: f32,
+    pub feedback: String,
+}
+
+fn default_question_number() -> u32 {
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_ping_wire_format() {
+        let msg = IPCMessage::Ping { timestamp: 42 };
+        let value = serde_json::to_value(&msg).unwrap();
+
+        assert_eq!(value, json!({"type": "Ping", "payload": {"timestamp": 42}}));
+    }
+
+    #[test]
+    fn test_shutdown_wire_format() {
+        let value = serde_json::to_value(IPCMessage::Shutdown).unwrap();
+        assert_eq!(value, json!({"type": "Shutdown"}));
+
+        let decoded: IPCMessage = serde_json::from_str(r#"{"type":"Shutdown"}"#).unwrap();
+        assert!(matches!(decoded, IPCMessage::Shutdown));
+    }
+
+    #[test]
+    fn test_feedback_request_defaults() {
+        let line = r#"{"type":"FeedbackRequest","payload":{"request_id":"r1","content":"answer","rubric":"rubric","criteria":[]}}"#;
+        let decoded: IPCMessage = serde_json::from_str(line).unwrap();
+
+        match decoded {
+            IPCMessage::FeedbackRequest {
+                question_number,
+                params,
+                prompt,
+                ..
+            } => {
+                assert_eq!(question_number, 1);
+                assert_eq!(params, GenerationParams::default());
+                assert_eq!(prompt, PromptOptions::default());
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_partial_generation_params() {
+        let params: GenerationParams = serde_json::from_str(r#"{"max_tokens":64}"#).unwrap();
+
+        assert_eq!(params.max_tokens, 64);
+        assert_eq!(params.temperature, 0.7);
+        assert_eq!(params.top_p, 0.9);
+        assert_eq!(params.response_format, ResponseFormat::Text);
+    }
+
+    #[test]
+    fn test_response_format_wire_format() {
+        let params: GenerationParams =
+            serde_json::from_str(r#"{"response_format":"rubric_json"}"#).unwrap();
+
+        assert_eq!(params.response_format, ResponseFormat::RubricJson);
+    }
+
+    #[test]
+    fn test_feedback_chunk_wire_format() {
+        let msg = IPCMessage::FeedbackChunk {
+            request_id: "r1".to_string(),
+            text: "Good ".to_string(),
+            tokens_generated: 2,
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "type": "FeedbackChunk",
+                "payload": {"request_id": "r1", "text": "Good ", "tokens_generated": 2}
+            })
+        );
+        assert_eq!(msg.request_id(), Some("r1"));
+    }
+
+    #[test]
+    fn test_error_kind_wire_format() {
+        let msg = IPCMessage::Error {
+            request_id: Some("r1".to_string()),
+            kind: ErrorKind::InvalidRequest,
+            message: "bad".to_string(),
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+
+        assert_eq!(value["payload"]["kind"], "invalid_request");
+        assert_eq!(value["payload"]["request_id"], "r1");
+    }
+
+    #[test]
+    fn test_cancel_wire_format() {
+        let msg = IPCMessage::Cancel {
+            request_id: "r1".to_string(),
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+
+        assert_eq!(value, json!({"type": "Cancel", "payload": {"request_id": "r1"}}));
+        assert_eq!(msg.request_id(), Some("r1"));
+
+        let timeout: ErrorKind = serde_json::from_str(r#""timeout""#).unwrap();
+        assert_eq!(timeout, ErrorKind::Timeout);
+        let cancelled: ErrorKind = serde_json::from_str(r#""cancelled""#).unwrap();
+        assert_eq!(cancelled, ErrorKind::Cancelled);
+    }
+
+    #[test]
+    fn test_set_framing_wire_format() {
+        let msg = IPCMessage::SetFraming {
+            framing: Framing::LengthPrefixed,
+            max_frame_size: 1024,
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "type": "SetFraming",
+                "payload": {"framing": "length_prefixed", "max_frame_size": 1024}
+            })
+        );
+        assert_eq!(msg.request_id(), None);
+    }
+
+    #[test]
+    fn test_hello_wire_format() {
+        let msg = IPCMessage::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities {
+                streaming: true,
+                max_batch_size: 8,
+                grammar: true,
+                models: vec!["formative".to_string()],
+            },
+        };
+        let value = serde_json::to_value(&msg).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "type": "Hello",
+                "payload": {
+                    "protocol_version": PROTOCOL_VERSION,
+                    "capabilities": {
+                        "streaming": true,
+                        "max_batch_size": 8,
+                        "grammar": true,
+                        "models": ["formative"]
+                    }
+                }
+            })
+        );
+        assert_eq!(msg.request_id(), None);
+
+        // A bare hello advertises nothing
+        let decoded: IPCMessage =
+            serde_json::from_str(r#"{"type":"Hello","payload":{"protocol_version":4}}"#).unwrap();
+        match decoded {
+            IPCMessage::Hello { capabilities, .. } => {
+                assert_eq!(capabilities, Capabilities::default());
+                assert!(capabilities.serves("anything"));
+            }
+            other => panic!("Expected Hello, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_without_request_id() {
+        let decoded: IPCMessage =
+            serde_json::from_str(r#"{"type":"Error","payload":{"message":"boom"}}"#).unwrap();
+
+        assert_eq!(decoded.request_id(), None);
+        match decoded {
+            IPCMessage::Error { kind, message, .. } => {
+                assert_eq!(kind, ErrorKind::ProcessingError);
+                assert_eq!(message, "boom");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+
+    #[test]
+    fn test_feedback_response_roundtrip() {
+        let msg = IPCMessage::FeedbackResponse {
+            request_id: "r1".to_string(),
+            feedback: "Good work".to_string(),
+            scores: vec![CriterionScore {
+                criterion_number: 1,
+                criterion_text: "Understanding".to_string(),
+                score: 8.0,
+                max_score: 10.0,
+                feedback: "Clear".to_string(),
+            }],
+            overall_grade: Some(80.0),
+            strengths: vec!["Clear structure".to_string()],
+            suggestions: Vec::new(),
+            metadata: InferenceMetadata {
+                confidence: 0.9,
+                rubric_alignment: 0.5,
+                tokens_generated: 12,
+                inference_time_ms: 30,
+                low_confidence_spans: vec![ConfidenceSpan {
+                    start: 0,
+                    end: 9,
+                    text: "Good work".to_string(),
+                    confidence: 0.3,
+                    entropy: 2.5,
+                }],
+                params: GenerationParams {
+                    seed: Some(7),
+                    ..Default::default()
+                },
+                batched: false,
+                context: ContextUsage {
+                    context_length: 4096,
+                    prompt_tokens: 3000,
+                    sections: 2,
+                    truncated: false,
+                    omitted_bytes: 0,
+                },
+                model: ModelInfo {
+                    id: "mistral-7b".to_string(),
+                    revision: "v0.2".to_string(),
+                    hash: String::new(),
+                },
+            },
+        };
+
+        let json = serde_json::to_string(&msg).unwrap();
+        let decoded: IPCMessage = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(decoded.request_id(), Some("r1"));
+        match decoded {
+            IPCMessage::FeedbackResponse {
+                scores,
+                overall_grade,
+                strengths,
+                metadata,
+                ..
+            } => {
+                assert_eq!(metadata.params.seed, Some(7));
+                assert_eq!(metadata.context.sections, 2);
+                assert_eq!(metadata.model.revision, "v0.2");
+                assert_eq!(scores.len(), 1);
+                assert_eq!(overall_grade, Some(80.0));
+                assert_eq!(strengths, vec!["Clear structure".to_string()]);
+                assert_eq!(metadata.tokens_generated, 12);
+                assert_eq!(metadata.low_confidence_spans[0].text, "Good work");
+            }
+            _ => panic!("Wrong message type"),
+        }
+    }
+}
@@ -508,6 +508,7 @@ pub fn generate_salt(length: usize) -> Vec<u8> {
 /// ```
 pub fn dilithium_keypair() -> (Vec<u8>, Vec<u8>) {
     use pqcrypto_dilithium::dilithium5;
+    use pqcrypto_traits::sign::{PublicKey, SecretKey};
     let (pk, sk) = dilithium5::keypair();
     (pk.as_bytes().to_vec(), sk.as_bytes().to_vec())
 }
@@ -524,7 +525,7 @@ pub fn dilithium_keypair() -> (Vec<u8>, Vec<u8>) {
 /// ```
 pub fn dilithium_sign(message: &[u8], secret_key: &[u8]) -> Result<Vec<u8>> {
     use pqcrypto_dilithium::dilithium5;
-    use pqcrypto_traits::sign::SecretKey;
+    use pqcrypto_traits::sign::{DetachedSignature, SecretKey};
 
     let sk = dilithium5::SecretKey::from_bytes(secret_key)
         .map_err(|_| SharedError::Crypto("Invalid Dilithium5 secret key".to_string()))?;
@@ -573,6 +574,7 @@ pub fn dilithium_verify(message: &[u8], signature: &[u8], public_key: &[u8]) ->
 /// ```
 pub fn kyber_keypair() -> (Vec<u8>, Vec<u8>) {
     use pqcrypto_kyber::kyber1024;
+    use pqcrypto_traits::kem::{PublicKey, SecretKey};
     let (pk, sk) = kyber1024::keypair();
     (pk.as_bytes().to_vec(), sk.as_bytes().to_vec())
 }
@@ -591,7 +593,7 @@ pub fn kyber_keypair() -> (Vec<u8>, Vec<u8>) {
 /// ```
 pub fn kyber_encapsulate(public_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
     use pqcrypto_kyber::kyber1024;
-    use pqcrypto_traits::kem::PublicKey;
+    use pqcrypto_traits::kem::{Ciphertext, PublicKey, SharedSecret};
 
     let pk = kyber1024::PublicKey::from_bytes(public_key)
         .map_err(|_| SharedError::Crypto("Invalid Kyber-1024 public key".to_string()))?;
@@ -613,7 +615,7 @@ pub fn kyber_encapsulate(public_key: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
 /// ```
 pub fn kyber_decapsulate(ciphertext: &[u8], secret_key: &[u8]) -> Result<Vec<u8>> {
     use pqcrypto_kyber::kyber1024;
-    use pqcrypto_traits::kem::{SecretKey, Ciphertext};
+    use pqcrypto_traits::kem::{SecretKey, Ciphertext, SharedSecret};
 
     let sk = kyber1024::SecretKey::from_bytes(secret_key)
         .map_err(|_| SharedError::Crypto("Invalid Kyber-1024 secret key".to_string()))?;
@@ -1,6 +1,13 @@
+use ai_jail::backend::InferenceBackend;
+use ai_jail::inference::InferenceEngine;
+use ai_jail::protocol::{InferenceRequest, ResponseFormat};
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::quantized_llama::ModelWeights;
 use criterion::{black_box, criterion_group, criterion_main, Criterion, BenchmarkId, Throughput};
+use std::io::Cursor;
 use std::time::{Duration, Instant};
-use sysinfo::{System, SystemExt};
+use sysinfo::System;
 
 /// Mock AI model for benchmarking
 /// In production, replace with actual Mistral/Candle implementation
@@ -52,6 +59,149 @@ fn memory_usage(&self) -> usize {
     }
 }
 
+/// One-layer Llama-architecture model with random Q4_K weights
+///
+/// Small enough to run on any CPU, but a real transformer with a KV cache,
+/// so it measures the jail's generation loop rather than a simulation.
+/// Tokens are bytes and there is no EOS, so every run generates exactly
+/// `max_tokens` tokens.
+struct TinyModel {
+    weights: ModelWeights,
+    device: Device,
+}
+
+impl TinyModel {
+    const HIDDEN: usize = 256;
+    const FFN: usize = 512;
+    const VOCAB: usize = 256;
+    const HEADS: u32 = 4;
+
+    fn new() -> Self {
+        let device = Device::Cpu;
+        let quantized = |shape: (usize, usize)| {
+            let weights = Tensor::randn(0f32, 0.02, shape, &device).unwrap();
+            QTensor::quantize(&weights, GgmlDType::Q4K).unwrap()
+        };
+        let norm = || {
+            let weights = Tensor::ones(Self::HIDDEN, DType::F32, &device).unwrap();
+            QTensor::quantize(&weights, GgmlDType::F32).unwrap()
+        };
+
+        let tensors = [
+            ("token_embd.weight", quantized((Self::VOCAB, Self::HIDDEN))),
+            ("output_norm.weight", norm()),
+            ("output.weight", quantized((Self::VOCAB, Self::HIDDEN))),
+            ("blk.0.attn_q.weight", quantized((Self::HIDDEN, Self::HIDDEN))),
+            ("blk.0.attn_k.weight", quantized((Self::HIDDEN, Self::HIDDEN))),
+            ("blk.0.attn_v.weight", quantized((Self::HIDDEN, Self::HIDDEN))),
+            ("blk.0.attn_output.weight", quantized((Self::HIDDEN, Self::HIDDEN))),
+            ("blk.0.ffn_gate.weight", quantized((Self::FFN, Self::HIDDEN))),
+            ("blk.0.ffn_down.weight", quantized((Self::HIDDEN, Self::FFN))),
+            ("blk.0.ffn_up.weight", quantized((Self::FFN, Self::HIDDEN))),
+            ("blk.0.attn_norm.weight", norm()),
+            ("blk.0.ffn_norm.weight", norm()),
+        ];
+        let metadata = [
+            ("llama.attention.head_count", gguf_file::Value::U32(Self::HEADS)),
+            ("llama.attention.head_count_kv", gguf_file::Value::U32(Self::HEADS)),
+            ("llama.block_count", gguf_file::Value::U32(1)),
+            ("llama.embedding_length", gguf_file::Value::U32(Self::HIDDEN as u32)),
+            ("llama.rope.dimension_count", gguf_file::Value::U32(Self::HIDDEN as u32 / Self::HEADS)),
+            ("llama.attention.layer_norm_rms_epsilon", gguf_file::Value::F32(1e-5)),
+        ];
+
+        let metadata: Vec<_> = metadata.iter().map(|(k, v)| (*k, v)).collect();
+        let tensors: Vec<_> = tensors.iter().map(|(k, t)| (*k, t)).collect();
+        let mut buffer = Cursor::new(Vec::new());
+        gguf_file::write(&mut buffer, &metadata, &tensors).unwrap();
+
+        buffer.set_position(0);
+        let content = gguf_file::Content::read(&mut buffer).unwrap();
+        let weights = ModelWeights::from_gguf(content, &mut buffer, &device).unwrap();
+
+        Self { weights, device }
+    }
+}
+
+impl InferenceBackend for TinyModel {
+    fn name(&self) -> &'static str {
+        "tiny"
+    }
+
+    fn device(&self) -> &Device {
+        &self.device
+    }
+
+    fn encode(&self, text: &str, _add_special_tokens: bool) -> anyhow::Result<Vec<u32>> {
+        Ok(text.bytes().map(u32::from).collect())
+    }
+
+    fn decode(&self, tokens: &[u32], _skip_special_tokens: bool) -> anyhow::Result<String> {
+        let bytes: Vec<u8> = tokens.iter().map(|&t| t as u8).collect();
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn eos_token_id(&self) -> Option<u32> {
+        Some(u32::MAX)
+    }
+
+    fn vocab_size(&self) -> usize {
+        Self::VOCAB
+    }
+
+    fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> anyhow::Result<Tensor> {
+        Ok(self.weights.forward(input_ids, seqlen_offset)?.unsqueeze(1)?)
+    }
+
+    fn reset(&mut self) {}
+}
+
+/// Benchmark real generation throughput (tokens per second) with the KV cache
+fn bench_generation_tokens_per_sec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ai_generation_tiny_model");
+    group.sample_size(10);
+
+    let mut engine = InferenceEngine::new(Box::new(TinyModel::new()));
+
+    for max_tokens in [32, 128, 512] {
+        let request = InferenceRequest {
+            tma_content: "Explain how recursion terminates.".to_string(),
+            rubric: "Award marks for identifying the base case.".to_string(),
+            question_number: 1,
+            student_answer: None,
+            max_tokens,
+            temperature: 0.7,
+            top_p: 0.9,
+            criteria: vec![],
+            response_format: ResponseFormat::Text,
+            seed: None,
+            top_k: 0,
+            min_p: 0.0,
+            repetition_penalty: 1.1,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            stop_sequences: vec![],
+            prompt: Default::default(),
+            model: None,
+            timeout_ms: None,
+            token_limit: None,
+            cancel: Default::default(),
+        };
+
+        // Tokens per second is reported as elements per second
+        group.throughput(Throughput::Elements(max_tokens as u64));
+        group.bench_with_input(
+            BenchmarkId::new("tokens", max_tokens),
+            &request,
+            |b, request| {
+                b.iter(|| engine.generate(black_box(request)).unwrap());
+            },
+        );
+    }
+
+    group.finish();
+}
+
 /// Benchmark model loading time
 fn bench_model_loading(c: &mut Criterion) {
     let mut group = c.benchmark_group("ai_model_loading");
@@ -177,10 +327,12 @@ fn bench_batch_inference(c: &mut Criterion) {
 fn bench_prompt_encoding(c: &mut Criterion) {
     let mut group = c.benchmark_group("ai_prompt_encoding");
 
+    let medium = format!("Grade this TMA:\n{}\n\nProvide detailed feedback.", "Question: ".repeat(50));
+    let long = format!("Grade this TMA:\n{}\n\nProvide detailed feedback with examples.", "Lorem ipsum dolor sit amet. ".repeat(200));
     let prompts = vec![
         ("short", "Grade this TMA: What is 2+2?"),
-        ("medium", &format!("Grade this TMA:\n{}\n\nProvide detailed feedback.", "Question: ".repeat(50))),
-        ("long", &format!("Grade this TMA:\n{}\n\nProvide detailed feedback with examples.", "Lorem ipsum dolor sit amet. ".repeat(200))),
+        ("medium", medium.as_str()),
+        ("long", long.as_str()),
     ];
 
     for (name, prompt) in prompts {
@@ -202,7 +354,7 @@ fn bench_prompt_encoding(c: &mut Criterion) {
 fn bench_context_management(c: &mut Criterion) {
     let mut group = c.benchmark_group("ai_context_management");
 
-    let context_sizes = vec![512, 1024, 2048, 4096];
+    let context_sizes: Vec<usize> = vec![512, 1024, 2048, 4096];
 
     for size in context_sizes {
         group.bench_with_input(
@@ -211,7 +363,7 @@ fn bench_context_management(c: &mut Criterion) {
             |b, &size| {
                 b.iter(|| {
                     // Simulate context window sliding
-                    let mut context: Vec<u32> = (0..size).collect();
+                    let mut context: Vec<u32> = (0..size as u32).collect();
 
                     // Add new tokens
                     for i in 0..100 {
@@ -322,7 +474,8 @@ fn bench_device_comparison(c: &mut Criterion) {
         bench_context_management,
         bench_feedback_generation,
         bench_quantization_comparison,
-        bench_device_comparison
+        bench_device_comparison,
+        bench_generation_tokens_per_sec
 );
 
 criterion_main!(benches);
@@ -1,10 +1,12 @@
+use aws_core::events::{Event, EventStore, EventType, LmdbEventStore};
 use criterion::{black_box, criterion_group, criterion_main, Criterion, BenchmarkId, Throughput};
-use heed::{EnvOpenOptions, Database};
+use heed::{Database, Env, EnvOpenOptions};
 use heed::types::*;
 use std::time::Duration;
 use tempfile::TempDir;
 use rand::{Rng, thread_rng};
 use serde::{Serialize, Deserialize};
+use std::ops::Bound;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct TestEvent {
@@ -30,6 +32,23 @@ fn generate(id: usize, data_size: usize) -> Self {
     }
 }
 
+/// Open a 10GB environment in `dir` with one `events` database
+fn open_events_db(dir: &TempDir) -> (Env, Database<Str, SerdeBincode<TestEvent>>) {
+    let env = unsafe {
+        EnvOpenOptions::new()
+            .map_size(10 * 1024 * 1024 * 1024)
+            .max_dbs(10)
+            .open(dir.path())
+            .unwrap()
+    };
+
+    let mut wtxn = env.write_txn().unwrap();
+    let db = env.create_database(&mut wtxn, Some("events")).unwrap();
+    wtxn.commit().unwrap();
+
+    (env, db)
+}
+
 /// Benchmark write performance (events/sec)
 fn bench_lmdb_write(c: &mut Criterion) {
     let mut group = c.benchmark_group("lmdb_write");
@@ -40,13 +59,7 @@ fn bench_lmdb_write(c: &mut Criterion) {
             b.iter_batched(
                 || {
                     let dir = TempDir::new().unwrap();
-                    let env = EnvOpenOptions::new()
-                        .map_size(10 * 1024 * 1024 * 1024) // 10GB
-                        .max_dbs(10)
-                        .open(dir.path())
-                        .unwrap();
-
-                    let db: Database<Str, SerdeBincode<TestEvent>> = env.create_database(Some("events")).unwrap();
+                    let (env, db) = open_events_db(&dir);
                     let events: Vec<TestEvent> = (0..count).map(|i| TestEvent::generate(i, 256)).collect();
 
                     (env, db, events, dir)
@@ -75,13 +88,7 @@ fn bench_lmdb_read(c: &mut Criterion) {
         group.bench_with_input(BenchmarkId::from_parameter(count), count, |b, &count| {
             // Setup
             let dir = TempDir::new().unwrap();
-            let env = EnvOpenOptions::new()
-                .map_size(10 * 1024 * 1024 * 1024)
-                .max_dbs(10)
-                .open(dir.path())
-                .unwrap();
-
-            let db: Database<Str, SerdeBincode<TestEvent>> = env.create_database(Some("events")).unwrap();
+            let (env, db) = open_events_db(&dir);
 
             // Populate
             let mut wtxn = env.write_txn().unwrap();
@@ -110,13 +117,7 @@ fn bench_lmdb_range_query(c: &mut Criterion) {
     let mut group = c.benchmark_group("lmdb_range_query");
 
     let dir = TempDir::new().unwrap();
-    let env = EnvOpenOptions::new()
-        .map_size(10 * 1024 * 1024 * 1024)
-        .max_dbs(10)
-        .open(dir.path())
-        .unwrap();
-
-    let db: Database<Str, SerdeBincode<TestEvent>> = env.create_database(Some("events")).unwrap();
+    let (env, db) = open_events_db(&dir);
 
     // Populate with 10k records
     let mut wtxn = env.write_txn().unwrap();
@@ -138,7 +139,7 @@ fn bench_lmdb_range_query(c: &mut Criterion) {
                     let end_key = format!("evt_{:08}", range_size);
 
                     let mut count = 0;
-                    for result in db.range(&rtxn, &(start_key..end_key.as_str())).unwrap() {
+                    for result in db.range(&rtxn, &(Bound::Included(start_key), Bound::Excluded(end_key.as_str()))).unwrap() {
                         black_box(result.unwrap());
                         count += 1;
                     }
@@ -158,13 +159,7 @@ fn bench_lmdb_transaction(c: &mut Criterion) {
     let mut group = c.benchmark_group("lmdb_transaction");
 
     let dir = TempDir::new().unwrap();
-    let env = EnvOpenOptions::new()
-        .map_size(10 * 1024 * 1024 * 1024)
-        .max_dbs(10)
-        .open(dir.path())
-        .unwrap();
-
-    let db: Database<Str, SerdeBincode<TestEvent>> = env.create_database(Some("events")).unwrap();
+    let (env, db) = open_events_db(&dir);
 
     group.bench_function("write_txn_overhead", |b| {
         b.iter(|| {
@@ -206,14 +201,7 @@ fn bench_lmdb_size_vs_perf(c: &mut Criterion) {
                 b.iter_batched(
                     || {
                         let dir = TempDir::new().unwrap();
-                        let env = EnvOpenOptions::new()
-                            .map_size(10 * 1024 * 1024 * 1024)
-                            .max_dbs(10)
-                            .open(dir.path())
-                            .unwrap();
-
-                        let db: Database<Str, SerdeBincode<TestEvent>> =
-                            env.create_database(Some("events")).unwrap();
+                        let (env, db) = open_events_db(&dir);
 
                         // Pre-populate
                         let mut wtxn = env.write_txn().unwrap();
@@ -254,14 +242,7 @@ fn bench_lmdb_bulk_insert(c: &mut Criterion) {
                 b.iter_batched(
                     || {
                         let dir = TempDir::new().unwrap();
-                        let env = EnvOpenOptions::new()
-                            .map_size(10 * 1024 * 1024 * 1024)
-                            .max_dbs(10)
-                            .open(dir.path())
-                            .unwrap();
-
-                        let db: Database<Str, SerdeBincode<TestEvent>> =
-                            env.create_database(Some("events")).unwrap();
+                        let (env, db) = open_events_db(&dir);
 
                         let events: Vec<TestEvent> = (0..batch_size)
                             .map(|i| TestEvent::generate(i, 256))
@@ -293,14 +274,7 @@ fn bench_lmdb_value_size(c: &mut Criterion) {
         group.throughput(Throughput::Bytes(*size as u64));
         group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, &size| {
             let dir = TempDir::new().unwrap();
-            let env = EnvOpenOptions::new()
-                .map_size(10 * 1024 * 1024 * 1024)
-                .max_dbs(10)
-                .open(dir.path())
-                .unwrap();
-
-            let db: Database<Str, SerdeBincode<TestEvent>> =
-                env.create_database(Some("events")).unwrap();
+            let (env, db) = open_events_db(&dir);
 
             b.iter(|| {
                 let mut wtxn = env.write_txn().unwrap();
@@ -314,6 +288,106 @@ fn bench_lmdb_value_size(c: &mut Criterion) {
     group.finish();
 }
 
+/// Benchmark LmdbEventStore queries at 1M events: full scans against the
+/// aggregate key range and the secondary indexes
+fn bench_event_store_queries(c: &mut Criterion) {
+    const AGGREGATES: usize = 10_000;
+    const EVENTS_PER_AGGREGATE: u64 = 100;
+
+    let mut group = c.benchmark_group("event_store_queries_1m");
+    group.sample_size(10);
+    group.measurement_time(Duration::from_secs(30));
+
+    let dir = TempDir::new().unwrap();
+    let store = LmdbEventStore::new(dir.path(), Some(16 * 1024 * 1024 * 1024)).unwrap();
+
+    // Each aggregate is submitted, gets 98 feedback events and one grade,
+    // so grades are 1% of the log
+    for aggregate in 0..AGGREGATES {
+        let aggregate_id = format!("tma-{:06}", aggregate);
+        let events = (1..=EVENTS_PER_AGGREGATE)
+            .map(|version| {
+                let event_type = match version {
+                    1 => EventType::TMASubmitted {
+                        student_id: format!("student_{}", aggregate),
+                        module_code: "TM112".to_string(),
+                        question_number: 1,
+                        content_hash: hex::encode([aggregate as u8; 32]),
+                    },
+                    EVENTS_PER_AGGREGATE => EventType::GradeAssigned {
+                        tma_id: uuid::Uuid::new_v4(),
+                        grade: 70.0,
+                        max_grade: 100.0,
+                    },
+                    _ => EventType::FeedbackGenerated {
+                        tma_id: uuid::Uuid::new_v4(),
+                        feedback: "Good structure, expand the analysis".to_string(),
+                        rubric_scores: vec![],
+                    },
+                };
+                Event::new(event_type, aggregate_id.clone(), version)
+            })
+            .collect();
+        store.append_expected(&aggregate_id, 0, events).unwrap();
+    }
+
+    let target = format!("tma-{:06}", AGGREGATES / 2);
+
+    group.bench_function("aggregate/full_scan", |b| {
+        b.iter(|| {
+            let events: Vec<Event> = store
+                .get_all_events()
+                .unwrap()
+                .into_iter()
+                .filter(|e| e.aggregate_id == target)
+                .collect();
+            black_box(events)
+        });
+    });
+
+    group.bench_function("aggregate/key_range", |b| {
+        b.iter(|| black_box(store.get_events(&target).unwrap()));
+    });
+
+    group.bench_function("type/full_scan", |b| {
+        b.iter(|| {
+            let events: Vec<Event> = store
+                .get_all_events()
+                .unwrap()
+                .into_iter()
+                .filter(|e| e.event_type.name() == "GradeAssigned")
+                .collect();
+            black_box(events)
+        });
+    });
+
+    group.bench_function("type/index", |b| {
+        b.iter(|| black_box(store.get_events_by_type("GradeAssigned").unwrap()));
+    });
+
+    let events = store.get_events(&target).unwrap();
+    let from = events.first().unwrap().timestamp;
+    let to = events.last().unwrap().timestamp;
+
+    group.bench_function("time/full_scan", |b| {
+        b.iter(|| {
+            let events: Vec<Event> = store
+                .get_all_events()
+                .unwrap()
+                .into_iter()
+                .filter(|e| e.timestamp >= from && e.timestamp < to)
+                .collect();
+            black_box(events)
+        });
+    });
+
+    group.bench_function("time/index", |b| {
+        b.iter(|| black_box(store.get_events_between(from, to).unwrap()));
+    });
+
+    group.finish();
+}
+
 criterion_group!(
     name = benches;
     config = Criterion::default()
@@ -326,7 +400,8 @@ fn bench_lmdb_value_size(c: &mut Criterion) {
         bench_lmdb_transaction,
         bench_lmdb_size_vs_perf,
         bench_lmdb_bulk_insert,
-        bench_lmdb_value_size
+        bench_lmdb_value_size,
+        bench_event_store_queries
 );
 
 criterion_main!(benches);